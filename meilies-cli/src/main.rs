@@ -5,10 +5,10 @@ use log::error;
 use structopt::StructOpt;
 use tokio::prelude::*;
 
-use meilies::reqresp::Request;
+use meilies::reqresp::{Request, Response};
 use meilies::resp::{FromResp, RespValue};
 use meilies::stream::Stream as EsStream;
-use meilies_client::{paired_connect, sub_connect};
+use meilies_client::{group_connect, paired_connect, sub_connect};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "meilies-cli", about = "A basic cli for MeiliES.", author)]
@@ -51,11 +51,11 @@ fn main() {
     };
 
     let fut = match command {
-        Request::SubscribeAll { range } => {
+        Request::SubscribeAll { range, filter } => {
             let fut = sub_connect(addr)
                 .map_err(|e| error!("{}", e))
                 .and_then(move |(mut ctrl, msgs)| {
-                    ctrl.subscribe_to(EsStream::all(range));
+                    ctrl.subscribe_to(EsStream::all(range).with_filter(filter));
 
                     msgs.for_each(move |msg| {
                         match msg {
@@ -97,18 +97,91 @@ fn main() {
 
             Box::new(fut) as Box<dyn Future<Item = (), Error = ()> + Send>
         }
+        Request::SubscribeGroup { group, stream } => {
+            let fut = group_connect(addr, group, stream)
+                .map_err(|e| error!("{}", e))
+                .and_then(|(mut ctrl, msgs)| {
+                    // the events are only printed, they are acknowledged right away
+                    msgs.for_each(move |msg| {
+                        match msg {
+                            Ok(Response::Event { number, .. }) => {
+                                ctrl.ack(vec![number]);
+                                println!("{:?}", msg);
+                            }
+                            Ok(response) => println!("{:?}", response),
+                            Err(error) => eprintln!("Error: {}", error),
+                        }
+                        future::ok(())
+                    })
+                    .map_err(|e| error!("{:?}", e))
+                })
+                .and_then(|_| {
+                    println!("Connection closed by the server");
+                    Err(())
+                });
+
+            Box::new(fut) as Box<dyn Future<Item = (), Error = ()> + Send>
+        }
+        Request::Ack { .. } | Request::Nack { .. } => {
+            return error!("events can only be acknowledged by the member they were delivered to");
+        }
+        Request::ParkedEvents { group, stream } => {
+            let fut = paired_connect(addr)
+                .map_err(|e| error!("{}", e))
+                .and_then(|conn| {
+                    conn.parked_events(group, stream)
+                        .map_err(|e| error!("{}", e))
+                })
+                .map(|(numbers, _conn)| {
+                    let numbers: Vec<_> = numbers.into_iter().map(|n| n.0).collect();
+                    println!("{:?}", numbers)
+                });
+
+            Box::new(fut) as Box<dyn Future<Item = (), Error = ()> + Send>
+        }
+        Request::Unsubscribe { .. } => {
+            return error!("unsubscribe can only be sent on a connection that subscribed");
+        }
+        Request::Hello { .. } => {
+            return error!("the protocol is negotiated by the client when it connects");
+        }
+        Request::WithId { .. } => {
+            return error!("the request ids are assigned by the multiplexed client");
+        }
         Request::Publish {
             stream,
             event_name,
             event_data,
+            expected_version,
+            metadata,
+            ..
         } => {
+            let fut = paired_connect(addr)
+                .map_err(|e| error!("{}", e))
+                .and_then(move |conn| {
+                    conn.publish_with_metadata(
+                        stream,
+                        event_name,
+                        event_data,
+                        expected_version,
+                        metadata,
+                    )
+                    .map_err(|e| error!("{}", e))
+                })
+                .map(|(number, _conn)| println!("Event {} sent to the stream", number.0));
+
+            Box::new(fut) as Box<dyn Future<Item = (), Error = ()> + Send>
+        }
+        Request::PublishBatch { stream, events, .. } => {
             let fut = paired_connect(addr)
                 .map_err(|e| error!("{}", e))
                 .and_then(|conn| {
-                    conn.publish(stream, event_name, event_data)
+                    conn.publish_batch(stream, events)
                         .map_err(|e| error!("{}", e))
                 })
-                .map(|_conn| println!("Event sent to the stream"));
+                .map(|(first, last, _conn)| {
+                    println!("Events {} to {} sent to the stream", first.0, last.0)
+                });
 
             Box::new(fut) as Box<dyn Future<Item = (), Error = ()> + Send>
         }
@@ -120,6 +193,30 @@ fn main() {
 
             Box::new(fut) as Box<dyn Future<Item = (), Error = ()> + Send>
         }
+        Request::Read {
+            stream,
+            from,
+            count,
+            backwards,
+        } => {
+            let fut = paired_connect(addr)
+                .map_err(|e| error!("{}", e))
+                .and_then(move |conn| {
+                    conn.read(stream, from, count, backwards)
+                        .map_err(|e| error!("{}", e))
+                })
+                .map(|(events, next, _conn)| {
+                    for (number, event_name, event_data) in events {
+                        println!("{} {} {:?}", number.0, event_name, event_data);
+                    }
+                    match next {
+                        Some(number) => println!("next page starts at {}", number.0),
+                        None => println!("no more events"),
+                    }
+                });
+
+            Box::new(fut) as Box<dyn Future<Item = (), Error = ()> + Send>
+        }
         Request::StreamNames => {
             let fut = paired_connect(addr)
                 .map_err(|e| error!("{}", e))
@@ -128,6 +225,47 @@ fn main() {
 
             Box::new(fut) as Box<dyn Future<Item = (), Error = ()> + Send>
         }
+        Request::DeleteStream { stream, hard } => {
+            let fut = paired_connect(addr)
+                .map_err(|e| error!("{}", e))
+                .and_then(move |conn| {
+                    conn.delete_stream(stream, hard)
+                        .map_err(|e| error!("{}", e))
+                })
+                .map(|_conn| println!("Stream deleted"));
+
+            Box::new(fut) as Box<dyn Future<Item = (), Error = ()> + Send>
+        }
+        Request::TruncateBefore { stream, number } => {
+            let fut = paired_connect(addr)
+                .map_err(|e| error!("{}", e))
+                .and_then(move |conn| {
+                    conn.truncate_before(stream, number)
+                        .map_err(|e| error!("{}", e))
+                })
+                .map(move |_conn| println!("Events before {} removed", number.0));
+
+            Box::new(fut) as Box<dyn Future<Item = (), Error = ()> + Send>
+        }
+        Request::SetRetention { stream, policy } => {
+            let fut = paired_connect(addr)
+                .map_err(|e| error!("{}", e))
+                .and_then(move |conn| {
+                    conn.set_retention(stream, policy)
+                        .map_err(|e| error!("{}", e))
+                })
+                .map(|_conn| println!("Retention policy set"));
+
+            Box::new(fut) as Box<dyn Future<Item = (), Error = ()> + Send>
+        }
+        Request::Retention { stream } => {
+            let fut = paired_connect(addr)
+                .map_err(|e| error!("{}", e))
+                .and_then(|conn| conn.retention(stream).map_err(|e| error!("{}", e)))
+                .map(|(policy, _conn)| println!("{:?}", policy));
+
+            Box::new(fut) as Box<dyn Future<Item = (), Error = ()> + Send>
+        }
     };
 
     tokio::run(fut);
@@ -0,0 +1,160 @@
+use std::io;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use log::{error, warn};
+use meilies::reqresp::{ClientCodec, Request, RequestMsgError, Response, ResponseMsgError};
+use meilies::resp::RespMsgError;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::time::delay_for;
+use tokio_codec::{Decoder, Encoder};
+
+/// The number of bytes read from the socket at once.
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// A connection with a server, the requests and responses are
+/// encoded with the codec of the `meilies` crate.
+pub struct Connection {
+    socket: TcpStream,
+    codec: ClientCodec,
+    read_buffer: BytesMut,
+    write_buffer: BytesMut,
+}
+
+impl Connection {
+    async fn open(addr: SocketAddr) -> io::Result<Connection> {
+        let socket = TcpStream::connect(addr).await?;
+        if let Err(e) = socket.set_keepalive(Some(Duration::from_millis(50))) {
+            warn!("set_keepalive error; {}", e);
+        }
+
+        Ok(Connection {
+            socket,
+            codec: ClientCodec::default(),
+            read_buffer: BytesMut::new(),
+            write_buffer: BytesMut::new(),
+        })
+    }
+
+    /// Open a connection with a server using RESP2.
+    pub async fn connect(addr: SocketAddr) -> io::Result<Connection> {
+        Connection::open(addr).await
+    }
+
+    /// Open a connection with a server using RESP3,
+    /// or RESP2 if the server does not understand the `HELLO` command.
+    ///
+    /// Older servers close the connection on unknown commands, a second
+    /// connection is then opened to talk to them, `connect` only opens one.
+    pub async fn connect_resp3(addr: SocketAddr) -> io::Result<Connection> {
+        let mut connection = Connection::open(addr).await?;
+
+        if connection.hello().await? {
+            Ok(connection)
+        } else {
+            warn!("falling back to RESP2 with {}", addr);
+            Connection::open(addr).await
+        }
+    }
+
+    /// Asks the server to switch to RESP3, returns `false` if the server refused.
+    async fn hello(&mut self) -> io::Result<bool> {
+        let request = Request::Hello { protocol: 3 };
+
+        self.send(request)
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))?;
+
+        let response = self
+            .receive()
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))?;
+
+        match response {
+            Some(Ok(Response::Hello { .. })) => Ok(true),
+            Some(Ok(response)) => {
+                warn!("unexpected HELLO response {:?}", response);
+                Ok(false)
+            }
+            Some(Err(e)) => {
+                warn!("HELLO refused; {}", e);
+                Ok(false)
+            }
+            None => Ok(false),
+        }
+    }
+
+    pub async fn send(&mut self, request: Request) -> Result<(), RequestMsgError> {
+        self.codec.encode(request, &mut self.write_buffer)?;
+        let result = self.socket.write_all(&self.write_buffer).await;
+        self.write_buffer.clear();
+
+        Ok(result?)
+    }
+
+    /// Receives the next response, returns `None` if the server closed the connection.
+    pub async fn receive(&mut self) -> Result<Option<Result<Response, String>>, ResponseMsgError> {
+        let mut chunk = [0; READ_CHUNK_SIZE];
+
+        loop {
+            if let Some(response) = self.codec.decode(&mut self.read_buffer)? {
+                return Ok(Some(response));
+            }
+
+            let read = self.socket.read(&mut chunk).await?;
+            if read == 0 {
+                return Ok(None);
+            }
+
+            self.read_buffer.extend_from_slice(&chunk[..read]);
+        }
+    }
+}
+
+/// Returns `true` if the error means the connection has been lost
+/// and a new one must be opened, not that the server misbehaved.
+pub fn is_connection_lost(error: &RespMsgError) -> bool {
+    match error {
+        RespMsgError::IoError(_) => true,
+        _ => false,
+    }
+}
+
+/// The delays to wait between two attempts to connect, they follow the fibonacci sequence.
+fn retry_delays() -> impl Iterator<Item = Duration> {
+    let mut delays = (100, 100);
+    std::iter::repeat_with(move || {
+        let delay = delays.0;
+        delays = (delays.1, delays.0 + delays.1);
+        Duration::from_millis(delay)
+    })
+    .take(50)
+}
+
+/// Open a connection with a server, trying again until it accepts it,
+/// the connection switches to RESP3 if `resp3` is `true`.
+pub async fn connect_with_retry(addr: SocketAddr, resp3: bool) -> io::Result<Connection> {
+    let mut delays = retry_delays();
+
+    loop {
+        warn!("Connecting to {}", addr);
+        let connection = if resp3 {
+            Connection::connect_resp3(addr).await
+        } else {
+            Connection::connect(addr).await
+        };
+
+        match connection {
+            Ok(connection) => return Ok(connection),
+            Err(e) => match delays.next() {
+                Some(delay) => {
+                    error!("Connection error with {}; {}", addr, e);
+                    delay_for(delay).await;
+                }
+                None => return Err(e),
+            },
+        }
+    }
+}
@@ -0,0 +1,11 @@
+//! An async/await client for MeiliES, built on tokio 0.2 and the standard futures.
+//!
+//! It offers the same connections as the `meilies-client` crate, which is built on
+//! futures 0.1, the requests and responses are encoded with the same codec.
+
+mod connection;
+mod paired;
+mod sub;
+
+pub use self::paired::{paired_connect, PairedConnection, PairedConnectionError};
+pub use self::sub::{sub_connect, sub_connect_resp3, ProtocolError, SubController, SubStream};
@@ -0,0 +1,491 @@
+use std::net::SocketAddr;
+use std::{fmt, io};
+
+use log::{error, info, warn};
+use meilies::reqresp::{self, Request, Response};
+use meilies::stream::{
+    EventData, EventId, EventName, EventNumber, ExpectedVersion, GroupName, NumberedEvent,
+    PublishMetadata, RetentionPolicy, StreamName,
+};
+
+use crate::connection::{connect_with_retry, is_connection_lost, Connection};
+
+/// Open a paired connection with a server.
+pub async fn paired_connect(addr: SocketAddr) -> io::Result<PairedConnection> {
+    PairedConnection::connect(addr).await
+}
+
+/// A paired connection returns a response to each message send, it is sequential.
+/// This connection is used to publish events to streams.
+pub struct PairedConnection {
+    addr: SocketAddr,
+    connection: Connection,
+    /// Whether a request has been sent and its reply not received yet, the future
+    /// of a request has been dropped before its end if set when sending another one.
+    pending: bool,
+}
+
+#[derive(Debug)]
+pub enum PairedConnectionError {
+    ServerSide(String),
+    ConnectionClosed,
+    RequestMsgError(reqresp::RequestMsgError),
+    ResponseMsgError(reqresp::ResponseMsgError),
+    InvalidServerResponse(Response),
+    WrongExpectedVersion {
+        stream: StreamName,
+        actual: Option<EventNumber>,
+    },
+}
+
+impl fmt::Display for PairedConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use PairedConnectionError::*;
+
+        match self {
+            ServerSide(error) => write!(f, "server side error: {}", error),
+            ConnectionClosed => write!(f, "connection closed"),
+            RequestMsgError(error) => write!(f, "invalid Request: {}", error),
+            ResponseMsgError(error) => write!(f, "invalid Response received: {}", error),
+            InvalidServerResponse(response) => {
+                write!(f, "invalid server response received: {:?}", response)
+            }
+            WrongExpectedVersion { stream, actual } => match actual {
+                Some(number) => write!(f, "wrong expected version, {} is at {}", stream, number.0),
+                None => write!(f, "wrong expected version, {} does not exist", stream),
+            },
+        }
+    }
+}
+
+impl PairedConnection {
+    /// Open a paired connection with a server.
+    pub async fn connect(addr: SocketAddr) -> io::Result<PairedConnection> {
+        let connection = connect_with_retry(addr, false).await?;
+        Ok(PairedConnection {
+            addr,
+            connection,
+            pending: false,
+        })
+    }
+
+    /// Sends a request and waits for its reply, the connection is opened again if it has
+    /// been lost. The request is only sent again on the new connection if `resend` is `true`,
+    /// it must be a request the server can handle twice without side effects.
+    ///
+    /// A request can be cancelled by dropping its future, a new connection is opened
+    /// when sending the next one: the cancelled request could have been partially
+    /// written and its reply would be read as the reply to the next request.
+    async fn request(
+        &mut self,
+        request: Request,
+        resend: bool,
+    ) -> Result<Result<Response, String>, PairedConnectionError> {
+        use PairedConnectionError::*;
+
+        if self.pending {
+            warn!(
+                "A request has been cancelled, reconnecting to {}",
+                self.addr
+            );
+            self.connection = connect_with_retry(self.addr, false)
+                .await
+                .map_err(|_| ConnectionClosed)?;
+        }
+
+        self.pending = true;
+
+        loop {
+            let reply = match self.connection.send(request.clone()).await {
+                Ok(()) => self.connection.receive().await.map_err(ResponseMsgError),
+                Err(error) => Err(RequestMsgError(error)),
+            };
+
+            let lost = match &reply {
+                Ok(Some(_)) => false,
+                Ok(None) => true,
+                Err(RequestMsgError(reqresp::RequestMsgError::RespMsgError(error)))
+                | Err(ResponseMsgError(reqresp::ResponseMsgError::RespMsgError(error))) => {
+                    is_connection_lost(error)
+                }
+                Err(_) => false,
+            };
+
+            if !lost {
+                self.pending = false;
+                return reply.and_then(|reply| reply.ok_or(ConnectionClosed));
+            }
+
+            error!("Connection lost with {}", self.addr);
+            self.connection = connect_with_retry(self.addr, false)
+                .await
+                .map_err(|_| ConnectionClosed)?;
+            info!("Successfully reconnected to {}", self.addr);
+
+            if !resend {
+                self.pending = false;
+                return Err(ConnectionClosed);
+            }
+
+            warn!("Connection lost before a reply was received, sending the request again");
+        }
+    }
+
+    /// Sends a request the server replies to with a simple `OK`.
+    async fn request_ok(&mut self, request: Request) -> Result<(), PairedConnectionError> {
+        use PairedConnectionError::*;
+
+        match self.request(request, false).await? {
+            Ok(Response::Ok) => Ok(()),
+            Ok(response) => Err(InvalidServerResponse(response)),
+            Err(error) => Err(ServerSide(error)),
+        }
+    }
+
+    /// Publish an event to a stream, specifying the event name and data.
+    ///
+    /// The event is only appended if the stream is at the expected version,
+    /// a `WrongExpectedVersion` error is returned otherwise.
+    ///
+    /// Returns the event number assigned to the event.
+    pub async fn publish(
+        &mut self,
+        stream: StreamName,
+        event_name: EventName,
+        event_data: EventData,
+        expected_version: ExpectedVersion,
+    ) -> Result<EventNumber, PairedConnectionError> {
+        let metadata = PublishMetadata::default();
+        self.publish_with_metadata(stream, event_name, event_data, expected_version, metadata)
+            .await
+    }
+
+    /// Publish an event to a stream along with its id, content type and headers,
+    /// an id is generated if not given and the default content type is used.
+    ///
+    /// The event is published again if the connection is lost before the server
+    /// replied, the server does not store an event twice with the same id.
+    ///
+    /// Returns the event number assigned to the event.
+    pub async fn publish_with_metadata(
+        &mut self,
+        stream: StreamName,
+        event_name: EventName,
+        event_data: EventData,
+        expected_version: ExpectedVersion,
+        mut metadata: PublishMetadata,
+    ) -> Result<EventNumber, PairedConnectionError> {
+        use PairedConnectionError::*;
+
+        if metadata.id.is_none() {
+            metadata.id = Some(EventId::new_v4());
+        }
+
+        let command = Request::Publish {
+            stream,
+            event_name,
+            event_data,
+            expected_version,
+            metadata,
+            reply_with_number: true,
+        };
+
+        match self.request(command, true).await? {
+            Ok(Response::Published { number, .. }) => Ok(number),
+            Ok(Response::WrongExpectedVersion { stream, actual }) => {
+                Err(WrongExpectedVersion { stream, actual })
+            }
+            Ok(response) => Err(InvalidServerResponse(response)),
+            Err(error) => Err(ServerSide(error)),
+        }
+    }
+
+    /// Publish multiple events to a stream, all of them or none are published.
+    ///
+    /// The batch is given an id and published again if the connection is lost
+    /// before the server replied, the server does not store a batch twice.
+    ///
+    /// Events are given contiguous event numbers,
+    /// returns the first and the last one.
+    pub async fn publish_batch(
+        &mut self,
+        stream: StreamName,
+        events: Vec<(EventName, EventData)>,
+    ) -> Result<(EventNumber, EventNumber), PairedConnectionError> {
+        use PairedConnectionError::*;
+
+        let command = Request::PublishBatch {
+            stream,
+            events,
+            id: Some(EventId::new_v4()),
+        };
+
+        match self.request(command, true).await? {
+            Ok(Response::PublishedBatch { first, last, .. }) => Ok((first, last)),
+            Ok(response) => Err(InvalidServerResponse(response)),
+            Err(error) => Err(ServerSide(error)),
+        }
+    }
+
+    /// Request the last event number that the stream is at.
+    ///
+    /// Returns `None` if the stream does not contain any event.
+    pub async fn last_event_number(
+        &mut self,
+        stream: StreamName,
+    ) -> Result<Option<EventNumber>, PairedConnectionError> {
+        use PairedConnectionError::*;
+
+        let command = Request::LastEventNumber { stream };
+
+        match self.request(command, true).await? {
+            Ok(Response::LastEventNumber { number, .. }) => Ok(number),
+            Ok(response) => Err(InvalidServerResponse(response)),
+            Err(error) => Err(ServerSide(error)),
+        }
+    }
+
+    /// Read a page of at most `count` events of a stream without subscribing to it,
+    /// starting at `from` and going towards the first event if `backwards`.
+    ///
+    /// Returns the events and the number the next page starts at,
+    /// `None` if there was no more event to read.
+    pub async fn read(
+        &mut self,
+        stream: StreamName,
+        from: EventNumber,
+        count: usize,
+        backwards: bool,
+    ) -> Result<(Vec<NumberedEvent>, Option<EventNumber>), PairedConnectionError> {
+        use PairedConnectionError::*;
+
+        let command = Request::Read {
+            stream,
+            from,
+            count,
+            backwards,
+        };
+
+        match self.request(command, true).await? {
+            Ok(Response::Events { events, next, .. }) => Ok((events, next)),
+            Ok(response) => Err(InvalidServerResponse(response)),
+            Err(error) => Err(ServerSide(error)),
+        }
+    }
+
+    /// Request the list of stream names
+    ///
+    /// Returns an empty Vec if the database does not contain any stream.
+    pub async fn stream_names(&mut self) -> Result<Vec<StreamName>, PairedConnectionError> {
+        use PairedConnectionError::*;
+
+        match self.request(Request::StreamNames, true).await? {
+            Ok(Response::StreamNames { streams }) => Ok(streams),
+            Ok(response) => Err(InvalidServerResponse(response)),
+            Err(error) => Err(ServerSide(error)),
+        }
+    }
+
+    /// Delete a stream, its subscribers receive a `StreamDeleted` response.
+    ///
+    /// A soft deleted stream can be published to again, its new events are numbered
+    /// after the deleted ones. A hard deleted stream can never be published to again.
+    pub async fn delete_stream(
+        &mut self,
+        stream: StreamName,
+        hard: bool,
+    ) -> Result<(), PairedConnectionError> {
+        self.request_ok(Request::DeleteStream { stream, hard })
+            .await
+    }
+
+    /// Remove the events of a stream numbered before `number`.
+    pub async fn truncate_before(
+        &mut self,
+        stream: StreamName,
+        number: EventNumber,
+    ) -> Result<(), PairedConnectionError> {
+        self.request_ok(Request::TruncateBefore { stream, number })
+            .await
+    }
+
+    /// Set how long the events of a stream are kept,
+    /// the server removes the older events in the background.
+    pub async fn set_retention(
+        &mut self,
+        stream: StreamName,
+        policy: RetentionPolicy,
+    ) -> Result<(), PairedConnectionError> {
+        self.request_ok(Request::SetRetention { stream, policy })
+            .await
+    }
+
+    /// Request the retention policy of a stream, unlimited if none has been set.
+    pub async fn retention(
+        &mut self,
+        stream: StreamName,
+    ) -> Result<RetentionPolicy, PairedConnectionError> {
+        use PairedConnectionError::*;
+
+        match self.request(Request::Retention { stream }, true).await? {
+            Ok(Response::Retention { policy, .. }) => Ok(policy),
+            Ok(response) => Err(InvalidServerResponse(response)),
+            Err(error) => Err(ServerSide(error)),
+        }
+    }
+
+    /// Request the numbers of the events of a stream a consumer group gave up on.
+    pub async fn parked_events(
+        &mut self,
+        group: GroupName,
+        stream: StreamName,
+    ) -> Result<Vec<EventNumber>, PairedConnectionError> {
+        use PairedConnectionError::*;
+
+        let command = Request::ParkedEvents { group, stream };
+
+        match self.request(command, true).await? {
+            Ok(Response::ParkedEvents { numbers, .. }) => Ok(numbers),
+            Ok(response) => Err(InvalidServerResponse(response)),
+            Err(error) => Err(ServerSide(error)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use std::{net, thread};
+
+    use bytes::BytesMut;
+    use meilies::reqresp::ServerCodec;
+    use tokio_codec::{Decoder, Encoder};
+
+    use super::*;
+
+    fn stream(name: &str) -> StreamName {
+        StreamName::new(name.to_owned()).unwrap()
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_all()
+            .build()
+            .unwrap();
+
+        runtime.block_on(future)
+    }
+
+    /// Starts a server replying to the requests with `reply`,
+    /// which is given the number of the connection they are received on.
+    fn server<F>(reply: F) -> SocketAddr
+    where
+        F: Fn(usize, Request) -> Result<Response, String> + Send + Sync + 'static,
+    {
+        let listener = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let reply = Arc::new(reply);
+
+        thread::spawn(move || {
+            for (number, socket) in listener.incoming().enumerate() {
+                let mut socket = socket.unwrap();
+                let reply = reply.clone();
+
+                thread::spawn(move || {
+                    let mut codec = ServerCodec::default();
+                    let mut buffer = BytesMut::new();
+                    let mut chunk = [0; 1024];
+
+                    loop {
+                        while let Some(request) = codec.decode(&mut buffer).unwrap() {
+                            let mut bytes = BytesMut::new();
+                            codec.encode(reply(number, request), &mut bytes).unwrap();
+                            if socket.write_all(&bytes).is_err() {
+                                return;
+                            }
+                        }
+
+                        match socket.read(&mut chunk) {
+                            Ok(0) | Err(_) => return,
+                            Ok(read) => buffer.extend_from_slice(&chunk[..read]),
+                        }
+                    }
+                });
+            }
+        });
+
+        addr
+    }
+
+    #[test]
+    fn send_requests_and_receive_replies() {
+        let addr = server(|_, request| match request {
+            Request::Publish { stream, .. } => Ok(Response::Published {
+                stream,
+                number: EventNumber(3),
+            }),
+            Request::LastEventNumber { stream } => Ok(Response::LastEventNumber {
+                stream,
+                number: None,
+            }),
+            Request::DeleteStream { .. } => Err("stream not found".to_owned()),
+            _ => Ok(Response::Ok),
+        });
+
+        block_on(async move {
+            let mut connection = paired_connect(addr).await.unwrap();
+
+            let name = EventName::new("created".to_owned()).unwrap();
+            let data = EventData(bytes::Bytes::from_static(b"hello"));
+            let number = connection
+                .publish(stream("order-1"), name, data, ExpectedVersion::Any)
+                .await
+                .unwrap();
+            assert_eq!(number, EventNumber(3));
+
+            let number = connection.last_event_number(stream("order-1")).await;
+            assert_eq!(number.unwrap(), None);
+
+            let deleted = connection.delete_stream(stream("order-1"), false).await;
+            match deleted {
+                Err(PairedConnectionError::ServerSide(error)) => {
+                    assert_eq!(error, "stream not found")
+                }
+                otherwise => panic!("unexpected reply {:?}", otherwise),
+            }
+        });
+    }
+
+    #[test]
+    fn do_not_read_the_reply_of_a_cancelled_request() {
+        // the first connection is slow to reply
+        let addr = server(|number, request| {
+            if number == 0 {
+                thread::sleep(Duration::from_millis(300));
+            }
+
+            match request {
+                Request::LastEventNumber { stream } => Ok(Response::LastEventNumber {
+                    stream,
+                    number: Some(EventNumber(number as u64)),
+                }),
+                _ => Ok(Response::Ok),
+            }
+        });
+
+        block_on(async move {
+            let mut connection = paired_connect(addr).await.unwrap();
+
+            let request = connection.last_event_number(stream("order-1"));
+            let timeout = tokio::time::timeout(Duration::from_millis(50), request);
+            assert!(timeout.await.is_err());
+
+            // the reply is received on a new connection, not the one of the cancelled request
+            let number = connection.last_event_number(stream("order-2")).await;
+            assert_eq!(number.unwrap(), Some(EventNumber(1)));
+        });
+    }
+}
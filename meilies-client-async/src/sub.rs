@@ -0,0 +1,453 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::{fmt, io};
+
+use futures::future::{select, Either};
+use futures::{pin_mut, Stream};
+use log::{error, info};
+use meilies::reqresp::{Request, RequestMsgError, Response, ResponseMsgError};
+use meilies::stream::{EventFilter, Stream as EsStream, StreamName};
+use tokio::sync::mpsc;
+
+use crate::connection::{connect_with_retry, is_connection_lost, Connection};
+
+/// The number of responses received and not yet read by the `SubStream`.
+const RESPONSES_BUFFER_SIZE: usize = 64;
+
+#[derive(Debug, Default)]
+struct StreamContext {
+    reconnected: bool,
+    position_start: Option<u64>,
+    position_end: Option<u64>,
+    filter: Option<EventFilter>,
+}
+
+/// The position reached in the subscribed streams,
+/// to resume them where they stopped when reconnecting.
+#[derive(Debug, Default)]
+struct Subscriptions {
+    state: HashMap<StreamName, StreamContext>,
+}
+
+impl Subscriptions {
+    fn request_sent(&mut self, request: &Request) {
+        if let Request::Subscribe { streams } = request {
+            for EsStream {
+                name,
+                range,
+                filter,
+            } in streams
+            {
+                let context = self.state.entry(name.clone()).or_default();
+                context.position_start = range.from();
+                context.position_end = range.to();
+                context.filter = filter.clone();
+            }
+        }
+
+        if let Request::Unsubscribe { streams } = request {
+            for name in streams {
+                self.state.remove(name);
+            }
+        }
+    }
+
+    /// Returns `false` if the response must not be returned to the user.
+    fn response_received(&mut self, response: &Result<Response, String>) -> bool {
+        match response {
+            Ok(Response::Event {
+                stream,
+                number,
+                link,
+                ..
+            }) => {
+                // the events read from a system stream, like `$all`,
+                // are resumed from their number in the system stream
+                let (stream, number) = match link {
+                    Some(link) => (&link.stream, link.number),
+                    None => (stream, *number),
+                };
+
+                // the events of a stream we unsubscribed from can still be in flight,
+                // they must not make us subscribe to it again when reconnecting.
+                if let Some(context) = self.state.get_mut(stream) {
+                    context.position_start = Some(number.0 + 1);
+                }
+            }
+            Ok(Response::Progress { stream, number }) => {
+                // the server checked the events filtered out up to this one,
+                // we resume after it, the user is not told about it
+                if let Some(context) = self.state.get_mut(stream) {
+                    context.position_start = Some(number.0 + 1);
+                }
+                return false;
+            }
+            Ok(Response::StreamDeleted { stream }) => {
+                // the server ended the subscription, we must not subscribe
+                // to the stream again when reconnecting
+                self.state.remove(stream);
+            }
+            Ok(Response::Subscribed { stream }) => {
+                // if we were already subscribed to a stream and we are reconnecting
+                // we do not return the message validating a subscription to the user
+                if let Some(context) = self.state.get_mut(stream) {
+                    if context.reconnected {
+                        context.reconnected = false;
+                        return false;
+                    }
+                }
+            }
+            _otherwise => (),
+        }
+
+        true
+    }
+
+    /// The request subscribing again to the streams with the appropriate event number.
+    fn resubscribe(&mut self) -> Option<Request> {
+        if self.state.is_empty() {
+            return None;
+        }
+
+        let mut streams = Vec::with_capacity(self.state.len());
+        for (name, context) in &mut self.state {
+            context.reconnected = true;
+            let stream =
+                EsStream::new_from_to(name.clone(), context.position_start, context.position_end);
+            streams.push(stream.with_filter(context.filter.clone()));
+        }
+
+        Some(Request::Subscribe { streams })
+    }
+}
+
+#[derive(Debug)]
+pub enum ProtocolError {
+    ResponseMsgError(ResponseMsgError),
+    RequestMsgError(RequestMsgError),
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProtocolError::ResponseMsgError(error) => write!(f, "{}", error),
+            ProtocolError::RequestMsgError(error) => write!(f, "{}", error),
+        }
+    }
+}
+
+enum Next {
+    Request(Option<Request>),
+    Response(Result<Option<Result<Response, String>>, ResponseMsgError>),
+}
+
+/// Owns the connection of the subscriptions, sends the requests of the `SubController`
+/// and forwards the responses to the `SubStream`, the connection is opened again and
+/// the streams subscribed to again when it is lost.
+struct Subscriber {
+    addr: SocketAddr,
+    resp3: bool,
+    connection: Connection,
+    subscriptions: Subscriptions,
+    requests: Option<mpsc::UnboundedReceiver<Request>>,
+    responses: mpsc::Sender<Result<Result<Response, String>, ProtocolError>>,
+}
+
+impl Subscriber {
+    async fn next(&mut self) -> Next {
+        let response = self.connection.receive();
+
+        // the responses are still read once the controllers are dropped
+        let requests = match &mut self.requests {
+            Some(requests) => requests,
+            None => return Next::Response(response.await),
+        };
+
+        let request = requests.recv();
+        pin_mut!(request);
+        pin_mut!(response);
+
+        match select(request, response).await {
+            Either::Left((request, _)) => Next::Request(request),
+            Either::Right((response, _)) => Next::Response(response),
+        }
+    }
+
+    async fn send(&mut self, request: Request) -> Result<(), ProtocolError> {
+        self.subscriptions.request_sent(&request);
+
+        match self.connection.send(request).await {
+            Err(RequestMsgError::RespMsgError(ref error)) if is_connection_lost(error) => {
+                error!("Connection error with {}; {}", self.addr, error);
+                self.reconnect().await
+            }
+            Err(error) => Err(ProtocolError::RequestMsgError(error)),
+            Ok(()) => Ok(()),
+        }
+    }
+
+    async fn reconnect(&mut self) -> Result<(), ProtocolError> {
+        // the server could not be reached during all the attempts to connect
+        let lost = |e: io::Error| ProtocolError::RequestMsgError(e.into());
+
+        loop {
+            self.connection = connect_with_retry(self.addr, self.resp3)
+                .await
+                .map_err(lost)?;
+            info!("Successfully reconnected to {}", self.addr);
+
+            // Now that a new connection has been successfully established
+            // we can re-send our subscriptions with the appropriate event number.
+            let request = match self.subscriptions.resubscribe() {
+                Some(request) => request,
+                None => return Ok(()),
+            };
+
+            match self.connection.send(request).await {
+                Err(RequestMsgError::RespMsgError(ref error)) if is_connection_lost(error) => {
+                    error!("Connection error with {}; {}", self.addr, error);
+                }
+                Err(error) => return Err(ProtocolError::RequestMsgError(error)),
+                Ok(()) => return Ok(()),
+            }
+        }
+    }
+
+    async fn run(mut self) -> Result<(), ProtocolError> {
+        loop {
+            match self.next().await {
+                Next::Request(Some(request)) => self.send(request).await?,
+                Next::Request(None) => self.requests = None,
+                Next::Response(Ok(Some(response))) => {
+                    if self.subscriptions.response_received(&response) {
+                        // the user dropped the SubStream
+                        if self.responses.send(Ok(response)).await.is_err() {
+                            return Ok(());
+                        }
+                    }
+                }
+                Next::Response(Ok(None)) => {
+                    error!("Connection closed with {}", self.addr);
+                    self.reconnect().await?;
+                }
+                Next::Response(Err(ResponseMsgError::RespMsgError(ref error)))
+                    if is_connection_lost(error) =>
+                {
+                    error!("Connection error with {}; {}", self.addr, error);
+                    self.reconnect().await?;
+                }
+                Next::Response(Err(error)) => return Err(ProtocolError::ResponseMsgError(error)),
+            }
+        }
+    }
+}
+
+/// Open a sub connection with a server.
+///
+/// The returned `SubStream` can be polled from any task, the connection
+/// is owned by a task spawned on the tokio runtime.
+pub async fn sub_connect(addr: SocketAddr) -> io::Result<(SubController, SubStream)> {
+    sub_connect_with(addr, false).await
+}
+
+/// Open a sub connection with a server using RESP3, the events are received
+/// with their metadata and link if the server supports it.
+///
+/// The subscriptions to the system streams, like `$all`, are only resumed
+/// at their position on reconnection over RESP3, the position is their link.
+pub async fn sub_connect_resp3(addr: SocketAddr) -> io::Result<(SubController, SubStream)> {
+    sub_connect_with(addr, true).await
+}
+
+async fn sub_connect_with(addr: SocketAddr, resp3: bool) -> io::Result<(SubController, SubStream)> {
+    let connection = connect_with_retry(addr, resp3).await?;
+
+    let (sender, requests) = mpsc::unbounded_channel();
+    let (responses, receiver) = mpsc::channel(RESPONSES_BUFFER_SIZE);
+
+    let subscriber = Subscriber {
+        addr,
+        resp3,
+        connection,
+        subscriptions: Subscriptions::default(),
+        requests: Some(requests),
+        responses: responses.clone(),
+    };
+
+    tokio::spawn(async move {
+        if let Err(error) = subscriber.run().await {
+            let mut responses = responses;
+            if responses.send(Err(error)).await.is_err() {
+                error!("the SubStream has been dropped");
+            }
+        }
+    });
+
+    let controller = SubController { sender };
+    let sub_stream = SubStream { receiver };
+
+    Ok((controller, sub_stream))
+}
+
+/// A sub controller control which streams to connect to.
+#[derive(Clone)]
+pub struct SubController {
+    sender: mpsc::UnboundedSender<Request>,
+}
+
+impl SubController {
+    /// Ask the server to send events of the given stream.
+    pub fn subscribe_to(&self, stream: EsStream) {
+        let command = Request::Subscribe {
+            streams: vec![stream],
+        };
+
+        if let Err(e) = self.sender.send(command) {
+            error!("{}", e);
+        }
+    }
+
+    /// Ask the server to stop sending events of the given stream,
+    /// the stream will not be subscribed again on reconnection.
+    ///
+    /// Events that were already sent by the server can still be received.
+    pub fn unsubscribe_from(&self, stream: StreamName) {
+        let command = Request::Unsubscribe {
+            streams: vec![stream],
+        };
+
+        if let Err(e) = self.sender.send(command) {
+            error!("{}", e);
+        }
+    }
+}
+
+/// A Stream that returns every event received on all subscribed streams,
+/// it ends after returning an error.
+pub struct SubStream {
+    receiver: mpsc::Receiver<Result<Result<Response, String>, ProtocolError>>,
+}
+
+impl Stream for SubStream {
+    type Item = Result<Result<Response, String>, ProtocolError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Self::Item>> {
+        self.receiver.poll_recv(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use meilies::stream::{EventData, EventLink, EventName, EventNumber, ReadRange};
+
+    use super::*;
+
+    fn stream(name: &str) -> StreamName {
+        StreamName::new(name.to_owned()).unwrap()
+    }
+
+    fn event(stream: StreamName, number: u64, link: Option<EventLink>) -> Result<Response, String> {
+        Ok(Response::Event {
+            stream,
+            number: EventNumber(number),
+            event_name: EventName::new("created".to_owned()).unwrap(),
+            event_data: EventData(Vec::new().into()),
+            metadata: None,
+            link,
+        })
+    }
+
+    fn subscribe(subscriptions: &mut Subscriptions, stream: EsStream) {
+        let request = Request::Subscribe {
+            streams: vec![stream],
+        };
+        subscriptions.request_sent(&request);
+    }
+
+    #[test]
+    fn resume_after_the_last_event_received() {
+        let mut subscriptions = Subscriptions::default();
+        assert!(subscriptions.resubscribe().is_none());
+
+        let filter = EventFilter::new(vec!["created".to_owned()]).unwrap();
+        let order = EsStream::new(stream("order-1"), ReadRange::ReadFrom(0));
+        subscribe(&mut subscriptions, order.with_filter(Some(filter.clone())));
+        subscribe(&mut subscriptions, EsStream::all(ReadRange::ReadFrom(0)));
+
+        assert!(subscriptions.response_received(&event(stream("order-1"), 4, None)));
+
+        // the events of `$all` are resumed from their number in `$all`
+        let link = EventLink {
+            stream: StreamName::all(),
+            number: EventNumber(10),
+        };
+        assert!(subscriptions.response_received(&event(stream("user-1"), 2, Some(link))));
+
+        // the progress of the filtered subscriptions is not returned to the user
+        let progress = Ok(Response::Progress {
+            stream: stream("order-1"),
+            number: EventNumber(7),
+        });
+        assert!(!subscriptions.response_received(&progress));
+
+        let mut streams = match subscriptions.resubscribe() {
+            Some(Request::Subscribe { streams }) => streams,
+            otherwise => panic!("unexpected request {:?}", otherwise),
+        };
+        streams.sort_by(|a, b| a.name.cmp(&b.name));
+
+        assert_eq!(streams.len(), 2);
+        assert_eq!(streams[0].name, StreamName::all());
+        assert_eq!(streams[0].range.from(), Some(11));
+        assert_eq!(streams[1].name, stream("order-1"));
+        assert_eq!(streams[1].range.from(), Some(8));
+        assert_eq!(streams[1].filter, Some(filter));
+    }
+
+    #[test]
+    fn hide_the_subscriptions_made_again() {
+        let mut subscriptions = Subscriptions::default();
+        subscribe(
+            &mut subscriptions,
+            EsStream::new(stream("order-1"), ReadRange::ReadFrom(0)),
+        );
+
+        let subscribed = Ok(Response::Subscribed {
+            stream: stream("order-1"),
+        });
+        assert!(subscriptions.response_received(&subscribed));
+
+        assert!(subscriptions.resubscribe().is_some());
+        assert!(!subscriptions.response_received(&subscribed));
+        assert!(subscriptions.response_received(&subscribed));
+    }
+
+    #[test]
+    fn forget_the_deleted_and_unsubscribed_streams() {
+        let mut subscriptions = Subscriptions::default();
+        subscribe(
+            &mut subscriptions,
+            EsStream::new(stream("order-1"), ReadRange::ReadFrom(0)),
+        );
+        subscribe(
+            &mut subscriptions,
+            EsStream::new(stream("order-2"), ReadRange::ReadFrom(0)),
+        );
+
+        let deleted = Ok(Response::StreamDeleted {
+            stream: stream("order-1"),
+        });
+        assert!(subscriptions.response_received(&deleted));
+
+        let request = Request::Unsubscribe {
+            streams: vec![stream("order-2")],
+        };
+        subscriptions.request_sent(&request);
+
+        // the events still in flight do not subscribe again
+        assert!(subscriptions.response_received(&event(stream("order-2"), 1, None)));
+        assert!(subscriptions.resubscribe().is_none());
+    }
+}
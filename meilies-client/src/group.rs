@@ -0,0 +1,211 @@
+use std::io;
+use std::net::SocketAddr;
+
+use futures::stream::SplitStream;
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use log::{error, warn};
+use meilies::reqresp::{Request, RequestMsgError, Response};
+use meilies::resp::RespMsgError;
+use meilies::stream::{EventNumber, GroupName, StreamName};
+use tokio::sync::mpsc;
+use tokio_retry::Retry;
+
+use super::{connect, retry_strategy, ProtocolError, SteelConnection};
+
+/// A tokio Stream of the events delivered to a member of a consumer group,
+/// that joins the group again when the connection is lost.
+///
+/// It preferable to use `group_connect` to get a `GroupController` and `GroupStream` tuple.
+pub struct GroupEventStream {
+    group: GroupName,
+    stream: StreamName,
+    reconnected: bool,
+    connection: SteelConnection,
+}
+
+impl GroupEventStream {
+    fn connect(
+        addr: SocketAddr,
+        group: GroupName,
+        stream: StreamName,
+    ) -> impl Future<Item = GroupEventStream, Error = tokio_retry::Error<io::Error>> {
+        Retry::spawn(retry_strategy(), move || {
+            warn!("Connecting to {}", addr);
+            let group = group.clone();
+            let stream = stream.clone();
+            connect(&addr).map(move |connection| {
+                let connection = SteelConnection::new(addr, connection);
+                GroupEventStream {
+                    group,
+                    stream,
+                    reconnected: false,
+                    connection,
+                }
+            })
+        })
+    }
+
+    fn rejoin(&mut self) -> Result<(), ProtocolError> {
+        // the events that were not acknowledged before the connection
+        // was lost are delivered again, maybe to another member
+        self.reconnected = true;
+
+        let request = Request::SubscribeGroup {
+            group: self.group.clone(),
+            stream: self.stream.clone(),
+        };
+        self.start_send(request)?;
+        self.poll_complete()?;
+
+        Ok(())
+    }
+}
+
+impl Stream for GroupEventStream {
+    type Item = Result<Response, String>;
+    type Error = ProtocolError;
+
+    fn poll(&mut self) -> Result<Async<Option<Self::Item>>, Self::Error> {
+        let result = match self.connection.poll() {
+            Ok(Async::Ready(Some(Ok(Response::SubscribedGroup { .. })))) if self.reconnected => {
+                // the user already received the message validating the membership
+                self.reconnected = false;
+                return self.poll();
+            }
+            otherwise => otherwise,
+        };
+
+        if self.connection.has_been_reconnected() {
+            self.rejoin()?;
+        }
+
+        result.map_err(ProtocolError::ResponseMsgError)
+    }
+}
+
+impl Sink for GroupEventStream {
+    type SinkItem = Request;
+    type SinkError = ProtocolError;
+
+    fn start_send(
+        &mut self,
+        item: Self::SinkItem,
+    ) -> Result<AsyncSink<Self::SinkItem>, Self::SinkError> {
+        let result = self.connection.start_send(item);
+
+        if self.connection.has_been_reconnected() {
+            self.rejoin()?;
+        }
+
+        result.map_err(ProtocolError::RequestMsgError)
+    }
+
+    fn poll_complete(&mut self) -> Result<Async<()>, Self::SinkError> {
+        let result = self.connection.poll_complete();
+
+        if self.connection.has_been_reconnected() {
+            self.rejoin()?;
+        }
+
+        result.map_err(ProtocolError::RequestMsgError)
+    }
+}
+
+/// Open a connection with a server and join the consumer group of the stream.
+///
+/// The members of a group share the events of the stream, every event is delivered
+/// to a single member until it is acknowledged with the `GroupController`.
+pub fn group_connect(
+    addr: SocketAddr,
+    group: GroupName,
+    stream: StreamName,
+) -> impl Future<Item = (GroupController, GroupStream), Error = tokio_retry::Error<io::Error>> {
+    GroupEventStream::connect(addr, group.clone(), stream.clone())
+        .map_err(move |e| {
+            error!("impossible to connect to {}; {}", addr, e);
+            e
+        })
+        .map(move |connection| {
+            let (writer, reader) = connection.split();
+            let (mut sender, receiver) = mpsc::unbounded_channel();
+
+            let requests = receiver
+                .map_err(|e| {
+                    let error = RespMsgError::IoError(io::Error::new(io::ErrorKind::BrokenPipe, e));
+                    ProtocolError::RequestMsgError(RequestMsgError::RespMsgError(error))
+                })
+                .forward(writer)
+                .map_err(|e| error!("{:?}", e))
+                .map(|_| ());
+
+            tokio::spawn(requests);
+
+            let join = Request::SubscribeGroup {
+                group: group.clone(),
+                stream: stream.clone(),
+            };
+            if let Err(e) = sender.try_send(join) {
+                error!("{}", e);
+            }
+
+            let controller = GroupController {
+                group,
+                stream,
+                sender,
+            };
+            let group_stream = GroupStream { connection: reader };
+
+            (controller, group_stream)
+        })
+}
+
+/// A group controller acknowledges the events delivered to the member.
+#[derive(Clone)]
+pub struct GroupController {
+    group: GroupName,
+    stream: StreamName,
+    sender: mpsc::UnboundedSender<Request>,
+}
+
+impl GroupController {
+    /// Tell the server the events have been handled, they will not be delivered again.
+    pub fn ack(&mut self, numbers: Vec<EventNumber>) {
+        let command = Request::Ack {
+            group: self.group.clone(),
+            stream: self.stream.clone(),
+            numbers,
+        };
+
+        if let Err(e) = self.sender.try_send(command) {
+            error!("{}", e);
+        }
+    }
+
+    /// Tell the server the events could not be handled, they will be delivered
+    /// again to a member of the group until they are parked.
+    pub fn nack(&mut self, numbers: Vec<EventNumber>) {
+        let command = Request::Nack {
+            group: self.group.clone(),
+            stream: self.stream.clone(),
+            numbers,
+        };
+
+        if let Err(e) = self.sender.try_send(command) {
+            error!("{}", e);
+        }
+    }
+}
+
+/// A tokio Stream that returns every event delivered to the member of the group.
+pub struct GroupStream {
+    connection: SplitStream<GroupEventStream>,
+}
+
+impl Stream for GroupStream {
+    type Item = Result<Response, String>;
+    type Error = ProtocolError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.connection.poll()
+    }
+}
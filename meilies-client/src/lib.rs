@@ -2,26 +2,35 @@ use std::io;
 use std::net::SocketAddr;
 use std::time::Duration;
 
+use futures::future::{self, Either};
 use futures::stream::{SplitSink, SplitStream};
-use futures::Future;
+use futures::{Future, Sink, Stream};
 use log::warn;
-use meilies::reqresp::ClientCodec;
+use meilies::reqresp::{ClientCodec, Request, Response};
 use tokio::codec::{Decoder, Framed};
 use tokio::net::TcpStream;
 
+mod group;
+mod multiplexed;
 mod paired;
 mod steel_connection;
 mod sub;
 
-pub use self::paired::{paired_connect, PairedConnection};
+pub use self::group::{group_connect, GroupController, GroupStream};
+pub use self::multiplexed::{
+    multiplexed_connect, MultiplexedConnection, MultiplexedError, MultiplexedSubscription,
+};
+pub use self::paired::{
+    paired_connect, PairedConnection, PairedConnectionError, PipelinedEvent, PipelinedPublish,
+};
 use self::steel_connection::{retry_strategy, SteelConnection};
-pub use self::sub::{sub_connect, ProtocolError, SubController, SubStream};
+pub use self::sub::{sub_connect, sub_connect_resp3, ProtocolError, SubController, SubStream};
 
 pub type ClientConnection = Framed<TcpStream, ClientCodec>;
 pub type ClientConnectionWriter = SplitSink<Framed<TcpStream, ClientCodec>>;
 pub type ClientConnectionReader = SplitStream<Framed<TcpStream, ClientCodec>>;
 
-/// Open a framed connection with a server using RESP
+/// Open a framed connection with a server using RESP2.
 pub fn connect(addr: &SocketAddr) -> impl Future<Item = ClientConnection, Error = io::Error> {
     TcpStream::connect(addr).map(|socket| {
         let duration = Duration::from_millis(50);
@@ -32,3 +41,50 @@ pub fn connect(addr: &SocketAddr) -> impl Future<Item = ClientConnection, Error
         ClientCodec::default().framed(socket)
     })
 }
+
+/// Asks the server to switch to RESP3, returns `None` if the server refused.
+fn hello(
+    connection: ClientConnection,
+) -> impl Future<Item = Option<ClientConnection>, Error = io::Error> {
+    let request = Request::Hello { protocol: 3 };
+
+    connection
+        .send(request)
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e.to_string()))
+        .and_then(|connection| {
+            connection
+                .into_future()
+                .map_err(|(e, _)| io::Error::new(io::ErrorKind::InvalidData, e.to_string()))
+        })
+        .map(|(response, connection)| match response {
+            Some(Ok(Response::Hello { .. })) => Some(connection),
+            Some(Ok(response)) => {
+                warn!("unexpected HELLO response {:?}", response);
+                None
+            }
+            Some(Err(e)) => {
+                warn!("HELLO refused; {}", e);
+                None
+            }
+            None => None,
+        })
+}
+
+/// Open a framed connection with a server using RESP3,
+/// or RESP2 if the server does not understand the `HELLO` command.
+///
+/// Older servers close the connection on unknown commands, a second
+/// connection is then opened to talk to them, `connect` only opens one.
+pub fn connect_resp3(addr: &SocketAddr) -> impl Future<Item = ClientConnection, Error = io::Error> {
+    let addr = *addr;
+
+    connect(&addr)
+        .and_then(hello)
+        .and_then(move |connection| match connection {
+            Some(connection) => Either::A(future::ok(connection)),
+            None => {
+                warn!("falling back to RESP2 with {}", addr);
+                Either::B(connect(&addr))
+            }
+        })
+}
@@ -0,0 +1,684 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::{fmt, io, mem};
+
+use futures::sync::{mpsc, oneshot};
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
+use log::{error, warn};
+use meilies::reqresp::{Request, RequestMsgError, Response, ResponseMsgError};
+use meilies::stream::{
+    EventData, EventFilter, EventId, EventName, EventNumber, ExpectedVersion, NumberedEvent,
+    PublishMetadata, Stream as EsStream, StreamName,
+};
+use tokio_retry::Retry;
+
+use super::{connect_resp3, retry_strategy, SteelConnection};
+
+type Reply = Result<Response, MultiplexedError>;
+type EventSender = mpsc::UnboundedSender<Result<Response, String>>;
+
+/// Open a connection with a server that sends many requests at once and
+/// receives the events of the subscriptions along with their replies.
+///
+/// The connection talks RESP3 if the server supports it, the events of the system
+/// streams, like `$all`, are only sent with the link they are routed by to RESP3 clients.
+pub fn multiplexed_connect(
+    addr: SocketAddr,
+) -> impl Future<Item = MultiplexedConnection, Error = tokio_retry::Error<io::Error>> {
+    Retry::spawn(retry_strategy(), move || {
+        warn!("Connecting to {}", addr);
+        connect_resp3(&addr).map(move |connection| {
+            let (sender, receiver) = mpsc::unbounded();
+            let multiplexer = Multiplexer {
+                connection: SteelConnection::new_resp3(addr, connection),
+                commands: receiver,
+                commands_closed: false,
+                next_id: 0,
+                pending: HashMap::new(),
+                subscriptions: HashMap::new(),
+                outgoing: VecDeque::new(),
+            };
+
+            tokio::spawn(multiplexer);
+            MultiplexedConnection { commands: sender }
+        })
+    })
+}
+
+#[derive(Debug)]
+pub enum MultiplexedError {
+    ServerSide(String),
+    ConnectionClosed,
+    InvalidServerResponse(Response),
+    /// The subscriptions must be made with `subscribe` to receive their events.
+    UnsupportedRequest(Request),
+    WrongExpectedVersion {
+        stream: StreamName,
+        actual: Option<EventNumber>,
+    },
+}
+
+impl fmt::Display for MultiplexedError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use MultiplexedError::*;
+
+        match self {
+            ServerSide(error) => write!(f, "server side error: {}", error),
+            ConnectionClosed => write!(f, "connection closed"),
+            InvalidServerResponse(response) => {
+                write!(f, "invalid server response received: {:?}", response)
+            }
+            UnsupportedRequest(request) => {
+                write!(
+                    f,
+                    "unsupported request, use subscribe instead: {:?}",
+                    request
+                )
+            }
+            WrongExpectedVersion { stream, actual } => match actual {
+                Some(number) => write!(f, "wrong expected version, {} is at {}", stream, number.0),
+                None => write!(f, "wrong expected version, {} does not exist", stream),
+            },
+        }
+    }
+}
+
+enum Command {
+    Request(Request, oneshot::Sender<Reply>),
+    Subscribe(EsStream, EventSender),
+    Unsubscribe(StreamName),
+}
+
+/// A connection that can be cloned and used from many tasks at once.
+///
+/// Every request is sent with an id and its reply is routed back by the server
+/// with this id, the events are routed to the subscriptions by stream. A lost
+/// connection is opened again, the streams are subscribed to again and the
+/// requests that can be sent twice without harm, like the reads or the publishes
+/// of events and batches with an id, are sent again. The other requests fail.
+#[derive(Clone)]
+pub struct MultiplexedConnection {
+    commands: mpsc::UnboundedSender<Command>,
+}
+
+impl MultiplexedConnection {
+    /// Send a request and wait for its reply, a request replied with
+    /// an error by the server returns a `ServerSide` error.
+    ///
+    /// Subscriptions must be made with `subscribe`, their events would not be routed
+    /// otherwise, the subscribe requests return an `UnsupportedRequest` error.
+    pub fn request(
+        &self,
+        request: Request,
+    ) -> impl Future<Item = Response, Error = MultiplexedError> {
+        let reply = match request {
+            Request::SubscribeAll { .. }
+            | Request::Subscribe { .. }
+            | Request::SubscribeGroup { .. } => Err(MultiplexedError::UnsupportedRequest(request)),
+            request => {
+                let (sender, receiver) = oneshot::channel();
+                let sent = self
+                    .commands
+                    .unbounded_send(Command::Request(request, sender));
+
+                sent.map(|()| receiver)
+                    .map_err(|_| MultiplexedError::ConnectionClosed)
+            }
+        };
+
+        futures::future::result(reply).and_then(|receiver| {
+            receiver
+                .map_err(|_| MultiplexedError::ConnectionClosed)
+                .and_then(|reply| reply)
+        })
+    }
+
+    /// Publish an event to a stream, specifying the event name and data.
+    ///
+    /// Returns the event number assigned to the event.
+    pub fn publish(
+        &self,
+        stream: StreamName,
+        event_name: EventName,
+        event_data: EventData,
+        expected_version: ExpectedVersion,
+    ) -> impl Future<Item = EventNumber, Error = MultiplexedError> {
+        let metadata = PublishMetadata::default();
+        self.publish_with_metadata(stream, event_name, event_data, expected_version, metadata)
+    }
+
+    /// Publish an event to a stream along with its id, content type and headers,
+    /// an id is generated if not given to publish the event again if needed.
+    ///
+    /// Returns the event number assigned to the event.
+    pub fn publish_with_metadata(
+        &self,
+        stream: StreamName,
+        event_name: EventName,
+        event_data: EventData,
+        expected_version: ExpectedVersion,
+        mut metadata: PublishMetadata,
+    ) -> impl Future<Item = EventNumber, Error = MultiplexedError> {
+        use MultiplexedError::*;
+
+        if metadata.id.is_none() {
+            metadata.id = Some(EventId::new_v4());
+        }
+
+        let command = Request::Publish {
+            stream,
+            event_name,
+            event_data,
+            expected_version,
+            metadata,
+            reply_with_number: true,
+        };
+
+        self.request(command).and_then(|response| match response {
+            Response::Published { number, .. } => Ok(number),
+            Response::WrongExpectedVersion { stream, actual } => {
+                Err(WrongExpectedVersion { stream, actual })
+            }
+            response => Err(InvalidServerResponse(response)),
+        })
+    }
+
+    /// Request the last event number that the stream is at.
+    ///
+    /// Returns `None` if the stream does not contain any event.
+    pub fn last_event_number(
+        &self,
+        stream: StreamName,
+    ) -> impl Future<Item = Option<EventNumber>, Error = MultiplexedError> {
+        let command = Request::LastEventNumber { stream };
+
+        self.request(command).and_then(|response| match response {
+            Response::LastEventNumber { number, .. } => Ok(number),
+            response => Err(MultiplexedError::InvalidServerResponse(response)),
+        })
+    }
+
+    /// Read a page of at most `count` events of a stream without subscribing to it,
+    /// starting at `from` and going towards the first event if `backwards`.
+    ///
+    /// Returns the events and the number the next page starts at,
+    /// `None` if there was no more event to read.
+    pub fn read(
+        &self,
+        stream: StreamName,
+        from: EventNumber,
+        count: usize,
+        backwards: bool,
+    ) -> impl Future<Item = (Vec<NumberedEvent>, Option<EventNumber>), Error = MultiplexedError>
+    {
+        let command = Request::Read {
+            stream,
+            from,
+            count,
+            backwards,
+        };
+
+        self.request(command).and_then(|response| match response {
+            Response::Events { events, next, .. } => Ok((events, next)),
+            response => Err(MultiplexedError::InvalidServerResponse(response)),
+        })
+    }
+
+    /// Subscribe to a stream, the returned stream yields the `Subscribed` response
+    /// followed by the events of the stream, the `$all` stream included.
+    ///
+    /// Subscribing again to a stream ends its previous subscription.
+    pub fn subscribe(&self, stream: EsStream) -> MultiplexedSubscription {
+        let (sender, receiver) = mpsc::unbounded();
+        if self
+            .commands
+            .unbounded_send(Command::Subscribe(stream, sender))
+            .is_err()
+        {
+            error!("the multiplexed connection is closed");
+        }
+
+        MultiplexedSubscription { receiver }
+    }
+
+    /// Ask the server to stop sending events of the given stream.
+    pub fn unsubscribe(&self, stream: StreamName) {
+        if self
+            .commands
+            .unbounded_send(Command::Unsubscribe(stream))
+            .is_err()
+        {
+            error!("the multiplexed connection is closed");
+        }
+    }
+}
+
+/// A tokio Stream of the responses of a subscription made on a multiplexed connection.
+pub struct MultiplexedSubscription {
+    receiver: mpsc::UnboundedReceiver<Result<Response, String>>,
+}
+
+impl Stream for MultiplexedSubscription {
+    type Item = Result<Response, String>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        self.receiver.poll()
+    }
+}
+
+struct Pending {
+    request: Request,
+    reply: oneshot::Sender<Reply>,
+}
+
+struct SubscriptionContext {
+    reconnected: bool,
+    position_start: Option<u64>,
+    position_end: Option<u64>,
+    filter: Option<EventFilter>,
+    events: EventSender,
+}
+
+/// The requests that can be sent again after a reconnection without changing
+/// their outcome, the server does not store twice an event with the same id.
+fn can_be_resent(request: &Request) -> bool {
+    match request {
+        Request::Publish { metadata, .. } => metadata.id.is_some(),
+        Request::PublishBatch { id, .. } => id.is_some(),
+        Request::LastEventNumber { .. }
+        | Request::Read { .. }
+        | Request::StreamNames
+        | Request::Retention { .. }
+        | Request::ParkedEvents { .. } => true,
+        _ => false,
+    }
+}
+
+/// Owns the connection, sends the requests of the handles and routes the responses.
+struct Multiplexer {
+    connection: SteelConnection,
+    commands: mpsc::UnboundedReceiver<Command>,
+    /// All the handles on the connection have been dropped.
+    commands_closed: bool,
+    next_id: u64,
+    pending: HashMap<u64, Pending>,
+    subscriptions: HashMap<StreamName, SubscriptionContext>,
+    outgoing: VecDeque<Request>,
+}
+
+impl Multiplexer {
+    fn receive_commands(&mut self) {
+        while !self.commands_closed {
+            match self.commands.poll() {
+                Ok(Async::Ready(Some(command))) => self.command(command),
+                Ok(Async::Ready(None)) | Err(()) => self.commands_closed = true,
+                Ok(Async::NotReady) => break,
+            }
+        }
+    }
+
+    fn command(&mut self, command: Command) {
+        match command {
+            Command::Request(request, reply) => {
+                let id = self.next_id;
+                self.next_id += 1;
+
+                self.outgoing.push_back(Request::WithId {
+                    id,
+                    request: Box::new(request.clone()),
+                });
+                self.pending.insert(id, Pending { request, reply });
+            }
+            Command::Subscribe(stream, events) => {
+                if self.subscriptions.contains_key(&stream.name) {
+                    let streams = vec![stream.name.clone()];
+                    self.outgoing.push_back(Request::Unsubscribe { streams });
+                }
+
+                let context = SubscriptionContext {
+                    reconnected: false,
+                    position_start: stream.range.from(),
+                    position_end: stream.range.to(),
+                    filter: stream.filter.clone(),
+                    events,
+                };
+                self.subscriptions.insert(stream.name.clone(), context);
+
+                let streams = vec![stream];
+                self.outgoing.push_back(Request::Subscribe { streams });
+            }
+            Command::Unsubscribe(stream) => {
+                self.subscriptions.remove(&stream);
+
+                let streams = vec![stream];
+                self.outgoing.push_back(Request::Unsubscribe { streams });
+            }
+        }
+    }
+
+    /// Routes a reply to its request and an event to its subscription.
+    fn route(&mut self, response: Result<Response, String>) {
+        match response {
+            Ok(Response::Reply { id, reply }) => match self.pending.remove(&id) {
+                Some(pending) => {
+                    let reply = (*reply).map_err(MultiplexedError::ServerSide);
+                    let _ = pending.reply.send(reply);
+                }
+                None => warn!("reply to an unknown request {}", id),
+            },
+            Ok(Response::Event {
+                ref stream,
+                number,
+                ref link,
+                ..
+            }) => {
+                // the events read from a system stream, like `$all`,
+                // are routed to the subscription of the system stream
+                let (stream, number) = match link {
+                    Some(link) => (link.stream.clone(), link.number),
+                    None => (stream.clone(), number),
+                };
+
+                if let Some(context) = self.subscriptions.get_mut(&stream) {
+                    context.position_start = Some(number.0 + 1);
+                }
+                self.forward(stream, response);
+            }
+            Ok(Response::Progress { ref stream, number }) => {
+                // the server checked the events filtered out up to this one,
+                // the subscription is resumed after it but is not told about it
+                if let Some(context) = self.subscriptions.get_mut(stream) {
+                    context.position_start = Some(number.0 + 1);
+                }
+            }
+            Ok(Response::Subscribed { ref stream }) => {
+                // the subscriptions made again after a reconnection are not confirmed again
+                let reconnected = match self.subscriptions.get_mut(stream) {
+                    Some(context) => mem::replace(&mut context.reconnected, false),
+                    None => return,
+                };
+
+                if !reconnected {
+                    let stream = stream.clone();
+                    self.forward(stream, response);
+                }
+            }
+            Ok(Response::StreamDeleted { ref stream }) => {
+                let stream = stream.clone();
+                self.forward(stream.clone(), response);
+                self.subscriptions.remove(&stream);
+            }
+            Ok(Response::Unsubscribed { .. }) => (),
+            Ok(response) => warn!("unexpected response {:?}", response),
+            Err(error) => error!("server side error; {}", error),
+        }
+    }
+
+    /// Sends the response to the subscription of the stream, a dropped
+    /// subscription is ended on the server too.
+    fn forward(&mut self, stream: StreamName, response: Result<Response, String>) {
+        let sent = match self.subscriptions.get(&stream) {
+            Some(context) => context.events.unbounded_send(response).is_ok(),
+            None => return,
+        };
+
+        if !sent {
+            self.subscriptions.remove(&stream);
+            let streams = vec![stream];
+            self.outgoing.push_back(Request::Unsubscribe { streams });
+        }
+    }
+
+    /// The replies of the requests sent on the lost connection will never come,
+    /// the requests that can be are sent again along with the subscriptions.
+    fn reconnected(&mut self) {
+        let mut resent = VecDeque::new();
+        for (id, pending) in mem::replace(&mut self.pending, HashMap::new()) {
+            if can_be_resent(&pending.request) {
+                resent.push_back(Request::WithId {
+                    id,
+                    request: Box::new(pending.request.clone()),
+                });
+                self.pending.insert(id, pending);
+            } else {
+                let _ = pending.reply.send(Err(MultiplexedError::ConnectionClosed));
+            }
+        }
+
+        let mut streams = Vec::with_capacity(self.subscriptions.len());
+        for (name, context) in &mut self.subscriptions {
+            context.reconnected = true;
+            let stream =
+                EsStream::new_from_to(name.clone(), context.position_start, context.position_end);
+            streams.push(stream.with_filter(context.filter.clone()));
+        }
+
+        if !streams.is_empty() {
+            resent.push_back(Request::Subscribe { streams });
+        }
+
+        // the requests not sent yet are sent after the ones sent again
+        resent.extend(self.outgoing.drain(..));
+        self.outgoing = resent;
+    }
+
+    fn write_requests(&mut self) -> Result<(), RequestMsgError> {
+        while let Some(request) = self.outgoing.pop_front() {
+            if let AsyncSink::NotReady(request) = self.connection.start_send(request)? {
+                self.outgoing.push_front(request);
+                break;
+            }
+        }
+
+        self.connection.poll_complete()?;
+        Ok(())
+    }
+
+    /// Reads the responses available, returns `false` if the connection is closed.
+    fn read_responses(&mut self) -> Result<bool, ResponseMsgError> {
+        loop {
+            match self.connection.poll()? {
+                Async::Ready(Some(response)) => self.route(response),
+                Async::Ready(None) => return Ok(false),
+                Async::NotReady => return Ok(true),
+            }
+        }
+    }
+
+    fn fail_pending(&mut self) {
+        for (_, pending) in self.pending.drain() {
+            let _ = pending.reply.send(Err(MultiplexedError::ConnectionClosed));
+        }
+    }
+}
+
+impl Future for Multiplexer {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        self.receive_commands();
+
+        loop {
+            if let Err(e) = self.write_requests() {
+                error!("error sending requests; {}", e);
+                self.fail_pending();
+                return Err(());
+            }
+
+            match self.read_responses() {
+                Ok(true) => (),
+                Ok(false) => {
+                    self.fail_pending();
+                    return Ok(Async::Ready(()));
+                }
+                Err(e) => {
+                    error!("error receiving responses; {}", e);
+                    self.fail_pending();
+                    return Err(());
+                }
+            }
+
+            // the connection may have been opened again while sending or receiving
+            if self.connection.has_been_reconnected() {
+                self.reconnected();
+                continue;
+            }
+
+            break;
+        }
+
+        if self.commands_closed && self.pending.is_empty() && self.subscriptions.is_empty() {
+            return Ok(Async::Ready(()));
+        }
+
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(name: &str) -> StreamName {
+        StreamName::new(name.to_owned()).unwrap()
+    }
+
+    fn multiplexer() -> (Multiplexer, MultiplexedConnection) {
+        let (sender, receiver) = mpsc::unbounded();
+        let addr = "127.0.0.1:6480".parse().unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let socket = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let socket =
+            tokio::net::TcpStream::from_std(socket, &tokio::reactor::Handle::default()).unwrap();
+        let connection =
+            tokio::codec::Decoder::framed(meilies::reqresp::ClientCodec::default(), socket);
+
+        let multiplexer = Multiplexer {
+            connection: SteelConnection::new(addr, connection),
+            commands: receiver,
+            commands_closed: false,
+            next_id: 0,
+            pending: HashMap::new(),
+            subscriptions: HashMap::new(),
+            outgoing: VecDeque::new(),
+        };
+
+        (multiplexer, MultiplexedConnection { commands: sender })
+    }
+
+    #[test]
+    fn route_replies_and_events() {
+        let (mut multiplexer, connection) = multiplexer();
+
+        let mut first = connection.request(Request::StreamNames);
+        let mut second = connection.last_event_number(stream("order-1"));
+        let mut subscription = connection.subscribe(EsStream::new(
+            stream("order-1"),
+            meilies::stream::ReadRange::ReadFrom(0),
+        ));
+
+        futures::future::lazy(|| {
+            multiplexer.receive_commands();
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+
+        let ids: Vec<_> = multiplexer
+            .outgoing
+            .iter()
+            .filter_map(|request| match request {
+                Request::WithId { id, .. } => Some(*id),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(ids, vec![0, 1]);
+
+        // the replies come in any order
+        multiplexer.route(Ok(Response::Reply {
+            id: 1,
+            reply: Box::new(Ok(Response::LastEventNumber {
+                stream: stream("order-1"),
+                number: Some(EventNumber(4)),
+            })),
+        }));
+        multiplexer.route(Ok(Response::Subscribed {
+            stream: stream("order-1"),
+        }));
+        multiplexer.route(Ok(Response::Reply {
+            id: 0,
+            reply: Box::new(Err("whoops".to_owned())),
+        }));
+
+        match second.poll() {
+            Ok(Async::Ready(Some(EventNumber(4)))) => (),
+            otherwise => panic!("unexpected reply {:?}", otherwise),
+        }
+        match first.poll() {
+            Err(MultiplexedError::ServerSide(ref error)) if error == "whoops" => (),
+            otherwise => panic!("unexpected reply {:?}", otherwise),
+        }
+
+        // the subscriptions are made again after the last event received
+        multiplexer.outgoing.clear();
+        multiplexer.reconnected();
+        match multiplexer.outgoing.pop_front() {
+            Some(Request::Subscribe { streams }) => {
+                assert_eq!(streams[0].range.from(), Some(0));
+            }
+            otherwise => panic!("unexpected request {:?}", otherwise),
+        }
+
+        futures::future::lazy(move || {
+            match subscription.poll() {
+                Ok(Async::Ready(Some(Ok(Response::Subscribed { .. })))) => (),
+                otherwise => panic!("unexpected response {:?}", otherwise),
+            }
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+
+    #[test]
+    fn reply_to_acks_and_reject_subscriptions() {
+        let (mut multiplexer, connection) = multiplexer();
+
+        let mut ack = connection.request(Request::Ack {
+            group: meilies::stream::GroupName::new("billing".to_owned()).unwrap(),
+            stream: stream("order-1"),
+            numbers: vec![EventNumber(0)],
+        });
+        let mut subscribe = connection.request(Request::Subscribe {
+            streams: vec![EsStream::new(
+                stream("order-1"),
+                meilies::stream::ReadRange::ReadFrom(0),
+            )],
+        });
+
+        futures::future::lazy(|| {
+            multiplexer.receive_commands();
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+
+        // only the ack is sent to the server
+        assert_eq!(multiplexer.outgoing.len(), 1);
+        match subscribe.poll() {
+            Err(MultiplexedError::UnsupportedRequest(Request::Subscribe { .. })) => (),
+            otherwise => panic!("unexpected reply {:?}", otherwise),
+        }
+
+        multiplexer.route(Ok(Response::Reply {
+            id: 0,
+            reply: Box::new(Ok(Response::Ok)),
+        }));
+        match ack.poll() {
+            Ok(Async::Ready(Response::Ok)) => (),
+            otherwise => panic!("unexpected reply {:?}", otherwise),
+        }
+    }
+}
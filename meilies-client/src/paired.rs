@@ -1,14 +1,18 @@
+use std::collections::VecDeque;
 use std::net::SocketAddr;
 use std::{fmt, io};
 
-use futures::{Future, Sink, Stream};
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
 use log::warn;
 use meilies::reqresp::{Request, RequestMsgError};
 use meilies::reqresp::{Response, ResponseMsgError};
-use meilies::stream::{EventData, EventName, EventNumber, StreamName};
+use meilies::stream::{
+    EventData, EventId, EventName, EventNumber, ExpectedVersion, GroupName, NumberedEvent,
+    PublishMetadata, RetentionPolicy, StreamName,
+};
 use tokio_retry::Retry;
 
-use super::{connect, SteelConnection};
+use super::{connect, ProtocolError, SteelConnection};
 use crate::steel_connection::retry_strategy;
 
 /// Open a framed paired connection with a server.
@@ -31,6 +35,10 @@ pub enum PairedConnectionError {
     RequestMsgError(RequestMsgError),
     ResponseMsgError(ResponseMsgError),
     InvalidServerResponse(Response),
+    WrongExpectedVersion {
+        stream: StreamName,
+        actual: Option<EventNumber>,
+    },
 }
 
 impl fmt::Display for PairedConnectionError {
@@ -45,6 +53,19 @@ impl fmt::Display for PairedConnectionError {
             InvalidServerResponse(response) => {
                 write!(f, "invalid server response received: {:?}", response)
             }
+            WrongExpectedVersion { stream, actual } => match actual {
+                Some(number) => write!(f, "wrong expected version, {} is at {}", stream, number.0),
+                None => write!(f, "wrong expected version, {} does not exist", stream),
+            },
+        }
+    }
+}
+
+impl From<ProtocolError> for PairedConnectionError {
+    fn from(error: ProtocolError) -> PairedConnectionError {
+        match error {
+            ProtocolError::ResponseMsgError(e) => PairedConnectionError::ResponseMsgError(e),
+            ProtocolError::RequestMsgError(e) => PairedConnectionError::RequestMsgError(e),
         }
     }
 }
@@ -64,52 +85,172 @@ impl PairedConnection {
     }
 
     /// Publish an event to a stream, specifying the event name and data.
+    ///
+    /// The event is only appended if the stream is at the expected version,
+    /// a `WrongExpectedVersion` error is returned otherwise.
+    ///
+    /// Returns the event number assigned to the event.
     pub fn publish(
         self,
         stream: StreamName,
         event_name: EventName,
         event_data: EventData,
-    ) -> impl Future<Item = PairedConnection, Error = PairedConnectionError> {
+        expected_version: ExpectedVersion,
+    ) -> impl Future<Item = (EventNumber, PairedConnection), Error = PairedConnectionError> {
+        let metadata = PublishMetadata::default();
+        self.publish_with_metadata(stream, event_name, event_data, expected_version, metadata)
+    }
+
+    /// Publish an event to a stream along with its id, content type and headers,
+    /// an id is generated if not given and the default content type is used.
+    ///
+    /// The event is published again if the connection is lost before the server
+    /// replied, the server does not store an event twice with the same id.
+    ///
+    /// Returns the event number assigned to the event.
+    pub fn publish_with_metadata(
+        self,
+        stream: StreamName,
+        event_name: EventName,
+        event_data: EventData,
+        expected_version: ExpectedVersion,
+        mut metadata: PublishMetadata,
+    ) -> impl Future<Item = (EventNumber, PairedConnection), Error = PairedConnectionError> {
         use PairedConnectionError::*;
 
+        if metadata.id.is_none() {
+            metadata.id = Some(EventId::new_v4());
+        }
+
         let command = Request::Publish {
             stream,
             event_name,
             event_data,
+            expected_version,
+            metadata,
+            reply_with_number: true,
+        };
+
+        Resend::new(self.connection, command).and_then(|(reply, connection)| match reply {
+            Ok(Response::Published { number, .. }) => Ok((number, PairedConnection { connection })),
+            Ok(Response::WrongExpectedVersion { stream, actual }) => {
+                Err(WrongExpectedVersion { stream, actual })
+            }
+            Ok(response) => Err(InvalidServerResponse(response)),
+            Err(error) => Err(ServerSide(error)),
+        })
+    }
+
+    /// Publish the events of a stream without waiting for the reply of an event
+    /// before sending the next one, at most `max_in_flight` events are waiting
+    /// for their reply at the same time.
+    ///
+    /// The returned stream yields the result of every event in order, the events
+    /// are published in order too. The events waiting for their reply are published
+    /// again if the connection is lost, an id is generated for the events without one.
+    pub fn publish_pipelined<S>(self, events: S, max_in_flight: usize) -> PipelinedPublish<S>
+    where
+        S: Stream<Item = PipelinedEvent>,
+        S::Error: Into<PairedConnectionError>,
+    {
+        PipelinedPublish {
+            connection: self.connection,
+            events,
+            events_done: false,
+            max_in_flight: max_in_flight.max(1),
+            in_flight: VecDeque::new(),
+            sent: 0,
+        }
+    }
+
+    /// Publish multiple events to a stream, all of them or none are published.
+    ///
+    /// The batch is given an id and published again if the connection is lost
+    /// before the server replied, the server does not store a batch twice.
+    ///
+    /// Events are given contiguous event numbers,
+    /// returns the first and the last one.
+    pub fn publish_batch(
+        self,
+        stream: StreamName,
+        events: Vec<(EventName, EventData)>,
+    ) -> impl Future<Item = (EventNumber, EventNumber, PairedConnection), Error = PairedConnectionError>
+    {
+        use PairedConnectionError::*;
+
+        let command = Request::PublishBatch {
+            stream,
+            events,
+            id: Some(EventId::new_v4()),
         };
 
+        Resend::new(self.connection, command).and_then(|(reply, connection)| match reply {
+            Ok(Response::PublishedBatch { first, last, .. }) => {
+                Ok((first, last, PairedConnection { connection }))
+            }
+            Ok(response) => Err(InvalidServerResponse(response)),
+            Err(error) => Err(ServerSide(error)),
+        })
+    }
+
+    /// Request the last event number that the stream is at.
+    ///
+    /// Returns `None` if the stream does not contain any event.
+    pub fn last_event_number(
+        self,
+        stream: StreamName,
+    ) -> impl Future<
+        Item = (StreamName, Option<EventNumber>, PairedConnection),
+        Error = PairedConnectionError,
+    > {
+        use PairedConnectionError::*;
+
+        let command = Request::LastEventNumber { stream };
+
         self.connection
             .send(command)
             .map_err(RequestMsgError)
             .and_then(|framed| framed.into_future().map_err(|(e, _)| ResponseMsgError(e)))
             .and_then(|(first, connection)| match first.ok_or(ConnectionClosed)? {
-                Ok(Response::Ok) => Ok(PairedConnection { connection }),
+                Ok(Response::LastEventNumber { stream, number }) => {
+                    Ok((stream, number, PairedConnection { connection }))
+                }
                 Ok(response) => Err(InvalidServerResponse(response)),
                 Err(error) => Err(ServerSide(error)),
             })
     }
 
-    /// Request the last event number that the stream is at.
+    /// Read a page of at most `count` events of a stream without subscribing to it,
+    /// starting at `from` and going towards the first event if `backwards`.
     ///
-    /// Returns `None` if the stream does not contain any event.
-    pub fn last_event_number(
+    /// Returns the events and the number the next page starts at,
+    /// `None` if there was no more event to read.
+    pub fn read(
         self,
         stream: StreamName,
+        from: EventNumber,
+        count: usize,
+        backwards: bool,
     ) -> impl Future<
-        Item = (StreamName, Option<EventNumber>, PairedConnection),
+        Item = (Vec<NumberedEvent>, Option<EventNumber>, PairedConnection),
         Error = PairedConnectionError,
     > {
         use PairedConnectionError::*;
 
-        let command = Request::LastEventNumber { stream };
+        let command = Request::Read {
+            stream,
+            from,
+            count,
+            backwards,
+        };
 
         self.connection
             .send(command)
             .map_err(RequestMsgError)
             .and_then(|framed| framed.into_future().map_err(|(e, _)| ResponseMsgError(e)))
             .and_then(|(first, connection)| match first.ok_or(ConnectionClosed)? {
-                Ok(Response::LastEventNumber { stream, number }) => {
-                    Ok((stream, number, PairedConnection { connection }))
+                Ok(Response::Events { events, next, .. }) => {
+                    Ok((events, next, PairedConnection { connection }))
                 }
                 Ok(response) => Err(InvalidServerResponse(response)),
                 Err(error) => Err(ServerSide(error)),
@@ -139,4 +280,320 @@ impl PairedConnection {
                 Err(error) => Err(ServerSide(error)),
             })
     }
+
+    /// Delete a stream, its subscribers receive a `StreamDeleted` response.
+    ///
+    /// A soft deleted stream can be published to again, its new events are numbered
+    /// after the deleted ones. A hard deleted stream can never be published to again.
+    pub fn delete_stream(
+        self,
+        stream: StreamName,
+        hard: bool,
+    ) -> impl Future<Item = PairedConnection, Error = PairedConnectionError> {
+        let command = Request::DeleteStream { stream, hard };
+        self.request_ok(command)
+    }
+
+    /// Remove the events of a stream numbered before `number`.
+    pub fn truncate_before(
+        self,
+        stream: StreamName,
+        number: EventNumber,
+    ) -> impl Future<Item = PairedConnection, Error = PairedConnectionError> {
+        let command = Request::TruncateBefore { stream, number };
+        self.request_ok(command)
+    }
+
+    /// Set how long the events of a stream are kept,
+    /// the server removes the older events in the background.
+    pub fn set_retention(
+        self,
+        stream: StreamName,
+        policy: RetentionPolicy,
+    ) -> impl Future<Item = PairedConnection, Error = PairedConnectionError> {
+        let command = Request::SetRetention { stream, policy };
+        self.request_ok(command)
+    }
+
+    /// Request the retention policy of a stream, unlimited if none has been set.
+    pub fn retention(
+        self,
+        stream: StreamName,
+    ) -> impl Future<Item = (RetentionPolicy, PairedConnection), Error = PairedConnectionError>
+    {
+        use PairedConnectionError::*;
+
+        let command = Request::Retention { stream };
+
+        self.connection
+            .send(command)
+            .map_err(RequestMsgError)
+            .and_then(|framed| framed.into_future().map_err(|(e, _)| ResponseMsgError(e)))
+            .and_then(|(first, connection)| match first.ok_or(ConnectionClosed)? {
+                Ok(Response::Retention { policy, .. }) => {
+                    Ok((policy, PairedConnection { connection }))
+                }
+                Ok(response) => Err(InvalidServerResponse(response)),
+                Err(error) => Err(ServerSide(error)),
+            })
+    }
+
+    /// Request the numbers of the events of a stream a consumer group gave up on.
+    pub fn parked_events(
+        self,
+        group: GroupName,
+        stream: StreamName,
+    ) -> impl Future<Item = (Vec<EventNumber>, PairedConnection), Error = PairedConnectionError>
+    {
+        use PairedConnectionError::*;
+
+        let command = Request::ParkedEvents { group, stream };
+
+        self.connection
+            .send(command)
+            .map_err(RequestMsgError)
+            .and_then(|framed| framed.into_future().map_err(|(e, _)| ResponseMsgError(e)))
+            .and_then(|(first, connection)| match first.ok_or(ConnectionClosed)? {
+                Ok(Response::ParkedEvents { numbers, .. }) => {
+                    Ok((numbers, PairedConnection { connection }))
+                }
+                Ok(response) => Err(InvalidServerResponse(response)),
+                Err(error) => Err(ServerSide(error)),
+            })
+    }
+
+    /// Sends a request the server replies to with a simple `OK`.
+    fn request_ok(
+        self,
+        command: Request,
+    ) -> impl Future<Item = PairedConnection, Error = PairedConnectionError> {
+        use PairedConnectionError::*;
+
+        self.connection
+            .send(command)
+            .map_err(RequestMsgError)
+            .and_then(|framed| framed.into_future().map_err(|(e, _)| ResponseMsgError(e)))
+            .and_then(|(first, connection)| match first.ok_or(ConnectionClosed)? {
+                Ok(Response::Ok) => Ok(PairedConnection { connection }),
+                Ok(response) => Err(InvalidServerResponse(response)),
+                Err(error) => Err(ServerSide(error)),
+            })
+    }
+}
+
+enum ResendState {
+    Sending,
+    Flushing,
+    Waiting,
+}
+
+/// Sends a request and waits for its reply, the request is sent again
+/// if the connection has been reconnected before the reply was received.
+///
+/// Must only be used with requests that the server can handle twice
+/// without side effects, like publishing an event or a batch with an id.
+struct Resend {
+    connection: Option<SteelConnection>,
+    request: Request,
+    state: ResendState,
+}
+
+impl Resend {
+    fn new(connection: SteelConnection, request: Request) -> Resend {
+        Resend {
+            connection: Some(connection),
+            request,
+            state: ResendState::Sending,
+        }
+    }
+}
+
+impl Future for Resend {
+    type Item = (Result<Response, String>, SteelConnection);
+    type Error = PairedConnectionError;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        use PairedConnectionError::*;
+
+        let connection = self.connection.as_mut().expect("cannot poll Resend twice");
+
+        loop {
+            match self.state {
+                ResendState::Sending => {
+                    let request = self.request.clone();
+                    match connection.start_send(request).map_err(RequestMsgError)? {
+                        AsyncSink::Ready => {
+                            // the request has been given to the current connection,
+                            // a previous reconnection does not concern it
+                            connection.has_been_reconnected();
+                            self.state = ResendState::Flushing;
+                        }
+                        AsyncSink::NotReady(_) => return Ok(Async::NotReady),
+                    }
+                }
+                ResendState::Flushing => {
+                    if let Async::NotReady = connection.poll_complete().map_err(RequestMsgError)? {
+                        return Ok(Async::NotReady);
+                    }
+                    self.state = ResendState::Waiting;
+                }
+                ResendState::Waiting => match connection.poll().map_err(ResponseMsgError)? {
+                    Async::Ready(Some(reply)) => {
+                        let connection = self.connection.take().unwrap();
+                        return Ok(Async::Ready((reply, connection)));
+                    }
+                    Async::Ready(None) => return Err(ConnectionClosed),
+                    Async::NotReady => (),
+                },
+            }
+
+            if connection.has_been_reconnected() {
+                warn!("Connection lost before a reply was received, sending the request again");
+                self.state = ResendState::Sending;
+            } else if let ResendState::Waiting = self.state {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+
+/// An event to publish with `PairedConnection::publish_pipelined`.
+#[derive(Debug, Clone)]
+pub struct PipelinedEvent {
+    pub stream: StreamName,
+    pub event_name: EventName,
+    pub event_data: EventData,
+    pub expected_version: ExpectedVersion,
+    pub metadata: PublishMetadata,
+}
+
+impl PipelinedEvent {
+    fn into_request(self) -> Request {
+        let mut metadata = self.metadata;
+        if metadata.id.is_none() {
+            metadata.id = Some(EventId::new_v4());
+        }
+
+        Request::Publish {
+            stream: self.stream,
+            event_name: self.event_name,
+            event_data: self.event_data,
+            expected_version: self.expected_version,
+            metadata,
+            reply_with_number: true,
+        }
+    }
+}
+
+/// A tokio Stream of the results of the events published with
+/// `PairedConnection::publish_pipelined`, in the order of the events.
+///
+/// The server replies to the requests of a connection in order, the first
+/// reply received is the one of the oldest event waiting for its reply.
+pub struct PipelinedPublish<S> {
+    connection: SteelConnection,
+    events: S,
+    events_done: bool,
+    max_in_flight: usize,
+    /// The requests waiting for their reply, in the order they are sent.
+    in_flight: VecDeque<Request>,
+    /// The number of requests of `in_flight` sent on the current connection.
+    sent: usize,
+}
+
+impl<S> PipelinedPublish<S> {
+    /// Sends the requests not sent yet, all of them are sent again in order
+    /// on the new connection if the connection has been lost.
+    fn send_requests(&mut self) -> Result<(), RequestMsgError> {
+        loop {
+            // a connection being reconnected is only given requests once reconnected,
+            // the requests must not be sent before the ones sent on the lost connection
+            if self.connection.is_connected() {
+                while self.sent < self.in_flight.len() {
+                    let request = self.in_flight[self.sent].clone();
+                    match self.connection.start_send(request)? {
+                        AsyncSink::Ready => self.sent += 1,
+                        AsyncSink::NotReady(_) => break,
+                    }
+                }
+            }
+
+            self.connection.poll_complete()?;
+
+            if self.connection.has_been_reconnected() {
+                self.reconnected();
+                continue;
+            }
+
+            return Ok(());
+        }
+    }
+
+    fn reconnected(&mut self) {
+        if !self.in_flight.is_empty() {
+            warn!(
+                "Connection lost before {} replies were received, sending the requests again",
+                self.in_flight.len()
+            );
+        }
+        self.sent = 0;
+    }
+}
+
+impl<S> Stream for PipelinedPublish<S>
+where
+    S: Stream<Item = PipelinedEvent>,
+    S::Error: Into<PairedConnectionError>,
+{
+    type Item = Result<EventNumber, PairedConnectionError>;
+    type Error = PairedConnectionError;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        use PairedConnectionError::*;
+
+        loop {
+            while !self.events_done && self.in_flight.len() < self.max_in_flight {
+                match self.events.poll().map_err(Into::into)? {
+                    Async::Ready(Some(event)) => self.in_flight.push_back(event.into_request()),
+                    Async::Ready(None) => self.events_done = true,
+                    Async::NotReady => break,
+                }
+            }
+
+            self.send_requests().map_err(RequestMsgError)?;
+
+            if self.sent == 0 {
+                if self.in_flight.is_empty() && self.events_done {
+                    return Ok(Async::Ready(None));
+                }
+                return Ok(Async::NotReady);
+            }
+
+            let reply = self.connection.poll().map_err(ResponseMsgError)?;
+            if self.connection.has_been_reconnected() {
+                self.reconnected();
+                continue;
+            }
+
+            let reply = match reply {
+                Async::Ready(Some(reply)) => reply,
+                Async::Ready(None) => return Err(ConnectionClosed),
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+
+            self.in_flight.pop_front();
+            self.sent -= 1;
+
+            let result = match reply {
+                Ok(Response::Published { number, .. }) => Ok(number),
+                Ok(Response::WrongExpectedVersion { stream, actual }) => {
+                    Err(WrongExpectedVersion { stream, actual })
+                }
+                Ok(response) => Err(InvalidServerResponse(response)),
+                Err(error) => Err(ServerSide(error)),
+            };
+
+            return Ok(Async::Ready(Some(result)));
+        }
+    }
 }
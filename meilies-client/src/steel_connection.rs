@@ -1,19 +1,22 @@
 use std::net::SocketAddr;
 use std::{io, mem};
 
-use futures::{Async, AsyncSink, Future, Sink, Stream};
+use futures::future::Either;
+use futures::{task, Async, AsyncSink, Future, Sink, Stream};
 use log::{error, info, warn};
 use meilies::reqresp::{Request, RequestMsgError, Response, ResponseMsgError};
 use tokio_retry::Error as TrError;
 use tokio_retry::{strategy::FibonacciBackoff, Retry};
 
-use super::{connect, ClientConnection};
+use super::{connect, connect_resp3, ClientConnection};
 
 /// A connection that try to reconnect when disconnected.
 ///
 /// It will keep the stream states (e.g. the stream position).
 pub struct SteelConnection {
     addr: SocketAddr,
+    /// Whether the connection is reopened using RESP3.
+    resp3: bool,
     reconnected: bool,
     conn_state: ConnState,
 }
@@ -28,15 +31,32 @@ impl SteelConnection {
     pub fn new(addr: SocketAddr, connection: ClientConnection) -> SteelConnection {
         SteelConnection {
             addr,
+            resp3: false,
             reconnected: false,
             conn_state: ConnState::Connected(connection),
         }
     }
 
+    /// Create a new steel connection that reconnects using RESP3.
+    pub fn new_resp3(addr: SocketAddr, connection: ClientConnection) -> SteelConnection {
+        SteelConnection {
+            resp3: true,
+            ..SteelConnection::new(addr, connection)
+        }
+    }
+
     /// Returns `true` if the connection has been reconnected since the last time called.
     pub fn has_been_reconnected(&mut self) -> bool {
         mem::replace(&mut self.reconnected, false)
     }
+
+    /// Returns `false` while the connection is being reconnected.
+    pub fn is_connected(&self) -> bool {
+        match self.conn_state {
+            ConnState::Connected(_) => true,
+            ConnState::Connecting(_) => false,
+        }
+    }
 }
 
 /// The retry strategy used to reconnect.
@@ -46,10 +66,15 @@ pub fn retry_strategy() -> std::iter::Take<FibonacciBackoff> {
 
 fn retry_future(
     addr: SocketAddr,
+    resp3: bool,
 ) -> Box<Future<Item = ClientConnection, Error = io::Error> + Send> {
     let retry = Retry::spawn(retry_strategy(), move || {
         warn!("Reconnecting to {}", addr);
-        connect(&addr)
+        if resp3 {
+            Either::A(connect_resp3(&addr))
+        } else {
+            Either::B(connect(&addr))
+        }
     })
     .map_err(|error| match error {
         TrError::OperationError(e) => e,
@@ -68,7 +93,7 @@ impl Stream for SteelConnection {
             ConnState::Connected(connection) => match connection.poll() {
                 Ok(Async::Ready(None)) => {
                     error!("Connection closed with {}", self.addr);
-                    self.conn_state = ConnState::Connecting(retry_future(self.addr));
+                    self.conn_state = ConnState::Connecting(retry_future(self.addr, self.resp3));
                     self.poll()
                 }
                 Err(error) => {
@@ -78,7 +103,8 @@ impl Stream for SteelConnection {
                     match error {
                         RespMsgError(IoError(e)) => {
                             error!("Connection error with {}; {}", self.addr, e);
-                            self.conn_state = ConnState::Connecting(retry_future(self.addr));
+                            self.conn_state =
+                                ConnState::Connecting(retry_future(self.addr, self.resp3));
                             self.poll()
                         }
                         otherwise => Err(otherwise),
@@ -108,11 +134,26 @@ impl Sink for SteelConnection {
         &mut self,
         item: Self::SinkItem,
     ) -> Result<AsyncSink<Self::SinkItem>, Self::SinkError> {
+        use meilies::resp::RespMsgError::IoError;
+        use RequestMsgError::RespMsgError;
+
         match &mut self.conn_state {
             ConnState::Connected(connection) => {
-                // `start_send` can't trigger any network error. As the name suggests,
-                // this method only _begins_ the process of sending the item.
-                connection.start_send(item)
+                // `start_send` only _begins_ the process of sending the item but it flushes
+                // the previous ones when its buffer is full, which can trigger network errors.
+                match connection.start_send(item.clone()) {
+                    Err(RespMsgError(IoError(e))) => {
+                        error!("Connection error with {}; {}", self.addr, e);
+                        self.conn_state =
+                            ConnState::Connecting(retry_future(self.addr, self.resp3));
+
+                        // the item is given back to be sent once the caller knows
+                        // about the reconnection, after the items sent before it
+                        task::current().notify();
+                        Ok(AsyncSink::NotReady(item))
+                    }
+                    otherwise => otherwise,
+                }
             }
             ConnState::Connecting(connect) => match connect.poll() {
                 Ok(Async::Ready(connection)) => {
@@ -137,7 +178,8 @@ impl Sink for SteelConnection {
                     match error {
                         RespMsgError(IoError(e)) => {
                             error!("Connection error with {}; {}", self.addr, e);
-                            self.conn_state = ConnState::Connecting(retry_future(self.addr));
+                            self.conn_state =
+                                ConnState::Connecting(retry_future(self.addr, self.resp3));
                             self.poll_complete()
                         }
                         otherwise => Err(otherwise),
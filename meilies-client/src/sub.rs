@@ -2,22 +2,24 @@ use std::collections::HashMap;
 use std::net::SocketAddr;
 use std::{fmt, io};
 
+use futures::future::Either;
 use futures::stream::SplitStream;
 use futures::{Async, AsyncSink, Future, Poll, Sink, Stream};
 use log::{error, warn};
 use meilies::reqresp::{Request, RequestMsgError, Response, ResponseMsgError};
 use meilies::resp::RespMsgError;
-use meilies::stream::{Stream as EsStream, StreamName};
+use meilies::stream::{EventFilter, Stream as EsStream, StreamName};
 use tokio::sync::mpsc;
 use tokio_retry::Retry;
 
-use super::{connect, retry_strategy, SteelConnection};
+use super::{connect, connect_resp3, retry_strategy, SteelConnection};
 
 #[derive(Debug, Default)]
 struct StreamContext {
     reconnected: bool,
     position_start: Option<u64>,
     position_end: Option<u64>,
+    filter: Option<EventFilter>,
 }
 
 /// A tokio Stream that reconnect when the connection is lost.
@@ -31,11 +33,22 @@ pub struct EventStream {
 impl EventStream {
     fn connect(
         addr: SocketAddr,
+        resp3: bool,
     ) -> impl Future<Item = EventStream, Error = tokio_retry::Error<io::Error>> {
         Retry::spawn(retry_strategy(), move || {
             warn!("Connecting to {}", addr);
-            connect(&addr).map(move |connection| {
-                let connection = SteelConnection::new(addr, connection);
+            let connection = if resp3 {
+                Either::A(connect_resp3(&addr))
+            } else {
+                Either::B(connect(&addr))
+            };
+
+            connection.map(move |connection| {
+                let connection = if resp3 {
+                    SteelConnection::new_resp3(addr, connection)
+                } else {
+                    SteelConnection::new(addr, connection)
+                };
                 EventStream {
                     state: HashMap::new(),
                     connection,
@@ -57,7 +70,7 @@ impl EventStream {
                 context.position_start.into(),
                 context.position_end.into(),
             );
-            streams.push(stream);
+            streams.push(stream.with_filter(context.filter.clone()));
         }
 
         let subscription = Request::Subscribe { streams };
@@ -76,9 +89,37 @@ impl Stream for EventStream {
         let result = match self.connection.poll() {
             Ok(Async::Ready(Some(item))) => {
                 match &item {
-                    Ok(Response::Event { stream, number, .. }) => {
-                        self.state.entry(stream.clone()).or_default().position_start =
-                            Some(number.0 + 1);
+                    Ok(Response::Event {
+                        stream,
+                        number,
+                        link,
+                        ..
+                    }) => {
+                        // the events read from a system stream, like `$all`,
+                        // are resumed from their number in the system stream
+                        let (stream, number) = match link {
+                            Some(link) => (&link.stream, link.number),
+                            None => (stream, *number),
+                        };
+
+                        // the events of a stream we unsubscribed from can still be in flight,
+                        // they must not make us subscribe to it again when reconnecting.
+                        if let Some(context) = self.state.get_mut(stream) {
+                            context.position_start = Some(number.0 + 1);
+                        }
+                    }
+                    Ok(Response::Progress { stream, number }) => {
+                        // the server checked the events filtered out up to this one,
+                        // we resume after it, the user is not told about it
+                        if let Some(context) = self.state.get_mut(stream) {
+                            context.position_start = Some(number.0 + 1);
+                        }
+                        return self.poll();
+                    }
+                    Ok(Response::StreamDeleted { stream }) => {
+                        // the server ended the subscription, we must not subscribe
+                        // to the stream again when reconnecting
+                        self.state.remove(stream);
                     }
                     Ok(Response::Subscribed { stream }) => {
                         // if we were already subscribed to a stream and we are reconnecting
@@ -112,9 +153,22 @@ impl Sink for EventStream {
         item: Self::SinkItem,
     ) -> Result<AsyncSink<Self::SinkItem>, Self::SinkError> {
         if let Request::Subscribe { streams } = &item {
-            for EsStream { name, range } in streams {
-                self.state.entry(name.clone()).or_default().position_start = range.from();
-                self.state.entry(name.clone()).or_default().position_end = range.to();
+            for EsStream {
+                name,
+                range,
+                filter,
+            } in streams
+            {
+                let context = self.state.entry(name.clone()).or_default();
+                context.position_start = range.from();
+                context.position_end = range.to();
+                context.filter = filter.clone();
+            }
+        }
+
+        if let Request::Unsubscribe { streams } = &item {
+            for name in streams {
+                self.state.remove(name);
             }
         }
 
@@ -142,7 +196,25 @@ impl Sink for EventStream {
 pub fn sub_connect(
     addr: SocketAddr,
 ) -> impl Future<Item = (SubController, SubStream), Error = tokio_retry::Error<io::Error>> {
-    EventStream::connect(addr)
+    sub_connect_with(addr, false)
+}
+
+/// Open a sup connection with a server using RESP3, the events are received
+/// with their metadata and link if the server supports it.
+///
+/// The subscriptions to the system streams, like `$all`, are only resumed
+/// at their position on reconnection over RESP3, the position is their link.
+pub fn sub_connect_resp3(
+    addr: SocketAddr,
+) -> impl Future<Item = (SubController, SubStream), Error = tokio_retry::Error<io::Error>> {
+    sub_connect_with(addr, true)
+}
+
+fn sub_connect_with(
+    addr: SocketAddr,
+    resp3: bool,
+) -> impl Future<Item = (SubController, SubStream), Error = tokio_retry::Error<io::Error>> {
+    EventStream::connect(addr, resp3)
         .map_err(|e| dbg!(e))
         .map(|connection| {
             let (writer, reader) = connection.split();
@@ -184,6 +256,20 @@ impl SubController {
             error!("{}", e);
         }
     }
+
+    /// Ask the server to stop sending events of the given stream,
+    /// the stream will not be subscribed again on reconnection.
+    ///
+    /// Events that were already sent by the server can still be received.
+    pub fn unsubscribe_from(&mut self, stream: StreamName) {
+        let command = Request::Unsubscribe {
+            streams: vec![stream],
+        };
+
+        if let Err(e) = self.sender.try_send(command) {
+            error!("{}", e);
+        }
+    }
 }
 
 /// A tokio Stream that returns every event received on all subscribed streams.
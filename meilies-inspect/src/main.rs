@@ -6,7 +6,7 @@ use futures::future::{self, Future};
 use futures::stream::Stream;
 use meilies::reqresp::Response;
 use meilies::stream::Stream as EsStream;
-use meilies_client::sub_connect;
+use meilies_client::sub_connect_resp3;
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -61,7 +61,7 @@ fn main() {
         Err(e) => panic!("error parsing addr; {}", e),
     };
 
-    let fut = sub_connect(addr)
+    let fut = sub_connect_resp3(addr)
         .map_err(|e| eprintln!("{}", e))
         .and_then(move |(mut ctrl, msgs)| {
             ctrl.subscribe_to(stream);
@@ -72,24 +72,36 @@ fn main() {
                         number,
                         event_name,
                         event_data,
+                        metadata,
+                        ..
                     }) => {
                         eprintln!("processing event number {}", number.0);
 
-                        let result = Command::new("/bin/bash")
+                        let mut process = Command::new("/bin/bash");
+                        process
                             .arg("-c")
                             .arg(&command)
                             .stdin(Stdio::piped())
                             .env("MEILIES_STREAM_NAME", stream.into_inner())
                             .env("MEILIES_EVENT_NAME", event_name.into_inner())
-                            .env("MEILIES_EVENT_NUMBER", number.0.to_string())
-                            .spawn();
+                            .env("MEILIES_EVENT_NUMBER", number.0.to_string());
+
+                        // servers speaking RESP2 only do not send the metadata
+                        if let Some(metadata) = metadata {
+                            process
+                                .env("MEILIES_EVENT_ID", metadata.id.to_string())
+                                .env("MEILIES_EVENT_TIMESTAMP", metadata.timestamp.to_string())
+                                .env("MEILIES_EVENT_CONTENT_TYPE", metadata.content_type);
+                        }
+
+                        let result = process.spawn();
 
                         let mut child = match result {
                             Ok(child) => child,
                             Err(e) => return future::err(e),
                         };
 
-                        let data = event_data.0.as_slice();
+                        let data = &event_data.0[..];
                         if let Err(e) = child.stdin.as_mut().unwrap().write_all(data) {
                             return future::err(e);
                         }
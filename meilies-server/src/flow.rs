@@ -0,0 +1,388 @@
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+use std::{fmt, mem, usize};
+
+use meilies::reqresp::Response;
+
+/// The number of bytes an event is counted for on top of its name and data,
+/// roughly the size of its metadata and of the RESP framing.
+const EVENT_OVERHEAD: usize = 128;
+
+/// How long a thread waiting for room checks whether it has been cancelled.
+const ROOM_CHECK_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A job run once the client of a connection made room in its buffer.
+pub type Job = Box<dyn FnOnce() + Send>;
+
+/// What happens to the subscribers of a connection whose client does not read
+/// the events as fast as they are published, once its buffer is full.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum SlowConsumerPolicy {
+    /// The events are sent when the client made room for them, the other
+    /// subscribers of the stream wait for the client too.
+    Block,
+    /// The client receives an error and the connection is closed.
+    Disconnect,
+    /// The subscriber goes back to reading the events from disk,
+    /// the other subscribers of the stream do not wait for the client.
+    CatchUp,
+}
+
+impl FromStr for SlowConsumerPolicy {
+    type Err = ParseSlowConsumerPolicyError;
+
+    fn from_str(s: &str) -> Result<SlowConsumerPolicy, Self::Err> {
+        match s {
+            "block" => Ok(SlowConsumerPolicy::Block),
+            "disconnect" => Ok(SlowConsumerPolicy::Disconnect),
+            "catch-up" => Ok(SlowConsumerPolicy::CatchUp),
+            _ => Err(ParseSlowConsumerPolicyError),
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct ParseSlowConsumerPolicyError;
+
+impl fmt::Display for ParseSlowConsumerPolicyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("expected block, disconnect or catch-up")
+    }
+}
+
+/// The number of bytes an event response is counted for in the buffer of a connection.
+pub fn response_size(response: &Response) -> usize {
+    match response {
+        Response::Event {
+            event_name,
+            event_data,
+            ..
+        } => event_name.as_str().len() + event_data.0.len() + EVENT_OVERHEAD,
+        _ => 0,
+    }
+}
+
+/// Counters describing the clients that do not keep up with the published events.
+#[derive(Debug, Default)]
+pub struct FlowMetrics {
+    buffered_bytes: AtomicUsize,
+    fell_behind: AtomicUsize,
+    disconnected: AtomicUsize,
+}
+
+impl FlowMetrics {
+    /// The number of bytes of events waiting to be sent to the clients.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buffered_bytes.load(Ordering::SeqCst)
+    }
+
+    /// The number of times a subscriber had to wait for its client or
+    /// to go back to reading from disk, since the server started.
+    pub fn fell_behind(&self) -> usize {
+        self.fell_behind.load(Ordering::SeqCst)
+    }
+
+    /// The number of connections closed because their client was too slow.
+    pub fn disconnected(&self) -> usize {
+        self.disconnected.load(Ordering::SeqCst)
+    }
+
+    pub fn record_fell_behind(&self) {
+        self.fell_behind.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+/// The buffer of the events sent to the client of a connection, shared by all its
+/// subscriptions. It is bounded in bytes, the policy tells what to do once it is full.
+pub struct Outbox {
+    max_bytes: usize,
+    policy: SlowConsumerPolicy,
+    metrics: Arc<FlowMetrics>,
+    buffered: Mutex<usize>,
+    room: Condvar,
+    /// The jobs waiting for the client to make room, always locked after `buffered`.
+    waiting: Mutex<Vec<Job>>,
+    disconnected: AtomicBool,
+}
+
+impl Outbox {
+    pub fn new(max_bytes: usize, policy: SlowConsumerPolicy, metrics: Arc<FlowMetrics>) -> Outbox {
+        Outbox {
+            max_bytes,
+            policy,
+            metrics,
+            buffered: Mutex::new(0),
+            room: Condvar::new(),
+            waiting: Mutex::new(Vec::new()),
+            disconnected: AtomicBool::new(false),
+        }
+    }
+
+    /// An outbox that is never full, for the subscriptions internal to the server.
+    pub fn unbounded(metrics: Arc<FlowMetrics>) -> Outbox {
+        Outbox::new(usize::MAX, SlowConsumerPolicy::CatchUp, metrics)
+    }
+
+    pub fn policy(&self) -> SlowConsumerPolicy {
+        self.policy
+    }
+
+    pub fn metrics(&self) -> &FlowMetrics {
+        &self.metrics
+    }
+
+    pub fn buffered_bytes(&self) -> usize {
+        *self.buffered.lock().unwrap()
+    }
+
+    /// An empty buffer always has room to let the events larger than the buffer through.
+    fn has_room(&self, buffered: usize, bytes: usize) -> bool {
+        buffered == 0 || buffered.saturating_add(bytes) <= self.max_bytes
+    }
+
+    /// Reserves room for the bytes.
+    fn try_reserve(&self, bytes: usize) -> bool {
+        if self.is_disconnected() {
+            return false;
+        }
+
+        let mut buffered = self.buffered.lock().unwrap();
+        if !self.has_room(*buffered, bytes) {
+            return false;
+        }
+
+        *buffered += bytes;
+        self.metrics
+            .buffered_bytes
+            .fetch_add(bytes, Ordering::SeqCst);
+        true
+    }
+
+    fn release(&self, bytes: usize) {
+        let waiting = {
+            let mut buffered = self.buffered.lock().unwrap();
+            *buffered -= bytes;
+            self.metrics
+                .buffered_bytes
+                .fetch_sub(bytes, Ordering::SeqCst);
+            self.room.notify_all();
+            self.take_waiting()
+        };
+
+        for job in waiting {
+            job();
+        }
+    }
+
+    fn take_waiting(&self) -> Vec<Job> {
+        mem::replace(&mut *self.waiting.lock().unwrap(), Vec::new())
+    }
+
+    /// Keeps the job until the client made room for the bytes,
+    /// returns it if there already is room or the connection is closed.
+    fn on_room(&self, bytes: usize, job: Job) -> Option<Job> {
+        let buffered = self.buffered.lock().unwrap();
+        if self.is_disconnected() || self.has_room(*buffered, bytes) {
+            return Some(job);
+        }
+
+        self.waiting.lock().unwrap().push(job);
+        None
+    }
+
+    fn wait_for_room(&self) {
+        let buffered = self.buffered.lock().unwrap();
+        let _ = self.room.wait_timeout(buffered, ROOM_CHECK_INTERVAL);
+    }
+
+    /// Marks the connection as closed because of its slow client,
+    /// returns `false` if it already was.
+    pub fn disconnect(&self) -> bool {
+        let first = !self.disconnected.swap(true, Ordering::SeqCst);
+        if first {
+            self.metrics.disconnected.fetch_add(1, Ordering::SeqCst);
+
+            // the jobs waiting for room will never get it
+            let waiting = {
+                let _buffered = self.buffered.lock().unwrap();
+                self.take_waiting()
+            };
+            for job in waiting {
+                job();
+            }
+        }
+        first
+    }
+
+    pub fn is_disconnected(&self) -> bool {
+        self.disconnected.load(Ordering::SeqCst)
+    }
+}
+
+/// The part of the buffer of a connection used by one of its subscriptions,
+/// it is given back when the subscription ends.
+pub struct Quota {
+    outbox: Arc<Outbox>,
+    /// The bytes reserved, `None` once the subscription ended.
+    reserved: Mutex<Option<usize>>,
+}
+
+impl Quota {
+    pub fn new(outbox: Arc<Outbox>) -> Quota {
+        Quota {
+            outbox,
+            reserved: Mutex::new(Some(0)),
+        }
+    }
+
+    pub fn outbox(&self) -> &Outbox {
+        &self.outbox
+    }
+
+    /// Reserves room for an event without waiting, returns `false` if the buffer
+    /// of the connection is full or the subscription ended.
+    pub fn try_reserve(&self, bytes: usize) -> bool {
+        let mut reserved = self.reserved.lock().unwrap();
+        match reserved.as_mut() {
+            Some(reserved) if self.outbox.try_reserve(bytes) => {
+                *reserved += bytes;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Waits for the client to make room for an event, returns `false`
+    /// if the subscription has been cancelled or ended meanwhile.
+    pub fn reserve(&self, bytes: usize, cancelled: &AtomicBool) -> bool {
+        loop {
+            if cancelled.load(Ordering::SeqCst) || self.outbox.is_disconnected() {
+                return false;
+            }
+
+            {
+                let mut reserved = self.reserved.lock().unwrap();
+                match reserved.as_mut() {
+                    Some(reserved) if self.outbox.try_reserve(bytes) => {
+                        *reserved += bytes;
+                        return true;
+                    }
+                    Some(_) => (),
+                    None => return false,
+                }
+            }
+
+            self.outbox.wait_for_room();
+        }
+    }
+
+    /// Runs the job once the client made room for an event, without waiting for it.
+    /// The job is run right away if there is room or the subscription ended.
+    pub fn on_room(&self, bytes: usize, job: Job) {
+        let job = {
+            let reserved = self.reserved.lock().unwrap();
+            match *reserved {
+                Some(_) => self.outbox.on_room(bytes, job),
+                None => Some(job),
+            }
+        };
+
+        if let Some(job) = job {
+            job();
+        }
+    }
+
+    /// Gives back the room of an event sent to the connection.
+    pub fn release(&self, bytes: usize) {
+        let mut reserved = self.reserved.lock().unwrap();
+        if let Some(reserved) = reserved.as_mut() {
+            *reserved -= bytes;
+            self.outbox.release(bytes);
+        }
+    }
+
+    /// Gives back the room of the events the subscription did not send.
+    pub fn close(&self) {
+        if let Some(reserved) = self.reserved.lock().unwrap().take() {
+            self.outbox.release(reserved);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn share_the_buffer_of_a_connection() {
+        let metrics = Arc::new(FlowMetrics::default());
+        let outbox = Arc::new(Outbox::new(100, SlowConsumerPolicy::Block, metrics.clone()));
+        let first = Quota::new(outbox.clone());
+        let second = Quota::new(outbox.clone());
+
+        // an empty buffer accepts an event larger than itself
+        assert!(first.try_reserve(150));
+        assert!(!second.try_reserve(10));
+        first.release(150);
+
+        assert!(first.try_reserve(60));
+        assert!(second.try_reserve(40));
+        assert!(!second.try_reserve(1));
+        assert_eq!(metrics.buffered_bytes(), 100);
+
+        // the room of the events not sent is given back with the subscription
+        first.close();
+        assert!(!first.try_reserve(1));
+        assert_eq!(outbox.buffered_bytes(), 40);
+        assert!(second.try_reserve(60));
+
+        let cancelled = AtomicBool::new(true);
+        assert!(!second.reserve(1, &cancelled));
+
+        assert!(outbox.disconnect());
+        assert!(!outbox.disconnect());
+        assert_eq!(metrics.disconnected(), 1);
+        second.close();
+        assert_eq!(metrics.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn run_the_jobs_waiting_for_room() {
+        let metrics = Arc::new(FlowMetrics::default());
+        let outbox = Arc::new(Outbox::new(100, SlowConsumerPolicy::CatchUp, metrics));
+        let first = Quota::new(outbox.clone());
+        let second = Quota::new(outbox.clone());
+        let runs = Arc::new(AtomicUsize::new(0));
+
+        let job = || -> Job {
+            let runs = runs.clone();
+            Box::new(move || {
+                runs.fetch_add(1, Ordering::SeqCst);
+            })
+        };
+
+        // the jobs are run right away if there is room
+        second.on_room(10, job());
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        assert!(first.try_reserve(95));
+        second.on_room(10, job());
+        second.on_room(10, job());
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+
+        first.release(95);
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+
+        // or once the subscription ended or the connection closed
+        assert!(first.try_reserve(95));
+        second.on_room(10, job());
+        assert!(outbox.disconnect());
+        assert_eq!(runs.load(Ordering::SeqCst), 4);
+
+        second.close();
+        second.on_room(10, job());
+        assert_eq!(runs.load(Ordering::SeqCst), 5);
+    }
+}
@@ -0,0 +1,638 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{mem, thread};
+
+use futures::sync::mpsc;
+use futures::Stream;
+use log::{error, info, warn};
+use sled::{Db, IVec};
+
+use meilies::reqresp::Response;
+use meilies::stream::{
+    EventNumber, GroupName, RawEvent, ReadRange, Stream as EsStream, StreamName as EsStreamName,
+};
+
+use crate::flow::{FlowMetrics, Outbox};
+use crate::hub::{Hub, Responses, Subscription};
+use crate::{store, Error};
+
+/// The number of events delivered to a member of a group that it has not
+/// acknowledged yet, no more events are delivered to it until it does.
+const MAX_IN_FLIGHT: usize = 32;
+
+type GroupKey = (GroupName, EsStreamName);
+type ResponseSender = mpsc::Sender<Result<Response, String>>;
+
+/// The responses sent to a member of a group, the events delivered to it.
+pub type MemberResponses = mpsc::Receiver<Result<Response, String>>;
+
+/// Shares the events of the streams between the members of their consumer groups.
+///
+/// Every event is delivered to a single member of the group, the least busy one,
+/// and delivered again to a member of the group until it is acknowledged: when the
+/// member nacks it or leaves the group. An event delivered too many times is parked
+/// and the group moves on. The server stores the number of the first event the group
+/// has not acknowledged, a group starts from there when a member joins it again.
+///
+/// A group is loaded with its first member and forgotten once its last member left,
+/// a thread is woken up by a subscription to the stream when events are published.
+#[derive(Clone)]
+pub struct Groups {
+    inner: Arc<GroupsInner>,
+}
+
+struct GroupsInner {
+    db: Db,
+    hub: Hub,
+    /// The wake up subscriptions are never slowed down by a full buffer.
+    outbox: Arc<Outbox>,
+    max_attempts: usize,
+    next_member_id: AtomicUsize,
+    groups: Mutex<HashMap<GroupKey, Arc<Group>>>,
+}
+
+struct Group {
+    group: GroupName,
+    stream: EsStreamName,
+    state: Mutex<GroupState>,
+}
+
+struct GroupState {
+    /// The number of the first event never delivered to the group.
+    next: EventNumber,
+    /// The number of the first event not acknowledged, as stored.
+    checkpoint: Option<EventNumber>,
+    /// The events delivered and not acknowledged yet.
+    pending: BTreeMap<EventNumber, Delivery>,
+    members: Vec<Member>,
+    /// Wakes the thread of the group up when events are published.
+    subscription: Option<Subscription>,
+}
+
+struct Delivery {
+    attempts: usize,
+    /// The member the event is delivered to, `None` if it must be delivered again.
+    member: Option<usize>,
+}
+
+struct Member {
+    id: usize,
+    sender: ResponseSender,
+    in_flight: usize,
+}
+
+impl GroupState {
+    /// The member with the least events in flight, if one can receive more events.
+    fn least_busy(&self) -> Option<usize> {
+        self.least_busy_except(&[])
+    }
+
+    /// The least busy member whose id is not one of the excluded ones.
+    fn least_busy_except(&self, excluded: &[usize]) -> Option<usize> {
+        self.members
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| m.in_flight < MAX_IN_FLIGHT && !excluded.contains(&m.id))
+            .min_by_key(|(_, m)| m.in_flight)
+            .map(|(i, _)| i)
+    }
+
+    /// Marks the event as not delivered to its member anymore.
+    fn release(&mut self, number: EventNumber) -> Option<Delivery> {
+        let delivery = self.pending.remove(&number)?;
+        if let Some(id) = delivery.member {
+            if let Some(member) = self.members.iter_mut().find(|m| m.id == id) {
+                member.in_flight -= 1;
+            }
+        }
+        Some(delivery)
+    }
+
+    /// Removes the member, the events delivered to it are delivered again.
+    fn remove_member(&mut self, id: usize) -> bool {
+        let len = self.members.len();
+        self.members.retain(|m| m.id != id);
+        for delivery in self.pending.values_mut() {
+            if delivery.member == Some(id) {
+                delivery.member = None;
+            }
+        }
+        self.members.len() != len
+    }
+
+    /// The number of the first event the group has not acknowledged.
+    fn first_pending(&self) -> EventNumber {
+        self.pending.keys().next().map_or(self.next, |n| *n)
+    }
+}
+
+impl Groups {
+    pub fn new(db: Db, hub: Hub, max_attempts: usize) -> Groups {
+        let inner = GroupsInner {
+            db,
+            hub,
+            outbox: Arc::new(Outbox::unbounded(Arc::new(FlowMetrics::default()))),
+            max_attempts,
+            next_member_id: AtomicUsize::new(0),
+            groups: Mutex::new(HashMap::new()),
+        };
+        Groups {
+            inner: Arc::new(inner),
+        }
+    }
+
+    /// Joins the group of the stream, the responses first yield the `SubscribedGroup`
+    /// response followed by the events delivered to the new member.
+    ///
+    /// The member leaves the group when the returned handle is dropped.
+    pub fn join(
+        &self,
+        group: GroupName,
+        stream: EsStreamName,
+    ) -> Result<(Membership, MemberResponses), Error> {
+        // the channel should never fill up, a member never has more events in flight
+        let (mut sender, receiver) = mpsc::channel(MAX_IN_FLIGHT + 1);
+        let subscribed = Response::SubscribedGroup {
+            group: group.clone(),
+            stream: stream.clone(),
+        };
+        let _ = sender.try_send(Ok(subscribed));
+
+        let id = self.inner.next_member_id.fetch_add(1, Ordering::SeqCst);
+        let member = Member {
+            id,
+            sender,
+            in_flight: 0,
+        };
+
+        let key = (group, stream);
+        let group = self.group(&key)?;
+        {
+            let mut state = group.state.lock().unwrap();
+            state.members.push(member);
+            self.fill(&group, &mut state)?;
+        }
+
+        let membership = Membership {
+            groups: self.clone(),
+            key,
+            id,
+        };
+
+        Ok((membership, receiver))
+    }
+
+    /// Returns the group of the stream, loading it and spawning its thread if needed.
+    fn group(&self, key: &GroupKey) -> Result<Arc<Group>, Error> {
+        let mut groups = self.inner.groups.lock().unwrap();
+        if let Some(group) = groups.get(key) {
+            return Ok(group.clone());
+        }
+
+        let (name, stream) = key;
+        let db = &self.inner.db;
+        let next = match store::checkpoint(db, name, stream)? {
+            Some(checkpoint) => checkpoint,
+            None => store::stream_start(db, stream)?,
+        };
+
+        // the events published from now on wake the thread up,
+        // the other ones are read by the first fill
+        let range = ReadRange::ReadFromEnd;
+        let (subscription, responses) = self.inner.hub.subscribe(
+            EsStream::new(stream.clone(), range),
+            self.inner.outbox.clone(),
+        )?;
+
+        let group = Arc::new(Group {
+            group: name.clone(),
+            stream: stream.clone(),
+            state: Mutex::new(GroupState {
+                next,
+                checkpoint: None,
+                pending: BTreeMap::new(),
+                members: Vec::new(),
+                subscription: Some(subscription),
+            }),
+        });
+
+        let this = self.clone();
+        let watched = group.clone();
+        thread::Builder::new()
+            .name(format!("group-{}-{}", name, stream))
+            .spawn(move || this.watch(watched, responses))?;
+
+        groups.insert(key.clone(), group.clone());
+        Ok(group)
+    }
+
+    fn watch(&self, group: Arc<Group>, responses: Responses) {
+        info!("group {} of {} loaded", group.group, group.stream);
+
+        for response in responses.wait() {
+            match response {
+                Ok(Ok(Response::Event { .. })) => {
+                    let mut state = group.state.lock().unwrap();
+                    if let Err(e) = self.fill(&group, &mut state) {
+                        error!(
+                            "error filling group {} of {}; {}",
+                            group.group, group.stream, e
+                        );
+                    }
+                }
+                Ok(Ok(response @ Response::StreamDeleted { .. })) => {
+                    self.stream_deleted(&group, response);
+                    break;
+                }
+                Ok(Ok(_)) => (),
+                Ok(Err(e)) => error!("error watching {}; {}", group.stream, e),
+                Err(()) => break,
+            }
+        }
+
+        info!("group {} of {} unloaded", group.group, group.stream);
+    }
+
+    /// Forwards the `StreamDeleted` response to the members and forgets the group.
+    fn stream_deleted(&self, group: &Arc<Group>, response: Response) {
+        let key = (group.group.clone(), group.stream.clone());
+        let mut groups = self.inner.groups.lock().unwrap();
+        if groups.get(&key).map_or(false, |g| Arc::ptr_eq(g, group)) {
+            groups.remove(&key);
+        }
+
+        let mut state = group.state.lock().unwrap();
+        for mut member in mem::replace(&mut state.members, Vec::new()) {
+            let _ = member.sender.try_send(Ok(response.clone()));
+        }
+        state.subscription = None;
+    }
+
+    /// Delivers the events waiting to be delivered again and the new events
+    /// of the stream to the members that can receive them.
+    fn fill(&self, group: &Group, state: &mut GroupState) -> Result<(), Error> {
+        let db = &self.inner.db;
+
+        let retries: Vec<_> = state
+            .pending
+            .iter()
+            .filter(|(_, d)| d.member.is_none())
+            .map(|(n, _)| *n)
+            .collect();
+
+        for number in retries {
+            if state.least_busy().is_none() {
+                break;
+            }
+
+            let attempts = state.pending[&number].attempts;
+            if attempts >= self.inner.max_attempts {
+                warn!(
+                    "group {} of {} parks event {} after {} attempts",
+                    group.group, group.stream, number.0, attempts
+                );
+                store::park_event(db, &group.group, &group.stream, number)?;
+                state.pending.remove(&number);
+                continue;
+            }
+
+            match store::read_raw_event(db, &group.stream, number)? {
+                Some(raw_event) => {
+                    if !self.deliver(group, state, number, raw_event) {
+                        break;
+                    }
+                }
+                // the event has been removed from the stream since
+                None => {
+                    state.pending.remove(&number);
+                }
+            }
+        }
+
+        loop {
+            let free: usize = state
+                .members
+                .iter()
+                .map(|m| MAX_IN_FLIGHT - m.in_flight)
+                .sum();
+
+            if free == 0 {
+                break;
+            }
+
+            let events = store::read_raw_events(db, &group.stream, state.next, free)?;
+            if events.is_empty() {
+                break;
+            }
+
+            for (number, raw_event) in events {
+                if !self.deliver(group, state, number, raw_event) {
+                    break;
+                }
+                state.next = number.next();
+            }
+        }
+
+        self.save_checkpoint(group, state)
+    }
+
+    /// Delivers the event to the least busy member, returns `false` if no member could receive it.
+    fn deliver(
+        &self,
+        group: &Group,
+        state: &mut GroupState,
+        number: EventNumber,
+        raw_event: RawEvent<IVec>,
+    ) -> bool {
+        let event = Response::Event {
+            stream: group.stream.clone(),
+            number,
+            event_name: raw_event.name().unwrap(),
+            event_data: raw_event.data().unwrap(),
+            metadata: Some(raw_event.metadata().unwrap()),
+            link: None,
+        };
+
+        let mut full = Vec::new();
+        while let Some(index) = state.least_busy_except(&full) {
+            let member = &mut state.members[index];
+            match member.sender.try_send(Ok(event.clone())) {
+                Ok(()) => (),
+                Err(ref e) if e.is_disconnected() => {
+                    // the connection of the member has been closed
+                    let id = member.id;
+                    state.remove_member(id);
+                    continue;
+                }
+                Err(_) => {
+                    // the member is not removed for a full channel,
+                    // another member gets the event
+                    warn!(
+                        "member {} of group {} of {} can not receive more events",
+                        member.id, group.group, group.stream
+                    );
+                    full.push(member.id);
+                    continue;
+                }
+            }
+
+            member.in_flight += 1;
+            let id = member.id;
+            let delivery = state.pending.entry(number).or_insert(Delivery {
+                attempts: 0,
+                member: None,
+            });
+            delivery.attempts += 1;
+            delivery.member = Some(id);
+            return true;
+        }
+
+        false
+    }
+
+    fn save_checkpoint(&self, group: &Group, state: &mut GroupState) -> Result<(), Error> {
+        let checkpoint = state.first_pending();
+        if state.checkpoint != Some(checkpoint) {
+            store::set_checkpoint(&self.inner.db, &group.group, &group.stream, checkpoint)?;
+            state.checkpoint = Some(checkpoint);
+        }
+        Ok(())
+    }
+
+    /// Acknowledges the events, they will not be delivered again.
+    fn ack(&self, key: &GroupKey, numbers: &[EventNumber]) -> Result<(), Error> {
+        self.update(key, |state| {
+            for number in numbers {
+                state.release(*number);
+            }
+        })
+    }
+
+    /// Delivers the events again, possibly to another member.
+    fn nack(&self, key: &GroupKey, numbers: &[EventNumber]) -> Result<(), Error> {
+        self.update(key, |state| {
+            for number in numbers {
+                if let Some(mut delivery) = state.release(*number) {
+                    delivery.member = None;
+                    state.pending.insert(*number, delivery);
+                }
+            }
+        })
+    }
+
+    fn update<F>(&self, key: &GroupKey, f: F) -> Result<(), Error>
+    where
+        F: FnOnce(&mut GroupState),
+    {
+        let group = match self.inner.groups.lock().unwrap().get(key) {
+            Some(group) => group.clone(),
+            None => return Ok(()),
+        };
+
+        let mut state = group.state.lock().unwrap();
+        f(&mut state);
+        self.fill(&group, &mut state)
+    }
+
+    fn leave(&self, key: &GroupKey, id: usize) {
+        let mut groups = self.inner.groups.lock().unwrap();
+        let group = match groups.get(key) {
+            Some(group) => group.clone(),
+            None => return,
+        };
+
+        let mut state = group.state.lock().unwrap();
+        if !state.remove_member(id) {
+            return;
+        }
+
+        if state.members.is_empty() {
+            // the thread stops once the subscription is cancelled
+            groups.remove(key);
+            state.subscription = None;
+        } else if let Err(e) = self.fill(&group, &mut state) {
+            error!("error filling group {} of {}; {}", key.0, key.1, e);
+        }
+    }
+
+    /// The number of groups that currently have members.
+    #[cfg(test)]
+    fn loaded_groups(&self) -> usize {
+        self.inner.groups.lock().unwrap().len()
+    }
+}
+
+/// A handle on the membership of a group, the member leaves the group when it is dropped.
+pub struct Membership {
+    groups: Groups,
+    key: GroupKey,
+    id: usize,
+}
+
+impl Membership {
+    pub fn ack(&self, numbers: &[EventNumber]) -> Result<(), Error> {
+        self.groups.ack(&self.key, numbers)
+    }
+
+    pub fn nack(&self, numbers: &[EventNumber]) -> Result<(), Error> {
+        self.groups.nack(&self.key, numbers)
+    }
+}
+
+impl Drop for Membership {
+    fn drop(&mut self) {
+        self.groups.leave(&self.key, self.id);
+    }
+}
+
+/// The memberships of a connection, the members leave their groups with it.
+#[derive(Default)]
+pub struct Memberships {
+    memberships: HashMap<GroupKey, Membership>,
+}
+
+impl Memberships {
+    /// Registers the membership, leaving the group it replaces.
+    pub fn insert(&mut self, membership: Membership) {
+        self.memberships.insert(membership.key.clone(), membership);
+    }
+
+    pub fn get(&self, group: &GroupName, stream: &EsStreamName) -> Option<&Membership> {
+        self.memberships.get(&(group.clone(), stream.clone()))
+    }
+
+    pub fn clear(&mut self) {
+        self.memberships.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use sled::Config;
+
+    use meilies::stream::{EventData, EventName, ExpectedVersion, PublishMetadata};
+
+    fn publish(db: &Db, stream: &EsStreamName, count: usize) {
+        let name = EventName::new("my-event".to_owned()).unwrap();
+        let data = EventData("hello".into());
+        for _ in 0..count {
+            let metadata = PublishMetadata::default();
+            store::publish_event(
+                db,
+                stream,
+                &name,
+                &data,
+                metadata,
+                ExpectedVersion::Any,
+                '-',
+            )
+            .unwrap()
+            .unwrap();
+        }
+    }
+
+    /// Reads the numbers of the next `count` events delivered to the member.
+    fn delivered(responses: &mut futures::stream::Wait<MemberResponses>, count: usize) -> Vec<u64> {
+        (0..count)
+            .map(|_| match responses.next() {
+                Some(Ok(Ok(Response::Event { number, .. }))) => number.0,
+                otherwise => panic!("unexpected response {:?}", otherwise),
+            })
+            .collect()
+    }
+
+    fn joined(responses: MemberResponses) -> futures::stream::Wait<MemberResponses> {
+        let mut responses = responses.wait();
+        match responses.next() {
+            Some(Ok(Ok(Response::SubscribedGroup { .. }))) => (),
+            otherwise => panic!("unexpected response {:?}", otherwise),
+        }
+        responses
+    }
+
+    fn setup(max_attempts: usize) -> (Db, Groups, GroupName, EsStreamName) {
+        let db = Config::new().temporary(true).open().unwrap();
+        let hub = Hub::new(db.clone(), 1).unwrap();
+        let groups = Groups::new(db.clone(), hub, max_attempts);
+        let group = GroupName::new("projector".to_owned()).unwrap();
+        let stream = EsStreamName::new("my-stream".to_owned()).unwrap();
+        (db, groups, group, stream)
+    }
+
+    #[test]
+    fn share_events_between_members() {
+        let (db, groups, group, stream) = setup(3);
+        publish(&db, &stream, 4);
+
+        let (first, responses) = groups.join(group.clone(), stream.clone()).unwrap();
+        let mut first_responses = joined(responses);
+        assert_eq!(delivered(&mut first_responses, 4), vec![0, 1, 2, 3]);
+
+        let (second, responses) = groups.join(group.clone(), stream.clone()).unwrap();
+        let mut second_responses = joined(responses);
+
+        // the new events go to the member with the least events in flight
+        publish(&db, &stream, 2);
+        assert_eq!(delivered(&mut second_responses, 2), vec![4, 5]);
+
+        first.ack(&[EventNumber(0), EventNumber(1)]).unwrap();
+        assert_eq!(
+            store::checkpoint(&db, &group, &stream).unwrap(),
+            Some(EventNumber(2))
+        );
+
+        // the events in flight of a member that leaves are delivered again
+        drop(first);
+        assert_eq!(delivered(&mut second_responses, 2), vec![2, 3]);
+
+        second
+            .ack(&[EventNumber(2), EventNumber(3), EventNumber(4)])
+            .unwrap();
+        second.ack(&[EventNumber(5)]).unwrap();
+        assert_eq!(
+            store::checkpoint(&db, &group, &stream).unwrap(),
+            Some(EventNumber(6))
+        );
+
+        drop(second);
+        assert_eq!(groups.loaded_groups(), 0);
+
+        // a member joining again starts from the checkpoint
+        publish(&db, &stream, 1);
+        let (_third, responses) = groups.join(group.clone(), stream.clone()).unwrap();
+        assert_eq!(delivered(&mut joined(responses), 1), vec![6]);
+    }
+
+    #[test]
+    fn park_events_delivered_too_many_times() {
+        let (db, groups, group, stream) = setup(2);
+        publish(&db, &stream, 2);
+
+        let (member, responses) = groups.join(group.clone(), stream.clone()).unwrap();
+        let mut responses = joined(responses);
+        assert_eq!(delivered(&mut responses, 2), vec![0, 1]);
+
+        member.ack(&[EventNumber(1)]).unwrap();
+        member.nack(&[EventNumber(0)]).unwrap();
+        assert_eq!(delivered(&mut responses, 1), vec![0]);
+
+        member.nack(&[EventNumber(0)]).unwrap();
+        assert_eq!(
+            store::parked_events(&db, &group, &stream).unwrap(),
+            vec![EventNumber(0)]
+        );
+        assert_eq!(
+            store::checkpoint(&db, &group, &stream).unwrap(),
+            Some(EventNumber(2))
+        );
+
+        publish(&db, &stream, 1);
+        assert_eq!(delivered(&mut responses, 1), vec![2]);
+
+        drop(member);
+        assert_eq!(groups.loaded_groups(), 0);
+    }
+}
@@ -0,0 +1,1415 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::{mem, thread};
+
+use futures::sync::{mpsc, oneshot};
+use futures::{Async, Future, Poll, Stream};
+use log::{error, info, warn};
+use sled::{Db, Event, IVec, Tree};
+
+use meilies::reqresp::Response;
+use meilies::stream::{
+    EventData, EventFilter, EventLink, EventMetadata, EventName, EventNumber, RawEvent, ReadRange,
+    Stream as EsStream, StreamName as EsStreamName,
+};
+
+use crate::flow::{response_size, Outbox, Quota, SlowConsumerPolicy};
+use crate::pool::Pool;
+use crate::{store, Error};
+
+/// The number of events a subscriber reads from disk before giving its thread
+/// back to the other subscribers catching up, it is then scheduled again.
+const CATCH_UP_CHUNK_SIZE: usize = 256;
+
+/// The number of events in a row the filter of a subscriber skips before it
+/// tells its client how far it checked the stream with a `Progress` response.
+const PROGRESS_INTERVAL: usize = 100;
+
+/// The responses of a subscription are bounded by the buffer of its connection,
+/// in bytes, see the `Outbox` and the policy applied once it is full.
+type ResponseReceiver = mpsc::UnboundedReceiver<Result<Response, String>>;
+type ResponseSender = mpsc::UnboundedSender<Result<Response, String>>;
+
+/// Sends the events of the streams to all of their subscribers.
+///
+/// A single thread watches a stream whatever the number of subscribers, it is spawned
+/// with the first subscription and stops once the stream has no subscriber anymore.
+/// Subscribers that start from a past event first read the stream from disk on a
+/// bounded pool of threads and then join the live subscribers of the stream.
+#[derive(Clone)]
+pub struct Hub {
+    inner: Arc<HubInner>,
+}
+
+struct HubInner {
+    db: Db,
+    catch_up_pool: Pool,
+    feeds: Mutex<HashMap<EsStreamName, Arc<Feed>>>,
+    active_subscriptions: Arc<AtomicUsize>,
+}
+
+/// The live subscribers of a stream, fed by the watcher thread.
+struct Feed {
+    tree: Tree,
+    /// The prefix of the keys of the events of the stream in the tree, it is never
+    /// used as a key itself: removing it wakes the watcher up without modifying the stream.
+    prefix: Vec<u8>,
+    state: Mutex<FeedState>,
+}
+
+impl Feed {
+    /// The key the event is stored under in the tree.
+    fn key(&self, number: EventNumber) -> Vec<u8> {
+        let mut key = self.prefix.clone();
+        key.extend_from_slice(&number.to_be_bytes());
+        key
+    }
+
+    /// The number of the event stored under the key.
+    fn number(&self, key: &[u8]) -> EventNumber {
+        EventNumber::try_from(&key[self.prefix.len()..]).unwrap()
+    }
+}
+
+#[derive(Default)]
+struct FeedState {
+    /// The last event number sent to the live subscribers.
+    last_number: Option<EventNumber>,
+    subscribers: Vec<Subscriber>,
+    /// The watcher stopped, subscribers must join the feed that replaced this one.
+    closed: bool,
+}
+
+struct Subscriber {
+    stream: EsStreamName,
+    next: EventNumber,
+    end: Option<EventNumber>,
+    /// The events whose names do not match are skipped but still count as sent.
+    filter: Option<EventFilter>,
+    /// How the stream was deleted when last checked, a subscriber catching up
+    /// must notice the deletions the live subscribers are notified of.
+    deleted: Option<store::Deletion>,
+    /// The number of events skipped since the last one sent or the last progress reported.
+    skipped: usize,
+    sender: ResponseSender,
+    /// The room the subscriber takes in the buffer of its connection.
+    quota: Arc<Quota>,
+    /// The subscriber is waiting for its client to make room.
+    throttled: bool,
+    cancelled: Arc<AtomicBool>,
+    active_subscriptions: Arc<AtomicUsize>,
+}
+
+impl Drop for Subscriber {
+    fn drop(&mut self) {
+        self.active_subscriptions.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+enum Status {
+    Continue,
+    Lagging,
+    /// The client must make room for an event of this size before it is sent.
+    Full(usize),
+    Done,
+}
+
+/// Where a subscriber stands after reading a chunk of the stream from disk.
+enum CatchUp {
+    /// There are more events to read, the subscriber must be scheduled again.
+    Pending(Subscriber),
+    /// The client must make room for an event of this size,
+    /// the subscriber is scheduled again once it did.
+    Full(Subscriber, usize),
+    /// The subscriber joined the live subscribers or its subscription ended.
+    Done,
+}
+
+/// An event read from the tree of a stream, the events of the system
+/// streams are the events they link to.
+struct FeedEvent {
+    stream: EsStreamName,
+    number: EventNumber,
+    event_name: EventName,
+    event_data: EventData,
+    metadata: EventMetadata,
+    link: Option<EventLink>,
+}
+
+impl Subscriber {
+    fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    fn is_done(&self) -> bool {
+        self.end.map_or(false, |end| self.next >= end)
+    }
+
+    fn accepts(&self, event: &FeedEvent) -> bool {
+        self.filter
+            .as_ref()
+            .map_or(true, |filter| filter.matches(&event.event_name))
+    }
+
+    fn event(&self, event: &FeedEvent) -> Response {
+        Response::Event {
+            stream: event.stream.clone(),
+            number: event.number,
+            event_name: event.event_name.clone(),
+            event_data: event.event_data.clone(),
+            metadata: Some(event.metadata.clone()),
+            link: event.link.clone(),
+        }
+    }
+
+    /// Skips the event the filter rejected, the client is periodically told
+    /// the number of the last event skipped to resume after it.
+    fn skip(&mut self, number: EventNumber) -> Status {
+        self.skipped += 1;
+        if self.skipped == PROGRESS_INTERVAL {
+            self.skipped = 0;
+            let progress = Response::Progress {
+                stream: self.stream.clone(),
+                number,
+            };
+            if self.sender.unbounded_send(Ok(progress)).is_err() {
+                return Status::Done;
+            }
+        }
+
+        self.sent(number)
+    }
+
+    fn sent(&mut self, number: EventNumber) -> Status {
+        self.next = number.next();
+        if self.is_done() {
+            Status::Done
+        } else {
+            Status::Continue
+        }
+    }
+
+    /// Sends the event without blocking, used by the watcher thread.
+    /// A full connection buffer blocks only with the `Block` policy.
+    ///
+    /// The number is the one of the event in the subscribed stream.
+    fn try_send_event(&mut self, number: EventNumber, event: &FeedEvent) -> Status {
+        if number < self.next {
+            return Status::Continue;
+        }
+
+        if self.end.map_or(false, |end| number >= end) {
+            return Status::Done;
+        }
+
+        if !self.accepts(event) {
+            return self.skip(number);
+        }
+
+        let event = self.event(event);
+        let size = response_size(&event);
+        if !self.quota.try_reserve(size) {
+            match self.quota.outbox().policy() {
+                SlowConsumerPolicy::Block => {
+                    if !self.wait_for_room(size) {
+                        return Status::Done;
+                    }
+                }
+                SlowConsumerPolicy::Disconnect => return self.disconnect(),
+                SlowConsumerPolicy::CatchUp => {
+                    self.quota.outbox().metrics().record_fell_behind();
+                    return Status::Lagging;
+                }
+            }
+        }
+
+        self.send(number, event)
+    }
+
+    /// Sends the event if the client has room for it, used by the threads reading
+    /// events from disk. They do not wait for the client, they read the events
+    /// of other subscribers meanwhile.
+    fn send_event(&mut self, number: EventNumber, event: &FeedEvent) -> Status {
+        if !self.accepts(event) {
+            return self.skip(number);
+        }
+
+        let event = self.event(event);
+        let size = response_size(&event);
+        if !self.quota.try_reserve(size) {
+            match self.quota.outbox().policy() {
+                SlowConsumerPolicy::Disconnect => return self.disconnect(),
+                SlowConsumerPolicy::Block | SlowConsumerPolicy::CatchUp => {
+                    self.throttle();
+                    return Status::Full(size);
+                }
+            }
+        }
+
+        self.send(number, event)
+    }
+
+    fn send(&mut self, number: EventNumber, event: Response) -> Status {
+        self.throttled = false;
+        self.skipped = 0;
+        match self.sender.unbounded_send(Ok(event)) {
+            Ok(()) => self.sent(number),
+            // the room reserved is given back when the responses are dropped
+            Err(_) => Status::Done,
+        }
+    }
+
+    /// Waits for the client to read the events buffered for its connection,
+    /// returns `false` if the subscription ended meanwhile.
+    fn wait_for_room(&mut self, size: usize) -> bool {
+        self.throttle();
+        self.quota.reserve(size, &self.cancelled)
+    }
+
+    /// Records that the subscriber has to wait for its client, once until it sends an event.
+    fn throttle(&mut self) {
+        let outbox = self.quota.outbox();
+        if !self.throttled {
+            self.throttled = true;
+            outbox.metrics().record_fell_behind();
+            warn!(
+                "subscriber of {} is waiting for its client to read {} buffered bytes",
+                self.stream,
+                outbox.buffered_bytes()
+            );
+        }
+    }
+
+    /// Closes the connection of the subscriber, its client does not read the events fast enough.
+    fn disconnect(&mut self) -> Status {
+        let outbox = self.quota.outbox();
+        if outbox.disconnect() {
+            warn!(
+                "disconnecting the slow client subscribed to {}, {} bytes are buffered",
+                self.stream,
+                outbox.buffered_bytes()
+            );
+            let error = format!(
+                "slow consumer, the events of {} are not read fast enough",
+                self.stream
+            );
+            let _ = self.sender.unbounded_send(Err(error));
+        }
+
+        Status::Done
+    }
+}
+
+impl Hub {
+    pub fn new(db: Db, catch_up_threads: usize) -> Result<Hub, Error> {
+        let catch_up_pool = Pool::new("catch-up", catch_up_threads)?;
+        let inner = HubInner {
+            db,
+            catch_up_pool,
+            feeds: Mutex::new(HashMap::new()),
+            active_subscriptions: Arc::new(AtomicUsize::new(0)),
+        };
+        Ok(Hub {
+            inner: Arc::new(inner),
+        })
+    }
+
+    /// The number of subscriptions that have not been cancelled or completed yet.
+    pub fn active_subscriptions(&self) -> usize {
+        self.inner.active_subscriptions.load(Ordering::SeqCst)
+    }
+
+    /// Subscribes to the stream, the responses first yield the `Subscribed`
+    /// response followed by the events of the stream in the requested range,
+    /// or by the `StreamDeleted` response if the stream has been hard deleted.
+    ///
+    /// The events wait in the outbox of the connection until the responses yield them.
+    /// The subscription is cancelled when the returned handle is dropped.
+    pub fn subscribe(
+        &self,
+        stream: EsStream,
+        outbox: Arc<Outbox>,
+    ) -> Result<(Subscription, Responses), Error> {
+        let (sender, receiver) = mpsc::unbounded();
+        let (cancel, cancelled_receiver) = oneshot::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+        let quota = Arc::new(Quota::new(outbox));
+
+        let subscribed = Response::Subscribed {
+            stream: stream.name.clone(),
+        };
+        let _ = sender.unbounded_send(Ok(subscribed));
+
+        // a hard deleted stream will never have events again
+        let deleted = store::deletion(&self.inner.db, &stream.name)?;
+        if deleted == Some(store::Deletion::Hard) {
+            let deleted = Response::StreamDeleted {
+                stream: stream.name.clone(),
+            };
+            let _ = sender.unbounded_send(Ok(deleted));
+        } else {
+            let (quota, cancelled) = (quota.clone(), cancelled.clone());
+            self.start(&stream, deleted, sender, quota, cancelled)?;
+        }
+
+        let subscription = Subscription {
+            hub: self.clone(),
+            stream: stream.name,
+            cancelled,
+            _cancel: cancel,
+        };
+
+        let responses = Responses {
+            receiver,
+            quota,
+            cancelled: cancelled_receiver,
+        };
+
+        Ok((subscription, responses))
+    }
+
+    /// Sends the events of the stream to the subscriber, from disk or as they are published.
+    fn start(
+        &self,
+        stream: &EsStream,
+        deleted: Option<store::Deletion>,
+        sender: ResponseSender,
+        quota: Arc<Quota>,
+        cancelled: Arc<AtomicBool>,
+    ) -> Result<(), Error> {
+        let (from, end) = match stream.range {
+            ReadRange::ReadFrom(from) => (Some(from), None),
+            ReadRange::ReadFromUntil(from, to) => (Some(from), Some(EventNumber(to))),
+            ReadRange::ReadFromEnd => (None, None),
+        };
+
+        self.inner
+            .active_subscriptions
+            .fetch_add(1, Ordering::SeqCst);
+        let subscriber = Subscriber {
+            stream: stream.name.clone(),
+            next: EventNumber(from.unwrap_or(0)),
+            end,
+            filter: stream.filter.clone(),
+            deleted,
+            skipped: 0,
+            sender,
+            quota,
+            throttled: false,
+            cancelled,
+            active_subscriptions: self.inner.active_subscriptions.clone(),
+        };
+
+        match from {
+            Some(_) => self.catch_up(subscriber),
+            None => self.follow(subscriber)?,
+        }
+
+        Ok(())
+    }
+
+    /// Sends the `StreamDeleted` response to the live subscribers of the stream
+    /// and ends their subscriptions, the watcher of the stream is stopped.
+    pub fn stream_deleted(&self, stream: &EsStreamName) {
+        let feed = match self.inner.feeds.lock().unwrap().remove(stream) {
+            Some(feed) => feed,
+            None => return,
+        };
+
+        let subscribers = {
+            let mut state = feed.state.lock().unwrap();
+            state.closed = true;
+            mem::replace(&mut state.subscribers, Vec::new())
+        };
+
+        for subscriber in subscribers {
+            let deleted = Response::StreamDeleted {
+                stream: stream.clone(),
+            };
+            let _ = subscriber.sender.unbounded_send(Ok(deleted));
+        }
+
+        if let Err(e) = feed.tree.remove(&feed.prefix) {
+            error!("error waking up the watcher of {}; {}", stream, e);
+        }
+    }
+
+    /// Returns the feed of the stream, spawning the watcher thread if needed.
+    fn feed(&self, stream: &EsStreamName) -> Result<Arc<Feed>, Error> {
+        let mut feeds = self.inner.feeds.lock().unwrap();
+        if let Some(feed) = feeds.get(stream) {
+            return Ok(feed.clone());
+        }
+
+        let (tree, prefix) = store::stream_tree(&self.inner.db, stream)?;
+        let watcher = tree.watch_prefix(prefix.clone());
+        let feed = Arc::new(Feed {
+            tree,
+            prefix,
+            state: Mutex::new(FeedState::default()),
+        });
+
+        let hub = self.clone();
+        let name = stream.clone();
+        let watched = feed.clone();
+        thread::Builder::new()
+            .name(format!("watcher-{}", stream))
+            .spawn(move || hub.watch(name, watched, watcher))?;
+
+        feeds.insert(stream.clone(), feed.clone());
+        Ok(feed)
+    }
+
+    /// Decodes the event stored in the tree of the stream under this number,
+    /// returns `None` if the event a system stream links to does not exist anymore.
+    fn feed_event(
+        &self,
+        stream: &EsStreamName,
+        number: EventNumber,
+        value: IVec,
+    ) -> Result<Option<FeedEvent>, Error> {
+        let (link, stream, number, raw_event) = if stream.is_system() {
+            match store::resolve_link(&self.inner.db, &value)? {
+                Some((origin, origin_number, raw_event)) => {
+                    let link = EventLink {
+                        stream: stream.clone(),
+                        number,
+                    };
+                    (Some(link), origin, origin_number, raw_event)
+                }
+                None => return Ok(None),
+            }
+        } else {
+            (None, stream.clone(), number, RawEvent::new(value))
+        };
+
+        Ok(Some(FeedEvent {
+            stream,
+            number,
+            event_name: raw_event.name().unwrap(),
+            event_data: raw_event.data().unwrap(),
+            metadata: raw_event.metadata().unwrap(),
+            link,
+        }))
+    }
+
+    /// Registers a subscriber which only wants the events published from now on.
+    fn follow(&self, mut subscriber: Subscriber) -> Result<(), Error> {
+        loop {
+            let feed = self.feed(&subscriber.stream)?;
+            let mut state = feed.state.lock().unwrap();
+            if state.closed {
+                continue;
+            }
+
+            // the counter is written in the same transaction as the events, the events
+            // published before the subscription are skipped even if not yet broadcast
+            let last = self.inner.db.get(&subscriber.stream)?;
+            let last = last.map(|k| EventNumber::try_from(k.as_ref()).unwrap());
+            subscriber.next = last.map_or(EventNumber::zero(), EventNumber::next);
+
+            state.subscribers.push(subscriber);
+            return Ok(());
+        }
+    }
+
+    /// Schedules the subscriber to read the stream from disk until
+    /// it reaches the events that are sent to the live subscribers.
+    ///
+    /// The stream is read by chunks, the subscriber is scheduled again after each
+    /// of them so that a long history does not hold a thread of the pool.
+    fn catch_up(&self, subscriber: Subscriber) {
+        let hub = self.clone();
+        self.inner.catch_up_pool.execute(move || {
+            let stream = subscriber.stream.clone();
+            match hub.run_catch_up(subscriber) {
+                Ok(CatchUp::Pending(subscriber)) => return hub.catch_up(subscriber),
+                Ok(CatchUp::Full(subscriber, size)) => {
+                    let quota = subscriber.quota.clone();
+                    let rescheduled = hub.clone();
+                    let job = Box::new(move || rescheduled.catch_up(subscriber));
+                    return quota.on_room(size, job);
+                }
+                Ok(CatchUp::Done) => (),
+                Err(e) => error!("error catching up {}; {}", stream, e),
+            }
+
+            // the subscriber may have been cancelled or completed
+            // after the feed has been opened for it
+            hub.remove_cancelled(&stream);
+        });
+    }
+
+    fn run_catch_up(&self, mut subscriber: Subscriber) -> Result<CatchUp, Error> {
+        let mut read = 0;
+
+        loop {
+            if subscriber.is_cancelled() || self.deleted_meanwhile(&mut subscriber)? {
+                return Ok(CatchUp::Done);
+            }
+
+            let feed = match self.feed(&subscriber.stream) {
+                Ok(feed) => feed,
+                Err(e) => {
+                    let _ = subscriber.sender.unbounded_send(Err(e.to_string()));
+                    return Err(e);
+                }
+            };
+
+            // the events before the start of a truncated stream are skipped
+            match store::stream_start(&self.inner.db, &subscriber.stream) {
+                Ok(start) if subscriber.next < start => subscriber.next = start,
+                Ok(_) => (),
+                Err(e) => {
+                    let _ = subscriber.sender.unbounded_send(Err(e.to_string()));
+                    return Err(e);
+                }
+            }
+
+            for result in feed.tree.range(feed.key(subscriber.next)..) {
+                if read == CATCH_UP_CHUNK_SIZE {
+                    return Ok(CatchUp::Pending(subscriber));
+                }
+                read += 1;
+
+                let (key, value) = match result {
+                    Ok(entry) => entry,
+                    Err(e) => {
+                        let _ = subscriber.sender.unbounded_send(Err(e.to_string()));
+                        return Err(Error::from(e));
+                    }
+                };
+
+                // the tree can contain the events of other streams after these ones
+                if !key.starts_with(&feed.prefix) {
+                    break;
+                }
+
+                let number = feed.number(&key);
+                if subscriber.is_cancelled() || subscriber.end.map_or(false, |end| number >= end) {
+                    return Ok(CatchUp::Done);
+                }
+
+                let event = match self.feed_event(&subscriber.stream, number, value) {
+                    Ok(Some(event)) => event,
+                    Ok(None) => {
+                        subscriber.sent(number);
+                        continue;
+                    }
+                    Err(e) => {
+                        let _ = subscriber.sender.unbounded_send(Err(e.to_string()));
+                        return Err(e);
+                    }
+                };
+
+                match subscriber.send_event(number, &event) {
+                    Status::Continue => (),
+                    Status::Full(size) => return Ok(CatchUp::Full(subscriber, size)),
+                    Status::Lagging | Status::Done => return Ok(CatchUp::Done),
+                }
+            }
+
+            // we hand the subscriber over to the watcher only if it did not broadcast
+            // an event we missed while reading, otherwise we read the stream again
+            let mut state = feed.state.lock().unwrap();
+            if state.closed || subscriber.is_cancelled() {
+                continue;
+            }
+
+            // the stream may have been deleted before the subscriber joins
+            // the live ones, it would not be notified of the deletion
+            if self.deleted_meanwhile(&mut subscriber)? {
+                return Ok(CatchUp::Done);
+            }
+
+            match state.last_number {
+                Some(last) if last >= subscriber.next => continue,
+                _ => {
+                    state.subscribers.push(subscriber);
+                    return Ok(CatchUp::Done);
+                }
+            }
+        }
+    }
+
+    /// Sends the `StreamDeleted` response to a subscriber catching up if the stream
+    /// has been deleted since it last checked, returns `true` if it has been.
+    ///
+    /// A soft deleted stream is only deleted again once it has been recreated.
+    fn deleted_meanwhile(&self, subscriber: &mut Subscriber) -> Result<bool, Error> {
+        let deletion = match store::deletion(&self.inner.db, &subscriber.stream) {
+            Ok(deletion) => deletion,
+            Err(e) => {
+                let _ = subscriber.sender.unbounded_send(Err(e.to_string()));
+                return Err(e);
+            }
+        };
+
+        let deleted = deletion == Some(store::Deletion::Hard)
+            || (deletion.is_some() && subscriber.deleted.is_none());
+
+        if deleted {
+            let deleted = Response::StreamDeleted {
+                stream: subscriber.stream.clone(),
+            };
+            let _ = subscriber.sender.unbounded_send(Ok(deleted));
+        } else {
+            subscriber.deleted = deletion;
+        }
+
+        Ok(deleted)
+    }
+
+    fn watch(&self, stream: EsStreamName, feed: Arc<Feed>, watcher: sled::Subscriber) {
+        info!("watcher on {} spawned", stream);
+
+        for event in watcher {
+            let (key, value) = match event {
+                Event::Insert(key, value) => (key, value),
+                Event::Remove(_) => {
+                    let unused = feed.state.lock().unwrap().subscribers.is_empty();
+                    if unused && self.close_if_unused(&stream, &feed) {
+                        break;
+                    }
+                    continue;
+                }
+            };
+
+            let number = feed.number(&key);
+            let event = match self.feed_event(&stream, number, value) {
+                Ok(event) => event,
+                Err(e) => {
+                    error!("error reading event {} of {}; {}", number.0, stream, e);
+                    None
+                }
+            };
+
+            let mut lagging = Vec::new();
+            let unused = {
+                let mut state = feed.state.lock().unwrap();
+                state.last_number = Some(number);
+
+                let event = match event {
+                    Some(event) => event,
+                    None => continue,
+                };
+
+                for mut subscriber in mem::replace(&mut state.subscribers, Vec::new()) {
+                    match subscriber.try_send_event(number, &event) {
+                        Status::Continue => state.subscribers.push(subscriber),
+                        Status::Lagging | Status::Full(_) => lagging.push(subscriber),
+                        Status::Done => (),
+                    }
+                }
+
+                state.subscribers.is_empty()
+            };
+
+            for subscriber in lagging {
+                let behind = number.0.saturating_sub(subscriber.next.0);
+                warn!(
+                    "subscriber of {} is {} events behind, reading them from disk",
+                    stream, behind
+                );
+                self.catch_up(subscriber);
+            }
+
+            if unused && self.close_if_unused(&stream, &feed) {
+                break;
+            }
+        }
+
+        info!("watcher on {} stopped", stream);
+    }
+
+    fn close_if_unused(&self, stream: &EsStreamName, feed: &Arc<Feed>) -> bool {
+        let mut feeds = self.inner.feeds.lock().unwrap();
+        let mut state = feed.state.lock().unwrap();
+
+        // the feed of a deleted stream is already closed and
+        // another feed may have replaced it since
+        if state.closed {
+            return true;
+        }
+
+        if state.subscribers.is_empty() {
+            state.closed = true;
+            feeds.remove(stream);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Removes the cancelled subscribers from the feed of the stream and
+    /// stops the watcher right away if there is no subscriber left.
+    fn remove_cancelled(&self, stream: &EsStreamName) {
+        let feed = match self.inner.feeds.lock().unwrap().get(stream) {
+            Some(feed) => feed.clone(),
+            None => return,
+        };
+
+        let unused = {
+            let mut state = feed.state.lock().unwrap();
+            state.subscribers.retain(|s| !s.is_cancelled());
+            state.subscribers.is_empty()
+        };
+
+        if unused {
+            if let Err(e) = feed.tree.remove(&feed.prefix) {
+                error!("error waking up the watcher of {}; {}", stream, e);
+            }
+        }
+    }
+
+    /// The number of streams that are currently watched.
+    #[cfg(test)]
+    fn watched_streams(&self) -> usize {
+        self.inner.feeds.lock().unwrap().len()
+    }
+}
+
+/// A handle on a subscription, the subscription is cancelled when it is dropped.
+pub struct Subscription {
+    hub: Hub,
+    stream: EsStreamName,
+    cancelled: Arc<AtomicBool>,
+    _cancel: oneshot::Sender<()>,
+}
+
+impl Drop for Subscription {
+    fn drop(&mut self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+        self.hub.remove_cancelled(&self.stream);
+    }
+}
+
+/// The responses of a subscription, ends as soon as the subscription is cancelled.
+pub struct Responses {
+    receiver: ResponseReceiver,
+    quota: Arc<Quota>,
+    cancelled: oneshot::Receiver<()>,
+}
+
+impl Stream for Responses {
+    type Item = Result<Response, String>;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        match self.cancelled.poll() {
+            Ok(Async::NotReady) => {
+                let response = self.receiver.poll();
+                if let Ok(Async::Ready(Some(Ok(ref event)))) = response {
+                    self.quota.release(response_size(event));
+                }
+                response
+            }
+            _ => Ok(Async::Ready(None)),
+        }
+    }
+}
+
+impl Drop for Responses {
+    fn drop(&mut self) {
+        self.quota.close();
+    }
+}
+
+/// The subscriptions of a connection, they are all cancelled with it.
+#[derive(Default)]
+pub struct Subscriptions {
+    subscriptions: HashMap<EsStreamName, Vec<Subscription>>,
+}
+
+impl Subscriptions {
+    pub fn insert(&mut self, subscription: Subscription) {
+        let stream = subscription.stream.clone();
+        self.subscriptions
+            .entry(stream)
+            .or_default()
+            .push(subscription);
+    }
+
+    /// Cancels the subscriptions to the stream, returns `false` if there was none.
+    pub fn remove(&mut self, stream: &EsStreamName) -> bool {
+        self.subscriptions.remove(stream).is_some()
+    }
+
+    pub fn clear(&mut self) {
+        self.subscriptions.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    use sled::Config;
+
+    use meilies::stream::{ExpectedVersion, PublishMetadata};
+
+    use crate::flow::FlowMetrics;
+    use crate::store;
+
+    fn outbox() -> Arc<Outbox> {
+        Arc::new(Outbox::unbounded(Arc::new(FlowMetrics::default())))
+    }
+
+    fn publish(db: &Db, stream: &EsStreamName, count: usize) {
+        publish_named(db, stream, "my-event", count)
+    }
+
+    fn publish_named(db: &Db, stream: &EsStreamName, name: &str, count: usize) {
+        let name = EventName::new(name.to_owned()).unwrap();
+        let data = EventData("hello".into());
+        for _ in 0..count {
+            let metadata = PublishMetadata::default();
+            store::publish_event(
+                db,
+                stream,
+                &name,
+                &data,
+                metadata,
+                ExpectedVersion::Any,
+                '-',
+            )
+            .unwrap()
+            .unwrap();
+        }
+    }
+
+    fn wait_until<F: Fn() -> bool>(condition: F) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            thread::yield_now();
+        }
+    }
+
+    fn event_numbers(responses: Responses, count: usize) -> Vec<u64> {
+        let mut responses = responses.wait();
+
+        match responses.next() {
+            Some(Ok(Ok(Response::Subscribed { .. }))) => (),
+            otherwise => panic!("unexpected response {:?}", otherwise),
+        }
+
+        responses
+            .take(count)
+            .map(|response| match response {
+                Ok(Ok(Response::Event { number, .. })) => number.0,
+                otherwise => panic!("unexpected response {:?}", otherwise),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn catch_up_then_follow() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let hub = Hub::new(db.clone(), 2).unwrap();
+        let name = EsStreamName::new("my-stream".to_owned()).unwrap();
+
+        publish(&db, &name, 100);
+
+        let stream = EsStream::new(name.clone(), ReadRange::ReadFrom(10));
+        let (_subscription, responses) = hub.subscribe(stream, outbox()).unwrap();
+
+        let publisher = {
+            let db = db.clone();
+            let name = name.clone();
+            thread::spawn(move || publish(&db, &name, 100))
+        };
+
+        let expected: Vec<_> = (10..200).collect();
+        assert_eq!(event_numbers(responses, 190), expected);
+
+        publisher.join().unwrap();
+    }
+
+    #[test]
+    fn catch_up_by_chunks() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let hub = Hub::new(db.clone(), 1).unwrap();
+        let long = EsStreamName::new("long-stream".to_owned()).unwrap();
+        let short = EsStreamName::new("short-stream".to_owned()).unwrap();
+
+        let count = CATCH_UP_CHUNK_SIZE as u64 * 3 + 1;
+        publish(&db, &long, count as usize);
+        publish(&db, &short, 1);
+
+        let stream = EsStream::new(long.clone(), ReadRange::ReadFrom(0));
+        let (_long_subscription, long) = hub.subscribe(stream, outbox()).unwrap();
+
+        // the only thread of the pool is not held until the long stream is read
+        let stream = EsStream::new(short.clone(), ReadRange::ReadFrom(0));
+        let (_short_subscription, short) = hub.subscribe(stream, outbox()).unwrap();
+
+        assert_eq!(event_numbers(short, 1), vec![0]);
+        let expected: Vec<_> = (0..count).collect();
+        assert_eq!(event_numbers(long, count as usize), expected);
+    }
+
+    #[test]
+    fn read_until_and_from_end() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let hub = Hub::new(db.clone(), 2).unwrap();
+        let name = EsStreamName::new("my-stream".to_owned()).unwrap();
+
+        publish(&db, &name, 10);
+
+        let stream = EsStream::new(name.clone(), ReadRange::ReadFromUntil(2, 5));
+        let (_until_subscription, until) = hub.subscribe(stream, outbox()).unwrap();
+
+        let stream = EsStream::new(name.clone(), ReadRange::ReadFromEnd);
+        let (_from_end_subscription, from_end) = hub.subscribe(stream, outbox()).unwrap();
+
+        publish(&db, &name, 5);
+
+        assert_eq!(event_numbers(until, 5), vec![2, 3, 4]);
+        assert_eq!(event_numbers(from_end, 5), vec![10, 11, 12, 13, 14]);
+    }
+
+    #[test]
+    fn one_watcher_per_stream() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let hub = Hub::new(db.clone(), 2).unwrap();
+        let name = EsStreamName::new("my-stream".to_owned()).unwrap();
+
+        let subscriptions: Vec<_> = (0..50)
+            .map(|_| {
+                let stream = EsStream::new(name.clone(), ReadRange::ReadFromEnd);
+                hub.subscribe(stream, outbox()).unwrap()
+            })
+            .collect();
+
+        assert_eq!(hub.watched_streams(), 1);
+
+        publish(&db, &name, 3);
+
+        for (_subscription, responses) in subscriptions {
+            assert_eq!(event_numbers(responses, 3), vec![0, 1, 2]);
+        }
+
+        wait_until(|| hub.watched_streams() == 0);
+    }
+
+    #[test]
+    fn cancel_subscriptions() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let hub = Hub::new(db.clone(), 2).unwrap();
+        let name = EsStreamName::new("my-stream".to_owned()).unwrap();
+
+        publish(&db, &name, 10);
+
+        let mut subscriptions = Subscriptions::default();
+        let mut responses = Vec::new();
+        for range in &[ReadRange::ReadFrom(0), ReadRange::ReadFromEnd] {
+            let stream = EsStream::new(name.clone(), *range);
+            let (subscription, receiver) = hub.subscribe(stream, outbox()).unwrap();
+            subscriptions.insert(subscription);
+            responses.push(receiver);
+        }
+
+        assert_eq!(hub.active_subscriptions(), 2);
+
+        // the subscriptions are cancelled without waiting for a new event
+        subscriptions.clear();
+        wait_until(|| hub.active_subscriptions() == 0 && hub.watched_streams() == 0);
+
+        for receiver in responses {
+            assert!(receiver.wait().next().is_none());
+        }
+
+        // the stream can still be subscribed to afterward
+        let stream = EsStream::new(name.clone(), ReadRange::ReadFrom(8));
+        let (_subscription, receiver) = hub.subscribe(stream, outbox()).unwrap();
+        publish(&db, &name, 1);
+        assert_eq!(event_numbers(receiver, 3), vec![8, 9, 10]);
+    }
+
+    #[test]
+    fn remove_one_stream_subscriptions() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let hub = Hub::new(db.clone(), 2).unwrap();
+        let first = EsStreamName::new("first-stream".to_owned()).unwrap();
+        let second = EsStreamName::new("second-stream".to_owned()).unwrap();
+
+        let mut subscriptions = Subscriptions::default();
+        let mut responses = Vec::new();
+        for name in &[&first, &first, &second] {
+            let stream = EsStream::new((*name).clone(), ReadRange::ReadFromEnd);
+            let (subscription, receiver) = hub.subscribe(stream, outbox()).unwrap();
+            subscriptions.insert(subscription);
+            responses.push(receiver);
+        }
+
+        assert!(subscriptions.remove(&first));
+        assert!(!subscriptions.remove(&first));
+        wait_until(|| hub.active_subscriptions() == 1 && hub.watched_streams() == 1);
+
+        publish(&db, &first, 1);
+        publish(&db, &second, 1);
+
+        let second_responses = responses.pop().unwrap();
+        for receiver in responses {
+            assert!(receiver.wait().next().is_none());
+        }
+        assert_eq!(event_numbers(second_responses, 1), vec![0]);
+    }
+
+    #[test]
+    fn remove_the_all_subscriptions_only() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let hub = Hub::new(db.clone(), 2).unwrap();
+        let stream = EsStreamName::new("my-stream".to_owned()).unwrap();
+
+        let mut subscriptions = Subscriptions::default();
+        let all = EsStream::all(ReadRange::ReadFromEnd);
+        let (subscription, all_responses) = hub.subscribe(all, outbox()).unwrap();
+        subscriptions.insert(subscription);
+
+        let one = EsStream::new(stream.clone(), ReadRange::ReadFromEnd);
+        let (subscription, responses) = hub.subscribe(one, outbox()).unwrap();
+        subscriptions.insert(subscription);
+
+        assert!(subscriptions.remove(&EsStreamName::all()));
+        wait_until(|| hub.active_subscriptions() == 1);
+
+        publish(&db, &stream, 1);
+
+        assert!(all_responses.wait().next().is_none());
+        assert_eq!(event_numbers(responses, 1), vec![0]);
+    }
+
+    #[test]
+    fn subscribe_to_all_in_commit_order() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let hub = Hub::new(db.clone(), 2).unwrap();
+        let first = EsStreamName::new("first-stream".to_owned()).unwrap();
+        let second = EsStreamName::new("second-stream".to_owned()).unwrap();
+
+        publish(&db, &first, 2);
+        publish(&db, &second, 1);
+        publish(&db, &first, 1);
+
+        let stream = EsStream::all(ReadRange::ReadFrom(1));
+        let (_subscription, responses) = hub.subscribe(stream, outbox()).unwrap();
+
+        publish(&db, &second, 1);
+
+        let events: Vec<_> = responses
+            .wait()
+            .skip(1)
+            .take(4)
+            .map(|response| match response {
+                Ok(Ok(Response::Event {
+                    stream,
+                    number,
+                    link: Some(link),
+                    ..
+                })) => {
+                    assert_eq!(link.stream, EsStreamName::all());
+                    (stream.into_inner(), number.0, link.number.0)
+                }
+                otherwise => panic!("unexpected response {:?}", otherwise),
+            })
+            .collect();
+
+        let expected = vec![
+            ("first-stream".to_owned(), 1, 1),
+            ("second-stream".to_owned(), 0, 2),
+            ("first-stream".to_owned(), 2, 3),
+            ("second-stream".to_owned(), 1, 4),
+        ];
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn subscribe_to_a_category() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let hub = Hub::new(db.clone(), 2).unwrap();
+        let first = EsStreamName::new("order-1".to_owned()).unwrap();
+        let second = EsStreamName::new("order-2".to_owned()).unwrap();
+        let other = EsStreamName::new("payment-1".to_owned()).unwrap();
+        let category = EsStreamName::new("$ce-order".to_owned()).unwrap();
+
+        publish(&db, &first, 1);
+        publish(&db, &other, 1);
+        publish(&db, &second, 1);
+
+        let stream = EsStream::new(category.clone(), ReadRange::ReadFrom(0));
+        let (_subscription, responses) = hub.subscribe(stream, outbox()).unwrap();
+
+        publish(&db, &other, 1);
+        publish(&db, &first, 1);
+
+        let events: Vec<_> = responses
+            .wait()
+            .skip(1)
+            .take(3)
+            .map(|response| match response {
+                Ok(Ok(Response::Event {
+                    stream,
+                    number,
+                    link: Some(link),
+                    ..
+                })) => {
+                    assert_eq!(link.stream, category);
+                    (stream.into_inner(), number.0, link.number.0)
+                }
+                otherwise => panic!("unexpected response {:?}", otherwise),
+            })
+            .collect();
+
+        let expected = vec![
+            ("order-1".to_owned(), 0, 0),
+            ("order-2".to_owned(), 0, 1),
+            ("order-1".to_owned(), 1, 2),
+        ];
+        assert_eq!(events, expected);
+    }
+
+    #[test]
+    fn subscribe_with_a_filter() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let hub = Hub::new(db.clone(), 2).unwrap();
+        let name = EsStreamName::new("my-stream".to_owned()).unwrap();
+
+        for _ in 0..5 {
+            publish_named(&db, &name, "UserRegistered", 1);
+            publish_named(&db, &name, "OrderPlaced", 1);
+        }
+
+        let filter = "User*".parse::<EventFilter>().unwrap();
+        let stream = EsStream::new(name.clone(), ReadRange::ReadFrom(3));
+        let (_subscription, responses) = hub
+            .subscribe(stream.with_filter(Some(filter)), outbox())
+            .unwrap();
+
+        let filter = "OrderPlaced".parse::<EventFilter>().unwrap();
+        let stream = EsStream::new(name.clone(), ReadRange::ReadFromUntil(0, 4));
+        let (_until_subscription, until) = hub
+            .subscribe(stream.with_filter(Some(filter)), outbox())
+            .unwrap();
+
+        for _ in 0..5 {
+            publish_named(&db, &name, "UserRegistered", 1);
+            publish_named(&db, &name, "OrderPlaced", 1);
+        }
+
+        assert_eq!(
+            event_numbers(responses, 8),
+            vec![4, 6, 8, 10, 12, 14, 16, 18]
+        );
+
+        let mut until = until.wait().skip(1).map(|response| match response {
+            Ok(Ok(Response::Event { number, .. })) => number.0,
+            otherwise => panic!("unexpected response {:?}", otherwise),
+        });
+        assert_eq!(until.next(), Some(1));
+        assert_eq!(until.next(), Some(3));
+        assert_eq!(until.next(), None);
+    }
+
+    #[test]
+    fn report_the_progress_of_filtered_subscriptions() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let hub = Hub::new(db.clone(), 2).unwrap();
+        let name = EsStreamName::new("my-stream".to_owned()).unwrap();
+
+        publish_named(&db, &name, "OrderPlaced", 2 * PROGRESS_INTERVAL + 10);
+        publish_named(&db, &name, "UserRegistered", 1);
+
+        let filter = "User*".parse::<EventFilter>().unwrap();
+        let stream = EsStream::new(name.clone(), ReadRange::ReadFrom(0));
+        let (_subscription, responses) = hub
+            .subscribe(stream.with_filter(Some(filter)), outbox())
+            .unwrap();
+
+        // the events published live are counted along with the ones read from disk
+        publish_named(&db, &name, "OrderPlaced", PROGRESS_INTERVAL);
+
+        let interval = PROGRESS_INTERVAL as u64;
+        let responses: Vec<_> = responses
+            .wait()
+            .skip(1)
+            .take(4)
+            .map(|response| match response {
+                Ok(Ok(Response::Progress { number, .. })) => (true, number.0),
+                Ok(Ok(Response::Event { number, .. })) => (false, number.0),
+                otherwise => panic!("unexpected response {:?}", otherwise),
+            })
+            .collect();
+
+        let last = 2 * interval + 10;
+        assert_eq!(
+            responses,
+            vec![
+                (true, interval - 1),
+                (true, 2 * interval - 1),
+                (false, last),
+                (true, last + interval),
+            ]
+        );
+    }
+
+    #[test]
+    fn notify_deleted_stream_subscribers() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let hub = Hub::new(db.clone(), 2).unwrap();
+        let name = EsStreamName::new("my-stream".to_owned()).unwrap();
+
+        publish(&db, &name, 3);
+
+        let stream = EsStream::new(name.clone(), ReadRange::ReadFromEnd);
+        let (_subscription, responses) = hub.subscribe(stream, outbox()).unwrap();
+        wait_until(|| hub.watched_streams() == 1);
+
+        assert!(store::delete_stream(&db, &name, false).unwrap());
+        hub.stream_deleted(&name);
+
+        let responses: Vec<_> = responses.wait().skip(1).map(Result::unwrap).collect();
+        let deleted = Response::StreamDeleted {
+            stream: name.clone(),
+        };
+        assert_eq!(responses, vec![Ok(deleted.clone())]);
+        wait_until(|| hub.watched_streams() == 0);
+
+        // the stream is recreated after its soft deleted events
+        publish(&db, &name, 2);
+        let stream = EsStream::new(name.clone(), ReadRange::ReadFrom(0));
+        let (_subscription, responses) = hub.subscribe(stream, outbox()).unwrap();
+        assert_eq!(event_numbers(responses, 2), vec![3, 4]);
+
+        assert!(store::delete_stream(&db, &name, true).unwrap());
+        hub.stream_deleted(&name);
+        store::drop_deleted_stream(&db, &name).unwrap();
+
+        let stream = EsStream::new(name.clone(), ReadRange::ReadFrom(0));
+        let (_subscription, responses) = hub.subscribe(stream, outbox()).unwrap();
+        let responses: Vec<_> = responses.wait().skip(1).map(Result::unwrap).collect();
+        assert_eq!(responses, vec![Ok(deleted)]);
+    }
+
+    #[test]
+    fn notify_subscribers_catching_up() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let hub = Hub::new(db.clone(), 1).unwrap();
+        let soft = EsStreamName::new("soft-stream".to_owned()).unwrap();
+        let hard = EsStreamName::new("hard-stream".to_owned()).unwrap();
+        let recreated = EsStreamName::new("recreated-stream".to_owned()).unwrap();
+
+        publish(&db, &soft, 3);
+        publish(&db, &hard, 3);
+        publish(&db, &recreated, 3);
+        assert!(store::delete_stream(&db, &recreated, false).unwrap());
+
+        // the only catch-up thread waits while the streams are deleted
+        let (resume, blocked) = std::sync::mpsc::channel::<()>();
+        hub.inner.catch_up_pool.execute(move || {
+            let _ = blocked.recv();
+        });
+
+        let mut subscriptions = Vec::new();
+        let mut responses = Vec::new();
+        for name in &[&soft, &hard, &recreated] {
+            let stream = EsStream::new((*name).clone(), ReadRange::ReadFrom(0));
+            let (subscription, receiver) = hub.subscribe(stream, outbox()).unwrap();
+            subscriptions.push(subscription);
+            responses.push(receiver);
+        }
+
+        assert!(store::delete_stream(&db, &soft, false).unwrap());
+        hub.stream_deleted(&soft);
+        assert!(store::delete_stream(&db, &hard, true).unwrap());
+        hub.stream_deleted(&hard);
+        store::drop_deleted_stream(&db, &hard).unwrap();
+        resume.send(()).unwrap();
+
+        let recreated_responses = responses.pop().unwrap();
+        for (name, receiver) in [&soft, &hard].iter().zip(responses) {
+            let deleted = Response::StreamDeleted {
+                stream: (*name).clone(),
+            };
+            let responses: Vec<_> = receiver.wait().skip(1).map(Result::unwrap).collect();
+            assert_eq!(responses, vec![Ok(deleted)]);
+        }
+
+        // the stream was already soft deleted when subscribing to it
+        publish(&db, &recreated, 1);
+        assert_eq!(event_numbers(recreated_responses, 1), vec![3]);
+    }
+
+    #[test]
+    fn do_not_wait_for_the_slow_clients_catching_up() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let hub = Hub::new(db.clone(), 2).unwrap();
+        let name = EsStreamName::new("my-stream".to_owned()).unwrap();
+        let metrics = Arc::new(FlowMetrics::default());
+
+        publish(&db, &name, 10);
+        let expected: Vec<_> = (0..10).collect();
+
+        // more clients than catch-up threads do not read their events,
+        // their buffer only holds a single event at a time
+        let mut stalled = Vec::new();
+        for _ in 0..4 {
+            let outbox = Outbox::new(1, SlowConsumerPolicy::CatchUp, metrics.clone());
+            let stream = EsStream::new(name.clone(), ReadRange::ReadFrom(0));
+            let (subscription, responses) = hub.subscribe(stream, Arc::new(outbox)).unwrap();
+            stalled.push((subscription, responses));
+        }
+        wait_until(|| metrics.fell_behind() == 4);
+
+        let stream = EsStream::new(name.clone(), ReadRange::ReadFrom(0));
+        let (_subscription, responses) = hub.subscribe(stream, outbox()).unwrap();
+        assert_eq!(event_numbers(responses, 10), expected);
+
+        // a client reading its events again is sent the following ones
+        let (_subscription, responses) = stalled.pop().unwrap();
+        assert_eq!(event_numbers(responses, 10), expected);
+    }
+
+    #[test]
+    fn apply_slow_consumer_policies() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let hub = Hub::new(db.clone(), 2).unwrap();
+        let name = EsStreamName::new("my-stream".to_owned()).unwrap();
+        let metrics = Arc::new(FlowMetrics::default());
+
+        // the buffer only holds a single event at a time
+        for policy in &[SlowConsumerPolicy::Block, SlowConsumerPolicy::CatchUp] {
+            let outbox = Arc::new(Outbox::new(1, *policy, metrics.clone()));
+            let stream = EsStream::new(name.clone(), ReadRange::ReadFromEnd);
+            let (_subscription, responses) = hub.subscribe(stream, outbox).unwrap();
+
+            let last = db.get(&name).unwrap();
+            let from = last.map_or(0, |l| EventNumber::try_from(l.as_ref()).unwrap().0 + 1);
+            publish(&db, &name, 20);
+
+            let expected: Vec<_> = (from..from + 20).collect();
+            assert_eq!(event_numbers(responses, 20), expected);
+        }
+        assert!(metrics.fell_behind() > 0);
+
+        let outbox = Arc::new(Outbox::new(
+            1,
+            SlowConsumerPolicy::Disconnect,
+            metrics.clone(),
+        ));
+        let stream = EsStream::new(name.clone(), ReadRange::ReadFrom(0));
+        let (_subscription, responses) = hub.subscribe(stream, outbox.clone()).unwrap();
+        wait_until(|| outbox.is_disconnected());
+
+        let responses: Vec<_> = responses.wait().skip(2).map(Result::unwrap).collect();
+        match responses.as_slice() {
+            [Err(error)] => assert!(error.starts_with("slow consumer")),
+            otherwise => panic!("unexpected responses {:?}", otherwise),
+        }
+        assert_eq!(metrics.disconnected(), 1);
+        wait_until(|| metrics.buffered_bytes() == 0);
+    }
+}
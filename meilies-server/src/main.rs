@@ -3,11 +3,13 @@ use std::fmt;
 use std::io::{Error as IoError, ErrorKind};
 use std::net::SocketAddr;
 use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
 use std::thread;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use futures::sync::oneshot;
 use log::{error, info};
-use sled::{Config, Db, Event, IVec, Tree};
+use sled::{Config, Db, TransactionError};
 use structopt::StructOpt;
 use tokio::codec::Decoder;
 use tokio::net::TcpListener;
@@ -16,21 +18,20 @@ use tokio::sync::mpsc;
 
 use meilies::reqresp::{Request, Response, ServerCodec};
 use meilies::reqresp::{RequestMsgError, ResponseMsgError};
-use meilies::resp::{RespBytesConvertError, RespMsgError, RespVecConvertError};
-use meilies::stream::{
-    EventNumber, RawEvent, ReadRange, Stream as EsStream, StreamName as EsStreamName,
+use meilies::resp::{
+    Protocol, RespBytesConvertError, RespCodec, RespLimits, RespMsgError, RespVecConvertError,
 };
+use meilies::stream::{EventNumber, Stream as EsStream, ALL_STREAMS};
 
-fn new_event_number(numbers: &Tree, name: &EsStreamName) -> sled::Result<EventNumber> {
-    let new_value = numbers.update_and_fetch(name, |previous| {
-        let previous = previous.map(|s| EventNumber::try_from(s).unwrap());
-        let new = previous.map_or(EventNumber::zero(), EventNumber::next);
-        let slice = &new.to_be_bytes()[..];
-        Some(IVec::from(slice))
-    })?;
+use crate::flow::{FlowMetrics, Outbox, SlowConsumerPolicy};
+use crate::groups::{Groups, Memberships};
+use crate::hub::{Hub, Subscriptions};
 
-    Ok(EventNumber::try_from(new_value.unwrap().as_ref()).unwrap())
-}
+mod flow;
+mod groups;
+mod hub;
+mod pool;
+mod store;
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "meilies-server", about = "Start the server", author)]
@@ -62,6 +63,59 @@ struct Opt {
         default_value = "/var/lib/meilies"
     )]
     db_path: PathBuf,
+
+    /// The character separating the category of a stream from the rest of its name,
+    /// the `$ce-order` stream links the events of the `order-123` stream.
+    #[structopt(long = "category-separator", default_value = "-")]
+    category_separator: char,
+
+    /// Build the category and event type streams again from the stored events.
+    #[structopt(long = "rebuild-system-streams")]
+    rebuild_system_streams: bool,
+
+    /// Number of seconds between two applications of the retention policies of the streams.
+    #[structopt(long = "scavenge-interval", default_value = "60")]
+    scavenge_interval: u64,
+
+    /// Number of times an event is delivered to the members of a consumer group
+    /// before it is parked, the group then moves on to the next events.
+    #[structopt(long = "max-delivery-attempts", default_value = "10")]
+    max_delivery_attempts: usize,
+
+    /// Number of threads reading past events for the subscribers.
+    #[structopt(long = "catch-up-threads", default_value = "4")]
+    catch_up_threads: usize,
+
+    /// Maximum length in bytes of the bulk strings sent by the clients, event data included.
+    #[structopt(long = "max-bulk-length", default_value = "536870912")]
+    max_bulk_length: usize,
+
+    /// Maximum number of elements of the arrays sent by the clients.
+    #[structopt(long = "max-array-length", default_value = "1048576")]
+    max_array_length: usize,
+
+    /// Maximum number of arrays nested in one another sent by the clients.
+    #[structopt(long = "max-nesting-depth", default_value = "16")]
+    max_nesting_depth: usize,
+
+    /// Maximum number of bytes buffered for a client request that is not complete yet.
+    #[structopt(long = "max-buffered-bytes", default_value = "1073741824")]
+    max_buffered_bytes: usize,
+
+    /// Maximum number of bytes of events buffered for a client that does not read them
+    /// as fast as they are published, shared by all the subscriptions of the connection.
+    #[structopt(long = "connection-buffer-size", default_value = "16777216")]
+    connection_buffer_size: usize,
+
+    /// What to do with the subscribers of a client once the buffer of its connection is full:
+    /// `block` the stream until the client made room, `disconnect` the client
+    /// or make the subscribers `catch-up` from disk.
+    #[structopt(long = "slow-consumer-policy", default_value = "catch-up")]
+    slow_consumer_policy: SlowConsumerPolicy,
+
+    /// Number of seconds between two reports of the clients falling behind, in the logs.
+    #[structopt(long = "metrics-interval", default_value = "60")]
+    metrics_interval: u64,
 }
 
 #[derive(Debug)]
@@ -89,6 +143,15 @@ impl From<sled::Error> for Error {
     }
 }
 
+impl From<TransactionError<()>> for Error {
+    fn from(error: TransactionError<()>) -> Error {
+        match error {
+            TransactionError::Abort(()) => unreachable!("publish transactions are never aborted"),
+            TransactionError::Storage(error) => Error::InternalError(error),
+        }
+    }
+}
+
 impl From<RespVecConvertError<RespBytesConvertError>> for Error {
     fn from(_: RespVecConvertError<RespBytesConvertError>) -> Error {
         Error::InvalidRequest
@@ -101,147 +164,146 @@ impl From<IoError> for Error {
     }
 }
 
-fn send_stream_events(
-    stream: EsStream,
-    tree: Tree,
-    mut sender: mpsc::Sender<Result<Response, String>>,
-) -> sled::Result<()> {
-    info!("blocking subscription on {} spawned", stream);
-
-    match stream.range {
-        ReadRange::ReadFrom(from) => {
-            let mut next_number = EventNumber(from);
-            let mut watcher = tree.watch_prefix(vec![]);
-
-            for result in tree.scan_prefix(next_number.to_be_bytes()) {
-                let (key, value) = result?;
-                let number = EventNumber::try_from(key.as_ref()).unwrap();
-
-                let raw_event = RawEvent::new(value);
-                let event = Response::Event {
-                    stream: stream.name.clone(),
-                    number,
-                    event_name: raw_event.name().unwrap(),
-                    event_data: raw_event.data(),
-                };
-
-                match sender.send(Ok(event)).wait() {
-                    Ok(s) => sender = s,
-                    Err(_) => {
-                        info!("encountered closed channel");
-                        return Ok(());
-                    }
-                }
+/// The subscriptions and the group memberships of a connection, they all end with it.
+struct Connection {
+    subscriptions: Subscriptions,
+    memberships: Memberships,
+    /// The buffer of the events of the subscriptions, shared by all of them.
+    outbox: Arc<Outbox>,
+}
 
-                next_number = number.next();
-                watcher = tree.watch_prefix(vec![]);
-            }
+impl Connection {
+    fn new(outbox: Outbox) -> Connection {
+        Connection {
+            subscriptions: Subscriptions::default(),
+            memberships: Memberships::default(),
+            outbox: Arc::new(outbox),
+        }
+    }
 
-            for event in watcher {
-                if let Event::Insert(key, value) = event {
-                    let number = EventNumber::try_from(key.as_ref()).unwrap();
-                    if number >= next_number {
-                        let raw_event = RawEvent::new(value);
-                        let event = Response::Event {
-                            stream: stream.name.clone(),
-                            number,
-                            event_name: raw_event.name().unwrap(),
-                            event_data: raw_event.data(),
-                        };
-
-                        match sender.send(Ok(event)).wait() {
-                            Ok(s) => sender = s,
-                            Err(_) => {
-                                info!("encountered closed channel");
-                                return Ok(());
-                            }
-                        }
-                    }
-                }
-            }
+    fn clear(&mut self) {
+        self.subscriptions.clear();
+        self.memberships.clear();
+    }
+}
+
+/// Sends the replies to a request, with the id of the request if it was sent with one.
+struct Replies {
+    sender: mpsc::Sender<Result<Response, String>>,
+    id: Option<u64>,
+}
+
+impl Replies {
+    fn new(sender: mpsc::Sender<Result<Response, String>>) -> Replies {
+        Replies { sender, id: None }
+    }
+
+    fn send(&self, reply: Result<Response, String>) {
+        let reply = match self.id {
+            Some(id) => Ok(Response::Reply {
+                id,
+                reply: Box::new(reply),
+            }),
+            None => reply,
+        };
+
+        send_reply(&self.sender, reply);
+    }
+
+    /// Replies `OK` to the requests that have no reply of their own, like subscribe,
+    /// when they are sent with an id: the client waits for a reply with this id.
+    fn acknowledge(&self) {
+        if self.id.is_some() {
+            self.send(Ok(Response::Ok));
         }
-        ReadRange::ReadFromUntil(from, to) => {
-            let mut next_number = EventNumber(from);
-            let to_event_number = EventNumber(to);
-            let mut watcher = tree.watch_prefix(vec![]);
-
-            for result in tree.range(next_number.to_be_bytes()..to_event_number.to_be_bytes()) {
-                let (key, value) = result?;
-                let number = EventNumber::try_from(key.as_ref()).unwrap();
-
-                let raw_event = RawEvent::new(value);
-                let event = Response::Event {
-                    stream: stream.name.clone(),
-                    number,
-                    event_name: raw_event.name().unwrap(),
-                    event_data: raw_event.data(),
-                };
-
-                match sender.send(Ok(event)).wait() {
-                    Ok(s) => sender = s,
-                    Err(_) => {
-                        info!("encountered closed channel");
-                        return Ok(());
-                    }
-                }
+    }
 
-                next_number = number.next();
-                if next_number >= to_event_number {
-                    return Ok(());
-                }
-                watcher = tree.watch_prefix(vec![]);
-            }
+    /// The sender of the responses of the subscriptions, they are never
+    /// sent with an id: the events are identified by their stream.
+    fn events(&self) -> mpsc::Sender<Result<Response, String>> {
+        self.sender.clone()
+    }
+}
 
-            for event in watcher {
-                if let Event::Insert(key, value) = event {
-                    let number = EventNumber::try_from(key.as_ref()).unwrap();
-                    if number >= to_event_number {
-                        return Ok(());
-                    }
-                    if number >= next_number {
-                        let raw_event = RawEvent::new(value);
-                        let event = Response::Event {
-                            stream: stream.name.clone(),
-                            number,
-                            event_name: raw_event.name().unwrap(),
-                            event_data: raw_event.data(),
-                        };
-
-                        match sender.send(Ok(event)).wait() {
-                            Ok(s) => sender = s,
-                            Err(_) => {
-                                info!("encountered closed channel");
-                                return Ok(());
-                            }
-                        }
-                    }
+/// Sends a reply to the writer of the connection, waiting for it to make room.
+///
+/// The worker thread is marked as blocked while waiting, the writer may need to be
+/// run by another worker to make room when a client sends many requests at once.
+fn send_reply(sender: &mpsc::Sender<Result<Response, String>>, reply: Result<Response, String>) {
+    let mut reply = Some(reply);
+    let mut send = || {
+        let reply = reply.take().expect("a reply is only sent once");
+        sender.clone().send(reply).wait().is_ok()
+    };
+
+    let sent = match tokio_threadpool::blocking(&mut send) {
+        Ok(Async::Ready(sent)) => sent,
+        // there is no room for another blocked worker, or we are not run by a pool
+        Ok(Async::NotReady) | Err(_) => send(),
+    };
+
+    if !sent {
+        info!("encountered closed channel");
+    }
+}
+
+/// Sends the responses of a subscription to the client, along with the other responses.
+fn forward_subscription<S>(responses: S, sender: mpsc::Sender<Result<Response, String>>)
+where
+    S: Stream<Item = Result<Response, String>, Error = ()> + Send + 'static,
+{
+    let forward = responses
+        .forward(sender.sink_map_err(|_| info!("encountered closed channel")))
+        .map(drop);
+
+    tokio::spawn(forward);
+}
+
+/// Logs the state of the subscriptions at regular intervals, if a client fell behind.
+fn spawn_metrics_reporter(
+    hub: Hub,
+    metrics: Arc<FlowMetrics>,
+    interval: Duration,
+) -> Result<(), IoError> {
+    thread::Builder::new()
+        .name("metrics".to_owned())
+        .spawn(move || {
+            let mut last_fell_behind = 0;
+            loop {
+                thread::sleep(interval);
+
+                let fell_behind = metrics.fell_behind();
+                if fell_behind == last_fell_behind && metrics.buffered_bytes() == 0 {
+                    continue;
                 }
+                last_fell_behind = fell_behind;
+
+                info!(
+                    "{} active subscriptions, {} bytes buffered for the clients, \
+                     subscribers fell behind {} times, {} slow clients disconnected",
+                    hub.active_subscriptions(),
+                    metrics.buffered_bytes(),
+                    fell_behind,
+                    metrics.disconnected()
+                );
             }
-        }
-        ReadRange::ReadFromEnd => {
-            let watcher = tree.watch_prefix(vec![]);
-
-            for event in watcher {
-                if let Event::Insert(key, value) = event {
-                    let raw_event = RawEvent::new(value);
-                    let event = Response::Event {
-                        stream: stream.name.clone(),
-                        number: EventNumber::try_from(key.as_ref()).unwrap(),
-                        event_name: raw_event.name().unwrap(),
-                        event_data: raw_event.data(),
-                    };
-
-                    match sender.send(Ok(event)).wait() {
-                        Ok(s) => sender = s,
-                        Err(_) => {
-                            info!("encountered closed channel");
-                            return Ok(());
-                        }
-                    }
-                }
+        })?;
+
+    Ok(())
+}
+
+/// Applies the retention policies of the streams at regular intervals.
+fn spawn_scavenger(db: Db, interval: Duration) -> Result<(), IoError> {
+    thread::Builder::new()
+        .name("scavenger".to_owned())
+        .spawn(move || loop {
+            thread::sleep(interval);
+            match store::scavenge(&db) {
+                Ok(0) => (),
+                Ok(count) => info!("{} events removed by the retention policies", count),
+                Err(e) => error!("error applying the retention policies; {}", e),
             }
-        }
-    }
+        })?;
 
     Ok(())
 }
@@ -249,129 +311,274 @@ fn send_stream_events(
 fn handle_request(
     request: Request,
     db: Db,
-    sender: mpsc::Sender<Result<Response, String>>,
+    hub: &Hub,
+    groups: &Groups,
+    connection: &mut Connection,
+    replies: Replies,
+    category_separator: char,
 ) -> Result<(), Error> {
     match request {
-        Request::SubscribeAll { range } => {
-            let tree_names = db
-                .tree_names()
-                .into_iter()
-                .filter(|n| n != b"__sled__default");
-            let stream_strings = tree_names
-                .into_iter()
-                .map(|b| String::from_utf8(b).unwrap());
-            let stream_names = stream_strings.map(|s| EsStreamName::new(s).unwrap());
-            let all_streams: Vec<_> = stream_names.map(|n| EsStream::new(n, range)).collect();
-
-            for stream in all_streams {
-                let sender = sender.clone();
-                let tree = db.open_tree(stream.name.clone().into_bytes())?;
-
-                thread::Builder::new().spawn(|| {
-                    let mut sender = sender;
-
-                    let subscribed = Response::Subscribed {
-                        stream: stream.name.clone(),
-                    };
-                    match sender.send(Ok(subscribed)).wait() {
-                        Ok(s) => sender = s,
-                        Err(_) => {
-                            info!("encountered closed channel");
-                            return;
-                        }
-                    }
-
-                    if let Err(e) = send_stream_events(stream, tree, sender.clone()) {
-                        if let Err(_) = sender.send(Err(e.to_string())).wait() {
-                            info!("encountered closed channel");
-                            return;
-                        }
-                    }
-                })?;
-            }
+        Request::SubscribeAll { range, filter } => {
+            let outbox = connection.outbox.clone();
+            let stream = EsStream::all(range).with_filter(filter);
+            let (subscription, responses) = hub.subscribe(stream, outbox)?;
+            connection.subscriptions.insert(subscription);
+            forward_subscription(responses, replies.events());
+            replies.acknowledge();
         }
         Request::Subscribe { streams } => {
             for stream in streams {
-                let sender = sender.clone();
-                let tree = db.open_tree(stream.name.clone().into_bytes())?;
-
-                thread::Builder::new().spawn(|| {
-                    let mut sender = sender;
-
-                    let subscribed = Response::Subscribed {
-                        stream: stream.name.clone(),
-                    };
-                    match sender.send(Ok(subscribed)).wait() {
-                        Ok(s) => sender = s,
-                        Err(_) => {
-                            info!("encountered closed channel");
-                            return;
-                        }
-                    }
+                let outbox = connection.outbox.clone();
+                let (subscription, responses) = hub.subscribe(stream, outbox)?;
+                connection.subscriptions.insert(subscription);
+                forward_subscription(responses, replies.events());
+            }
+            replies.acknowledge();
+        }
+        Request::Unsubscribe { streams } => {
+            for stream in streams {
+                if !connection.subscriptions.remove(&stream) {
+                    info!("{:?} was not subscribed", stream);
+                }
 
-                    if let Err(e) = send_stream_events(stream, tree, sender.clone()) {
-                        if let Err(_) = sender.send(Err(e.to_string())).wait() {
-                            info!("encountered closed channel");
-                            return;
-                        }
-                    }
-                })?;
+                let unsubscribed = Response::Unsubscribed { stream };
+                replies.send(Ok(unsubscribed));
             }
         }
+        Request::Publish { ref stream, .. } | Request::PublishBatch { ref stream, .. }
+            if stream.is_system() =>
+        {
+            let error = format!("the {} stream is maintained by the server", stream);
+            replies.send(Err(error));
+        }
         Request::Publish {
             stream,
             event_name,
             event_data,
+            expected_version,
+            metadata,
+            reply_with_number,
         } => {
-            let tree = db.open_tree(stream.clone().into_bytes())?;
-
-            let event_number = new_event_number(&db, &stream)?;
-            let raw_length = event_name.as_str().len().to_be_bytes();
-            let raw_name = event_name.as_str().as_bytes();
-            let raw_data = event_data.0;
-
-            let mut raw_event = Vec::new();
-            raw_event.extend_from_slice(&raw_length);
-            raw_event.extend_from_slice(&raw_name);
-            raw_event.extend_from_slice(&raw_data);
-
-            if let Err(e) = tree.insert(event_number.to_be_bytes(), raw_event) {
-                return Err(Error::InternalError(e));
-            }
+            let result = store::publish_event(
+                &db,
+                &stream,
+                &event_name,
+                &event_data,
+                metadata,
+                expected_version,
+                category_separator,
+            )?;
+
+            let response = match result {
+                Ok(number) => {
+                    info!("{:?} {:?} {:?}", stream, event_name, number);
+                    if reply_with_number {
+                        Ok(Response::Published { stream, number })
+                    } else {
+                        Ok(Response::Ok)
+                    }
+                }
+                Err(store::PublishError::WrongExpectedVersion { actual }) => {
+                    info!(
+                        "{:?} expected {} but is at {:?}",
+                        stream, expected_version, actual
+                    );
+                    Ok(Response::WrongExpectedVersion { stream, actual })
+                }
+                Err(store::PublishError::StreamDeleted) => {
+                    Err(format!("the {} stream has been deleted", stream))
+                }
+            };
 
-            info!("{:?} {:?} {:?}", stream, event_name, event_number);
+            replies.send(response);
+        }
+        Request::PublishBatch { stream, events, id } => {
+            let result = store::publish_events(&db, &stream, &events, id, category_separator)?;
+
+            let response = match result {
+                Ok((first, last)) => {
+                    info!(
+                        "{:?} {} events {:?}..={:?}",
+                        stream,
+                        events.len(),
+                        first,
+                        last
+                    );
+
+                    Ok(Response::PublishedBatch {
+                        stream,
+                        first,
+                        last,
+                    })
+                }
+                Err(store::PublishError::WrongExpectedVersion { actual }) => {
+                    Ok(Response::WrongExpectedVersion { stream, actual })
+                }
+                Err(store::PublishError::StreamDeleted) => {
+                    Err(format!("the {} stream has been deleted", stream))
+                }
+            };
 
-            if sender.send(Ok(Response::Ok)).wait().is_err() {
-                info!("encountered closed channel");
-            }
+            replies.send(response);
         }
         Request::LastEventNumber { stream } => {
             let key = db.get(&stream)?;
             let number = key.map(|k| EventNumber::try_from(k.as_ref()).unwrap());
 
             let last_event_number = Response::LastEventNumber { stream, number };
-            if sender.send(Ok(last_event_number)).wait().is_err() {
-                info!("encountered closed channel");
-            }
+            replies.send(Ok(last_event_number));
+        }
+        Request::Read { ref stream, .. } if stream.is_system() => {
+            let error = format!("the {} stream can only be subscribed to", stream);
+            replies.send(Err(error));
+        }
+        Request::Read {
+            stream,
+            from,
+            count,
+            backwards,
+        } => {
+            let (events, next) = store::read_events(&db, &stream, from, count, backwards)?;
+
+            let response = Response::Events {
+                stream,
+                events,
+                next,
+            };
+            replies.send(Ok(response));
         }
         Request::StreamNames => {
-            let tree_names = db
-                .tree_names()
-                .into_iter()
-                .filter(|n| n != b"__sled__default");
-            let stream_strings = tree_names
-                .into_iter()
-                .map(|b| String::from_utf8(b).unwrap());
-            let stream_names = stream_strings
-                .map(|s| EsStreamName::new(s).unwrap())
-                .collect();
             let streams = Response::StreamNames {
-                streams: stream_names,
+                streams: store::stream_names(&db)?,
+            };
+
+            replies.send(Ok(streams));
+        }
+        Request::DeleteStream { ref stream, .. } | Request::TruncateBefore { ref stream, .. }
+            if stream.is_system() =>
+        {
+            let error = format!("the {} stream is maintained by the server", stream);
+            replies.send(Err(error));
+        }
+        Request::DeleteStream { stream, hard } => {
+            let response = if store::delete_stream(&db, &stream, hard)? {
+                info!("{:?} deleted (hard: {})", stream, hard);
+
+                // the subscribers are notified before the events are removed
+                hub.stream_deleted(&stream);
+                if hard {
+                    store::drop_deleted_stream(&db, &stream)?;
+                }
+
+                Ok(Response::Ok)
+            } else {
+                Err(format!("the {} stream does not exist", stream))
+            };
+
+            replies.send(response);
+        }
+        Request::TruncateBefore { stream, number } => {
+            let response = if store::truncate_before(&db, &stream, number)? {
+                info!("{:?} truncated before {:?}", stream, number);
+                Ok(Response::Ok)
+            } else {
+                Err(format!("the {} stream does not exist", stream))
             };
 
-            if sender.send(Ok(streams)).wait().is_err() {
-                info!("encountered closed channel");
+            replies.send(response);
+        }
+        Request::SetRetention { ref stream, .. } if stream.is_system() => {
+            let error = format!("the {} stream is maintained by the server", stream);
+            replies.send(Err(error));
+        }
+        Request::SetRetention { stream, policy } => {
+            store::set_retention(&db, &stream, policy)?;
+            info!("{:?} retention set to {:?}", stream, policy);
+
+            replies.send(Ok(Response::Ok));
+        }
+        Request::Retention { stream } => {
+            let policy = store::retention(&db, &stream)?;
+
+            let response = Response::Retention { stream, policy };
+            replies.send(Ok(response));
+        }
+        Request::SubscribeGroup { ref stream, .. } if stream.is_system() => {
+            let error = format!("the {} stream can only be subscribed to", stream);
+            replies.send(Err(error));
+        }
+        Request::SubscribeGroup { group, stream } => {
+            info!("joining group {:?} of {:?}", group, stream);
+            let (membership, responses) = groups.join(group, stream)?;
+            connection.memberships.insert(membership);
+            forward_subscription(responses, replies.events());
+            replies.acknowledge();
+        }
+        Request::Ack {
+            group,
+            stream,
+            numbers,
+        } => match connection.memberships.get(&group, &stream) {
+            Some(membership) => {
+                membership.ack(&numbers)?;
+                replies.acknowledge();
+            }
+            None => {
+                let error = format!("not a member of the {} group of {}", group, stream);
+                replies.send(Err(error));
+            }
+        },
+        Request::Nack {
+            group,
+            stream,
+            numbers,
+        } => match connection.memberships.get(&group, &stream) {
+            Some(membership) => {
+                membership.nack(&numbers)?;
+                replies.acknowledge();
             }
+            None => {
+                let error = format!("not a member of the {} group of {}", group, stream);
+                replies.send(Err(error));
+            }
+        },
+        Request::ParkedEvents { group, stream } => {
+            let numbers = store::parked_events(&db, &group, &stream)?;
+
+            let response = Response::ParkedEvents {
+                group,
+                stream,
+                numbers,
+            };
+            replies.send(Ok(response));
+        }
+        Request::Hello { protocol } => {
+            // the codec switches to the protocol when encoding this reply
+            let response = match Protocol::from_version(protocol) {
+                Some(_) => Ok(Response::Hello {
+                    version: env!("CARGO_PKG_VERSION").to_owned(),
+                    protocol,
+                }),
+                None => Err(format!("NOPROTO unsupported protocol version {}", protocol)),
+            };
+
+            replies.send(response);
+        }
+        Request::WithId { id, request } => {
+            // the requests without a reply, like subscribe, are replied to with OK
+            let replies = Replies {
+                sender: replies.sender,
+                id: Some(id),
+            };
+            handle_request(
+                *request,
+                db,
+                hub,
+                groups,
+                connection,
+                replies,
+                category_separator,
+            )?;
         }
     }
 
@@ -457,44 +664,136 @@ fn main() {
     };
     info!("kv-store loaded in {:.2?}", now.elapsed());
 
+    match store::migrate_events(&db) {
+        Ok(0) => (),
+        Ok(count) => info!("{} events migrated to the latest envelope", count),
+        Err(e) => return error!("error migrating events; {}", e),
+    }
+
+    match store::index_all_events(&db) {
+        Ok(0) => (),
+        Ok(count) => info!("{} events indexed in the {} stream", count, ALL_STREAMS),
+        Err(e) => return error!("error indexing events; {}", e),
+    }
+
+    match store::index_categories(&db, opt.category_separator, opt.rebuild_system_streams) {
+        Ok(0) => (),
+        Ok(count) => info!("{} events linked from the category streams", count),
+        Err(e) => return error!("error building the category streams; {}", e),
+    }
+
+    match store::index_event_types(&db, opt.rebuild_system_streams) {
+        Ok(0) => (),
+        Ok(count) => info!("{} events linked from the event type streams", count),
+        Err(e) => return error!("error building the event type streams; {}", e),
+    }
+
+    let interval = Duration::from_secs(opt.scavenge_interval);
+    if let Err(e) = spawn_scavenger(db.clone(), interval) {
+        return error!("error starting the scavenger; {}", e);
+    }
+
+    let hub = match Hub::new(db.clone(), opt.catch_up_threads) {
+        Ok(hub) => hub,
+        Err(e) => return error!("error starting the subscriptions hub; {}", e),
+    };
+
+    let groups = Groups::new(db.clone(), hub.clone(), opt.max_delivery_attempts);
+
+    let metrics = Arc::new(FlowMetrics::default());
+    let interval = Duration::from_secs(opt.metrics_interval);
+    if let Err(e) = spawn_metrics_reporter(hub.clone(), metrics.clone(), interval) {
+        return error!("error starting the metrics reporter; {}", e);
+    }
+
     let listener = match TcpListener::bind(&addr) {
         Ok(listener) => listener,
         Err(e) => return error!("error binding address; {}", e),
     };
     println!("server is listening on {}", addr);
 
+    let limits = RespLimits {
+        max_bulk_length: opt.max_bulk_length,
+        max_array_length: opt.max_array_length,
+        max_depth: opt.max_nesting_depth,
+        max_buffered_bytes: opt.max_buffered_bytes,
+    };
+    let category_separator = opt.category_separator;
+    let buffer_size = opt.connection_buffer_size;
+    let policy = opt.slow_consumer_policy;
+
     let server = listener
         .incoming()
         .map_err(|e| error!("error accepting socket; {}", e))
         .for_each(move |socket| {
-            let framed = ServerCodec::default().framed(socket);
+            let framed = ServerCodec::new(RespCodec::new(limits)).framed(socket);
             let (writer, reader) = framed.split();
             let (sender, receiver) = mpsc::channel(10);
 
             let error_sender = sender.clone();
 
+            // the subscriptions are cancelled as soon as the client stops sending
+            // requests or we can not send it responses anymore
+            let outbox = Outbox::new(buffer_size, policy, metrics.clone());
+            let connection = Connection::new(outbox);
+            let outbox = connection.outbox.clone();
+            let connection = Arc::new(Mutex::new(connection));
+            let requests_connection = connection.clone();
+            let closed_connection = connection.clone();
+
+            // the requests are not read anymore once the responses can not be written,
+            // the socket is closed when both are dropped
+            let (close, closed) = oneshot::channel::<()>();
+
             let db = db.clone();
+            let hub = hub.clone();
+            let groups = groups.clone();
+            let closed_hub = hub.clone();
             let requests = reader
                 .map_err(Error::RequestMsgError)
                 .for_each(move |request| {
                     let db = db.clone();
-                    let sender = sender.clone();
-                    future::result(handle_request(request, db, sender))
+                    let replies = Replies::new(sender.clone());
+                    let mut connection = requests_connection.lock().unwrap();
+                    future::result(handle_request(
+                        request,
+                        db,
+                        &hub,
+                        &groups,
+                        &mut connection,
+                        replies,
+                        category_separator,
+                    ))
                 })
                 .or_else(move |error| {
                     error!("error; {}", error);
-                    if error_sender.send(Err(error.to_string())).wait().is_err() {
-                        info!("encountered closed channel");
-                    }
+                    send_reply(&error_sender, Err(error.to_string()));
 
-                    future::ok(())
+                    future::ok::<(), ()>(())
+                })
+                .select2(closed)
+                .then(move |_| -> Result<(), ()> {
+                    closed_connection.lock().unwrap().clear();
+                    info!(
+                        "connection closed, {} active subscriptions",
+                        closed_hub.active_subscriptions()
+                    );
+                    Ok(())
                 });
 
+            // a slow client is disconnected after it received the error explaining why
+            let mut disconnecting = false;
+
             let responses = receiver
                 .map_err(|e| {
                     let error = RespMsgError::IoError(IoError::new(ErrorKind::BrokenPipe, e));
                     ResponseMsgError::RespMsgError(error)
                 })
+                .take_while(move |response| {
+                    let write = !disconnecting;
+                    disconnecting = outbox.is_disconnected() && response.is_err();
+                    future::ok(write)
+                })
                 .forward(writer)
                 .map_err(|error| {
                     use crate::RespMsgError::IoError;
@@ -507,7 +806,11 @@ fn main() {
                         other => error!("{}", other),
                     }
                 })
-                .map(drop);
+                .then(move |result| {
+                    connection.lock().unwrap().clear();
+                    let _ = close.send(());
+                    result.map(drop)
+                });
 
             tokio::spawn(requests);
             tokio::spawn(responses);
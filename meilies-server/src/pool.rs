@@ -0,0 +1,41 @@
+use std::sync::{mpsc, Arc, Mutex};
+use std::{io, thread};
+
+type Job = Box<dyn FnOnce() + Send>;
+
+/// A fixed number of threads executing blocking jobs in the order they are sent.
+pub struct Pool {
+    sender: Mutex<mpsc::Sender<Job>>,
+}
+
+impl Pool {
+    pub fn new(name: &str, size: usize) -> io::Result<Pool> {
+        let (sender, receiver) = mpsc::channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+
+        for i in 0..size.max(1) {
+            let receiver = receiver.clone();
+            thread::Builder::new()
+                .name(format!("{}-{}", name, i))
+                .spawn(move || loop {
+                    let job = match receiver.lock().unwrap().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    job();
+                })?;
+        }
+
+        Ok(Pool {
+            sender: Mutex::new(sender),
+        })
+    }
+
+    pub fn execute<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        // the receiver lives as long as the threads which never stop
+        let _ = self.sender.lock().unwrap().send(Box::new(job));
+    }
+}
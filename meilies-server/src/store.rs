@@ -0,0 +1,1964 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sled::{
+    Batch, ConflictableTransactionResult, Db, IVec, Transactional, TransactionalTree, Tree,
+};
+
+use meilies::stream::{
+    EventData, EventId, EventMetadata, EventName, EventNumber, ExpectedVersion, GroupName,
+    NumberedEvent, PublishMetadata, RawEvent, RetentionPolicy, StreamName, ALL_STREAMS,
+    CATEGORY_STREAM_PREFIX, ENVELOPE_VERSION, EVENT_TYPE_STREAM_PREFIX,
+};
+
+use crate::Error;
+
+/// Why an event has not been published, nothing has been written.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PublishError {
+    /// The stream was not at the expected version.
+    WrongExpectedVersion { actual: Option<EventNumber> },
+    /// The stream has been hard deleted, it can not be published to anymore.
+    StreamDeleted,
+}
+
+/// How a stream has been deleted.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Deletion {
+    /// The events are hidden, publishing to the stream again recreates it.
+    Soft,
+    /// The events have been removed, the stream can not be recreated.
+    Hard,
+}
+
+impl Deletion {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            Deletion::Soft => b"soft",
+            Deletion::Hard => b"hard",
+        }
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Deletion {
+        match bytes {
+            b"hard" => Deletion::Hard,
+            _ => Deletion::Soft,
+        }
+    }
+}
+
+/// The key of the default tree storing the version of the envelope all the events
+/// are stored in, it can not clash with a stream counter as stream names have no colon.
+const ENVELOPE_VERSION_KEY: &[u8] = b"meilies:envelope-version";
+
+/// The prefix of the keys of the default tree indexing the ids of the events
+/// of the streams, the stream name, a colon and the event id follow it.
+const EVENT_IDS_PREFIX: &[u8] = b"meilies:event-ids:";
+
+/// The prefix of the keys of the default tree storing the number of the first
+/// event of the streams truncated or soft deleted, the stream name follows it.
+/// The events numbered before it are hidden, if not removed yet.
+const STREAM_STARTS_PREFIX: &[u8] = b"meilies:stream-starts:";
+
+/// The prefix of the keys of the default tree marking the deleted streams,
+/// the stream name follows it and the value tells how it has been deleted.
+const DELETED_STREAMS_PREFIX: &[u8] = b"meilies:deleted-streams:";
+
+/// The prefix of the keys of the default tree storing the retention
+/// policies of the streams, the stream name follows it.
+const RETENTIONS_PREFIX: &[u8] = b"meilies:retentions:";
+
+/// The prefix of the keys of the default tree storing the number of the first event
+/// the consumer groups have not acknowledged, the stream name, a colon and the group
+/// name follow it.
+const CHECKPOINTS_PREFIX: &[u8] = b"meilies:checkpoints:";
+
+/// The prefix of the keys of the default tree marking the events the consumer groups
+/// gave up on, the stream name, a colon, the group name, a colon and the event number
+/// follow it.
+const PARKED_EVENTS_PREFIX: &[u8] = b"meilies:parked-events:";
+
+/// The key of the default tree storing the separator the category streams
+/// have been built with, they are built again when it changes.
+const CATEGORY_SEPARATOR_KEY: &[u8] = b"meilies:category-separator";
+
+/// The key of the default tree present once the event type streams have been built.
+const EVENT_TYPES_KEY: &[u8] = b"meilies:event-type-streams";
+
+/// The name of the tree the internal data of sled is stored in,
+/// the stream counters are stored there too.
+const DEFAULT_TREE: &[u8] = b"__sled__default";
+
+/// The tree storing the links of the system streams other than `$all`,
+/// like the category streams. The keys are the name of the system stream,
+/// a colon and the number of the link in the system stream.
+const LINKS_TREE: &str = "$links";
+
+/// The maximum number of events returned by a read, whatever the count requested,
+/// the clients read the following events from the number of the next page.
+pub const MAX_READ_COUNT: usize = 1000;
+
+/// The number of the last events of a stream whose ids are remembered to recognize
+/// an event published again, the ids of the older events are forgotten.
+pub const DEDUP_WINDOW: u64 = 10_000;
+
+/// The prefix of the keys of the links of a stream stored in the links tree.
+fn links_prefix(stream: &StreamName) -> Vec<u8> {
+    let mut prefix = Vec::with_capacity(stream.as_str().len() + 1);
+    prefix.extend_from_slice(stream.as_str().as_bytes());
+    prefix.push(b':');
+    prefix
+}
+
+fn link_key(stream: &StreamName, number: EventNumber) -> Vec<u8> {
+    let mut key = links_prefix(stream);
+    key.extend_from_slice(&number.to_be_bytes());
+    key
+}
+
+/// The tree the events of the stream are stored in and the prefix of their keys,
+/// the keys end with the event numbers.
+///
+/// Every stream has its own tree, like `$all`, except the system streams stored
+/// in the links tree.
+pub fn stream_tree(db: &Db, stream: &StreamName) -> Result<(Tree, Vec<u8>), Error> {
+    if stream.is_system() && *stream != ALL_STREAMS {
+        Ok((db.open_tree(LINKS_TREE)?, links_prefix(stream)))
+    } else {
+        Ok((db.open_tree(stream.as_str())?, Vec::new()))
+    }
+}
+
+/// Encodes a link to an event of a stream, the way it is stored in the tree
+/// of a system stream: the event number followed by the stream name.
+fn link_value(stream: &StreamName, number: EventNumber) -> Vec<u8> {
+    let mut value = Vec::with_capacity(8 + stream.as_str().len());
+    value.extend_from_slice(&number.to_be_bytes());
+    value.extend_from_slice(stream.as_str().as_bytes());
+    value
+}
+
+/// Decodes a link encoded by `link_value`.
+fn parse_link(value: &[u8]) -> (StreamName, EventNumber) {
+    let (number, stream) = value.split_at(8);
+    let number = EventNumber::try_from(number).unwrap();
+    let stream = StreamName::new(String::from_utf8(stream.to_vec()).unwrap()).unwrap();
+    (stream, number)
+}
+
+/// Reads the event a system stream links to.
+///
+/// Returns `None` if the event does not exist anymore.
+pub fn resolve_link(
+    db: &Db,
+    value: &[u8],
+) -> Result<Option<(StreamName, EventNumber, RawEvent<IVec>)>, Error> {
+    let (stream, number) = parse_link(value);
+
+    // the stream may have been deleted or truncated since it has been linked
+    if db.get(&stream)?.is_none() || number < stream_start(db, &stream)? {
+        return Ok(None);
+    }
+
+    let tree = db.open_tree(stream.as_str())?;
+    let raw_event = tree.get(number.to_be_bytes())?;
+    Ok(raw_event.map(|value| (stream, number, RawEvent::new(value))))
+}
+
+/// The names of the streams events have been published to,
+/// the system streams and the deleted streams excluded.
+pub fn stream_names(db: &Db) -> Result<Vec<StreamName>, Error> {
+    let names = db
+        .tree_names()
+        .into_iter()
+        .filter(|n| n != DEFAULT_TREE)
+        .filter_map(|b| StreamName::new(String::from_utf8(b).unwrap()).ok())
+        .filter(|s| !s.is_system());
+
+    let mut streams = Vec::new();
+    for stream in names {
+        if deletion(db, &stream)?.is_none() {
+            streams.push(stream);
+        }
+    }
+
+    Ok(streams)
+}
+
+/// The key of the default tree storing a property of the stream.
+fn stream_key(prefix: &[u8], stream: &StreamName) -> Vec<u8> {
+    let mut key = Vec::with_capacity(prefix.len() + stream.as_str().len());
+    key.extend_from_slice(prefix);
+    key.extend_from_slice(stream.as_str().as_bytes());
+    key
+}
+
+/// The prefix of the keys indexing the ids of the events of the stream.
+fn event_ids_prefix(stream: &StreamName) -> Vec<u8> {
+    let mut prefix = stream_key(EVENT_IDS_PREFIX, stream);
+    prefix.push(b':');
+    prefix
+}
+
+/// The key under which the number of the event with this id is stored.
+fn event_id_key(stream: &StreamName, id: &EventId) -> Vec<u8> {
+    let mut key = event_ids_prefix(stream);
+    key.extend_from_slice(id.as_bytes());
+    key
+}
+
+/// The number of the first event of the stream that has not been truncated.
+pub fn stream_start(db: &Db, stream: &StreamName) -> Result<EventNumber, Error> {
+    let start = db.get(stream_key(STREAM_STARTS_PREFIX, stream))?;
+    Ok(start.map_or(EventNumber::zero(), |s| {
+        EventNumber::try_from(s.as_ref()).unwrap()
+    }))
+}
+
+/// How the stream has been deleted, `None` if it has not been.
+pub fn deletion(db: &Db, stream: &StreamName) -> Result<Option<Deletion>, Error> {
+    let deletion = db.get(stream_key(DELETED_STREAMS_PREFIX, stream))?;
+    Ok(deletion.map(|d| Deletion::from_bytes(&d)))
+}
+
+/// The key of the default tree storing a property of the consumer group of the stream.
+fn group_key(prefix: &[u8], group: &GroupName, stream: &StreamName) -> Vec<u8> {
+    let mut key = stream_key(prefix, stream);
+    key.push(b':');
+    key.extend_from_slice(group.as_str().as_bytes());
+    key
+}
+
+/// The number of the first event of the stream the group has not acknowledged,
+/// `None` if the group has never acknowledged an event of the stream.
+pub fn checkpoint(
+    db: &Db,
+    group: &GroupName,
+    stream: &StreamName,
+) -> Result<Option<EventNumber>, Error> {
+    let checkpoint = db.get(group_key(CHECKPOINTS_PREFIX, group, stream))?;
+    Ok(checkpoint.map(|c| EventNumber::try_from(c.as_ref()).unwrap()))
+}
+
+pub fn set_checkpoint(
+    db: &Db,
+    group: &GroupName,
+    stream: &StreamName,
+    number: EventNumber,
+) -> Result<(), Error> {
+    let key = group_key(CHECKPOINTS_PREFIX, group, stream);
+    db.insert(key, &number.to_be_bytes()[..])?;
+    Ok(())
+}
+
+/// Marks the event as given up on by the group, it will not be delivered again.
+pub fn park_event(
+    db: &Db,
+    group: &GroupName,
+    stream: &StreamName,
+    number: EventNumber,
+) -> Result<(), Error> {
+    let mut key = group_key(PARKED_EVENTS_PREFIX, group, stream);
+    key.push(b':');
+    key.extend_from_slice(&number.to_be_bytes());
+    db.insert(key, &[][..])?;
+    Ok(())
+}
+
+/// The numbers of the events of the stream the group gave up on, in order.
+pub fn parked_events(
+    db: &Db,
+    group: &GroupName,
+    stream: &StreamName,
+) -> Result<Vec<EventNumber>, Error> {
+    let mut prefix = group_key(PARKED_EVENTS_PREFIX, group, stream);
+    prefix.push(b':');
+
+    let mut numbers = Vec::new();
+    for result in db.scan_prefix(&prefix).keys() {
+        let key = result?;
+        numbers.push(EventNumber::try_from(&key[prefix.len()..]).unwrap());
+    }
+
+    Ok(numbers)
+}
+
+/// The number of milliseconds since the unix epoch.
+fn now_timestamp() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+/// An event encoded the way it is stored in a stream tree.
+struct EncodedEvent {
+    /// The id given by the publisher, only these ids are indexed as
+    /// the events with an id generated by the server can not be sent again.
+    id: Option<EventId>,
+    /// The event type stream linking the event, if its name allows one.
+    event_type: Option<StreamName>,
+    raw: Vec<u8>,
+}
+
+/// Encodes an event the way it is stored in a stream tree,
+/// the server assigns the timestamp and the missing metadata.
+fn raw_event(
+    event_name: &EventName,
+    event_data: &EventData,
+    metadata: PublishMetadata,
+) -> EncodedEvent {
+    let given_id = metadata.id.is_some();
+    let metadata = metadata.complete(now_timestamp());
+    let raw_event = RawEvent::encode(event_name, event_data, &metadata);
+    EncodedEvent {
+        id: if given_id { Some(metadata.id) } else { None },
+        event_type: StreamName::event_type_stream(event_name),
+        raw: raw_event.into_inner(),
+    }
+}
+
+/// The trees written when publishing events, viewed in a transaction.
+struct PublishTrees<'a> {
+    /// The default tree, storing the counters of the streams.
+    numbers: &'a TransactionalTree,
+    events: &'a TransactionalTree,
+    all: &'a TransactionalTree,
+    links: &'a TransactionalTree,
+}
+
+/// Opens the trees written when publishing events to the stream.
+fn publish_trees(db: &Db, stream: &StreamName) -> Result<(Tree, Tree, Tree), Error> {
+    let events = db.open_tree(stream.as_str())?;
+    let all = db.open_tree(ALL_STREAMS)?;
+    let links = db.open_tree(LINKS_TREE)?;
+    Ok((events, all, links))
+}
+
+/// Reads the stream counter, checks it against the expected version,
+/// increments it and inserts the events under the new numbers.
+/// The ids given to the events are indexed along with them and the id
+/// of the event published `DEDUP_WINDOW` events earlier is forgotten.
+///
+/// A hard deleted stream is never published to, a soft deleted one
+/// is recreated and numbers the events after its hidden ones.
+///
+/// The events are also given the next positions of the `$all` stream,
+/// its tree links every position to the event published at it, and are
+/// linked from the category stream of the stream if it has one and
+/// from the event type stream of their name.
+///
+/// Must be called inside a transaction involving all the publish trees,
+/// this way the counters and the events are either all written or not
+/// written at all. Every publish writes the `$all` counter, the positions
+/// are therefore given in the order the events are committed.
+fn append_raw_events(
+    trees: &PublishTrees,
+    stream: &StreamName,
+    category_separator: char,
+    expected_version: ExpectedVersion,
+    raw_events: &[EncodedEvent],
+) -> ConflictableTransactionResult<Result<(EventNumber, EventNumber), PublishError>> {
+    let deleted_key = stream_key(DELETED_STREAMS_PREFIX, stream);
+    let deletion = trees.numbers.get(&deleted_key)?;
+    let deletion = deletion.map(|d| Deletion::from_bytes(&d));
+    if deletion == Some(Deletion::Hard) {
+        return Ok(Err(PublishError::StreamDeleted));
+    }
+
+    let previous = trees.numbers.get(stream)?;
+    let previous = previous.map(|s| EventNumber::try_from(s.as_ref()).unwrap());
+
+    if !expected_version.matches(previous) {
+        return Ok(Err(PublishError::WrongExpectedVersion { actual: previous }));
+    }
+
+    if deletion == Some(Deletion::Soft) {
+        trees.numbers.remove(deleted_key)?;
+    }
+
+    let position = trees.numbers.get(ALL_STREAMS)?;
+    let position = position.map(|s| EventNumber::try_from(s.as_ref()).unwrap());
+    let mut position = position.map_or(EventNumber::zero(), EventNumber::next);
+
+    let first = previous.map_or(EventNumber::zero(), EventNumber::next);
+    let mut last = first;
+
+    let mut batch = Batch::default();
+    let mut links = Batch::default();
+    for (i, event) in raw_events.iter().enumerate() {
+        if i != 0 {
+            position = position.next();
+        }
+
+        last = EventNumber(first.0 + i as u64);
+        batch.insert(&last.to_be_bytes()[..], event.raw.as_slice());
+        links.insert(&position.to_be_bytes()[..], link_value(stream, last));
+        if let Some(id) = &event.id {
+            let key = event_id_key(stream, id);
+            trees.numbers.insert(key, &last.to_be_bytes()[..])?;
+        }
+
+        if last.0 >= DEDUP_WINDOW {
+            let forgotten = EventNumber(last.0 - DEDUP_WINDOW);
+            if let Some(value) = trees.events.get(&forgotten.to_be_bytes()[..])? {
+                if let Ok(metadata) = RawEvent::new(value).metadata() {
+                    trees.numbers.remove(event_id_key(stream, &metadata.id))?;
+                }
+            }
+        }
+
+        if let Some(event_type) = &event.event_type {
+            append_links(trees, event_type, stream, last, last)?;
+        }
+    }
+
+    trees
+        .numbers
+        .insert(stream.as_ref(), &last.to_be_bytes()[..])?;
+    trees
+        .numbers
+        .insert(ALL_STREAMS, &position.to_be_bytes()[..])?;
+    trees.events.apply_batch(batch)?;
+    trees.all.apply_batch(links)?;
+
+    if let Some(category) = stream.category_stream(category_separator) {
+        append_links(trees, &category, stream, first, last)?;
+    }
+
+    Ok(Ok((first, last)))
+}
+
+/// Links the events of the stream from `first` to `last` included
+/// from a system stream stored in the links tree.
+fn append_links(
+    trees: &PublishTrees,
+    system_stream: &StreamName,
+    stream: &StreamName,
+    first: EventNumber,
+    last: EventNumber,
+) -> ConflictableTransactionResult<()> {
+    let previous = trees.numbers.get(system_stream)?;
+    let previous = previous.map(|s| EventNumber::try_from(s.as_ref()).unwrap());
+    let next = previous.map_or(EventNumber::zero(), EventNumber::next);
+
+    let mut number = next;
+    for (i, linked) in (first.0..=last.0).enumerate() {
+        number = EventNumber(next.0 + i as u64);
+        let value = link_value(stream, EventNumber(linked));
+        trees.links.insert(link_key(system_stream, number), value)?;
+    }
+
+    trees
+        .numbers
+        .insert(system_stream.as_ref(), &number.to_be_bytes()[..])?;
+
+    Ok(())
+}
+
+/// Atomically assigns the next event number of the stream and stores the event.
+///
+/// The counter lives in the default tree of the database, the events in a tree
+/// named after the stream. A failure can never burn a number or leave a hole in
+/// the stream and concurrent publishers are serialized, events are therefore
+/// inserted in the stream in the order of their numbers.
+///
+/// The expected version is checked against the counter in the same transaction,
+/// nothing is written if the stream is not at the expected version.
+///
+/// Publishing is idempotent: if an event with the same id is one of the last
+/// `DEDUP_WINDOW` events of the stream nothing is written and its number is returned.
+/// The expected version is then checked against the stream as it was before the
+/// event, like when it was first published, a retried publish does not fail
+/// because of its own event but a different one with the same id does.
+pub fn publish_event(
+    db: &Db,
+    stream: &StreamName,
+    event_name: &EventName,
+    event_data: &EventData,
+    metadata: PublishMetadata,
+    expected_version: ExpectedVersion,
+    category_separator: char,
+) -> Result<Result<EventNumber, PublishError>, Error> {
+    let (tree, all, links) = publish_trees(db, stream)?;
+    let raw_events = [raw_event(event_name, event_data, metadata)];
+    let id_key = raw_events[0].id.map(|id| event_id_key(stream, &id));
+
+    let result = (&**db, &tree, &all, &links).transaction(|(numbers, events, all, links)| {
+        let published = match &id_key {
+            Some(id_key) => numbers.get(id_key)?,
+            None => None,
+        };
+
+        if let Some(number) = published {
+            let number = EventNumber::try_from(number.as_ref()).unwrap();
+            let before = number.0.checked_sub(1).map(EventNumber);
+            if expected_version.matches(before) {
+                return Ok(Ok((number, number)));
+            }
+
+            let actual = numbers.get(stream)?;
+            let actual = actual.map(|s| EventNumber::try_from(s.as_ref()).unwrap());
+            return Ok(Err(PublishError::WrongExpectedVersion { actual }));
+        }
+
+        let trees = PublishTrees {
+            numbers,
+            events,
+            all,
+            links,
+        };
+        append_raw_events(
+            &trees,
+            stream,
+            category_separator,
+            expected_version,
+            &raw_events,
+        )
+    })?;
+
+    Ok(result.map(|(number, _)| number))
+}
+
+/// Atomically stores all the events in the stream with contiguous event numbers.
+///
+/// The id of the batch is given to its first event, the batch is idempotent like
+/// a single event: if its first event is one of the last `DEDUP_WINDOW` events
+/// of the stream nothing is written and the numbers of the batch are returned.
+///
+/// Returns the first and last event numbers assigned,
+/// an empty batch is an invalid request.
+pub fn publish_events(
+    db: &Db,
+    stream: &StreamName,
+    events: &[(EventName, EventData)],
+    id: Option<EventId>,
+    category_separator: char,
+) -> Result<Result<(EventNumber, EventNumber), PublishError>, Error> {
+    if events.is_empty() {
+        return Err(Error::InvalidRequest);
+    }
+
+    let (tree, all, links) = publish_trees(db, stream)?;
+    let raw_events: Vec<_> = events
+        .iter()
+        .enumerate()
+        .map(|(i, (n, d))| {
+            let id = if i == 0 { id } else { None };
+            let metadata = PublishMetadata {
+                id,
+                ..PublishMetadata::default()
+            };
+            raw_event(n, d, metadata)
+        })
+        .collect();
+    let id_key = id.map(|id| event_id_key(stream, &id));
+    let count = raw_events.len() as u64;
+
+    let result = (&**db, &tree, &all, &links).transaction(|(numbers, events, all, links)| {
+        let published = match &id_key {
+            Some(id_key) => numbers.get(id_key)?,
+            None => None,
+        };
+
+        if let Some(first) = published {
+            let first = EventNumber::try_from(first.as_ref()).unwrap();
+            return Ok(Ok((first, EventNumber(first.0 + count - 1))));
+        }
+
+        let trees = PublishTrees {
+            numbers,
+            events,
+            all,
+            links,
+        };
+        append_raw_events(
+            &trees,
+            stream,
+            category_separator,
+            ExpectedVersion::Any,
+            &raw_events,
+        )
+    })?;
+
+    Ok(result)
+}
+
+/// Reads at most `count` events of the stream starting at `from` included,
+/// in decreasing order if `backwards`. The count is clamped to `MAX_READ_COUNT`.
+///
+/// Also returns the number of the event the next page starts at,
+/// `None` if no event was left to read. A backwards read starting
+/// after the end of the stream starts at its last event.
+pub fn read_events(
+    db: &Db,
+    stream: &StreamName,
+    from: EventNumber,
+    count: usize,
+    backwards: bool,
+) -> Result<(Vec<NumberedEvent>, Option<EventNumber>), Error> {
+    let count = cmp::min(count, MAX_READ_COUNT);
+
+    // we do not want to create the tree of a stream that does not exist
+    if db.get(stream)?.is_none() {
+        return Ok((Vec::new(), None));
+    }
+
+    // the events before the start of the stream may not have been removed yet
+    let start = stream_start(db, stream)?;
+    let tree = db.open_tree(stream.as_str())?;
+    let entries: Box<dyn Iterator<Item = sled::Result<(IVec, IVec)>>> = if backwards {
+        if from < start {
+            return Ok((Vec::new(), None));
+        }
+        Box::new(tree.range(start.to_be_bytes()..=from.to_be_bytes()).rev())
+    } else {
+        let from = cmp::max(from, start);
+        Box::new(tree.range(from.to_be_bytes()..))
+    };
+
+    let mut events = Vec::new();
+    for result in entries {
+        let (key, value) = result?;
+        let number = EventNumber::try_from(key.as_ref()).unwrap();
+
+        if events.len() == count {
+            return Ok((events, Some(number)));
+        }
+
+        let raw_event = RawEvent::new(value);
+        let event_name = raw_event.name().unwrap();
+        events.push((number, event_name, raw_event.data().unwrap()));
+    }
+
+    Ok((events, None))
+}
+
+/// Reads at most `count` events of the stream starting at `from` included,
+/// the way they are stored.
+pub fn read_raw_events(
+    db: &Db,
+    stream: &StreamName,
+    from: EventNumber,
+    count: usize,
+) -> Result<Vec<(EventNumber, RawEvent<IVec>)>, Error> {
+    if db.get(stream)?.is_none() {
+        return Ok(Vec::new());
+    }
+
+    let from = cmp::max(from, stream_start(db, stream)?);
+    let tree = db.open_tree(stream.as_str())?;
+
+    let mut events = Vec::new();
+    for result in tree.range(from.to_be_bytes()..).take(count) {
+        let (key, value) = result?;
+        let number = EventNumber::try_from(key.as_ref()).unwrap();
+        events.push((number, RawEvent::new(value)));
+    }
+
+    Ok(events)
+}
+
+/// Reads a single event of the stream, `None` if it has been removed or is hidden.
+pub fn read_raw_event(
+    db: &Db,
+    stream: &StreamName,
+    number: EventNumber,
+) -> Result<Option<RawEvent<IVec>>, Error> {
+    let mut events = read_raw_events(db, stream, number, 1)?;
+    match events.pop() {
+        Some((found, raw_event)) if found == number => Ok(Some(raw_event)),
+        _ => Ok(None),
+    }
+}
+
+/// Deletes the stream, returns `false` if it does not exist or is already deleted.
+///
+/// A soft delete hides the events of the stream, they stay on disk until the
+/// stream is truncated and publishing to it again numbers the new events after
+/// them. A hard delete removes the counter of the stream and marks it deleted
+/// for good, `drop_deleted_stream` removes its events.
+///
+/// The links of the system streams to the deleted events are skipped when read.
+pub fn delete_stream(db: &Db, stream: &StreamName, hard: bool) -> Result<bool, Error> {
+    let deleted_key = stream_key(DELETED_STREAMS_PREFIX, stream);
+    let start_key = stream_key(STREAM_STARTS_PREFIX, stream);
+
+    let deleted = db.transaction(|numbers| -> ConflictableTransactionResult<bool> {
+        let last = match numbers.get(stream)? {
+            Some(last) => EventNumber::try_from(last.as_ref()).unwrap(),
+            None => return Ok(false),
+        };
+
+        if hard {
+            numbers.remove(stream.as_ref())?;
+            numbers.remove(start_key.as_slice())?;
+            numbers.insert(deleted_key.as_slice(), Deletion::Hard.as_bytes())?;
+        } else {
+            if numbers.get(&deleted_key)?.is_some() {
+                return Ok(false);
+            }
+            numbers.insert(start_key.as_slice(), &last.next().to_be_bytes()[..])?;
+            numbers.insert(deleted_key.as_slice(), Deletion::Soft.as_bytes())?;
+        }
+
+        Ok(true)
+    })?;
+
+    Ok(deleted)
+}
+
+/// Removes the events of a hard deleted stream and the ids indexing them,
+/// it must be called once the subscribers of the stream have been notified.
+pub fn drop_deleted_stream(db: &Db, stream: &StreamName) -> Result<(), Error> {
+    let mut ids = Batch::default();
+    for result in db.scan_prefix(event_ids_prefix(stream)).keys() {
+        ids.remove(result?);
+    }
+    db.apply_batch(ids)?;
+    db.drop_tree(stream.as_str().as_bytes())?;
+    Ok(())
+}
+
+/// Removes the events of the stream numbered before `number` and their ids,
+/// returns `false` if the stream does not exist.
+///
+/// The stream keeps its counter: the next events are numbered after the
+/// last one, even if the whole stream has been truncated.
+pub fn truncate_before(db: &Db, stream: &StreamName, number: EventNumber) -> Result<bool, Error> {
+    match move_start(db, stream, number)? {
+        Some(start) => {
+            remove_events_before(db, stream, start)?;
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+/// Moves the start of the stream to `number`, never backwards nor after its last event.
+///
+/// Returns the new start of the stream, `None` if it does not exist.
+fn move_start(
+    db: &Db,
+    stream: &StreamName,
+    number: EventNumber,
+) -> Result<Option<EventNumber>, Error> {
+    let start_key = stream_key(STREAM_STARTS_PREFIX, stream);
+
+    let start = db.transaction(|numbers| -> ConflictableTransactionResult<_> {
+        let last = match numbers.get(stream)? {
+            Some(last) => EventNumber::try_from(last.as_ref()).unwrap(),
+            None => return Ok(None),
+        };
+
+        let start = numbers.get(&start_key)?;
+        let start = start.map_or(EventNumber::zero(), |s| {
+            EventNumber::try_from(s.as_ref()).unwrap()
+        });
+        let start = cmp::max(start, cmp::min(number, last.next()));
+        numbers.insert(start_key.as_slice(), &start.to_be_bytes()[..])?;
+
+        Ok(Some(start))
+    })?;
+
+    Ok(start)
+}
+
+/// Removes the events of the stream numbered before `start` and their ids,
+/// returns the number of events removed.
+fn remove_events_before(db: &Db, stream: &StreamName, start: EventNumber) -> Result<usize, Error> {
+    let tree = db.open_tree(stream.as_str())?;
+
+    let mut events = Batch::default();
+    let mut ids = Batch::default();
+    let mut removed = 0;
+    for result in tree.range(..start.to_be_bytes()) {
+        let (key, value) = result?;
+        if let Ok(metadata) = RawEvent::new(value).metadata() {
+            ids.remove(event_id_key(stream, &metadata.id));
+        }
+        events.remove(key);
+        removed += 1;
+    }
+
+    db.apply_batch(ids)?;
+    tree.apply_batch(events)?;
+
+    Ok(removed)
+}
+
+fn encode_retention(policy: RetentionPolicy) -> Vec<u8> {
+    let mut value = Vec::with_capacity(18);
+    for limit in &[policy.max_count, policy.max_age] {
+        match limit {
+            Some(limit) => {
+                value.push(1);
+                value.extend_from_slice(&limit.to_be_bytes());
+            }
+            None => value.extend_from_slice(&[0; 9]),
+        }
+    }
+    value
+}
+
+fn decode_retention(value: &[u8]) -> RetentionPolicy {
+    let limit = |bytes: &[u8]| match bytes[0] {
+        0 => None,
+        _ => Some(u64::from_be_bytes(
+            <[u8; 8]>::try_from(&bytes[1..9]).unwrap(),
+        )),
+    };
+
+    RetentionPolicy {
+        max_count: limit(&value[..9]),
+        max_age: limit(&value[9..]),
+    }
+}
+
+/// Sets the retention policy of the stream, it is applied by `scavenge`.
+pub fn set_retention(db: &Db, stream: &StreamName, policy: RetentionPolicy) -> Result<(), Error> {
+    let key = stream_key(RETENTIONS_PREFIX, stream);
+    if policy.is_unlimited() {
+        db.remove(key)?;
+    } else {
+        db.insert(key, encode_retention(policy))?;
+    }
+    Ok(())
+}
+
+/// The retention policy of the stream, unlimited if none has been set.
+pub fn retention(db: &Db, stream: &StreamName) -> Result<RetentionPolicy, Error> {
+    let policy = db.get(stream_key(RETENTIONS_PREFIX, stream))?;
+    Ok(policy.map_or_else(RetentionPolicy::default, |p| decode_retention(&p)))
+}
+
+/// Truncates the streams to the events their retention policies keep,
+/// returns the number of events removed.
+pub fn scavenge(db: &Db) -> Result<usize, Error> {
+    scavenge_at(db, now_timestamp())
+}
+
+fn scavenge_at(db: &Db, now: u64) -> Result<usize, Error> {
+    let mut removed = 0;
+    for result in db.scan_prefix(RETENTIONS_PREFIX) {
+        let (key, value) = result?;
+        let name = String::from_utf8(key[RETENTIONS_PREFIX.len()..].to_vec()).unwrap();
+        let stream = StreamName::new(name).unwrap();
+        let policy = decode_retention(&value);
+
+        let last = match db.get(&stream)? {
+            Some(last) => EventNumber::try_from(last.as_ref()).unwrap(),
+            None => continue,
+        };
+
+        let mut start = stream_start(db, &stream)?;
+        if let Some(max_count) = policy.max_count {
+            let kept_from = (last.0 + 1).saturating_sub(max_count);
+            start = cmp::max(start, EventNumber(kept_from));
+        }
+
+        // the events are published in order, the first one
+        // that has not expired is the start of the stream.
+        // The events migrated from a previous version have a zero timestamp,
+        // their age is unknown, they only expire with the max count.
+        if let Some(max_age) = policy.max_age {
+            let expired_before = now.saturating_sub(max_age.saturating_mul(1000));
+            let tree = db.open_tree(stream.as_str())?;
+            let mut kept_from = last.next();
+            for result in tree.range(start.to_be_bytes()..) {
+                let (key, value) = result?;
+                let timestamp = RawEvent::new(value).metadata().unwrap().timestamp;
+                if timestamp == 0 || timestamp >= expired_before {
+                    kept_from = EventNumber::try_from(key.as_ref()).unwrap();
+                    break;
+                }
+            }
+            start = kept_from;
+        }
+
+        if let Some(start) = move_start(db, &stream, start)? {
+            removed += remove_events_before(db, &stream, start)?;
+        }
+    }
+
+    Ok(removed)
+}
+
+/// Rewrites the events stored before the envelope existed in the latest envelope.
+///
+/// The time they were published at is unknown, they keep a zero timestamp
+/// but are given a new id. Returns the number of events rewritten.
+pub fn migrate_events(db: &Db) -> Result<usize, Error> {
+    if let Some(version) = db.get(ENVELOPE_VERSION_KEY)? {
+        if version.as_ref() == [ENVELOPE_VERSION] {
+            return Ok(0);
+        }
+    }
+
+    let mut migrated = 0;
+    for stream in stream_names(db)? {
+        let tree = db.open_tree(stream.as_str())?;
+        for result in tree.iter() {
+            let (key, value) = result?;
+            let raw_event = RawEvent::new(value);
+            if raw_event.version() == ENVELOPE_VERSION {
+                continue;
+            }
+
+            let event_name = raw_event.name().unwrap();
+            let event_data = raw_event.data().unwrap();
+            let metadata = EventMetadata {
+                id: EventId::new_v4(),
+                ..raw_event.metadata().unwrap()
+            };
+
+            let raw_event = RawEvent::encode(&event_name, &event_data, &metadata);
+            tree.insert(key, raw_event.into_inner())?;
+            migrated += 1;
+        }
+    }
+
+    db.insert(ENVELOPE_VERSION_KEY, &[ENVELOPE_VERSION][..])?;
+
+    Ok(migrated)
+}
+
+/// Gives a position in the `$all` stream to the events published
+/// before it existed, in the order of their timestamps.
+///
+/// The events published at the same time, like the ones without a timestamp,
+/// are ordered by stream name and then by number. Returns the number of events
+/// indexed, nothing is done if the `$all` stream already exists.
+pub fn index_all_events(db: &Db) -> Result<usize, Error> {
+    if db.get(ALL_STREAMS)?.is_some() {
+        return Ok(0);
+    }
+
+    let mut events = Vec::new();
+    for stream in stream_names(db)? {
+        let tree = db.open_tree(stream.as_str())?;
+        for result in tree.iter() {
+            let (key, value) = result?;
+            let number = EventNumber::try_from(key.as_ref()).unwrap();
+            let timestamp = RawEvent::new(value).metadata().unwrap().timestamp;
+            events.push((timestamp, stream.clone(), number));
+        }
+    }
+
+    if events.is_empty() {
+        return Ok(0);
+    }
+
+    events.sort();
+
+    let all = db.open_tree(ALL_STREAMS)?;
+    let mut last = EventNumber::zero();
+    for (position, (_, stream, number)) in events.iter().enumerate() {
+        last = EventNumber(position as u64);
+        all.insert(last.to_be_bytes(), link_value(stream, *number))?;
+    }
+
+    // the counter is written last, the events are indexed again if interrupted
+    db.insert(ALL_STREAMS, &last.to_be_bytes()[..])?;
+
+    Ok(events.len())
+}
+
+/// Removes the system streams whose names start with the prefix and builds them again,
+/// `system_stream` gives the system stream linking an event if any.
+///
+/// The events are linked in the order of their position in the `$all` stream,
+/// returns the number of events linked.
+fn rebuild_links<F>(db: &Db, prefix: &str, system_stream: F) -> Result<usize, Error>
+where
+    F: Fn(&StreamName, &RawEvent<IVec>) -> Option<StreamName>,
+{
+    let links = db.open_tree(LINKS_TREE)?;
+    for result in links.scan_prefix(prefix).keys() {
+        links.remove(result?)?;
+    }
+    for result in db.scan_prefix(prefix).keys() {
+        db.remove(result?)?;
+    }
+
+    let mut counters = HashMap::new();
+    let all = db.open_tree(ALL_STREAMS)?;
+    for result in all.iter().values() {
+        let value = result?;
+        let (stream, _, raw_event) = match resolve_link(db, &value)? {
+            Some(event) => event,
+            None => continue,
+        };
+
+        if let Some(system_stream) = system_stream(&stream, &raw_event) {
+            let next = counters.entry(system_stream.clone()).or_insert(0);
+            links.insert(link_key(&system_stream, EventNumber(*next)), value)?;
+            *next += 1;
+        }
+    }
+
+    let mut linked = 0;
+    for (system_stream, count) in counters {
+        let last = EventNumber(count - 1);
+        db.insert(system_stream, &last.to_be_bytes()[..])?;
+        linked += count as usize;
+    }
+
+    Ok(linked)
+}
+
+/// Builds the category streams again if they have been built
+/// with another separator, never been built or if asked to.
+///
+/// Returns the number of events linked from a category stream.
+pub fn index_categories(db: &Db, category_separator: char, rebuild: bool) -> Result<usize, Error> {
+    let mut separator = [0; 4];
+    let separator = category_separator.encode_utf8(&mut separator).as_bytes();
+    if let Some(previous) = db.get(CATEGORY_SEPARATOR_KEY)? {
+        if previous.as_ref() == separator && !rebuild {
+            return Ok(0);
+        }
+    }
+
+    let linked = rebuild_links(db, CATEGORY_STREAM_PREFIX, |stream, _| {
+        stream.category_stream(category_separator)
+    })?;
+    db.insert(CATEGORY_SEPARATOR_KEY, separator)?;
+
+    Ok(linked)
+}
+
+/// Builds the event type streams if they have never been built or if asked to.
+///
+/// Returns the number of events linked from an event type stream.
+pub fn index_event_types(db: &Db, rebuild: bool) -> Result<usize, Error> {
+    if db.get(EVENT_TYPES_KEY)?.is_some() && !rebuild {
+        return Ok(0);
+    }
+
+    let linked = rebuild_links(db, EVENT_TYPE_STREAM_PREFIX, |_, raw_event| {
+        StreamName::event_type_stream(&raw_event.name().unwrap())
+    })?;
+    db.insert(EVENT_TYPES_KEY, &[1][..])?;
+
+    Ok(linked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    use sled::{abort, Config};
+
+    fn stream_numbers(db: &Db, stream: &StreamName) -> Vec<u64> {
+        let tree = db.open_tree(stream.as_str()).unwrap();
+        tree.iter()
+            .keys()
+            .map(|k| EventNumber::try_from(k.unwrap().as_ref()).unwrap().0)
+            .collect()
+    }
+
+    #[test]
+    fn no_gap_after_failing_publish() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let stream = StreamName::new("my-stream".to_owned()).unwrap();
+        let name = EventName::new("my-event".to_owned()).unwrap();
+        let data = EventData("hello".into());
+
+        publish_event(
+            &db,
+            &stream,
+            &name,
+            &data,
+            PublishMetadata::default(),
+            ExpectedVersion::Any,
+            '-',
+        )
+        .unwrap()
+        .unwrap();
+
+        // simulate a crash after the counter has been incremented
+        // but before the event has been committed to the stream
+        let (tree, all, links) = publish_trees(&db, &stream).unwrap();
+        let raw_events = [raw_event(&name, &data, PublishMetadata::default())];
+        let result = (&*db, &tree, &all, &links).transaction(|(numbers, events, all, links)| {
+            let trees = PublishTrees {
+                numbers,
+                events,
+                all,
+                links,
+            };
+            let expected_version = ExpectedVersion::Any;
+            append_raw_events(&trees, &stream, '-', expected_version, &raw_events)?.unwrap();
+            abort::<(), _>(())
+        });
+        assert!(result.is_err());
+
+        let number = db.get(&stream).unwrap().unwrap();
+        assert_eq!(
+            EventNumber::try_from(number.as_ref()).unwrap(),
+            EventNumber(0)
+        );
+
+        let number = publish_event(
+            &db,
+            &stream,
+            &name,
+            &data,
+            PublishMetadata::default(),
+            ExpectedVersion::Any,
+            '-',
+        )
+        .unwrap();
+        assert_eq!(number, Ok(EventNumber(1)));
+
+        assert_eq!(stream_numbers(&db, &stream), vec![0, 1]);
+    }
+
+    #[test]
+    fn no_gap_with_concurrent_publishers() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let stream = StreamName::new("my-stream".to_owned()).unwrap();
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let db = db.clone();
+                let stream = stream.clone();
+                thread::spawn(move || {
+                    let name = EventName::new("my-event".to_owned()).unwrap();
+                    let data = EventData("hello".into());
+                    for _ in 0..50 {
+                        publish_event(
+                            &db,
+                            &stream,
+                            &name,
+                            &data,
+                            PublishMetadata::default(),
+                            ExpectedVersion::Any,
+                            '-',
+                        )
+                        .unwrap()
+                        .unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let expected: Vec<_> = (0..400).collect();
+        assert_eq!(stream_numbers(&db, &stream), expected);
+
+        let number = db.get(&stream).unwrap().unwrap();
+        assert_eq!(
+            EventNumber::try_from(number.as_ref()).unwrap(),
+            EventNumber(399)
+        );
+    }
+
+    #[test]
+    fn check_expected_version() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let stream = StreamName::new("my-stream".to_owned()).unwrap();
+        let name = EventName::new("my-event".to_owned()).unwrap();
+        let data = EventData("hello".into());
+
+        let result = publish_event(
+            &db,
+            &stream,
+            &name,
+            &data,
+            PublishMetadata::default(),
+            ExpectedVersion::NoStream,
+            '-',
+        );
+        assert_eq!(result.unwrap(), Ok(EventNumber(0)));
+
+        let result = publish_event(
+            &db,
+            &stream,
+            &name,
+            &data,
+            PublishMetadata::default(),
+            ExpectedVersion::NoStream,
+            '-',
+        );
+        let error = PublishError::WrongExpectedVersion {
+            actual: Some(EventNumber(0)),
+        };
+        assert_eq!(result.unwrap(), Err(error));
+
+        let expected = ExpectedVersion::Exact(EventNumber(1));
+        let result = publish_event(
+            &db,
+            &stream,
+            &name,
+            &data,
+            PublishMetadata::default(),
+            expected,
+            '-',
+        );
+        assert_eq!(result.unwrap(), Err(error));
+
+        let expected = ExpectedVersion::Exact(EventNumber(0));
+        let result = publish_event(
+            &db,
+            &stream,
+            &name,
+            &data,
+            PublishMetadata::default(),
+            expected,
+            '-',
+        );
+        assert_eq!(result.unwrap(), Ok(EventNumber(1)));
+
+        assert_eq!(stream_numbers(&db, &stream), vec![0, 1]);
+    }
+
+    #[test]
+    fn publish_contiguous_batch() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let stream = StreamName::new("my-stream".to_owned()).unwrap();
+        let name = EventName::new("my-event".to_owned()).unwrap();
+        let data = EventData("hello".into());
+
+        let result = publish_event(
+            &db,
+            &stream,
+            &name,
+            &data,
+            PublishMetadata::default(),
+            ExpectedVersion::Any,
+            '-',
+        );
+        assert_eq!(result.unwrap(), Ok(EventNumber(0)));
+
+        let events = vec![(name.clone(), data.clone()); 3];
+        let result = publish_events(&db, &stream, &events, None, '-');
+        assert_eq!(result.unwrap(), Ok((EventNumber(1), EventNumber(3))));
+
+        let result = publish_event(
+            &db,
+            &stream,
+            &name,
+            &data,
+            PublishMetadata::default(),
+            ExpectedVersion::Any,
+            '-',
+        );
+        assert_eq!(result.unwrap(), Ok(EventNumber(4)));
+
+        assert_eq!(stream_numbers(&db, &stream), vec![0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn read_pages_of_events() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let stream = StreamName::new("my-stream".to_owned()).unwrap();
+        let data = EventData("hello".into());
+
+        let (events, next) = read_events(&db, &stream, EventNumber(0), 2, false).unwrap();
+        assert!(events.is_empty());
+        assert_eq!(next, None);
+        assert!(db.tree_names().iter().all(|n| n != b"my-stream"));
+
+        for i in 0..5 {
+            let name = EventName::new(format!("event-{}", i)).unwrap();
+            publish_event(
+                &db,
+                &stream,
+                &name,
+                &data,
+                PublishMetadata::default(),
+                ExpectedVersion::Any,
+                '-',
+            )
+            .unwrap()
+            .unwrap();
+        }
+
+        let numbers = |events: Vec<NumberedEvent>| -> Vec<u64> {
+            events.into_iter().map(|(n, _, _)| n.0).collect()
+        };
+
+        let (events, next) = read_events(&db, &stream, EventNumber(1), 2, false).unwrap();
+        assert_eq!(events[0].1.as_str(), "event-1");
+        assert_eq!(numbers(events), vec![1, 2]);
+        assert_eq!(next, Some(EventNumber(3)));
+
+        let (events, next) = read_events(&db, &stream, EventNumber(3), 2, false).unwrap();
+        assert_eq!(numbers(events), vec![3, 4]);
+        assert_eq!(next, None);
+
+        let from = EventNumber(std::u64::MAX);
+        let (events, next) = read_events(&db, &stream, from, 3, true).unwrap();
+        assert_eq!(numbers(events), vec![4, 3, 2]);
+        assert_eq!(next, Some(EventNumber(1)));
+
+        let (events, next) = read_events(&db, &stream, EventNumber(1), 3, true).unwrap();
+        assert_eq!(numbers(events), vec![1, 0]);
+        assert_eq!(next, None);
+
+        // a page never holds more than the maximum number of events
+        let name = EventName::new("my-event".to_owned()).unwrap();
+        let events = vec![(name, data); MAX_READ_COUNT];
+        publish_events(&db, &stream, &events, None, '-')
+            .unwrap()
+            .unwrap();
+
+        let count = std::usize::MAX;
+        let (events, next) = read_events(&db, &stream, EventNumber(0), count, false).unwrap();
+        assert_eq!(events.len(), MAX_READ_COUNT);
+        assert_eq!(next, Some(EventNumber(MAX_READ_COUNT as u64)));
+    }
+
+    #[test]
+    fn publish_the_same_event_id_once() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let stream = StreamName::new("my-stream".to_owned()).unwrap();
+        let other = StreamName::new("other-stream".to_owned()).unwrap();
+        let name = EventName::new("my-event".to_owned()).unwrap();
+        let data = EventData("hello".into());
+
+        let metadata = PublishMetadata {
+            id: Some(EventId::new_v4()),
+            ..PublishMetadata::default()
+        };
+
+        let publish = |stream, expected_version| {
+            publish_event(
+                &db,
+                stream,
+                &name,
+                &data,
+                metadata.clone(),
+                expected_version,
+                '-',
+            )
+            .unwrap()
+        };
+
+        assert_eq!(publish(&stream, ExpectedVersion::Any), Ok(EventNumber(0)));
+        assert_eq!(publish(&stream, ExpectedVersion::Any), Ok(EventNumber(0)));
+
+        // a retried publish must not fail because of its own event
+        let expected_version = ExpectedVersion::NoStream;
+        assert_eq!(publish(&stream, expected_version), Ok(EventNumber(0)));
+        assert_eq!(stream_numbers(&db, &stream), vec![0]);
+
+        // the ids are indexed by stream
+        assert_eq!(publish(&other, ExpectedVersion::Any), Ok(EventNumber(0)));
+        assert_eq!(stream_numbers(&db, &other), vec![0]);
+    }
+
+    #[test]
+    fn check_expected_version_of_an_event_published_again() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let stream = StreamName::new("my-stream".to_owned()).unwrap();
+        let name = EventName::new("my-event".to_owned()).unwrap();
+        let data = EventData("hello".into());
+
+        let metadata = PublishMetadata {
+            id: Some(EventId::new_v4()),
+            ..PublishMetadata::default()
+        };
+
+        let publish = |metadata: &PublishMetadata, expected_version| {
+            publish_event(
+                &db,
+                &stream,
+                &name,
+                &data,
+                metadata.clone(),
+                expected_version,
+                '-',
+            )
+            .unwrap()
+        };
+
+        let default = PublishMetadata::default();
+        assert_eq!(publish(&default, ExpectedVersion::Any), Ok(EventNumber(0)));
+
+        let expected_version = ExpectedVersion::Exact(EventNumber(0));
+        assert_eq!(publish(&metadata, expected_version), Ok(EventNumber(1)));
+        assert_eq!(publish(&metadata, expected_version), Ok(EventNumber(1)));
+        assert_eq!(publish(&metadata, ExpectedVersion::Any), Ok(EventNumber(1)));
+
+        // the stream was not empty when the event has been published
+        let actual = Some(EventNumber(1));
+        assert_eq!(
+            publish(&metadata, ExpectedVersion::NoStream),
+            Err(PublishError::WrongExpectedVersion { actual })
+        );
+        assert_eq!(stream_numbers(&db, &stream), vec![0, 1]);
+    }
+
+    #[test]
+    fn reject_empty_batches() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let stream = StreamName::new("my-stream".to_owned()).unwrap();
+
+        match publish_events(&db, &stream, &[], None, '-') {
+            Err(Error::InvalidRequest) => (),
+            otherwise => panic!("unexpected result {:?}", otherwise),
+        }
+        assert_eq!(db.get(&stream).unwrap(), None);
+    }
+
+    #[test]
+    fn publish_a_batch_again() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let stream = StreamName::new("my-stream".to_owned()).unwrap();
+        let name = EventName::new("my-event".to_owned()).unwrap();
+        let data = EventData("hello".into());
+        let events = vec![(name.clone(), data.clone()); 3];
+        let id = Some(EventId::new_v4());
+
+        let publish = |id| publish_events(&db, &stream, &events, id, '-').unwrap();
+
+        let numbers = Ok((EventNumber(0), EventNumber(2)));
+        assert_eq!(publish(id), numbers);
+        assert_eq!(publish(id), numbers);
+        assert_eq!(stream_numbers(&db, &stream), vec![0, 1, 2]);
+
+        // the batches without id are always stored
+        assert_eq!(publish(None), Ok((EventNumber(3), EventNumber(5))));
+        assert_eq!(publish(None), Ok((EventNumber(6), EventNumber(8))));
+
+        // the id of the batch is the id of its first event
+        let metadata = PublishMetadata {
+            id,
+            ..PublishMetadata::default()
+        };
+        let result = publish_event(
+            &db,
+            &stream,
+            &name,
+            &data,
+            metadata,
+            ExpectedVersion::Any,
+            '-',
+        );
+        assert_eq!(result.unwrap(), Ok(EventNumber(0)));
+        assert_eq!(stream_numbers(&db, &stream).len(), 9);
+    }
+
+    #[test]
+    fn forget_the_ids_of_old_events() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let stream = StreamName::new("my-stream".to_owned()).unwrap();
+        let name = EventName::new("my-event".to_owned()).unwrap();
+        let data = EventData("hello".into());
+
+        let metadata = PublishMetadata {
+            id: Some(EventId::new_v4()),
+            ..PublishMetadata::default()
+        };
+
+        let publish = || {
+            publish_event(
+                &db,
+                &stream,
+                &name,
+                &data,
+                metadata.clone(),
+                ExpectedVersion::Any,
+                '-',
+            )
+            .unwrap()
+        };
+
+        assert_eq!(publish(), Ok(EventNumber(0)));
+
+        // the ids generated by the server are not indexed
+        let events = vec![(name.clone(), data.clone()); DEDUP_WINDOW as usize - 1];
+        publish_events(&db, &stream, &events, None, '-')
+            .unwrap()
+            .unwrap();
+        assert_eq!(db.scan_prefix(event_ids_prefix(&stream)).count(), 1);
+        assert_eq!(publish(), Ok(EventNumber(0)));
+
+        // the id of the first event is forgotten with the next one
+        let events = vec![(name.clone(), data.clone())];
+        publish_events(&db, &stream, &events, None, '-')
+            .unwrap()
+            .unwrap();
+        assert_eq!(db.scan_prefix(event_ids_prefix(&stream)).count(), 0);
+
+        let number = EventNumber(DEDUP_WINDOW + 1);
+        assert_eq!(publish(), Ok(number));
+        assert_eq!(db.scan_prefix(event_ids_prefix(&stream)).count(), 1);
+    }
+
+    #[test]
+    fn index_events_in_all() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let first = StreamName::new("first-stream".to_owned()).unwrap();
+        let second = StreamName::new("second-stream".to_owned()).unwrap();
+        let name = EventName::new("my-event".to_owned()).unwrap();
+        let data = EventData("hello".into());
+
+        let publish = |stream| {
+            let metadata = PublishMetadata::default();
+            publish_event(
+                &db,
+                stream,
+                &name,
+                &data,
+                metadata,
+                ExpectedVersion::Any,
+                '-',
+            )
+            .unwrap()
+            .unwrap();
+        };
+
+        publish(&first);
+        publish(&second);
+        publish(&first);
+
+        let events = [(name.clone(), data.clone()), (name.clone(), data.clone())];
+        publish_events(&db, &second, &events, None, '-')
+            .unwrap()
+            .unwrap();
+
+        let linked = |db: &Db| -> Vec<(String, u64)> {
+            let all = db.open_tree(ALL_STREAMS).unwrap();
+            all.iter()
+                .values()
+                .map(|value| {
+                    let (stream, number, _) = resolve_link(db, &value.unwrap()).unwrap().unwrap();
+                    (stream.into_inner(), number.0)
+                })
+                .collect()
+        };
+
+        let expected = vec![
+            ("first-stream".to_owned(), 0),
+            ("second-stream".to_owned(), 0),
+            ("first-stream".to_owned(), 1),
+            ("second-stream".to_owned(), 1),
+            ("second-stream".to_owned(), 2),
+        ];
+        assert_eq!(linked(&db), expected);
+        assert_eq!(stream_numbers(&db, &StreamName::all()), vec![0, 1, 2, 3, 4]);
+        let streams = stream_names(&db).unwrap();
+        assert_eq!(streams, vec![first.clone(), second.clone()]);
+
+        // the events published before the `$all` stream existed
+        db.drop_tree(ALL_STREAMS.as_bytes()).unwrap();
+        db.remove(ALL_STREAMS).unwrap();
+
+        assert_eq!(index_all_events(&db).unwrap(), 5);
+        assert_eq!(index_all_events(&db).unwrap(), 0);
+        assert_eq!(linked(&db).len(), 5);
+
+        let position = db.get(ALL_STREAMS).unwrap().unwrap();
+        assert_eq!(
+            EventNumber::try_from(position.as_ref()).unwrap(),
+            EventNumber(4)
+        );
+    }
+
+    #[test]
+    fn build_category_streams() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let name = EventName::new("my-event".to_owned()).unwrap();
+        let data = EventData("hello".into());
+
+        let publish = |stream: &str, separator| {
+            let stream = StreamName::new(stream.to_owned()).unwrap();
+            let metadata = PublishMetadata::default();
+            let expected_version = ExpectedVersion::Any;
+            publish_event(
+                &db,
+                &stream,
+                &name,
+                &data,
+                metadata,
+                expected_version,
+                separator,
+            )
+            .unwrap()
+            .unwrap();
+        };
+
+        let linked = |category: &str| -> Vec<(String, u64)> {
+            let category = StreamName::new(category.to_owned()).unwrap();
+            let (tree, prefix) = stream_tree(&db, &category).unwrap();
+            tree.scan_prefix(prefix)
+                .values()
+                .map(|value| {
+                    let (stream, number) = parse_link(&value.unwrap());
+                    (stream.into_inner(), number.0)
+                })
+                .collect()
+        };
+
+        assert_eq!(index_categories(&db, '-', false).unwrap(), 0);
+
+        publish("order-1", '-');
+        publish("order_2", '-');
+        publish("order-1", '-');
+        publish("order", '-');
+
+        let expected = vec![("order-1".to_owned(), 0), ("order-1".to_owned(), 1)];
+        assert_eq!(linked("$ce-order"), expected);
+        assert_eq!(index_categories(&db, '-', false).unwrap(), 0);
+
+        // the category streams are built again with the new separator
+        assert_eq!(index_categories(&db, '_', false).unwrap(), 1);
+        assert_eq!(linked("$ce-order"), vec![("order_2".to_owned(), 0)]);
+
+        let number = db.get("$ce-order").unwrap().unwrap();
+        assert_eq!(
+            EventNumber::try_from(number.as_ref()).unwrap(),
+            EventNumber(0)
+        );
+
+        publish("order_3", '_');
+        assert_eq!(linked("$ce-order").len(), 2);
+    }
+
+    #[test]
+    fn build_event_type_streams() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let registered = EventName::new("UserRegistered".to_owned()).unwrap();
+        let renamed = EventName::new("UserRenamed".to_owned()).unwrap();
+        let data = EventData("hello".into());
+
+        let first = StreamName::new("user-1".to_owned()).unwrap();
+        let second = StreamName::new("user-2".to_owned()).unwrap();
+
+        let metadata = PublishMetadata::default();
+        publish_event(
+            &db,
+            &first,
+            &registered,
+            &data,
+            metadata,
+            ExpectedVersion::Any,
+            '-',
+        )
+        .unwrap()
+        .unwrap();
+
+        let events = [
+            (registered.clone(), data.clone()),
+            (renamed.clone(), data.clone()),
+            (renamed.clone(), data.clone()),
+        ];
+        publish_events(&db, &second, &events, None, '-')
+            .unwrap()
+            .unwrap();
+
+        let linked = |event_name: &EventName| -> Vec<(String, u64)> {
+            let event_type = StreamName::event_type_stream(event_name).unwrap();
+            let (tree, prefix) = stream_tree(&db, &event_type).unwrap();
+            tree.scan_prefix(prefix)
+                .values()
+                .map(|value| {
+                    let (stream, number) = parse_link(&value.unwrap());
+                    (stream.into_inner(), number.0)
+                })
+                .collect()
+        };
+
+        let expected_registered = vec![("user-1".to_owned(), 0), ("user-2".to_owned(), 0)];
+        let expected_renamed = vec![("user-2".to_owned(), 1), ("user-2".to_owned(), 2)];
+        assert_eq!(linked(&registered), expected_registered);
+        assert_eq!(linked(&renamed), expected_renamed);
+
+        let number = db.get("$et-UserRenamed").unwrap().unwrap();
+        assert_eq!(
+            EventNumber::try_from(number.as_ref()).unwrap(),
+            EventNumber(1)
+        );
+
+        // the streams built from the stored events are the same
+        assert_eq!(index_event_types(&db, false).unwrap(), 4);
+        assert_eq!(index_event_types(&db, false).unwrap(), 0);
+        assert_eq!(index_event_types(&db, true).unwrap(), 4);
+        assert_eq!(linked(&registered), expected_registered);
+        assert_eq!(linked(&renamed), expected_renamed);
+    }
+
+    #[test]
+    fn migrate_legacy_events() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let stream = StreamName::new("my-stream".to_owned()).unwrap();
+        let name = EventName::new("my-event".to_owned()).unwrap();
+        let data = EventData("hello".into());
+
+        publish_event(
+            &db,
+            &stream,
+            &name,
+            &data,
+            PublishMetadata::default(),
+            ExpectedVersion::Any,
+            '-',
+        )
+        .unwrap()
+        .unwrap();
+
+        // an event stored before the envelope existed
+        let mut legacy = Vec::new();
+        legacy.extend_from_slice(&(name.as_str().len() as u64).to_be_bytes());
+        legacy.extend_from_slice(name.as_str().as_bytes());
+        legacy.extend_from_slice(&data.0);
+
+        let tree = db.open_tree(stream.as_str()).unwrap();
+        tree.insert(&EventNumber(1).to_be_bytes()[..], legacy)
+            .unwrap();
+
+        assert_eq!(migrate_events(&db).unwrap(), 1);
+        assert_eq!(migrate_events(&db).unwrap(), 0);
+
+        let raw_event = RawEvent::new(tree.get(EventNumber(1).to_be_bytes()).unwrap().unwrap());
+        assert_eq!(raw_event.version(), ENVELOPE_VERSION);
+        assert_eq!(raw_event.name().unwrap(), name);
+        assert_eq!(raw_event.data().unwrap(), data);
+
+        let metadata = raw_event.metadata().unwrap();
+        assert_eq!(metadata.timestamp, 0);
+        assert_ne!(metadata.id, EventId::nil());
+    }
+
+    #[test]
+    fn delete_and_truncate_streams() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let soft = StreamName::new("soft-stream".to_owned()).unwrap();
+        let hard = StreamName::new("hard-stream".to_owned()).unwrap();
+        let name = EventName::new("my-event".to_owned()).unwrap();
+        let data = EventData("hello".into());
+
+        let id = EventId::new_v4();
+        let publish = |stream: &StreamName, id: Option<EventId>| {
+            let metadata = PublishMetadata {
+                id,
+                ..PublishMetadata::default()
+            };
+            publish_event(
+                &db,
+                stream,
+                &name,
+                &data,
+                metadata,
+                ExpectedVersion::Any,
+                '-',
+            )
+            .unwrap()
+        };
+
+        assert_eq!(publish(&soft, Some(id)), Ok(EventNumber(0)));
+        for _ in 0..4 {
+            publish(&soft, None).unwrap();
+            publish(&hard, None).unwrap();
+        }
+
+        // the truncated events and their ids are removed
+        assert!(truncate_before(&db, &soft, EventNumber(2)).unwrap());
+        assert_eq!(stream_numbers(&db, &soft), vec![2, 3, 4]);
+        assert_eq!(stream_start(&db, &soft).unwrap(), EventNumber(2));
+        assert_eq!(publish(&soft, Some(id)), Ok(EventNumber(5)));
+
+        // a truncation never moves the start of the stream backwards
+        assert!(truncate_before(&db, &soft, EventNumber(1)).unwrap());
+        assert_eq!(stream_start(&db, &soft).unwrap(), EventNumber(2));
+
+        let (events, _) = read_events(&db, &soft, EventNumber(0), 10, false).unwrap();
+        let numbers: Vec<_> = events.iter().map(|(n, _, _)| n.0).collect();
+        assert_eq!(numbers, vec![2, 3, 4, 5]);
+
+        // the soft deleted events are hidden but kept
+        assert!(delete_stream(&db, &soft, false).unwrap());
+        assert!(!delete_stream(&db, &soft, false).unwrap());
+        assert_eq!(deletion(&db, &soft).unwrap(), Some(Deletion::Soft));
+        assert_eq!(stream_names(&db).unwrap(), vec![hard.clone()]);
+        assert_eq!(stream_numbers(&db, &soft), vec![2, 3, 4, 5]);
+        let (events, next) = read_events(&db, &soft, EventNumber(5), 10, true).unwrap();
+        assert!(events.is_empty());
+        assert_eq!(next, None);
+
+        // publishing recreates the stream after its deleted events
+        assert_eq!(publish(&soft, None), Ok(EventNumber(6)));
+        assert_eq!(deletion(&db, &soft).unwrap(), None);
+        let (events, _) = read_events(&db, &soft, EventNumber(0), 10, false).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].0, EventNumber(6));
+
+        // the links to the hidden events are skipped
+        let all = db.open_tree(ALL_STREAMS).unwrap();
+        let linked = all
+            .iter()
+            .values()
+            .filter_map(|v| resolve_link(&db, &v.unwrap()).unwrap())
+            .count();
+        assert_eq!(linked, 5);
+
+        // a hard deleted stream is removed and can not be published to again
+        assert!(delete_stream(&db, &hard, true).unwrap());
+        drop_deleted_stream(&db, &hard).unwrap();
+        assert_eq!(deletion(&db, &hard).unwrap(), Some(Deletion::Hard));
+        assert_eq!(db.get(&hard).unwrap(), None);
+        assert!(db.tree_names().iter().all(|n| n != b"hard-stream"));
+        assert_eq!(publish(&hard, None), Err(PublishError::StreamDeleted));
+        assert!(!delete_stream(&db, &hard, true).unwrap());
+        assert!(!truncate_before(&db, &hard, EventNumber(1)).unwrap());
+        assert_eq!(stream_names(&db).unwrap(), vec![soft]);
+    }
+
+    #[test]
+    fn apply_retention_policies() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let stream = StreamName::new("telemetry".to_owned()).unwrap();
+        let other = StreamName::new("orders".to_owned()).unwrap();
+        let name = EventName::new("my-event".to_owned()).unwrap();
+        let events = vec![(name, EventData("hello".into())); 10];
+
+        publish_events(&db, &stream, &events, None, '-')
+            .unwrap()
+            .unwrap();
+        publish_events(&db, &other, &events, None, '-')
+            .unwrap()
+            .unwrap();
+        assert_eq!(retention(&db, &stream).unwrap(), RetentionPolicy::default());
+
+        let policy = RetentionPolicy {
+            max_count: Some(4),
+            max_age: Some(24 * 60 * 60),
+        };
+        set_retention(&db, &stream, policy).unwrap();
+        assert_eq!(retention(&db, &stream).unwrap(), policy);
+
+        let now = now_timestamp();
+        assert_eq!(scavenge_at(&db, now).unwrap(), 6);
+        assert_eq!(scavenge_at(&db, now).unwrap(), 0);
+        assert_eq!(stream_numbers(&db, &stream), vec![6, 7, 8, 9]);
+        assert_eq!(stream_start(&db, &stream).unwrap(), EventNumber(6));
+        assert_eq!(stream_numbers(&db, &other).len(), 10);
+
+        // reading from the first event starts at the truncation point
+        let (events, _) = read_events(&db, &stream, EventNumber(0), 2, false).unwrap();
+        let numbers: Vec<_> = events.iter().map(|(n, _, _)| n.0).collect();
+        assert_eq!(numbers, vec![6, 7]);
+
+        // the events are kept a day
+        let two_days_later = now + 2 * 24 * 60 * 60 * 1000;
+        assert_eq!(scavenge_at(&db, two_days_later).unwrap(), 4);
+        assert!(stream_numbers(&db, &stream).is_empty());
+        assert_eq!(stream_start(&db, &stream).unwrap(), EventNumber(10));
+
+        set_retention(&db, &stream, RetentionPolicy::default()).unwrap();
+        assert_eq!(retention(&db, &stream).unwrap(), RetentionPolicy::default());
+        assert!(db.scan_prefix(RETENTIONS_PREFIX).next().is_none());
+    }
+
+    #[test]
+    fn keep_the_events_without_timestamp() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let stream = StreamName::new("telemetry".to_owned()).unwrap();
+        let name = EventName::new("my-event".to_owned()).unwrap();
+        let data = EventData("hello".into());
+        let events = vec![(name.clone(), data.clone()); 6];
+
+        publish_events(&db, &stream, &events, None, '-')
+            .unwrap()
+            .unwrap();
+
+        // the first events have been migrated from a previous version
+        let tree = db.open_tree(stream.as_str()).unwrap();
+        for number in 0..3 {
+            let metadata = EventMetadata {
+                id: EventId::new_v4(),
+                ..EventMetadata::legacy()
+            };
+            let raw_event = RawEvent::encode(&name, &data, &metadata);
+            tree.insert(
+                &EventNumber(number).to_be_bytes()[..],
+                raw_event.into_inner(),
+            )
+            .unwrap();
+        }
+
+        let policy = RetentionPolicy {
+            max_count: None,
+            max_age: Some(24 * 60 * 60),
+        };
+        set_retention(&db, &stream, policy).unwrap();
+
+        let two_days_later = now_timestamp() + 2 * 24 * 60 * 60 * 1000;
+        assert_eq!(scavenge_at(&db, two_days_later).unwrap(), 0);
+        assert_eq!(stream_numbers(&db, &stream), vec![0, 1, 2, 3, 4, 5]);
+
+        // they are removed by the max count
+        let policy = RetentionPolicy {
+            max_count: Some(2),
+            ..policy
+        };
+        set_retention(&db, &stream, policy).unwrap();
+        assert_eq!(scavenge_at(&db, two_days_later).unwrap(), 6);
+        assert!(stream_numbers(&db, &stream).is_empty());
+    }
+
+    #[test]
+    fn store_group_checkpoints_and_parked_events() {
+        let db = Config::new().temporary(true).open().unwrap();
+        let stream = StreamName::new("my-stream".to_owned()).unwrap();
+        let group = GroupName::new("projector".to_owned()).unwrap();
+        let other = GroupName::new("mailer".to_owned()).unwrap();
+        let event_name = EventName::new("my-event".to_owned()).unwrap();
+        let event_data = EventData("hello".into());
+
+        assert_eq!(checkpoint(&db, &group, &stream).unwrap(), None);
+        set_checkpoint(&db, &group, &stream, EventNumber(3)).unwrap();
+        assert_eq!(
+            checkpoint(&db, &group, &stream).unwrap(),
+            Some(EventNumber(3))
+        );
+        assert_eq!(checkpoint(&db, &other, &stream).unwrap(), None);
+
+        park_event(&db, &group, &stream, EventNumber(2)).unwrap();
+        park_event(&db, &group, &stream, EventNumber(1)).unwrap();
+        assert_eq!(
+            parked_events(&db, &group, &stream).unwrap(),
+            vec![EventNumber(1), EventNumber(2)]
+        );
+        assert!(parked_events(&db, &other, &stream).unwrap().is_empty());
+
+        for _ in 0..4 {
+            let metadata = PublishMetadata::default();
+            publish_event(
+                &db,
+                &stream,
+                &event_name,
+                &event_data,
+                metadata,
+                ExpectedVersion::Any,
+                '-',
+            )
+            .unwrap()
+            .unwrap();
+        }
+
+        truncate_before(&db, &stream, EventNumber(1)).unwrap();
+        let events = read_raw_events(&db, &stream, EventNumber(0), 2).unwrap();
+        let numbers: Vec<_> = events.into_iter().map(|(n, _)| n.0).collect();
+        assert_eq!(numbers, vec![1, 2]);
+        assert!(read_raw_event(&db, &stream, EventNumber(0))
+            .unwrap()
+            .is_none());
+        assert!(read_raw_event(&db, &stream, EventNumber(3))
+            .unwrap()
+            .is_some());
+        assert!(read_raw_event(&db, &stream, EventNumber(4))
+            .unwrap()
+            .is_none());
+    }
+}
@@ -1,11 +1,10 @@
 use std::net::ToSocketAddrs;
 
-use futures::future::Either;
-use futures::{future, Future, Stream};
+use futures::{Future, Stream};
 use log::{error, info};
 use meilies::reqresp::Response;
-use meilies::stream::Stream as EsStream;
-use meilies_client::{paired_connect, sub_connect};
+use meilies::stream::{ExpectedVersion, PublishMetadata, Stream as EsStream};
+use meilies_client::{paired_connect, sub_connect_resp3, PipelinedEvent};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -23,6 +22,16 @@ struct Opt {
     #[structopt(long = "dst-server")]
     dst_server: String,
 
+    /// The number of events sent to the destination server
+    /// without waiting for their replies.
+    #[structopt(long = "max-in-flight", default_value = "256")]
+    max_in_flight: usize,
+
+    /// Continue the migration when an event can not be published to the
+    /// destination server, the migration is stopped on the first error otherwise.
+    #[structopt(long = "continue-on-error")]
+    continue_on_error: bool,
+
     /// List of streams to migrate from the source server to the destination one
     /// (i.e. hello:10, super-stream).
     ///
@@ -70,7 +79,9 @@ fn main() {
         return error!("the source and destination can not be the same");
     }
 
-    let fut = sub_connect(src_server)
+    let max_in_flight = opt.max_in_flight;
+    let continue_on_error = opt.continue_on_error;
+    let fut = sub_connect_resp3(src_server)
         .map_err(|e| error!("{}", e))
         .and_then(move |(mut ctrl, msgs)| {
             for stream in opt.streams {
@@ -79,31 +90,59 @@ fn main() {
 
             paired_connect(dst_server)
                 .map_err(|e| error!("{}", e))
-                .and_then(|dst_conn| {
-                    msgs.map_err(|e| error!("{}", e))
-                        .fold(dst_conn, move |dst_conn, msg| match msg {
-                            Ok(Response::Event {
+                .and_then(move |dst_conn| {
+                    let events = msgs.filter_map(|msg| match msg {
+                        Ok(Response::Event {
+                            stream,
+                            number,
+                            event_name,
+                            event_data,
+                            metadata,
+                            ..
+                        }) => {
+                            info!("{:?} {:?} {:?}", stream, event_name, number);
+
+                            // the event keeps its id, content type and headers,
+                            // only the timestamp is assigned by the destination,
+                            // servers speaking RESP2 only do not send them
+                            let metadata = match metadata {
+                                Some(metadata) => PublishMetadata {
+                                    id: Some(metadata.id),
+                                    content_type: Some(metadata.content_type),
+                                    headers: metadata.headers,
+                                },
+                                None => PublishMetadata::default(),
+                            };
+
+                            Some(PipelinedEvent {
                                 stream,
-                                number,
                                 event_name,
                                 event_data,
-                            }) => {
-                                info!("{:?} {:?} {:?}", stream, event_name, number);
-                                Either::A(
-                                    dst_conn
-                                        .publish(stream, event_name, event_data)
-                                        .map_err(|e| error!("{}", e)),
-                                )
-                            }
-                            Ok(response) => {
-                                info!("{:?}", response);
-                                Either::B(future::ok(dst_conn))
-                            }
-                            Err(error) => {
-                                error!("{}", error);
-                                Either::B(future::ok(dst_conn))
+                                expected_version: ExpectedVersion::Any,
+                                metadata,
+                            })
+                        }
+                        Ok(response) => {
+                            info!("{:?}", response);
+                            None
+                        }
+                        Err(error) => {
+                            error!("{}", error);
+                            None
+                        }
+                    });
+
+                    dst_conn
+                        .publish_pipelined(events, max_in_flight)
+                        .for_each(move |result| match result {
+                            Err(ref e) if continue_on_error => {
+                                error!("{}", e);
+                                Ok(())
                             }
+                            Err(e) => Err(e),
+                            Ok(_) => Ok(()),
                         })
+                        .map_err(|e| error!("{}", e))
                 })
         })
         .and_then(|_| Err(println!("Connection closed by the server")));
@@ -0,0 +1,141 @@
+use bytes::BytesMut;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::codec::{Decoder, Encoder};
+
+use meilies::resp::{RespCodec, RespValue};
+
+const CHUNK_SIZE: usize = 1024;
+
+/// The decoder the codec used before it resumed partial frames: every time more
+/// bytes are received the frame is parsed from its start and the bulk strings
+/// are copied out of the buffer. Only the RESP2 types are supported.
+mod restart {
+    use bytes::BytesMut;
+    use meilies::resp::RespValue;
+    use subslice::SubsliceExt;
+
+    const CRLF_NEWLINE: &[u8; 2] = b"\r\n";
+
+    fn decode_until_crlf(buf: &[u8]) -> Option<&[u8]> {
+        buf.find(CRLF_NEWLINE).map(|off| buf.split_at(off).0)
+    }
+
+    fn decode_line(buf: &[u8]) -> Option<(&str, usize)> {
+        decode_until_crlf(buf).map(|line| {
+            let advance = line.len() + CRLF_NEWLINE.len();
+            (std::str::from_utf8(line).unwrap(), advance)
+        })
+    }
+
+    fn decode_message(buf: &[u8]) -> Option<(RespValue, usize)> {
+        let (msg, advance) = match *buf.first()? {
+            b'+' => decode_line(&buf[1..]).map(|(s, a)| (RespValue::string(s), a))?,
+            b'-' => decode_line(&buf[1..]).map(|(s, a)| (RespValue::error(s), a))?,
+            b':' => {
+                decode_line(&buf[1..]).map(|(s, a)| (RespValue::Integer(s.parse().unwrap()), a))?
+            }
+            b'$' => {
+                let (length, advance) = decode_line(&buf[1..])?;
+                let length: i64 = length.parse().unwrap();
+                if length < 0 {
+                    (RespValue::Nil, advance)
+                } else {
+                    let bytes = &buf[1 + advance..];
+                    let length = length as usize;
+                    if bytes.len() < length + CRLF_NEWLINE.len() {
+                        return None;
+                    }
+                    let msg = RespValue::bulk_string(bytes[..length].to_vec());
+                    (msg, advance + length + CRLF_NEWLINE.len())
+                }
+            }
+            b'*' => {
+                let (length, mut advance) = decode_line(&buf[1..])?;
+                let length: i64 = length.parse().unwrap();
+                let mut elements = Vec::new();
+                for _ in 0..length {
+                    let (msg, adv) = decode_message(&buf[1 + advance..])?;
+                    elements.push(msg);
+                    advance += adv;
+                }
+                (RespValue::Array(elements), advance)
+            }
+            byte => panic!("invalid prefix byte {}", byte),
+        };
+
+        Some((msg, advance + 1))
+    }
+
+    pub fn decode(buf: &mut BytesMut) -> Option<RespValue> {
+        let (msg, advance) = decode_message(buf)?;
+        buf.advance(advance);
+        Some(msg)
+    }
+}
+
+fn encode(msg: RespValue) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    RespCodec::default().encode(msg, &mut buf).unwrap();
+    buf.to_vec()
+}
+
+/// Feeds the frame to a decoder by chunks, like when it is received in many TCP segments.
+///
+/// When not resuming, the previous decoder is used: it parses
+/// the frame from its start every time a chunk is received.
+fn decode_in_chunks(frame: &[u8], resume: bool) -> RespValue {
+    let mut codec = RespCodec::default();
+    let mut buf = BytesMut::with_capacity(frame.len());
+
+    for chunk in frame.chunks(CHUNK_SIZE) {
+        buf.extend_from_slice(chunk);
+
+        let msg = if resume {
+            codec.decode(&mut buf).unwrap()
+        } else {
+            restart::decode(&mut buf)
+        };
+
+        if let Some(msg) = msg {
+            return msg;
+        }
+    }
+
+    panic!("the frame is not complete")
+}
+
+fn decode(c: &mut Criterion) {
+    let event = RespValue::Array(vec![
+        RespValue::bulk_string("publish"),
+        RespValue::bulk_string("my-stream"),
+        RespValue::bulk_string("my-event"),
+        RespValue::bulk_string(vec![42; 1024 * 1024]),
+    ]);
+
+    let elements = RespValue::Array((0..20_000).map(RespValue::Integer).collect());
+
+    let mut group = c.benchmark_group("decode by 1KB chunks");
+    group.sample_size(10);
+
+    for (name, msg) in &[("1MB event", event), ("20k elements array", elements)] {
+        let frame = encode(msg.clone());
+        group.throughput(Throughput::Bytes(frame.len() as u64));
+
+        // both decoders must agree before being compared
+        assert_eq!(decode_in_chunks(&frame, false), *msg);
+        assert_eq!(decode_in_chunks(&frame, true), *msg);
+
+        group.bench_with_input(BenchmarkId::new("restart", *name), &frame, |b, frame| {
+            b.iter(|| decode_in_chunks(frame, false))
+        });
+
+        group.bench_with_input(BenchmarkId::new("resume", *name), &frame, |b, frame| {
+            b.iter(|| decode_in_chunks(frame, true))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, decode);
+criterion_main!(benches);
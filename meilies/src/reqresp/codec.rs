@@ -5,20 +5,37 @@ use tokio::codec::{Decoder, Encoder};
 use tokio::io;
 
 use super::{Request, RespRequestConvertError, RespResponseConvertError, Response};
-use crate::resp::{FromResp, RespCodec, RespMsgError, RespValue};
+use crate::resp::{FromResp, Protocol, RespCodec, RespMsgError, RespValue};
 
 #[derive(Debug, Default)]
-pub struct ClientCodec;
+pub struct ClientCodec {
+    codec: RespCodec,
+}
+
+impl ClientCodec {
+    pub fn new(codec: RespCodec) -> ClientCodec {
+        ClientCodec { codec }
+    }
+}
 
 impl Decoder for ClientCodec {
     type Item = Result<Response, String>;
     type Error = ResponseMsgError;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        match RespCodec.decode(buf)? {
-            Some(value) => Ok(Some(FromResp::from_resp(value)?)),
-            None => Ok(None),
+        let response = match self.codec.decode(buf)? {
+            Some(value) => FromResp::from_resp(value)?,
+            None => return Ok(None),
+        };
+
+        // the server replies to HELLO in the protocol it switched to
+        if let Ok(Response::Hello { protocol, .. }) = &response {
+            if let Some(protocol) = Protocol::from_version(*protocol) {
+                self.codec.set_protocol(protocol);
+            }
         }
+
+        Ok(Some(response))
     }
 }
 
@@ -27,19 +44,27 @@ impl Encoder for ClientCodec {
     type Error = RequestMsgError;
 
     fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> Result<(), Self::Error> {
-        Ok(RespCodec.encode(msg.into(), buf)?)
+        Ok(self.codec.encode(msg.into(), buf)?)
     }
 }
 
 #[derive(Debug, Default)]
-pub struct ServerCodec;
+pub struct ServerCodec {
+    codec: RespCodec,
+}
+
+impl ServerCodec {
+    pub fn new(codec: RespCodec) -> ServerCodec {
+        ServerCodec { codec }
+    }
+}
 
 impl Decoder for ServerCodec {
     type Item = Request;
     type Error = RequestMsgError;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        match RespCodec.decode(buf)? {
+        match self.codec.decode(buf)? {
             Some(value) => Ok(Some(FromResp::from_resp(value)?)),
             None => Ok(None),
         }
@@ -51,12 +76,19 @@ impl Encoder for ServerCodec {
     type Error = ResponseMsgError;
 
     fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        // the reply to HELLO is the first message sent in the new protocol
+        if let Ok(Response::Hello { protocol, .. }) = &msg {
+            if let Some(protocol) = Protocol::from_version(*protocol) {
+                self.codec.set_protocol(protocol);
+            }
+        }
+
         let msg = match msg {
-            Ok(item) => item.into(),
+            Ok(item) => item.into_resp(self.codec.protocol()),
             Err(error) => RespValue::Error(error),
         };
 
-        Ok(RespCodec.encode(msg, buf)?)
+        Ok(self.codec.encode(msg, buf)?)
     }
 }
 
@@ -125,3 +157,420 @@ impl From<io::Error> for ResponseMsgError {
         ResponseMsgError::from(RespMsgError::from(error))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stream::{EventData, EventMetadata, EventName, EventNumber, StreamName};
+    use crate::stream::{EventId, EventLink, GroupName, Headers, ReadRange, RetentionPolicy};
+
+    fn event() -> Response {
+        let mut headers = Headers::new();
+        headers.insert("causation-id".to_owned(), "12".to_owned());
+
+        Response::Event {
+            stream: StreamName::new("my-stream".to_owned()).unwrap(),
+            number: EventNumber(4),
+            event_name: EventName::new("my-event".to_owned()).unwrap(),
+            event_data: EventData("hello".into()),
+            metadata: Some(EventMetadata {
+                id: EventId::from_bytes([7; 16]),
+                timestamp: 1_571_000_000_000,
+                content_type: "text/plain".to_owned(),
+                headers,
+            }),
+            link: None,
+        }
+    }
+
+    #[test]
+    fn switch_to_resp3_after_hello() {
+        let mut server = ServerCodec::default();
+        let mut client = ClientCodec::default();
+        let mut buf = BytesMut::new();
+
+        // RESP2 clients receive arrays and the events without their metadata
+        let mut without_metadata = event();
+        if let Response::Event {
+            ref mut metadata, ..
+        } = without_metadata
+        {
+            *metadata = None;
+        }
+        server.encode(Ok(event()), &mut buf).unwrap();
+        assert_eq!(&buf[..1], b"*");
+        let response = client.decode(&mut buf).unwrap().unwrap().unwrap();
+        assert_eq!(response, without_metadata);
+
+        let hello = Response::Hello {
+            version: "0.0.0".to_owned(),
+            protocol: 3,
+        };
+        server.encode(Ok(hello.clone()), &mut buf).unwrap();
+        assert_eq!(&buf[..1], b"%");
+        assert_eq!(client.decode(&mut buf).unwrap().unwrap().unwrap(), hello);
+
+        server.encode(Ok(event()), &mut buf).unwrap();
+        assert_eq!(&buf[..1], b">");
+        assert_eq!(client.decode(&mut buf).unwrap().unwrap().unwrap(), event());
+
+        let last_event_number = Response::LastEventNumber {
+            stream: StreamName::new("my-stream".to_owned()).unwrap(),
+            number: None,
+        };
+        server
+            .encode(Ok(last_event_number.clone()), &mut buf)
+            .unwrap();
+        assert_eq!(&buf[..1], b"%");
+        let response = client.decode(&mut buf).unwrap().unwrap().unwrap();
+        assert_eq!(response, last_event_number);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn event_read_from_all() {
+        let mut server = ServerCodec::default();
+        let mut client = ClientCodec::default();
+        let mut buf = BytesMut::new();
+
+        let mut linked = event();
+        if let Response::Event { ref mut link, .. } = linked {
+            *link = Some(EventLink {
+                stream: StreamName::all(),
+                number: EventNumber(42),
+            });
+        }
+
+        // RESP2 clients receive neither the metadata nor the link,
+        // the event ends with its data like before
+        let mut unlinked = event();
+        if let Response::Event {
+            ref mut metadata, ..
+        } = unlinked
+        {
+            *metadata = None;
+        }
+        server.encode(Ok(linked.clone()), &mut buf).unwrap();
+        let response = client.decode(&mut buf).unwrap().unwrap().unwrap();
+        assert_eq!(response, unlinked);
+
+        let hello = Response::Hello {
+            version: "0.0.0".to_owned(),
+            protocol: 3,
+        };
+        server.encode(Ok(hello.clone()), &mut buf).unwrap();
+        assert_eq!(client.decode(&mut buf).unwrap().unwrap().unwrap(), hello);
+
+        server.encode(Ok(linked.clone()), &mut buf).unwrap();
+        assert_eq!(client.decode(&mut buf).unwrap().unwrap().unwrap(), linked);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn linked_event_in_resp2() {
+        let mut linked = event();
+        if let Response::Event { ref mut link, .. } = linked {
+            *link = Some(EventLink {
+                stream: StreamName::all(),
+                number: EventNumber(42),
+            });
+        }
+
+        // the five elements older clients know how to decode
+        let expected = RespValue::Array(vec![
+            RespValue::string("event"),
+            RespValue::string("my-stream"),
+            RespValue::Integer(4),
+            RespValue::string("my-event"),
+            RespValue::bulk_string("hello"),
+        ]);
+        assert_eq!(linked.into_resp(Protocol::Resp2), expected);
+    }
+
+    #[test]
+    fn hello_in_resp2() {
+        let mut server = ServerCodec::default();
+        let mut buf = BytesMut::new();
+
+        let hello = Response::Hello {
+            version: "0.0.0".to_owned(),
+            protocol: 2,
+        };
+        server.encode(Ok(hello.clone()), &mut buf).unwrap();
+        assert_eq!(&buf[..1], b"*");
+
+        let response = ClientCodec::default().decode(&mut buf).unwrap();
+        assert_eq!(response.unwrap().unwrap(), hello);
+    }
+
+    #[test]
+    fn subscribe_to_all_with_a_filter() {
+        let args = vec![
+            RespValue::bulk_string("subscribe"),
+            RespValue::bulk_string("$all:0[UserRegistered]"),
+        ];
+        let request = Request::from_resp(RespValue::Array(args)).unwrap();
+
+        let filter = "UserRegistered".parse().unwrap();
+        let expected = Request::SubscribeAll {
+            range: ReadRange::ReadFrom(0),
+            filter: Some(filter),
+        };
+        assert_eq!(request, expected);
+
+        let mut buf = BytesMut::new();
+        ClientCodec::default()
+            .encode(request.clone(), &mut buf)
+            .unwrap();
+        assert_eq!(
+            ServerCodec::default().decode(&mut buf).unwrap(),
+            Some(request)
+        );
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn publish_batch_requests() {
+        let mut buf = BytesMut::new();
+
+        // an event can be named like the id of the batch
+        let events = vec![
+            (
+                EventName::new("id".to_owned()).unwrap(),
+                EventData("936da01f-9abd-4d9d-80c7-02af85c822a8".into()),
+            ),
+            (
+                EventName::new("my-event".to_owned()).unwrap(),
+                EventData("hello".into()),
+            ),
+        ];
+
+        for id in &[None, Some(EventId::from_bytes([7; 16]))] {
+            let request = Request::PublishBatch {
+                stream: StreamName::new("my-stream".to_owned()).unwrap(),
+                events: events.clone(),
+                id: *id,
+            };
+            ClientCodec::default()
+                .encode(request.clone(), &mut buf)
+                .unwrap();
+            assert_eq!(
+                ServerCodec::default().decode(&mut buf).unwrap(),
+                Some(request)
+            );
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn read_request_and_events_response() {
+        let mut buf = BytesMut::new();
+
+        let request = Request::Read {
+            stream: StreamName::new("my-stream".to_owned()).unwrap(),
+            from: EventNumber(12),
+            count: 2,
+            backwards: true,
+        };
+        ClientCodec::default()
+            .encode(request.clone(), &mut buf)
+            .unwrap();
+        assert_eq!(
+            ServerCodec::default().decode(&mut buf).unwrap(),
+            Some(request)
+        );
+
+        let events = Response::Events {
+            stream: StreamName::new("my-stream".to_owned()).unwrap(),
+            events: vec![
+                (
+                    EventNumber(12),
+                    EventName::new("my-event".to_owned()).unwrap(),
+                    EventData("hello".into()),
+                ),
+                (
+                    EventNumber(11),
+                    EventName::new("my-event".to_owned()).unwrap(),
+                    EventData("world".into()),
+                ),
+            ],
+            next: Some(EventNumber(10)),
+        };
+        ServerCodec::default()
+            .encode(Ok(events.clone()), &mut buf)
+            .unwrap();
+        let response = ClientCodec::default().decode(&mut buf).unwrap();
+        assert_eq!(response.unwrap().unwrap(), events);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn delete_stream_requests_and_response() {
+        let mut buf = BytesMut::new();
+        let stream = StreamName::new("my-stream".to_owned()).unwrap();
+
+        let requests = vec![
+            Request::DeleteStream {
+                stream: stream.clone(),
+                hard: true,
+            },
+            Request::TruncateBefore {
+                stream: stream.clone(),
+                number: EventNumber(12),
+            },
+        ];
+        for request in requests {
+            ClientCodec::default()
+                .encode(request.clone(), &mut buf)
+                .unwrap();
+            assert_eq!(
+                ServerCodec::default().decode(&mut buf).unwrap(),
+                Some(request)
+            );
+        }
+
+        let deleted = Response::StreamDeleted { stream };
+        ServerCodec::default()
+            .encode(Ok(deleted.clone()), &mut buf)
+            .unwrap();
+        let response = ClientCodec::default().decode(&mut buf).unwrap();
+        assert_eq!(response.unwrap().unwrap(), deleted);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn retention_request_and_response() {
+        let mut buf = BytesMut::new();
+        let stream = StreamName::new("my-stream".to_owned()).unwrap();
+        let policy = RetentionPolicy {
+            max_count: Some(1000),
+            max_age: None,
+        };
+
+        let request = Request::SetRetention {
+            stream: stream.clone(),
+            policy,
+        };
+        ClientCodec::default()
+            .encode(request.clone(), &mut buf)
+            .unwrap();
+        assert_eq!(
+            ServerCodec::default().decode(&mut buf).unwrap(),
+            Some(request)
+        );
+
+        let retention = Response::Retention { stream, policy };
+        ServerCodec::default()
+            .encode(Ok(retention.clone()), &mut buf)
+            .unwrap();
+        let response = ClientCodec::default().decode(&mut buf).unwrap();
+        assert_eq!(response.unwrap().unwrap(), retention);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn consumer_group_requests_and_responses() {
+        let mut buf = BytesMut::new();
+        let group = GroupName::new("projector".to_owned()).unwrap();
+        let stream = StreamName::new("my-stream".to_owned()).unwrap();
+
+        let requests = vec![
+            Request::SubscribeGroup {
+                group: group.clone(),
+                stream: stream.clone(),
+            },
+            Request::Ack {
+                group: group.clone(),
+                stream: stream.clone(),
+                numbers: vec![EventNumber(3), EventNumber(4)],
+            },
+            Request::Nack {
+                group: group.clone(),
+                stream: stream.clone(),
+                numbers: vec![EventNumber(5)],
+            },
+        ];
+        for request in requests {
+            ClientCodec::default()
+                .encode(request.clone(), &mut buf)
+                .unwrap();
+            assert_eq!(
+                ServerCodec::default().decode(&mut buf).unwrap(),
+                Some(request)
+            );
+        }
+
+        let responses = vec![
+            Response::SubscribedGroup {
+                group: group.clone(),
+                stream: stream.clone(),
+            },
+            Response::ParkedEvents {
+                group,
+                stream,
+                numbers: vec![EventNumber(5)],
+            },
+        ];
+        for response in responses {
+            ServerCodec::default()
+                .encode(Ok(response.clone()), &mut buf)
+                .unwrap();
+            let decoded = ClientCodec::default().decode(&mut buf).unwrap();
+            assert_eq!(decoded.unwrap().unwrap(), response);
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn requests_and_replies_with_ids() {
+        let mut buf = BytesMut::new();
+        let stream = StreamName::new("my-stream".to_owned()).unwrap();
+
+        let request = Request::WithId {
+            id: 42,
+            request: Box::new(Request::LastEventNumber {
+                stream: stream.clone(),
+            }),
+        };
+        ClientCodec::default()
+            .encode(request.clone(), &mut buf)
+            .unwrap();
+        assert_eq!(
+            ServerCodec::default().decode(&mut buf).unwrap(),
+            Some(request)
+        );
+
+        // a HELLO can not be tagged, its reply switches the protocol
+        let value = RespValue::Array(vec![
+            RespValue::bulk_string(&"with-id"[..]),
+            RespValue::bulk_string(&"1"[..]),
+            RespValue::bulk_string(&"hello"[..]),
+            RespValue::bulk_string(&"3"[..]),
+        ]);
+        assert!(Request::from_resp(value).is_err());
+
+        let replies = vec![
+            Ok(Response::LastEventNumber {
+                stream,
+                number: Some(EventNumber(3)),
+            }),
+            Err("the stream does not exist".to_owned()),
+        ];
+        for protocol in &[Protocol::Resp2, Protocol::Resp3] {
+            for reply in replies.clone() {
+                let response = Response::Reply {
+                    id: 42,
+                    reply: Box::new(reply),
+                };
+                let mut codec = RespCodec::default();
+                codec.set_protocol(*protocol);
+                ServerCodec::new(codec)
+                    .encode(Ok(response.clone()), &mut buf)
+                    .unwrap();
+                let decoded = ClientCodec::default().decode(&mut buf).unwrap();
+                assert_eq!(decoded.unwrap().unwrap(), response);
+            }
+        }
+        assert!(buf.is_empty());
+    }
+}
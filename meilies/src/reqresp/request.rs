@@ -1,33 +1,119 @@
 use crate::resp::{FromResp, RespValue};
 use crate::stream::ALL_STREAMS;
-use crate::stream::{EventData, EventName, ReadRange, Stream, StreamName};
+use crate::stream::{
+    EventData, EventFilter, EventId, EventName, EventNumber, ExpectedVersion, GroupName,
+    PublishMetadata, ReadRange, RetentionPolicy, Stream, StreamName,
+};
+use std::convert::TryFrom;
 use std::fmt;
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Request {
     SubscribeAll {
         range: ReadRange,
+        filter: Option<EventFilter>,
     },
     Subscribe {
         streams: Vec<Stream>,
     },
+    Unsubscribe {
+        streams: Vec<StreamName>,
+    },
     Publish {
         stream: StreamName,
         event_name: EventName,
         event_data: EventData,
+        expected_version: ExpectedVersion,
+        metadata: PublishMetadata,
+        /// Whether the server replies with the event number assigned (`publish-numbered`)
+        /// or with a simple `OK` like it always did (`publish`).
+        reply_with_number: bool,
+    },
+    PublishBatch {
+        stream: StreamName,
+        events: Vec<(EventName, EventData)>,
+        /// The id of the first event of the batch (`publish-batch-with-id`), a batch
+        /// published again with the same id is not stored twice.
+        id: Option<EventId>,
     },
     LastEventNumber {
         stream: StreamName,
     },
+    /// Reads a page of at most `count` events starting at `from`,
+    /// towards the first event of the stream if `backwards`.
+    ///
+    /// The server returns fewer events if `count` is above its maximum page size.
+    Read {
+        stream: StreamName,
+        from: EventNumber,
+        count: usize,
+        backwards: bool,
+    },
     StreamNames,
+    /// Deletes a stream: a soft delete hides its events, it can be published to again
+    /// and continues after them; a hard delete removes them and the stream for good.
+    DeleteStream {
+        stream: StreamName,
+        hard: bool,
+    },
+    /// Removes the events of the stream numbered before `number`.
+    TruncateBefore {
+        stream: StreamName,
+        number: EventNumber,
+    },
+    /// Sets how long the events of the stream are kept, an unlimited policy keeps them all.
+    SetRetention {
+        stream: StreamName,
+        policy: RetentionPolicy,
+    },
+    Retention {
+        stream: StreamName,
+    },
+    /// Joins the consumer group of the stream, the members of a group share its events
+    /// and the server remembers the events the group has acknowledged.
+    SubscribeGroup {
+        group: GroupName,
+        stream: StreamName,
+    },
+    /// Acknowledges events delivered to a member of the group,
+    /// sent on the subscription connection and only replied to when sent with an id.
+    Ack {
+        group: GroupName,
+        stream: StreamName,
+        numbers: Vec<EventNumber>,
+    },
+    /// Asks for events delivered to a member of the group to be delivered again,
+    /// they are parked once they have been delivered too many times.
+    Nack {
+        group: GroupName,
+        stream: StreamName,
+        numbers: Vec<EventNumber>,
+    },
+    ParkedEvents {
+        group: GroupName,
+        stream: StreamName,
+    },
+    /// Asks the server to switch to another version of the protocol, like the Redis `HELLO`.
+    Hello {
+        protocol: i64,
+    },
+    /// A request the server replies to with a `Reply` carrying the same id,
+    /// the replies of the requests sent with an id can come in any order.
+    ///
+    /// Written `with-id <id>` followed by the request, which can not be
+    /// a `hello` nor another request with an id.
+    WithId {
+        id: u64,
+        request: Box<Request>,
+    },
 }
 
 impl Into<RespValue> for Request {
     fn into(self) -> RespValue {
         match self {
-            Request::SubscribeAll { range } => {
+            Request::SubscribeAll { range, filter } => {
                 let command = RespValue::bulk_string(&"subscribe"[..]);
-                let all = Stream::all(range).into();
+                let all = Stream::all(range).with_filter(filter).into();
                 RespValue::Array(vec![command, all])
             }
             Request::Subscribe { streams } => {
@@ -36,27 +122,204 @@ impl Into<RespValue> for Request {
                 let args = Some(command).into_iter().chain(streams).collect();
                 RespValue::Array(args)
             }
+            Request::Unsubscribe { streams } => {
+                let command = RespValue::bulk_string(&"unsubscribe"[..]);
+                let streams = streams
+                    .into_iter()
+                    .map(|s| RespValue::bulk_string(s.to_string()));
+                let args = Some(command).into_iter().chain(streams).collect();
+                RespValue::Array(args)
+            }
             Request::Publish {
                 stream,
                 event_name,
                 event_data,
-            } => RespValue::Array(vec![
-                RespValue::bulk_string(&"publish"[..]),
-                RespValue::bulk_string(stream.to_string()),
-                RespValue::bulk_string(event_name.to_string()),
-                RespValue::bulk_string(event_data.0),
-            ]),
+                expected_version,
+                metadata,
+                reply_with_number,
+            } => {
+                let command = if reply_with_number {
+                    "publish-numbered"
+                } else {
+                    "publish"
+                };
+
+                let mut args = vec![
+                    RespValue::bulk_string(command),
+                    RespValue::bulk_string(stream.to_string()),
+                    RespValue::bulk_string(event_name.to_string()),
+                    RespValue::bulk_string(event_data.0),
+                ];
+
+                // we do not send the default option to stay
+                // compatible with servers that do not know it
+                if expected_version != ExpectedVersion::Any {
+                    args.push(RespValue::bulk_string(&"expected-version"[..]));
+                    args.push(expected_version.into());
+                }
+
+                if let Some(id) = metadata.id {
+                    args.push(RespValue::bulk_string(&"id"[..]));
+                    args.push(id.into());
+                }
+
+                if let Some(content_type) = metadata.content_type {
+                    args.push(RespValue::bulk_string(&"content-type"[..]));
+                    args.push(RespValue::bulk_string(content_type));
+                }
+
+                for (key, value) in metadata.headers {
+                    args.push(RespValue::bulk_string(&"header"[..]));
+                    args.push(RespValue::bulk_string(key));
+                    args.push(RespValue::bulk_string(value));
+                }
+
+                RespValue::Array(args)
+            }
+            Request::PublishBatch { stream, events, id } => {
+                let mut args = Vec::with_capacity(3 + events.len() * 2);
+
+                // the id can not be an option of the command,
+                // it could not be told apart from an event name
+                match id {
+                    Some(id) => {
+                        args.push(RespValue::bulk_string(&"publish-batch-with-id"[..]));
+                        args.push(RespValue::bulk_string(stream.to_string()));
+                        args.push(id.into());
+                    }
+                    None => {
+                        args.push(RespValue::bulk_string(&"publish-batch"[..]));
+                        args.push(RespValue::bulk_string(stream.to_string()));
+                    }
+                }
+
+                for (event_name, event_data) in events {
+                    args.push(RespValue::bulk_string(event_name.to_string()));
+                    args.push(RespValue::bulk_string(event_data.0));
+                }
+
+                RespValue::Array(args)
+            }
             Request::LastEventNumber { stream } => RespValue::Array(vec![
                 RespValue::bulk_string(&"last-event-number"[..]),
                 RespValue::bulk_string(stream.to_string()),
             ]),
+            Request::Read {
+                stream,
+                from,
+                count,
+                backwards,
+            } => {
+                let mut args = vec![
+                    RespValue::bulk_string(&"read"[..]),
+                    RespValue::bulk_string(stream.to_string()),
+                    RespValue::bulk_string(from.0.to_string()),
+                    RespValue::bulk_string(count.to_string()),
+                ];
+
+                if backwards {
+                    args.push(RespValue::bulk_string(&"backwards"[..]));
+                }
+
+                RespValue::Array(args)
+            }
             Request::StreamNames => {
                 RespValue::Array(vec![RespValue::bulk_string(&"stream-names"[..])])
             }
+            Request::DeleteStream { stream, hard } => {
+                let mode = if hard { "hard" } else { "soft" };
+                RespValue::Array(vec![
+                    RespValue::bulk_string(&"delete-stream"[..]),
+                    RespValue::bulk_string(stream.to_string()),
+                    RespValue::bulk_string(mode),
+                ])
+            }
+            Request::TruncateBefore { stream, number } => RespValue::Array(vec![
+                RespValue::bulk_string(&"truncate-before"[..]),
+                RespValue::bulk_string(stream.to_string()),
+                RespValue::bulk_string(number.0.to_string()),
+            ]),
+            Request::SetRetention { stream, policy } => {
+                let mut args = vec![
+                    RespValue::bulk_string(&"set-retention"[..]),
+                    RespValue::bulk_string(stream.to_string()),
+                ];
+
+                if let Some(max_count) = policy.max_count {
+                    args.push(RespValue::bulk_string(&"max-count"[..]));
+                    args.push(RespValue::bulk_string(max_count.to_string()));
+                }
+
+                if let Some(max_age) = policy.max_age {
+                    args.push(RespValue::bulk_string(&"max-age"[..]));
+                    args.push(RespValue::bulk_string(max_age.to_string()));
+                }
+
+                RespValue::Array(args)
+            }
+            Request::Retention { stream } => RespValue::Array(vec![
+                RespValue::bulk_string(&"retention"[..]),
+                RespValue::bulk_string(stream.to_string()),
+            ]),
+            Request::SubscribeGroup { group, stream } => RespValue::Array(vec![
+                RespValue::bulk_string(&"subscribe-group"[..]),
+                RespValue::bulk_string(group.into_inner()),
+                RespValue::bulk_string(stream.into_inner()),
+            ]),
+            Request::Ack {
+                group,
+                stream,
+                numbers,
+            } => numbers_request("ack", group, stream, numbers),
+            Request::Nack {
+                group,
+                stream,
+                numbers,
+            } => numbers_request("nack", group, stream, numbers),
+            Request::ParkedEvents { group, stream } => RespValue::Array(vec![
+                RespValue::bulk_string(&"parked-events"[..]),
+                RespValue::bulk_string(group.into_inner()),
+                RespValue::bulk_string(stream.into_inner()),
+            ]),
+            Request::Hello { protocol } => RespValue::Array(vec![
+                RespValue::bulk_string(&"hello"[..]),
+                RespValue::bulk_string(protocol.to_string()),
+            ]),
+            Request::WithId { id, request } => {
+                let mut args = vec![
+                    RespValue::bulk_string(&"with-id"[..]),
+                    RespValue::bulk_string(id.to_string()),
+                ];
+
+                match (*request).into() {
+                    RespValue::Array(request) => args.extend(request),
+                    request => args.push(request),
+                }
+
+                RespValue::Array(args)
+            }
         }
     }
 }
 
+fn numbers_request(
+    command: &str,
+    group: GroupName,
+    stream: StreamName,
+    numbers: Vec<EventNumber>,
+) -> RespValue {
+    let mut args = Vec::with_capacity(3 + numbers.len());
+    args.push(RespValue::bulk_string(command));
+    args.push(RespValue::bulk_string(group.into_inner()));
+    args.push(RespValue::bulk_string(stream.into_inner()));
+
+    for number in numbers {
+        args.push(RespValue::bulk_string(number.0.to_string()));
+    }
+
+    RespValue::Array(args)
+}
+
 #[derive(Debug)]
 pub enum RespRequestConvertError {
     InvalidCommandRespType,
@@ -65,6 +328,7 @@ pub enum RespRequestConvertError {
     UnknownCommandName,
     MissingArgument,
     TooManyArguments,
+    UnknownOptionName,
 }
 
 impl fmt::Display for RespRequestConvertError {
@@ -77,6 +341,7 @@ impl fmt::Display for RespRequestConvertError {
             UnknownCommandName => write!(f, "Unknown command name"),
             MissingArgument => write!(f, "Missing argument"),
             TooManyArguments => write!(f, "Too many arguments"),
+            UnknownOptionName => write!(f, "Unknown option name"),
         }
     }
 }
@@ -98,20 +363,38 @@ impl FromResp for Request {
             .ok_or(MissingCommandName)?
             .map_err(|_| InvalidArgumentRespType)?;
 
-        match command.as_str() {
+        // Redis clients usually send the commands in uppercase (e.g. HELLO)
+        match command.to_ascii_lowercase().as_str() {
             "subscribe" => {
                 let streams: Result<Vec<_>, _> = iter.map(Stream::from_resp).collect();
                 let streams = streams.map_err(|_| InvalidArgumentRespType)?;
 
-                if let Some(stream) = streams.iter().find(|s| s.name == ALL_STREAMS) {
-                    return Ok(Request::SubscribeAll {
-                        range: stream.range,
-                    });
+                // `$all` can also be subscribed to along with other streams,
+                // like when a client subscribes again after a reconnection
+                if let [stream] = streams.as_slice() {
+                    if stream.name == ALL_STREAMS {
+                        return Ok(Request::SubscribeAll {
+                            range: stream.range,
+                            filter: stream.filter.clone(),
+                        });
+                    }
                 }
 
                 Ok(Request::Subscribe { streams })
             }
-            "publish" => {
+            "unsubscribe" => {
+                let streams: Result<Vec<_>, _> = iter.map(StreamName::from_resp).collect();
+                let streams = streams.map_err(|_| InvalidArgumentRespType)?;
+
+                if streams.is_empty() {
+                    return Err(MissingArgument);
+                }
+
+                Ok(Request::Unsubscribe { streams })
+            }
+            command @ "publish" | command @ "publish-numbered" => {
+                let reply_with_number = command == "publish-numbered";
+
                 let stream = iter
                     .next()
                     .map(StreamName::from_resp)
@@ -130,16 +413,102 @@ impl FromResp for Request {
                     .ok_or(MissingArgument)?
                     .map_err(|_| InvalidArgumentRespType)?;
 
-                if iter.next().is_some() {
-                    return Err(TooManyArguments);
+                let mut expected_version = ExpectedVersion::Any;
+                let mut metadata = PublishMetadata::default();
+
+                while let Some(option) = iter.next() {
+                    let option = String::from_resp(option).map_err(|_| InvalidArgumentRespType)?;
+                    match option.as_str() {
+                        "expected-version" => {
+                            expected_version = iter
+                                .next()
+                                .map(ExpectedVersion::from_resp)
+                                .ok_or(MissingArgument)?
+                                .map_err(|_| InvalidArgumentRespType)?;
+                        }
+                        "id" => {
+                            let id = iter
+                                .next()
+                                .map(EventId::from_resp)
+                                .ok_or(MissingArgument)?
+                                .map_err(|_| InvalidArgumentRespType)?;
+                            metadata.id = Some(id);
+                        }
+                        "content-type" => {
+                            let content_type = iter
+                                .next()
+                                .map(String::from_resp)
+                                .ok_or(MissingArgument)?
+                                .map_err(|_| InvalidArgumentRespType)?;
+                            metadata.content_type = Some(content_type);
+                        }
+                        "header" => {
+                            let key = iter
+                                .next()
+                                .map(String::from_resp)
+                                .ok_or(MissingArgument)?
+                                .map_err(|_| InvalidArgumentRespType)?;
+
+                            let value = iter
+                                .next()
+                                .map(String::from_resp)
+                                .ok_or(MissingArgument)?
+                                .map_err(|_| InvalidArgumentRespType)?;
+
+                            metadata.headers.insert(key, value);
+                        }
+                        _otherwise => return Err(UnknownOptionName),
+                    }
                 }
 
                 Ok(Request::Publish {
                     stream,
                     event_name,
                     event_data,
+                    expected_version,
+                    metadata,
+                    reply_with_number,
                 })
             }
+            command @ "publish-batch" | command @ "publish-batch-with-id" => {
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let id = if command == "publish-batch-with-id" {
+                    let id = iter
+                        .next()
+                        .map(EventId::from_resp)
+                        .ok_or(MissingArgument)?
+                        .map_err(|_| InvalidArgumentRespType)?;
+                    Some(id)
+                } else {
+                    None
+                };
+
+                let mut events = Vec::with_capacity(iter.len() / 2);
+
+                while let Some(event_name) = iter.next() {
+                    let event_name =
+                        EventName::from_resp(event_name).map_err(|_| InvalidArgumentRespType)?;
+
+                    let event_data = iter
+                        .next()
+                        .map(EventData::from_resp)
+                        .ok_or(MissingArgument)?
+                        .map_err(|_| InvalidArgumentRespType)?;
+
+                    events.push((event_name, event_data));
+                }
+
+                if events.is_empty() {
+                    return Err(MissingArgument);
+                }
+
+                Ok(Request::PublishBatch { stream, events, id })
+            }
             "last-event-number" => {
                 let stream = iter
                     .next()
@@ -153,8 +522,227 @@ impl FromResp for Request {
 
                 Ok(Request::LastEventNumber { stream })
             }
+            "read" => {
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let from = iter
+                    .next()
+                    .map(number_from_resp)
+                    .ok_or(MissingArgument)?
+                    .ok_or(InvalidArgumentRespType)?;
+
+                let count = iter
+                    .next()
+                    .map(number_from_resp)
+                    .ok_or(MissingArgument)?
+                    .ok_or(InvalidArgumentRespType)?;
+                let count = usize::try_from(count).map_err(|_| InvalidArgumentRespType)?;
+
+                let backwards = match iter.next().map(String::from_resp) {
+                    Some(Ok(ref direction)) if direction == "backwards" => true,
+                    Some(Ok(ref direction)) if direction == "forwards" => false,
+                    Some(_) => return Err(UnknownOptionName),
+                    None => false,
+                };
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                Ok(Request::Read {
+                    stream,
+                    from: EventNumber(from),
+                    count,
+                    backwards,
+                })
+            }
             "stream-names" => Ok(Request::StreamNames),
+            "delete-stream" => {
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let hard = match iter.next().map(String::from_resp) {
+                    Some(Ok(ref mode)) if mode == "hard" => true,
+                    Some(Ok(ref mode)) if mode == "soft" => false,
+                    Some(_) => return Err(UnknownOptionName),
+                    None => false,
+                };
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                Ok(Request::DeleteStream { stream, hard })
+            }
+            "truncate-before" => {
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let number = iter
+                    .next()
+                    .map(number_from_resp)
+                    .ok_or(MissingArgument)?
+                    .ok_or(InvalidArgumentRespType)?;
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                Ok(Request::TruncateBefore {
+                    stream,
+                    number: EventNumber(number),
+                })
+            }
+            "set-retention" => {
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let mut policy = RetentionPolicy::default();
+
+                while let Some(option) = iter.next() {
+                    let option = String::from_resp(option).map_err(|_| InvalidArgumentRespType)?;
+                    let value = iter
+                        .next()
+                        .map(number_from_resp)
+                        .ok_or(MissingArgument)?
+                        .ok_or(InvalidArgumentRespType)?;
+
+                    match option.as_str() {
+                        "max-count" => policy.max_count = Some(value),
+                        "max-age" => policy.max_age = Some(value),
+                        _otherwise => return Err(UnknownOptionName),
+                    }
+                }
+
+                Ok(Request::SetRetention { stream, policy })
+            }
+            "retention" => {
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                Ok(Request::Retention { stream })
+            }
+            command @ "subscribe-group" | command @ "parked-events" => {
+                let group = iter
+                    .next()
+                    .map(GroupName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                if command == "subscribe-group" {
+                    Ok(Request::SubscribeGroup { group, stream })
+                } else {
+                    Ok(Request::ParkedEvents { group, stream })
+                }
+            }
+            command @ "ack" | command @ "nack" => {
+                let group = iter
+                    .next()
+                    .map(GroupName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let mut numbers = Vec::with_capacity(iter.len());
+                for number in iter {
+                    let number = number_from_resp(number).ok_or(InvalidArgumentRespType)?;
+                    numbers.push(EventNumber(number));
+                }
+
+                if numbers.is_empty() {
+                    return Err(MissingArgument);
+                }
+
+                if command == "ack" {
+                    Ok(Request::Ack {
+                        group,
+                        stream,
+                        numbers,
+                    })
+                } else {
+                    Ok(Request::Nack {
+                        group,
+                        stream,
+                        numbers,
+                    })
+                }
+            }
+            "hello" => {
+                let protocol = match iter.next() {
+                    Some(RespValue::Integer(protocol)) => protocol,
+                    Some(value) => String::from_resp(value)
+                        .ok()
+                        .and_then(|s| s.parse().ok())
+                        .ok_or(InvalidArgumentRespType)?,
+                    None => return Err(MissingArgument),
+                };
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                Ok(Request::Hello { protocol })
+            }
+            "with-id" => {
+                let id = iter
+                    .next()
+                    .map(number_from_resp)
+                    .ok_or(MissingArgument)?
+                    .ok_or(InvalidArgumentRespType)?;
+
+                // the codec switches protocol when replying to HELLO, it must not be tagged
+                match Request::from_resp(RespValue::Array(iter.collect()))? {
+                    Request::Hello { .. } | Request::WithId { .. } => Err(InvalidArgumentRespType),
+                    request => Ok(Request::WithId {
+                        id,
+                        request: Box::new(request),
+                    }),
+                }
+            }
             _otherwise => Err(UnknownCommandName),
         }
     }
 }
+
+/// Numbers are sent as bulk strings by the command line clients.
+fn number_from_resp(value: RespValue) -> Option<u64> {
+    match value {
+        RespValue::Integer(number) if number >= 0 => Some(number as u64),
+        value => String::from_resp(value).ok()?.parse().ok(),
+    }
+}
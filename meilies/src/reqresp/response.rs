@@ -1,18 +1,55 @@
-use crate::resp::{FromResp, RespValue};
-use crate::stream::{EventData, EventName, EventNumber, StreamName};
-use std::fmt;
+use crate::resp::{FromResp, Protocol, RespValue};
+use crate::stream::{
+    EventData, EventLink, EventMetadata, EventName, EventNumber, GroupName, NumberedEvent,
+    RetentionPolicy, StreamName,
+};
+use std::collections::HashMap;
+use std::{fmt, mem};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Response {
     Ok,
+    Published {
+        stream: StreamName,
+        number: EventNumber,
+    },
+    PublishedBatch {
+        stream: StreamName,
+        first: EventNumber,
+        last: EventNumber,
+    },
     Subscribed {
         stream: StreamName,
     },
+    Unsubscribed {
+        stream: StreamName,
+    },
+    SubscribedGroup {
+        group: GroupName,
+        stream: StreamName,
+    },
+    /// The stream has been deleted, the subscription to it has ended.
+    StreamDeleted {
+        stream: StreamName,
+    },
+    /// The filtered subscription to the stream checked the events up to `number`
+    /// included, the ones not sent did not match. A client subscribing again
+    /// after a reconnection resumes after it instead of after its last event.
+    Progress {
+        stream: StreamName,
+        number: EventNumber,
+    },
     Event {
         stream: StreamName,
         number: EventNumber,
         event_name: EventName,
         event_data: EventData,
+        /// Only sent to the clients speaking RESP3, older clients
+        /// do not expect it and could not decode the event.
+        metadata: Option<EventMetadata>,
+        /// The stream the event has been read from if it is not `stream`,
+        /// like the `$all` stream, and the number of the event in it.
+        link: Option<EventLink>,
     },
     LastEventNumber {
         stream: StreamName,
@@ -21,28 +58,174 @@ pub enum Response {
     StreamNames {
         streams: Vec<StreamName>,
     },
+    /// A page of events, `next` is where the following page starts
+    /// or `None` if there was no more event to read.
+    Events {
+        stream: StreamName,
+        events: Vec<NumberedEvent>,
+        next: Option<EventNumber>,
+    },
+    WrongExpectedVersion {
+        stream: StreamName,
+        actual: Option<EventNumber>,
+    },
+    Retention {
+        stream: StreamName,
+        policy: RetentionPolicy,
+    },
+    /// The events the group has given up on after delivering them too many times.
+    ParkedEvents {
+        group: GroupName,
+        stream: StreamName,
+        numbers: Vec<EventNumber>,
+    },
+    Hello {
+        version: String,
+        protocol: i64,
+    },
+    /// The reply to a request sent with an id.
+    Reply {
+        id: u64,
+        reply: Box<Result<Response, String>>,
+    },
+}
+
+impl Response {
+    /// Converts the response into the value sent to a client speaking the given protocol.
+    ///
+    /// RESP3 clients receive the subscription messages as push messages
+    /// and the replies describing a stream as maps, RESP2 clients receive arrays.
+    /// The metadata and the link of the events are only sent to RESP3 clients,
+    /// RESP2 clients expect the events to end with their data.
+    pub fn into_resp(self, protocol: Protocol) -> RespValue {
+        match (self, protocol) {
+            (
+                Response::Event {
+                    stream,
+                    number,
+                    event_name,
+                    event_data,
+                    ..
+                },
+                Protocol::Resp2,
+            ) => Response::Event {
+                stream,
+                number,
+                event_name,
+                event_data,
+                metadata: None,
+                link: None,
+            }
+            .into(),
+            (Response::LastEventNumber { stream, number }, Protocol::Resp3) => {
+                let number = match number {
+                    Some(number) => RespValue::Integer(number.0 as i64),
+                    None => RespValue::Nil,
+                };
+
+                RespValue::Map(vec![
+                    (
+                        RespValue::string("type"),
+                        RespValue::string("last-event-number"),
+                    ),
+                    (RespValue::string("stream"), RespValue::string(stream)),
+                    (RespValue::string("number"), number),
+                ])
+            }
+            (response @ Response::Subscribed { .. }, Protocol::Resp3)
+            | (response @ Response::Unsubscribed { .. }, Protocol::Resp3)
+            | (response @ Response::StreamDeleted { .. }, Protocol::Resp3)
+            | (response @ Response::Progress { .. }, Protocol::Resp3)
+            | (response @ Response::SubscribedGroup { .. }, Protocol::Resp3)
+            | (response @ Response::Event { .. }, Protocol::Resp3) => match response.into() {
+                RespValue::Array(elements) => RespValue::Push(elements),
+                value => value,
+            },
+            (Response::Reply { id, reply }, protocol) => {
+                let reply = match *reply {
+                    Ok(response) => response.into_resp(protocol),
+                    Err(error) => RespValue::Error(error),
+                };
+
+                RespValue::Array(vec![
+                    RespValue::string("reply"),
+                    RespValue::Integer(id as i64),
+                    reply,
+                ])
+            }
+            (response, _) => response.into(),
+        }
+    }
 }
 
 impl Into<RespValue> for Response {
     fn into(self) -> RespValue {
         match self {
             Response::Ok => RespValue::string("OK"),
+            Response::Published { stream, number } => RespValue::Array(vec![
+                RespValue::string("published"),
+                RespValue::string(stream),
+                RespValue::Integer(number.0 as i64),
+            ]),
+            Response::PublishedBatch {
+                stream,
+                first,
+                last,
+            } => RespValue::Array(vec![
+                RespValue::string("published-batch"),
+                RespValue::string(stream),
+                RespValue::Integer(first.0 as i64),
+                RespValue::Integer(last.0 as i64),
+            ]),
             Response::Subscribed { stream } => RespValue::Array(vec![
                 RespValue::string("subscribed"),
                 RespValue::string(stream),
             ]),
+            Response::Unsubscribed { stream } => RespValue::Array(vec![
+                RespValue::string("unsubscribed"),
+                RespValue::string(stream),
+            ]),
+            Response::SubscribedGroup { group, stream } => RespValue::Array(vec![
+                RespValue::string("subscribed-group"),
+                RespValue::string(group),
+                RespValue::string(stream),
+            ]),
+            Response::StreamDeleted { stream } => RespValue::Array(vec![
+                RespValue::string("stream-deleted"),
+                RespValue::string(stream),
+            ]),
+            Response::Progress { stream, number } => RespValue::Array(vec![
+                RespValue::string("progress"),
+                RespValue::string(stream),
+                RespValue::Integer(number.0 as i64),
+            ]),
             Response::Event {
                 stream,
                 number,
                 event_name,
                 event_data,
-            } => RespValue::Array(vec![
-                RespValue::string("event"),
-                RespValue::string(stream),
-                RespValue::Integer(number.0 as i64),
-                RespValue::string(event_name),
-                RespValue::bulk_string(event_data.0),
-            ]),
+                metadata,
+                link,
+            } => {
+                let mut elements = vec![
+                    RespValue::string("event"),
+                    RespValue::string(stream),
+                    RespValue::Integer(number.0 as i64),
+                    RespValue::string(event_name),
+                    RespValue::bulk_string(event_data.0),
+                ];
+
+                if let Some(metadata) = metadata {
+                    elements.push(metadata.into());
+                }
+
+                // the link is only sent with the events of the system streams
+                if let Some(link) = link {
+                    elements.push(link.into());
+                }
+
+                RespValue::Array(elements)
+            }
             Response::LastEventNumber { stream, number } => {
                 let number = match number {
                     Some(number) => RespValue::Integer(number.0 as i64),
@@ -63,6 +246,83 @@ impl Into<RespValue> for Response {
                 let args = Some(command).into_iter().chain(streams).collect();
                 RespValue::Array(args)
             }
+            Response::Events {
+                stream,
+                events,
+                next,
+            } => {
+                let next = match next {
+                    Some(number) => RespValue::Integer(number.0 as i64),
+                    None => RespValue::Nil,
+                };
+
+                let events = events
+                    .into_iter()
+                    .map(|(number, event_name, event_data)| {
+                        RespValue::Array(vec![
+                            RespValue::Integer(number.0 as i64),
+                            RespValue::string(event_name),
+                            RespValue::bulk_string(event_data.0),
+                        ])
+                    })
+                    .collect();
+
+                RespValue::Array(vec![
+                    RespValue::string("events"),
+                    RespValue::string(stream),
+                    next,
+                    RespValue::Array(events),
+                ])
+            }
+            Response::WrongExpectedVersion { stream, actual } => {
+                let actual = match actual {
+                    Some(number) => RespValue::Integer(number.0 as i64),
+                    None => RespValue::Nil,
+                };
+
+                RespValue::Array(vec![
+                    RespValue::string("wrong-expected-version"),
+                    RespValue::string(stream),
+                    actual,
+                ])
+            }
+            Response::Retention { stream, policy } => {
+                let limit = |limit: Option<u64>| match limit {
+                    Some(limit) => RespValue::Integer(limit as i64),
+                    None => RespValue::Nil,
+                };
+
+                RespValue::Array(vec![
+                    RespValue::string("retention"),
+                    RespValue::string(stream),
+                    limit(policy.max_count),
+                    limit(policy.max_age),
+                ])
+            }
+            Response::ParkedEvents {
+                group,
+                stream,
+                numbers,
+            } => {
+                let numbers = numbers
+                    .into_iter()
+                    .map(|n| RespValue::Integer(n.0 as i64))
+                    .collect();
+
+                RespValue::Array(vec![
+                    RespValue::string("parked-events"),
+                    RespValue::string(group),
+                    RespValue::string(stream),
+                    RespValue::Array(numbers),
+                ])
+            }
+            // the same map as the Redis one, converted into an array for RESP2 clients
+            Response::Hello { version, protocol } => RespValue::Map(vec![
+                (RespValue::string("server"), RespValue::string("meilies")),
+                (RespValue::string("version"), RespValue::string(version)),
+                (RespValue::string("proto"), RespValue::Integer(protocol)),
+            ]),
+            response @ Response::Reply { .. } => response.into_resp(Protocol::Resp2),
         }
     }
 }
@@ -99,7 +359,8 @@ impl FromResp for Response {
 
         let mut iter = match value {
             RespValue::SimpleString(ref text) if text == "OK" => return Ok(Response::Ok),
-            RespValue::Array(array) => array.into_iter(),
+            RespValue::Map(pairs) => return response_from_map(pairs),
+            RespValue::Array(array) | RespValue::Push(array) => array.into_iter(),
             _otherwise => return Err(InvalidResponseRespType),
         };
 
@@ -110,6 +371,54 @@ impl FromResp for Response {
             .map_err(|_| InvalidArgumentRespType)?;
 
         match response_type.as_str() {
+            "published" => {
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let number = iter
+                    .next()
+                    .map(EventNumber::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                Ok(Response::Published { stream, number })
+            }
+            "published-batch" => {
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let first = iter
+                    .next()
+                    .map(EventNumber::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let last = iter
+                    .next()
+                    .map(EventNumber::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                Ok(Response::PublishedBatch {
+                    stream,
+                    first,
+                    last,
+                })
+            }
             "subscribed" => {
                 let stream = iter
                     .next()
@@ -123,6 +432,70 @@ impl FromResp for Response {
 
                 Ok(Response::Subscribed { stream })
             }
+            "unsubscribed" => {
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                Ok(Response::Unsubscribed { stream })
+            }
+            "subscribed-group" => {
+                let group = iter
+                    .next()
+                    .map(GroupName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                Ok(Response::SubscribedGroup { group, stream })
+            }
+            "stream-deleted" => {
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                Ok(Response::StreamDeleted { stream })
+            }
+            "progress" => {
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let number = iter
+                    .next()
+                    .map(EventNumber::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                Ok(Response::Progress { stream, number })
+            }
             "event" => {
                 let stream = iter
                     .next()
@@ -148,6 +521,24 @@ impl FromResp for Response {
                     .ok_or(MissingArgument)?
                     .map_err(|_| InvalidArgumentRespType)?;
 
+                // the metadata is a map, only sent to RESP3 clients,
+                // the link is an array
+                let mut next = iter.next();
+                let metadata = match next {
+                    Some(RespValue::Map(_)) => {
+                        let value = mem::replace(&mut next, iter.next()).unwrap();
+                        Some(EventMetadata::from_resp(value).map_err(|_| InvalidArgumentRespType)?)
+                    }
+                    _ => None,
+                };
+
+                let link = match next {
+                    Some(value) => {
+                        Some(EventLink::from_resp(value).map_err(|_| InvalidArgumentRespType)?)
+                    }
+                    None => None,
+                };
+
                 if iter.next().is_some() {
                     return Err(TooManyArguments);
                 }
@@ -157,6 +548,8 @@ impl FromResp for Response {
                     number,
                     event_name,
                     event_data,
+                    metadata,
+                    link,
                 })
             }
             "last-event-number" => {
@@ -178,11 +571,233 @@ impl FromResp for Response {
 
                 Ok(Response::LastEventNumber { stream, number })
             }
+            "events" => {
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let next = iter
+                    .next()
+                    .map(FromResp::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let values: Vec<Vec<RespValue>> = iter
+                    .next()
+                    .map(FromResp::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                let mut events = Vec::with_capacity(values.len());
+                for event in values {
+                    let mut event = event.into_iter();
+
+                    let number = event
+                        .next()
+                        .map(EventNumber::from_resp)
+                        .ok_or(MissingArgument)?
+                        .map_err(|_| InvalidArgumentRespType)?;
+
+                    let event_name = event
+                        .next()
+                        .map(EventName::from_resp)
+                        .ok_or(MissingArgument)?
+                        .map_err(|_| InvalidArgumentRespType)?;
+
+                    let event_data = event
+                        .next()
+                        .map(EventData::from_resp)
+                        .ok_or(MissingArgument)?
+                        .map_err(|_| InvalidArgumentRespType)?;
+
+                    if event.next().is_some() {
+                        return Err(TooManyArguments);
+                    }
+
+                    events.push((number, event_name, event_data));
+                }
+
+                Ok(Response::Events {
+                    stream,
+                    events,
+                    next,
+                })
+            }
             "stream-names" => match iter.map(StreamName::from_resp).collect() {
                 Ok(streams) => Ok(Response::StreamNames { streams }),
                 Err(_) => Err(InvalidArgumentRespType),
             },
+            "wrong-expected-version" => {
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let actual = iter
+                    .next()
+                    .map(FromResp::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                Ok(Response::WrongExpectedVersion { stream, actual })
+            }
+            "retention" => {
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let mut limits = [None, None];
+                for limit in limits.iter_mut() {
+                    let value: Option<i64> = iter
+                        .next()
+                        .map(FromResp::from_resp)
+                        .ok_or(MissingArgument)?
+                        .map_err(|_| InvalidArgumentRespType)?;
+
+                    *limit = match value {
+                        Some(value) if value < 0 => return Err(InvalidArgumentRespType),
+                        value => value.map(|v| v as u64),
+                    };
+                }
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                let policy = RetentionPolicy {
+                    max_count: limits[0],
+                    max_age: limits[1],
+                };
+                Ok(Response::Retention { stream, policy })
+            }
+            "reply" => {
+                let id = iter
+                    .next()
+                    .map(i64::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let reply = iter
+                    .next()
+                    .map(Result::<Response, String>::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                Ok(Response::Reply {
+                    id: id as u64,
+                    reply: Box::new(reply),
+                })
+            }
+            "parked-events" => {
+                let group = iter
+                    .next()
+                    .map(GroupName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let stream = iter
+                    .next()
+                    .map(StreamName::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                let numbers = iter
+                    .next()
+                    .map(Vec::<EventNumber>::from_resp)
+                    .ok_or(MissingArgument)?
+                    .map_err(|_| InvalidArgumentRespType)?;
+
+                if iter.next().is_some() {
+                    return Err(TooManyArguments);
+                }
+
+                Ok(Response::ParkedEvents {
+                    group,
+                    stream,
+                    numbers,
+                })
+            }
+            // the HELLO reply map converted into an array for RESP2 clients
+            "server" => {
+                let server = iter.next().ok_or(MissingArgument)?;
+                let mut pairs = vec![(RespValue::string("server"), server)];
+                while let (Some(key), Some(value)) = (iter.next(), iter.next()) {
+                    pairs.push((key, value));
+                }
+
+                response_from_map(pairs)
+            }
             _otherwise => Err(UnknownTypeName),
         }
     }
 }
+
+fn response_from_map(
+    pairs: Vec<(RespValue, RespValue)>,
+) -> Result<Response, RespResponseConvertError> {
+    use RespResponseConvertError::*;
+
+    let mut fields = HashMap::new();
+    for (key, value) in pairs {
+        let key = String::from_resp(key).map_err(|_| InvalidArgumentRespType)?;
+        fields.insert(key, value);
+    }
+
+    let response_type = match fields.remove("type") {
+        Some(value) => String::from_resp(value).map_err(|_| InvalidArgumentRespType)?,
+        // the HELLO reply is the only map without a type, like the Redis one
+        None if fields.contains_key("server") => String::from("hello"),
+        None => return Err(MissingTypeName),
+    };
+
+    match response_type.as_str() {
+        "last-event-number" => {
+            let stream = fields
+                .remove("stream")
+                .map(StreamName::from_resp)
+                .ok_or(MissingArgument)?
+                .map_err(|_| InvalidArgumentRespType)?;
+
+            let number = fields
+                .remove("number")
+                .map(FromResp::from_resp)
+                .ok_or(MissingArgument)?
+                .map_err(|_| InvalidArgumentRespType)?;
+
+            Ok(Response::LastEventNumber { stream, number })
+        }
+        "hello" => {
+            let version = fields
+                .remove("version")
+                .map(String::from_resp)
+                .ok_or(MissingArgument)?
+                .map_err(|_| InvalidArgumentRespType)?;
+
+            let protocol = fields
+                .remove("proto")
+                .map(i64::from_resp)
+                .ok_or(MissingArgument)?
+                .map_err(|_| InvalidArgumentRespType)?;
+
+            Ok(Response::Hello { version, protocol })
+        }
+        _otherwise => Err(UnknownTypeName),
+    }
+}
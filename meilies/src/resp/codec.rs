@@ -1,11 +1,12 @@
-use std::{fmt, num, str};
+use std::ops::Range;
+use std::{cmp, fmt, num, str};
 
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
 use subslice::SubsliceExt;
 use tokio::codec::{Decoder, Encoder};
 use tokio::io;
 
-use super::RespValue;
+use super::{Double, RespValue};
 
 const CRLF_NEWLINE: &[u8; 2] = &[b'\r', b'\n'];
 const SIMPLE_STRING_CHAR: u8 = b'+';
@@ -13,14 +14,90 @@ const ERROR_CHAR: u8 = b'-';
 const INTEGER_CHAR: u8 = b':';
 const BULK_STRING_CHAR: u8 = b'$';
 const ARRAY_CHAR: u8 = b'*';
+const NULL_CHAR: u8 = b'_';
+const BOOLEAN_CHAR: u8 = b'#';
+const DOUBLE_CHAR: u8 = b',';
+const BIG_NUMBER_CHAR: u8 = b'(';
+const BLOB_ERROR_CHAR: u8 = b'!';
+const VERBATIM_CHAR: u8 = b'=';
+const MAP_CHAR: u8 = b'%';
+const SET_CHAR: u8 = b'~';
+const PUSH_CHAR: u8 = b'>';
+
+/// The version of the protocol used to encode the messages.
+///
+/// The RESP3 types are understood whatever the version when decoding,
+/// they are converted to the nearest RESP2 type when encoding in RESP2.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Protocol {
+    Resp2,
+    Resp3,
+}
+
+impl Protocol {
+    /// Returns the protocol corresponding to the version sent in a `HELLO` command.
+    pub fn from_version(version: i64) -> Option<Protocol> {
+        match version {
+            2 => Some(Protocol::Resp2),
+            3 => Some(Protocol::Resp3),
+            _ => None,
+        }
+    }
+
+    pub fn version(self) -> i64 {
+        match self {
+            Protocol::Resp2 => 2,
+            Protocol::Resp3 => 3,
+        }
+    }
+}
+
+impl Default for Protocol {
+    fn default() -> Protocol {
+        Protocol::Resp2
+    }
+}
+
+/// The limits a codec enforces on the messages it decodes,
+/// they protect from frames that would exhaust the memory or the stack.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct RespLimits {
+    /// The maximum number of bytes of a bulk string.
+    pub max_bulk_length: usize,
+    /// The maximum number of elements of an array.
+    pub max_array_length: usize,
+    /// The maximum number of arrays nested in one another.
+    pub max_depth: usize,
+    /// The maximum number of bytes buffered while waiting for a frame to complete.
+    pub max_buffered_bytes: usize,
+}
+
+impl Default for RespLimits {
+    fn default() -> RespLimits {
+        RespLimits {
+            max_bulk_length: 512 * 1024 * 1024,
+            max_array_length: 1024 * 1024,
+            max_depth: 16,
+            max_buffered_bytes: 1024 * 1024 * 1024,
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum RespMsgError {
     InvalidPrefixByte(u8),
     InvalidInteger(num::ParseIntError),
     InvalidUtf8String(str::Utf8Error),
+    InvalidDouble(num::ParseFloatError),
+    InvalidBoolean,
+    InvalidBigNumber,
+    InvalidVerbatimString,
     SimpleStringContainCrlf,
     MissingBulkStringFinalCrlf,
+    BulkStringTooLong(i64),
+    ArrayTooLong(i64),
+    TooDeeplyNested(usize),
+    FrameTooLarge(usize),
     IoError(io::Error),
 }
 
@@ -31,8 +108,16 @@ impl fmt::Display for RespMsgError {
             InvalidPrefixByte(byte) => write!(fmt, "invalid prefix byte: {:?}", byte),
             InvalidInteger(error) => write!(fmt, "invalid integer: {}", error),
             InvalidUtf8String(error) => write!(fmt, "invalid utf8 string: {}", error),
+            InvalidDouble(error) => write!(fmt, "invalid double: {}", error),
+            InvalidBoolean => write!(fmt, "invalid boolean"),
+            InvalidBigNumber => write!(fmt, "invalid big number"),
+            InvalidVerbatimString => write!(fmt, "invalid verbatim string"),
             SimpleStringContainCrlf => write!(fmt, "simple string contain crlf"),
             MissingBulkStringFinalCrlf => write!(fmt, "missing bulk string final crlf"),
+            BulkStringTooLong(length) => write!(fmt, "bulk string too long: {} bytes", length),
+            ArrayTooLong(length) => write!(fmt, "array too long: {} elements", length),
+            TooDeeplyNested(depth) => write!(fmt, "arrays too deeply nested: {} levels", depth),
+            FrameTooLarge(length) => write!(fmt, "frame too large: {} bytes buffered", length),
             IoError(error) => write!(fmt, "io error: {}", error),
         }
     }
@@ -56,6 +141,12 @@ impl From<num::ParseIntError> for RespMsgError {
     }
 }
 
+impl From<num::ParseFloatError> for RespMsgError {
+    fn from(error: num::ParseFloatError) -> RespMsgError {
+        RespMsgError::InvalidDouble(error)
+    }
+}
+
 impl From<str::Utf8Error> for RespMsgError {
     fn from(error: str::Utf8Error) -> RespMsgError {
         RespMsgError::InvalidUtf8String(error)
@@ -100,7 +191,14 @@ fn decode_integer(buf: &[u8]) -> Result<Option<(RespValue, usize)>, RespMsgError
     }
 }
 
-fn decode_bulk_string(buf: &[u8]) -> Result<Option<(RespValue, usize)>, RespMsgError> {
+/// The bytes of a bulk string, a verbatim string or a blob error,
+/// `None` when the declared length is negative.
+type Blob<'a> = Option<&'a [u8]>;
+
+fn decode_blob<'a>(
+    buf: &'a [u8],
+    limits: &RespLimits,
+) -> Result<Option<(Blob<'a>, usize)>, RespMsgError> {
     match decode_until_crlf(buf) {
         Some(bytes_string) => {
             let string = str::from_utf8(bytes_string)?;
@@ -109,52 +207,247 @@ fn decode_bulk_string(buf: &[u8]) -> Result<Option<(RespValue, usize)>, RespMsgE
             let advance = bytes_string.len() + CRLF_NEWLINE.len();
             let buf = &buf[advance..];
 
-            match length {
-                len if len < 0 => Ok(Some((RespValue::Nil, advance))),
-                _ => {
-                    // FIXME handle overflows !!!
-                    if buf.len() as i64 >= length + CRLF_NEWLINE.len() as i64 {
-                        let bytes = match decode_until_crlf(buf) {
-                            Some(bytes_string) => bytes_string.to_vec(),
-                            None => return Err(RespMsgError::MissingBulkStringFinalCrlf),
-                        };
-
-                        let advance = advance + bytes.len() + CRLF_NEWLINE.len();
-                        Ok(Some((RespValue::BulkString(bytes), advance)))
-                    } else {
-                        Ok(None)
-                    }
-                }
+            if length < 0 {
+                return Ok(Some((None, advance)));
+            }
+
+            if length as u64 > limits.max_bulk_length as u64 {
+                return Err(RespMsgError::BulkStringTooLong(length));
+            }
+
+            // the bytes can contain crlfs, we must only rely on the declared length
+            let bytes_length = length as usize;
+            let frame_length = match bytes_length.checked_add(CRLF_NEWLINE.len()) {
+                Some(frame_length) => frame_length,
+                None => return Err(RespMsgError::BulkStringTooLong(length)),
+            };
+
+            if buf.len() < frame_length {
+                return Ok(None);
+            }
+
+            let (bytes, final_crlf) = buf[..frame_length].split_at(bytes_length);
+            if final_crlf != CRLF_NEWLINE {
+                return Err(RespMsgError::MissingBulkStringFinalCrlf);
+            }
+
+            let advance = advance + frame_length;
+            Ok(Some((Some(bytes), advance)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// The bytes of a bulk string are not copied, only their length is returned,
+/// they are sliced out of the frame once it is complete.
+fn decode_bulk_string(
+    buf: &[u8],
+    limits: &RespLimits,
+) -> Result<Option<(Decoded, usize)>, RespMsgError> {
+    match decode_blob(buf, limits)? {
+        Some((Some(bytes), advance)) => Ok(Some((Decoded::BulkString(bytes.len()), advance))),
+        Some((None, advance)) => Ok(Some((Decoded::Value(RespValue::Nil), advance))),
+        None => Ok(None),
+    }
+}
+
+fn decode_blob_error(
+    buf: &[u8],
+    limits: &RespLimits,
+) -> Result<Option<(RespValue, usize)>, RespMsgError> {
+    match decode_blob(buf, limits)? {
+        Some((Some(bytes), advance)) => {
+            let string = str::from_utf8(bytes)?;
+            Ok(Some((RespValue::Error(string.to_owned()), advance)))
+        }
+        Some((None, advance)) => Ok(Some((RespValue::Nil, advance))),
+        None => Ok(None),
+    }
+}
+
+fn decode_verbatim(
+    buf: &[u8],
+    limits: &RespLimits,
+) -> Result<Option<(RespValue, usize)>, RespMsgError> {
+    match decode_blob(buf, limits)? {
+        Some((Some(bytes), advance)) => {
+            // the text is prefixed by its three bytes format and a colon (e.g. "txt:")
+            if bytes.len() < 4 || bytes[3] != b':' {
+                return Err(RespMsgError::InvalidVerbatimString);
+            }
+
+            let format = str::from_utf8(&bytes[..3])?.to_owned();
+            let text = str::from_utf8(&bytes[4..])?.to_owned();
+            Ok(Some((RespValue::Verbatim { format, text }, advance)))
+        }
+        Some((None, advance)) => Ok(Some((RespValue::Nil, advance))),
+        None => Ok(None),
+    }
+}
+
+fn decode_null(buf: &[u8]) -> Result<Option<(RespValue, usize)>, RespMsgError> {
+    match decode_until_crlf(buf) {
+        Some(bytes_string) => {
+            let advance = bytes_string.len() + CRLF_NEWLINE.len();
+            Ok(Some((RespValue::Nil, advance)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn decode_boolean(buf: &[u8]) -> Result<Option<(RespValue, usize)>, RespMsgError> {
+    match decode_until_crlf(buf) {
+        Some(bytes_string) => {
+            let boolean = match bytes_string {
+                b"t" => true,
+                b"f" => false,
+                _ => return Err(RespMsgError::InvalidBoolean),
+            };
+            let advance = bytes_string.len() + CRLF_NEWLINE.len();
+            Ok(Some((RespValue::Boolean(boolean), advance)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn decode_double(buf: &[u8]) -> Result<Option<(RespValue, usize)>, RespMsgError> {
+    match decode_until_crlf(buf) {
+        Some(bytes_string) => {
+            let double = match str::from_utf8(bytes_string)? {
+                "inf" => std::f64::INFINITY,
+                "-inf" => std::f64::NEG_INFINITY,
+                "nan" => std::f64::NAN,
+                string => string.parse()?,
+            };
+            let advance = bytes_string.len() + CRLF_NEWLINE.len();
+            Ok(Some((RespValue::Double(Double(double)), advance)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn is_big_number(string: &str) -> bool {
+    let digits = if string.starts_with('-') {
+        &string[1..]
+    } else {
+        string
+    };
+    !digits.is_empty() && digits.bytes().all(|b| b.is_ascii_digit())
+}
+
+fn decode_big_number(buf: &[u8]) -> Result<Option<(RespValue, usize)>, RespMsgError> {
+    match decode_until_crlf(buf) {
+        Some(bytes_string) => {
+            let string = str::from_utf8(bytes_string)?;
+            if !is_big_number(string) {
+                return Err(RespMsgError::InvalidBigNumber);
             }
+            let advance = bytes_string.len() + CRLF_NEWLINE.len();
+            Ok(Some((RespValue::BigNumber(string.to_owned()), advance)))
         }
         None => Ok(None),
     }
 }
 
-fn decode_array(buf: &[u8]) -> Result<Option<(RespValue, usize)>, RespMsgError> {
+fn format_double(double: f64) -> String {
+    if double.is_nan() {
+        "nan".to_owned()
+    } else if double.is_infinite() && double > 0.0 {
+        "inf".to_owned()
+    } else if double.is_infinite() {
+        "-inf".to_owned()
+    } else {
+        double.to_string()
+    }
+}
+
+/// The RESP types containing other values, they are all decoded like arrays.
+#[derive(Debug, Copy, Clone)]
+enum Aggregate {
+    Array,
+    Set,
+    Push,
+    Map,
+}
+
+impl Aggregate {
+    fn value(self, elements: Vec<RespValue>) -> RespValue {
+        match self {
+            Aggregate::Array => RespValue::Array(elements),
+            Aggregate::Set => RespValue::Set(elements),
+            Aggregate::Push => RespValue::Push(elements),
+            Aggregate::Map => {
+                let mut pairs = Vec::with_capacity(elements.len() / 2);
+                let mut elements = elements.into_iter();
+                while let (Some(key), Some(value)) = (elements.next(), elements.next()) {
+                    pairs.push((key, value));
+                }
+                RespValue::Map(pairs)
+            }
+        }
+    }
+}
+
+/// Converts the RESP3 types into the RESP2 types that represent them best,
+/// the elements of the aggregates are converted when they are encoded.
+fn into_resp2(msg: RespValue) -> RespValue {
+    match msg {
+        RespValue::Map(pairs) => {
+            let mut elements = Vec::with_capacity(pairs.len() * 2);
+            for (key, value) in pairs {
+                elements.push(key);
+                elements.push(value);
+            }
+            RespValue::Array(elements)
+        }
+        RespValue::Set(elements) | RespValue::Push(elements) => RespValue::Array(elements),
+        RespValue::Boolean(boolean) => RespValue::Integer(boolean as i64),
+        RespValue::Double(Double(double)) => RespValue::bulk_string(format_double(double)),
+        RespValue::BigNumber(number) => RespValue::bulk_string(number),
+        RespValue::Verbatim { text, .. } => RespValue::bulk_string(text),
+        otherwise => otherwise,
+    }
+}
+
+/// A part of a message, aggregates are decoded one element at a time.
+enum Decoded {
+    Value(RespValue),
+    /// The length of a bulk string, its bytes end the part before the final crlf.
+    BulkString(usize),
+    AggregateStart(Aggregate, usize),
+}
+
+fn decode_aggregate_length(
+    buf: &[u8],
+    limits: &RespLimits,
+    depth: usize,
+    aggregate: Aggregate,
+) -> Result<Option<(Decoded, usize)>, RespMsgError> {
     match decode_until_crlf(buf) {
         Some(bytes_string) => {
             let string = str::from_utf8(bytes_string)?;
             let length = i64::from_str_radix(string, 10)?;
 
-            let mut advance = bytes_string.len() + CRLF_NEWLINE.len();
+            let advance = bytes_string.len() + CRLF_NEWLINE.len();
 
             match length {
-                len if len < 0 => Ok(Some((RespValue::Nil, advance))),
-                _ => {
-                    let mut array = Vec::with_capacity(length as usize);
-                    for _ in 0..length {
-                        match decode_message(&buf[advance..]) {
-                            Ok(Some((msg, adv))) => {
-                                array.push(msg);
-                                advance += adv;
-                            }
-                            Ok(None) => return Ok(None),
-                            Err(e) => return Err(e),
+                len if len < 0 => Ok(Some((Decoded::Value(RespValue::Nil), advance))),
+                len if len as u64 > limits.max_array_length as u64 => {
+                    Err(RespMsgError::ArrayTooLong(len))
+                }
+                _ if depth >= limits.max_depth => Err(RespMsgError::TooDeeplyNested(depth + 1)),
+                len => {
+                    // a map declares its number of pairs, not its number of elements
+                    let length = match aggregate {
+                        Aggregate::Map => (len as usize).checked_mul(2),
+                        _ => Some(len as usize),
+                    };
+                    match length {
+                        Some(length) => {
+                            Ok(Some((Decoded::AggregateStart(aggregate, length), advance)))
                         }
+                        None => Err(RespMsgError::ArrayTooLong(len)),
                     }
-
-                    Ok(Some((RespValue::Array(array), advance)))
                 }
             }
         }
@@ -162,43 +455,215 @@ fn decode_array(buf: &[u8]) -> Result<Option<(RespValue, usize)>, RespMsgError>
     }
 }
 
-fn decode_message(buf: &[u8]) -> Result<Option<(RespValue, usize)>, RespMsgError> {
+fn decoded_value(
+    result: Result<Option<(RespValue, usize)>, RespMsgError>,
+) -> Result<Option<(Decoded, usize)>, RespMsgError> {
+    result.map(|decoded| decoded.map(|(msg, advance)| (Decoded::Value(msg), advance)))
+}
+
+fn decode_part(
+    buf: &[u8],
+    limits: &RespLimits,
+    depth: usize,
+) -> Result<Option<(Decoded, usize)>, RespMsgError> {
     if buf.is_empty() {
         return Ok(None);
     }
 
     let result = match buf[0] {
-        SIMPLE_STRING_CHAR => decode_simple_string(&buf[1..]),
-        ERROR_CHAR => decode_error(&buf[1..]),
-        INTEGER_CHAR => decode_integer(&buf[1..]),
-        BULK_STRING_CHAR => decode_bulk_string(&buf[1..]),
-        ARRAY_CHAR => decode_array(&buf[1..]),
+        SIMPLE_STRING_CHAR => decoded_value(decode_simple_string(&buf[1..])),
+        ERROR_CHAR => decoded_value(decode_error(&buf[1..])),
+        INTEGER_CHAR => decoded_value(decode_integer(&buf[1..])),
+        BULK_STRING_CHAR => decode_bulk_string(&buf[1..], limits),
+        NULL_CHAR => decoded_value(decode_null(&buf[1..])),
+        BOOLEAN_CHAR => decoded_value(decode_boolean(&buf[1..])),
+        DOUBLE_CHAR => decoded_value(decode_double(&buf[1..])),
+        BIG_NUMBER_CHAR => decoded_value(decode_big_number(&buf[1..])),
+        BLOB_ERROR_CHAR => decoded_value(decode_blob_error(&buf[1..], limits)),
+        VERBATIM_CHAR => decoded_value(decode_verbatim(&buf[1..], limits)),
+        ARRAY_CHAR => decode_aggregate_length(&buf[1..], limits, depth, Aggregate::Array),
+        SET_CHAR => decode_aggregate_length(&buf[1..], limits, depth, Aggregate::Set),
+        PUSH_CHAR => decode_aggregate_length(&buf[1..], limits, depth, Aggregate::Push),
+        MAP_CHAR => decode_aggregate_length(&buf[1..], limits, depth, Aggregate::Map),
         invalid_byte => Err(RespMsgError::InvalidPrefixByte(invalid_byte)),
     };
 
     match result {
-        Ok(Some((msg, advance))) => Ok(Some((msg, advance + 1))),
+        Ok(Some((part, advance))) => Ok(Some((part, advance + 1))),
         Ok(None) => Ok(None),
         Err(e) => Err(e),
     }
 }
 
-#[derive(Debug, Default)]
-pub struct RespCodec;
+/// A decoded element of a frame, the bytes of the bulk strings are
+/// sliced out of the frame once it is complete instead of being copied.
+#[derive(Debug, Clone)]
+enum Element {
+    Value(RespValue),
+    /// The range of the bytes of a bulk string in the frame.
+    BulkString(Range<usize>),
+    Aggregate(Aggregate, Vec<Element>),
+}
+
+impl Element {
+    fn into_value(self, frame: &Bytes) -> RespValue {
+        match self {
+            Element::Value(msg) => msg,
+            Element::BulkString(range) => {
+                RespValue::BulkString(frame.slice(range.start, range.end))
+            }
+            Element::Aggregate(aggregate, elements) => {
+                let elements = elements.into_iter().map(|e| e.into_value(frame));
+                aggregate.value(elements.collect())
+            }
+        }
+    }
+}
+
+/// An array, or another aggregate, whose elements have not all been received yet.
+#[derive(Debug, Clone)]
+struct PartialArray {
+    aggregate: Aggregate,
+    length: usize,
+    elements: Vec<Element>,
+}
+
+/// Where the decoding of a frame stopped, waiting for more bytes.
+///
+/// The bytes of a frame stay in the buffer until it is complete
+/// but the parts that were already decoded are not parsed again.
+#[derive(Debug, Default, Clone)]
+struct DecodeState {
+    /// The number of bytes of the frame already decoded.
+    offset: usize,
+    /// The arrays being decoded, the innermost one is the last.
+    arrays: Vec<PartialArray>,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct RespCodec {
+    limits: RespLimits,
+    protocol: Protocol,
+    state: DecodeState,
+}
+
+impl RespCodec {
+    /// Creates a codec that refuses to decode messages exceeding the limits.
+    pub fn new(limits: RespLimits) -> RespCodec {
+        RespCodec {
+            limits,
+            protocol: Protocol::default(),
+            state: DecodeState::default(),
+        }
+    }
+
+    /// The protocol used to encode the messages, RESP2 until it is changed.
+    pub fn protocol(&self) -> Protocol {
+        self.protocol
+    }
+
+    pub fn set_protocol(&mut self, protocol: Protocol) {
+        self.protocol = protocol;
+    }
+
+    fn decode_frame(&mut self, buf: &mut BytesMut) -> Result<Option<RespValue>, RespMsgError> {
+        let state = &mut self.state;
+
+        loop {
+            let depth = state.arrays.len();
+            let mut element = match decode_part(&buf[state.offset..], &self.limits, depth)? {
+                Some((Decoded::Value(msg), advance)) => {
+                    state.offset += advance;
+                    Element::Value(msg)
+                }
+                Some((Decoded::BulkString(length), advance)) => {
+                    let end = state.offset + advance - CRLF_NEWLINE.len();
+                    state.offset += advance;
+                    Element::BulkString(end - length..end)
+                }
+                Some((Decoded::AggregateStart(aggregate, length), advance)) => {
+                    state.offset += advance;
+                    if length == 0 {
+                        Element::Aggregate(aggregate, Vec::new())
+                    } else {
+                        // an element takes at least three bytes, we do not trust the
+                        // declared length to allocate more than what has been received
+                        let capacity = cmp::min(length, (buf.len() - state.offset) / 3);
+                        let elements = Vec::with_capacity(capacity);
+                        state.arrays.push(PartialArray {
+                            aggregate,
+                            length,
+                            elements,
+                        });
+                        continue;
+                    }
+                }
+                None => return Ok(None),
+            };
+
+            // the element can be the last element of
+            // one or many arrays which are now complete
+            loop {
+                match state.arrays.last_mut() {
+                    Some(array) => {
+                        array.elements.push(element);
+                        if array.elements.len() < array.length {
+                            break;
+                        }
+
+                        let array = state.arrays.pop().unwrap();
+                        element = Element::Aggregate(array.aggregate, array.elements);
+                    }
+                    None => {
+                        // the bulk strings share the memory of the frame
+                        let frame = buf.split_to(state.offset).freeze();
+                        state.offset = 0;
+                        return Ok(Some(element.into_value(&frame)));
+                    }
+                }
+            }
+        }
+    }
+
+    fn encode_elements(
+        &mut self,
+        prefix: u8,
+        elements: Vec<RespValue>,
+        buf: &mut BytesMut,
+    ) -> Result<(), RespMsgError> {
+        let length = elements.len();
+        let integer_string = length.to_string();
+        buf.reserve(1 + integer_string.len() + CRLF_NEWLINE.len());
+
+        buf.put_u8(prefix);
+        buf.put(integer_string);
+        buf.put(&CRLF_NEWLINE[..]);
+
+        for msg in elements {
+            self.encode(msg, buf)?;
+        }
+
+        Ok(())
+    }
+}
 
 impl Decoder for RespCodec {
     type Item = RespValue;
     type Error = RespMsgError;
 
     fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        match decode_message(buf) {
-            Ok(Some((msg, advance))) => {
-                buf.split_to(advance);
-                Ok(Some(msg))
+        let result = match self.decode_frame(buf) {
+            Ok(None) if buf.len() > self.limits.max_buffered_bytes => {
+                Err(RespMsgError::FrameTooLarge(buf.len()))
             }
-            Ok(None) => Ok(None),
-            Err(e) => Err(e),
+            otherwise => otherwise,
+        };
+
+        if result.is_err() {
+            self.state = DecodeState::default();
         }
+
+        result
     }
 }
 
@@ -207,6 +672,11 @@ impl Encoder for RespCodec {
     type Error = RespMsgError;
 
     fn encode(&mut self, msg: Self::Item, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        let msg = match self.protocol {
+            Protocol::Resp2 => into_resp2(msg),
+            Protocol::Resp3 => msg,
+        };
+
         match msg {
             RespValue::SimpleString(string) => {
                 if string.as_bytes().find(CRLF_NEWLINE).is_some() {
@@ -257,19 +727,13 @@ impl Encoder for RespCodec {
 
                 Ok(())
             }
-            RespValue::Array(array) => {
-                let length = array.len();
-                let integer_string = length.to_string();
-                buf.reserve(1 + integer_string.len() + CRLF_NEWLINE.len());
+            RespValue::Array(array) => self.encode_elements(ARRAY_CHAR, array, buf),
+            RespValue::Nil if self.protocol == Protocol::Resp3 => {
+                buf.reserve(1 + CRLF_NEWLINE.len());
 
-                buf.put_u8(ARRAY_CHAR);
-                buf.put(integer_string);
+                buf.put_u8(NULL_CHAR);
                 buf.put(&CRLF_NEWLINE[..]);
 
-                for msg in array {
-                    self.encode(msg, buf)?;
-                }
-
                 Ok(())
             }
             RespValue::Nil => {
@@ -283,6 +747,75 @@ impl Encoder for RespCodec {
 
                 Ok(())
             }
+            RespValue::Boolean(boolean) => {
+                buf.reserve(2 + CRLF_NEWLINE.len());
+
+                buf.put_u8(BOOLEAN_CHAR);
+                buf.put_u8(if boolean { b't' } else { b'f' });
+                buf.put(&CRLF_NEWLINE[..]);
+
+                Ok(())
+            }
+            RespValue::Double(Double(double)) => {
+                let double_string = format_double(double);
+                buf.reserve(1 + double_string.len() + CRLF_NEWLINE.len());
+
+                buf.put_u8(DOUBLE_CHAR);
+                buf.put(double_string);
+                buf.put(&CRLF_NEWLINE[..]);
+
+                Ok(())
+            }
+            RespValue::BigNumber(number) => {
+                if !is_big_number(&number) {
+                    return Err(RespMsgError::InvalidBigNumber);
+                }
+
+                buf.reserve(1 + number.len() + CRLF_NEWLINE.len());
+
+                buf.put_u8(BIG_NUMBER_CHAR);
+                buf.put(number);
+                buf.put(&CRLF_NEWLINE[..]);
+
+                Ok(())
+            }
+            RespValue::Verbatim { format, text } => {
+                if format.len() != 3 {
+                    return Err(RespMsgError::InvalidVerbatimString);
+                }
+
+                let length = format.len() + 1 + text.len();
+                let integer_string = length.to_string();
+                buf.reserve(1 + integer_string.len() + length + CRLF_NEWLINE.len() * 2);
+
+                buf.put_u8(VERBATIM_CHAR);
+                buf.put(integer_string);
+                buf.put(&CRLF_NEWLINE[..]);
+                buf.put(format);
+                buf.put_u8(b':');
+                buf.put(text);
+                buf.put(&CRLF_NEWLINE[..]);
+
+                Ok(())
+            }
+            RespValue::Map(pairs) => {
+                let length = pairs.len();
+                let integer_string = length.to_string();
+                buf.reserve(1 + integer_string.len() + CRLF_NEWLINE.len());
+
+                buf.put_u8(MAP_CHAR);
+                buf.put(integer_string);
+                buf.put(&CRLF_NEWLINE[..]);
+
+                for (key, value) in pairs {
+                    self.encode(key, buf)?;
+                    self.encode(value, buf)?;
+                }
+
+                Ok(())
+            }
+            RespValue::Set(elements) => self.encode_elements(SET_CHAR, elements, buf),
+            RespValue::Push(elements) => self.encode_elements(PUSH_CHAR, elements, buf),
         }
     }
 }
@@ -296,8 +829,10 @@ mod tests {
         let mut buf = BytesMut::new();
 
         let inmsg = RespValue::SimpleString("kiki".to_owned());
-        RespCodec.encode(inmsg.clone(), &mut buf).unwrap();
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg), outmsg);
         assert!(buf.is_empty());
@@ -308,8 +843,10 @@ mod tests {
         let mut buf = BytesMut::new();
 
         let inmsg = RespValue::Error("whoops, it is and error".to_owned());
-        RespCodec.encode(inmsg.clone(), &mut buf).unwrap();
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg), outmsg);
         assert!(buf.is_empty());
@@ -320,8 +857,10 @@ mod tests {
         let mut buf = BytesMut::new();
 
         let inmsg = RespValue::Integer(12);
-        RespCodec.encode(inmsg.clone(), &mut buf).unwrap();
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg), outmsg);
         assert!(buf.is_empty());
@@ -329,8 +868,10 @@ mod tests {
         let mut buf = BytesMut::new();
 
         let inmsg = RespValue::Integer(-10);
-        RespCodec.encode(inmsg.clone(), &mut buf).unwrap();
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg), outmsg);
         assert!(buf.is_empty());
@@ -340,18 +881,22 @@ mod tests {
     fn one_bulk_string() {
         let mut buf = BytesMut::new();
 
-        let inmsg = RespValue::BulkString(vec![]);
-        RespCodec.encode(inmsg.clone(), &mut buf).unwrap();
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        let inmsg = RespValue::bulk_string(vec![]);
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg), outmsg);
         assert!(buf.is_empty());
 
         let mut buf = BytesMut::new();
 
-        let inmsg = RespValue::BulkString(vec![1, 2, 3, 4, 5, 35, 70]);
-        RespCodec.encode(inmsg.clone(), &mut buf).unwrap();
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        let inmsg = RespValue::bulk_string(vec![1, 2, 3, 4, 5, 35, 70]);
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg), outmsg);
         assert!(buf.is_empty());
@@ -362,15 +907,19 @@ mod tests {
         let mut buf = BytesMut::new();
 
         let inmsg = RespValue::Array(vec![]);
-        RespCodec.encode(inmsg.clone(), &mut buf).unwrap();
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg), outmsg);
         assert!(buf.is_empty());
 
-        let inmsg = RespValue::Array(vec![RespValue::BulkString(b"hello".to_vec())]);
-        RespCodec.encode(inmsg.clone(), &mut buf).unwrap();
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        let inmsg = RespValue::Array(vec![RespValue::bulk_string(b"hello".to_vec())]);
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg), outmsg);
         assert!(buf.is_empty());
@@ -379,11 +928,13 @@ mod tests {
             RespValue::SimpleString("hello".to_owned()),
             RespValue::Error("what the f*ck!".to_owned()),
             RespValue::Integer(25),
-            RespValue::BulkString(b"hello".to_vec()),
+            RespValue::bulk_string(b"hello".to_vec()),
             RespValue::Array(vec![RespValue::Integer(45)]),
         ]);
-        RespCodec.encode(inmsg.clone(), &mut buf).unwrap();
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg), outmsg);
         assert!(buf.is_empty());
@@ -394,8 +945,10 @@ mod tests {
         let mut buf = BytesMut::new();
 
         let inmsg = RespValue::Nil;
-        RespCodec.encode(inmsg.clone(), &mut buf).unwrap();
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg), outmsg);
         assert!(buf.is_empty());
@@ -409,13 +962,19 @@ mod tests {
         let inmsg2 = RespValue::SimpleString("kiki".to_owned());
         let inmsg3 = RespValue::SimpleString("kiki".to_owned());
 
-        RespCodec.encode(inmsg1.clone(), &mut buf).unwrap();
-        RespCodec.encode(inmsg2.clone(), &mut buf).unwrap();
-        RespCodec.encode(inmsg3.clone(), &mut buf).unwrap();
+        RespCodec::default()
+            .encode(inmsg1.clone(), &mut buf)
+            .unwrap();
+        RespCodec::default()
+            .encode(inmsg2.clone(), &mut buf)
+            .unwrap();
+        RespCodec::default()
+            .encode(inmsg3.clone(), &mut buf)
+            .unwrap();
 
-        let outmsg1 = RespCodec.decode(&mut buf).unwrap();
-        let outmsg2 = RespCodec.decode(&mut buf).unwrap();
-        let outmsg3 = RespCodec.decode(&mut buf).unwrap();
+        let outmsg1 = RespCodec::default().decode(&mut buf).unwrap();
+        let outmsg2 = RespCodec::default().decode(&mut buf).unwrap();
+        let outmsg3 = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg1), outmsg1);
         assert_eq!(Some(inmsg2), outmsg2);
@@ -431,13 +990,19 @@ mod tests {
         let inmsg2 = RespValue::Error("another error".to_owned());
         let inmsg3 = RespValue::Error("again and again, another one".to_owned());
 
-        RespCodec.encode(inmsg1.clone(), &mut buf).unwrap();
-        RespCodec.encode(inmsg2.clone(), &mut buf).unwrap();
-        RespCodec.encode(inmsg3.clone(), &mut buf).unwrap();
+        RespCodec::default()
+            .encode(inmsg1.clone(), &mut buf)
+            .unwrap();
+        RespCodec::default()
+            .encode(inmsg2.clone(), &mut buf)
+            .unwrap();
+        RespCodec::default()
+            .encode(inmsg3.clone(), &mut buf)
+            .unwrap();
 
-        let outmsg1 = RespCodec.decode(&mut buf).unwrap();
-        let outmsg2 = RespCodec.decode(&mut buf).unwrap();
-        let outmsg3 = RespCodec.decode(&mut buf).unwrap();
+        let outmsg1 = RespCodec::default().decode(&mut buf).unwrap();
+        let outmsg2 = RespCodec::default().decode(&mut buf).unwrap();
+        let outmsg3 = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg1), outmsg1);
         assert_eq!(Some(inmsg2), outmsg2);
@@ -453,13 +1018,19 @@ mod tests {
         let inmsg2 = RespValue::Integer(-50);
         let inmsg3 = RespValue::Integer(2535);
 
-        RespCodec.encode(inmsg1.clone(), &mut buf).unwrap();
-        RespCodec.encode(inmsg2.clone(), &mut buf).unwrap();
-        RespCodec.encode(inmsg3.clone(), &mut buf).unwrap();
+        RespCodec::default()
+            .encode(inmsg1.clone(), &mut buf)
+            .unwrap();
+        RespCodec::default()
+            .encode(inmsg2.clone(), &mut buf)
+            .unwrap();
+        RespCodec::default()
+            .encode(inmsg3.clone(), &mut buf)
+            .unwrap();
 
-        let outmsg1 = RespCodec.decode(&mut buf).unwrap();
-        let outmsg2 = RespCodec.decode(&mut buf).unwrap();
-        let outmsg3 = RespCodec.decode(&mut buf).unwrap();
+        let outmsg1 = RespCodec::default().decode(&mut buf).unwrap();
+        let outmsg2 = RespCodec::default().decode(&mut buf).unwrap();
+        let outmsg3 = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg1), outmsg1);
         assert_eq!(Some(inmsg2), outmsg2);
@@ -471,17 +1042,23 @@ mod tests {
     fn multiple_bulk_string() {
         let mut buf = BytesMut::new();
 
-        let inmsg1 = RespValue::BulkString(vec![8, 7, 6, 5, 4]);
-        let inmsg2 = RespValue::BulkString(vec![1, 2, 3, 4, 5, 35, 70]);
-        let inmsg3 = RespValue::BulkString(vec![]);
+        let inmsg1 = RespValue::bulk_string(vec![8, 7, 6, 5, 4]);
+        let inmsg2 = RespValue::bulk_string(vec![1, 2, 3, 4, 5, 35, 70]);
+        let inmsg3 = RespValue::bulk_string(vec![]);
 
-        RespCodec.encode(inmsg1.clone(), &mut buf).unwrap();
-        RespCodec.encode(inmsg2.clone(), &mut buf).unwrap();
-        RespCodec.encode(inmsg3.clone(), &mut buf).unwrap();
+        RespCodec::default()
+            .encode(inmsg1.clone(), &mut buf)
+            .unwrap();
+        RespCodec::default()
+            .encode(inmsg2.clone(), &mut buf)
+            .unwrap();
+        RespCodec::default()
+            .encode(inmsg3.clone(), &mut buf)
+            .unwrap();
 
-        let outmsg1 = RespCodec.decode(&mut buf).unwrap();
-        let outmsg2 = RespCodec.decode(&mut buf).unwrap();
-        let outmsg3 = RespCodec.decode(&mut buf).unwrap();
+        let outmsg1 = RespCodec::default().decode(&mut buf).unwrap();
+        let outmsg2 = RespCodec::default().decode(&mut buf).unwrap();
+        let outmsg3 = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg1), outmsg1);
         assert_eq!(Some(inmsg2), outmsg2);
@@ -496,29 +1073,41 @@ mod tests {
         let inmsg1 = RespValue::SimpleString("kiki".to_owned());
         let inmsg2 = RespValue::Error("whoops, it is and error".to_owned());
         let inmsg3 = RespValue::Integer(12);
-        let inmsg4 = RespValue::BulkString(vec![8, 7, 6, 5, 4]);
-        let inmsg5 = RespValue::BulkString(vec![1, 2, 3, 4, 5, 35, 70]);
+        let inmsg4 = RespValue::bulk_string(vec![8, 7, 6, 5, 4]);
+        let inmsg5 = RespValue::bulk_string(vec![1, 2, 3, 4, 5, 35, 70]);
         let inmsg6 = RespValue::Array(vec![
             RespValue::SimpleString("hello".to_owned()),
             RespValue::Error("what the f*ck!".to_owned()),
             RespValue::Integer(25),
-            RespValue::BulkString(b"hello".to_vec()),
+            RespValue::bulk_string(b"hello".to_vec()),
             RespValue::Array(vec![RespValue::Integer(45)]),
         ]);
 
-        RespCodec.encode(inmsg1.clone(), &mut buf).unwrap();
-        RespCodec.encode(inmsg2.clone(), &mut buf).unwrap();
-        RespCodec.encode(inmsg3.clone(), &mut buf).unwrap();
-        RespCodec.encode(inmsg4.clone(), &mut buf).unwrap();
-        RespCodec.encode(inmsg5.clone(), &mut buf).unwrap();
-        RespCodec.encode(inmsg6.clone(), &mut buf).unwrap();
-
-        let outmsg1 = RespCodec.decode(&mut buf).unwrap();
-        let outmsg2 = RespCodec.decode(&mut buf).unwrap();
-        let outmsg3 = RespCodec.decode(&mut buf).unwrap();
-        let outmsg4 = RespCodec.decode(&mut buf).unwrap();
-        let outmsg5 = RespCodec.decode(&mut buf).unwrap();
-        let outmsg6 = RespCodec.decode(&mut buf).unwrap();
+        RespCodec::default()
+            .encode(inmsg1.clone(), &mut buf)
+            .unwrap();
+        RespCodec::default()
+            .encode(inmsg2.clone(), &mut buf)
+            .unwrap();
+        RespCodec::default()
+            .encode(inmsg3.clone(), &mut buf)
+            .unwrap();
+        RespCodec::default()
+            .encode(inmsg4.clone(), &mut buf)
+            .unwrap();
+        RespCodec::default()
+            .encode(inmsg5.clone(), &mut buf)
+            .unwrap();
+        RespCodec::default()
+            .encode(inmsg6.clone(), &mut buf)
+            .unwrap();
+
+        let outmsg1 = RespCodec::default().decode(&mut buf).unwrap();
+        let outmsg2 = RespCodec::default().decode(&mut buf).unwrap();
+        let outmsg3 = RespCodec::default().decode(&mut buf).unwrap();
+        let outmsg4 = RespCodec::default().decode(&mut buf).unwrap();
+        let outmsg5 = RespCodec::default().decode(&mut buf).unwrap();
+        let outmsg6 = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg1), outmsg1);
         assert_eq!(Some(inmsg2), outmsg2);
@@ -535,15 +1124,17 @@ mod tests {
 
         let inmsg = RespValue::SimpleString("kiki".to_owned());
 
-        RespCodec.encode(inmsg.clone(), &mut buf).unwrap();
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
 
         let buf2 = buf.split_off(2);
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(None, outmsg);
 
         buf.unsplit(buf2);
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg), outmsg);
         assert!(buf.is_empty());
@@ -553,17 +1144,19 @@ mod tests {
     fn partial_bulk_string() {
         let mut buf = BytesMut::new();
 
-        let inmsg = RespValue::BulkString(vec![1, 2, 3, 4, 5, 35, 70]);
+        let inmsg = RespValue::bulk_string(vec![1, 2, 3, 4, 5, 35, 70]);
 
-        RespCodec.encode(inmsg.clone(), &mut buf).unwrap();
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
 
         let buf2 = buf.split_off(5);
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(None, outmsg);
 
         buf.unsplit(buf2);
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg), outmsg);
         assert!(buf.is_empty());
@@ -577,27 +1170,354 @@ mod tests {
             RespValue::SimpleString("hello".to_owned()),
             RespValue::Error("what the f*ck!".to_owned()),
             RespValue::Integer(25),
-            RespValue::BulkString(b"hello".to_vec()),
+            RespValue::bulk_string(b"hello".to_vec()),
             RespValue::Array(vec![RespValue::Integer(45)]),
         ]);
 
-        RespCodec.encode(inmsg.clone(), &mut buf).unwrap();
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
 
         let buf2 = buf.split_off(15);
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(None, outmsg);
 
         buf.unsplit(buf2);
         let buf2 = buf.split_off(32);
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
+
+        assert_eq!(None, outmsg);
+
+        buf.unsplit(buf2);
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
+
+        assert_eq!(Some(inmsg), outmsg);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn bulk_string_with_crlf() {
+        let mut buf = BytesMut::new();
+
+        let inmsg = RespValue::bulk_string(b"\r\nhello\r\nworld\r\n".to_vec());
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
+
+        // the final crlf is not yet received
+        let buf2 = buf.split_off(buf.len() - 2);
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(None, outmsg);
 
         buf.unsplit(buf2);
-        let outmsg = RespCodec.decode(&mut buf).unwrap();
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
+
+        assert_eq!(Some(inmsg), outmsg);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn bulk_string_invalid_final_crlf() {
+        let mut buf = BytesMut::from(&b"$3\r\nhello\r\n"[..]);
+
+        match RespCodec::default().decode(&mut buf) {
+            Err(RespMsgError::MissingBulkStringFinalCrlf) => (),
+            otherwise => panic!("unexpected result {:?}", otherwise),
+        }
+    }
+
+    #[test]
+    fn bulk_string_too_long() {
+        let mut buf = BytesMut::new();
+
+        let inmsg = RespValue::bulk_string(b"hello".to_vec());
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
+
+        match RespCodec::new(RespLimits {
+            max_bulk_length: 4,
+            ..RespLimits::default()
+        })
+        .decode(&mut buf.clone())
+        {
+            Err(RespMsgError::BulkStringTooLong(5)) => (),
+            otherwise => panic!("unexpected result {:?}", otherwise),
+        }
+
+        let outmsg = RespCodec::new(RespLimits {
+            max_bulk_length: 5,
+            ..RespLimits::default()
+        })
+        .decode(&mut buf)
+        .unwrap();
+        assert_eq!(Some(inmsg), outmsg);
+
+        // huge declared lengths are refused before the bytes are received
+        let mut buf = BytesMut::from(&b"*1\r\n$9223372036854775807\r\n"[..]);
+        match RespCodec::default().decode(&mut buf) {
+            Err(RespMsgError::BulkStringTooLong(9_223_372_036_854_775_807)) => (),
+            otherwise => panic!("unexpected result {:?}", otherwise),
+        }
+
+        // the length must not overflow even without any limit
+        let mut buf = BytesMut::from(&b"$9223372036854775807\r\n"[..]);
+        match RespCodec::new(RespLimits {
+            max_bulk_length: usize::max_value(),
+            ..RespLimits::default()
+        })
+        .decode(&mut buf)
+        {
+            Err(RespMsgError::BulkStringTooLong(_)) | Ok(None) => (),
+            otherwise => panic!("unexpected result {:?}", otherwise),
+        }
+    }
+
+    #[test]
+    fn array_too_long() {
+        let mut buf = BytesMut::new();
+
+        let inmsg = RespValue::Array(vec![RespValue::Integer(1); 3]);
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
+
+        let limits = RespLimits {
+            max_array_length: 2,
+            ..RespLimits::default()
+        };
+        match RespCodec::new(limits).decode(&mut buf.clone()) {
+            Err(RespMsgError::ArrayTooLong(3)) => (),
+            otherwise => panic!("unexpected result {:?}", otherwise),
+        }
+
+        // the declared length is refused before the elements are received
+        let mut buf = BytesMut::from(&b"*9223372036854775807\r\n"[..]);
+        match RespCodec::default().decode(&mut buf) {
+            Err(RespMsgError::ArrayTooLong(9_223_372_036_854_775_807)) => (),
+            otherwise => panic!("unexpected result {:?}", otherwise),
+        }
+    }
+
+    #[test]
+    fn array_too_deeply_nested() {
+        let mut inmsg = RespValue::Integer(42);
+        for _ in 0..3 {
+            inmsg = RespValue::Array(vec![inmsg]);
+        }
+
+        let mut buf = BytesMut::new();
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
+
+        let limits = RespLimits {
+            max_depth: 2,
+            ..RespLimits::default()
+        };
+        match RespCodec::new(limits).decode(&mut buf.clone()) {
+            Err(RespMsgError::TooDeeplyNested(3)) => (),
+            otherwise => panic!("unexpected result {:?}", otherwise),
+        }
+
+        let limits = RespLimits {
+            max_depth: 3,
+            ..RespLimits::default()
+        };
+        let outmsg = RespCodec::new(limits).decode(&mut buf).unwrap();
+        assert_eq!(Some(inmsg), outmsg);
+
+        // a malicious client can not make the decoder overflow its stack
+        let frame: Vec<_> = b"*1\r\n".iter().cycle().take(400_000).cloned().collect();
+        let mut buf = BytesMut::from(frame);
+        match RespCodec::default().decode(&mut buf) {
+            Err(RespMsgError::TooDeeplyNested(_)) => (),
+            otherwise => panic!("unexpected result {:?}", otherwise),
+        }
+    }
+
+    #[test]
+    fn frame_too_large() {
+        let mut buf = BytesMut::new();
+
+        let inmsg = RespValue::bulk_string(vec![0; 100]);
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
+        let buf2 = buf.split_off(50);
+
+        let limits = RespLimits {
+            max_buffered_bytes: 40,
+            ..RespLimits::default()
+        };
+        match RespCodec::new(limits).decode(&mut buf.clone()) {
+            Err(RespMsgError::FrameTooLarge(50)) => (),
+            otherwise => panic!("unexpected result {:?}", otherwise),
+        }
+
+        // complete frames are decoded whatever their size
+        buf.unsplit(buf2);
+        let outmsg = RespCodec::new(limits).decode(&mut buf).unwrap();
+        assert_eq!(Some(inmsg), outmsg);
+    }
+
+    #[test]
+    fn bulk_strings_share_the_buffer() {
+        let mut buf = BytesMut::new();
+
+        let inmsg = RespValue::Array(vec![RespValue::bulk_string(vec![42; 1024])]);
+        RespCodec::default()
+            .encode(inmsg.clone(), &mut buf)
+            .unwrap();
+
+        let start = buf.as_ptr() as usize;
+        let received = start..start + buf.len();
+
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
+        match &outmsg {
+            Some(RespValue::Array(elements)) => match elements.as_slice() {
+                [RespValue::BulkString(bytes)] => {
+                    assert!(received.contains(&(bytes.as_ptr() as usize)))
+                }
+                otherwise => panic!("unexpected elements {:?}", otherwise),
+            },
+            otherwise => panic!("unexpected message {:?}", otherwise),
+        }
+        assert_eq!(outmsg, Some(inmsg));
+    }
+
+    #[test]
+    fn resume_partial_frames() {
+        let mut buf = BytesMut::new();
+
+        let inmsg1 = RespValue::Array(vec![
+            RespValue::SimpleString("hello".to_owned()),
+            RespValue::Array(vec![]),
+            RespValue::Array(vec![
+                RespValue::Integer(25),
+                RespValue::Array(vec![RespValue::bulk_string(b"\r\nhello".to_vec())]),
+            ]),
+            RespValue::Nil,
+        ]);
+        let inmsg2 = RespValue::bulk_string(vec![1, 2, 3, 4, 5, 35, 70]);
+
+        RespCodec::default()
+            .encode(inmsg1.clone(), &mut buf)
+            .unwrap();
+        RespCodec::default()
+            .encode(inmsg2.clone(), &mut buf)
+            .unwrap();
+
+        // the bytes are received one by one by the same codec
+        let mut codec = RespCodec::default();
+        let mut partial = BytesMut::new();
+        let mut outmsgs = Vec::new();
+
+        for byte in buf.iter() {
+            partial.extend_from_slice(&[*byte]);
+            if let Some(outmsg) = codec.decode(&mut partial).unwrap() {
+                outmsgs.push(outmsg);
+            }
+        }
+
+        assert_eq!(outmsgs, vec![inmsg1, inmsg2]);
+        assert!(partial.is_empty());
+    }
+
+    #[test]
+    fn resp3_values() {
+        let mut buf = BytesMut::new();
+
+        let inmsg = RespValue::Push(vec![
+            RespValue::Map(vec![
+                (RespValue::string("number"), RespValue::Integer(12)),
+                (RespValue::string("missing"), RespValue::Nil),
+            ]),
+            RespValue::Set(vec![RespValue::Boolean(true), RespValue::Boolean(false)]),
+            RespValue::Double(Double(1.5)),
+            RespValue::Double(Double(std::f64::NEG_INFINITY)),
+            RespValue::BigNumber("-3492890328409238509324850943850943825024385".to_owned()),
+            RespValue::Verbatim {
+                format: "txt".to_owned(),
+                text: "Some\r\nstring".to_owned(),
+            },
+            RespValue::Map(vec![]),
+        ]);
+
+        let mut codec = RespCodec::default();
+        codec.set_protocol(Protocol::Resp3);
+        codec.encode(inmsg.clone(), &mut buf).unwrap();
+
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
 
         assert_eq!(Some(inmsg), outmsg);
         assert!(buf.is_empty());
     }
+
+    #[test]
+    fn decode_resp3_frames() {
+        let mut buf =
+            BytesMut::from(&b"%1\r\n+key\r\n_\r\n,nan\r\n!5\r\noops!\r\n>2\r\n:1\r\n#f\r\n"[..]);
+        let mut codec = RespCodec::default();
+
+        let map = RespValue::Map(vec![(RespValue::string("key"), RespValue::Nil)]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(map));
+
+        match codec.decode(&mut buf).unwrap() {
+            Some(RespValue::Double(Double(double))) => assert!(double.is_nan()),
+            otherwise => panic!("unexpected result {:?}", otherwise),
+        }
+
+        // the doubles are compared by their bits, a NaN equals itself
+        let nan = RespValue::Double(Double(std::f64::NAN));
+        assert_eq!(nan.clone(), nan);
+
+        let error = RespValue::Error("oops!".to_owned());
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(error));
+
+        let push = RespValue::Push(vec![RespValue::Integer(1), RespValue::Boolean(false)]);
+        assert_eq!(codec.decode(&mut buf).unwrap(), Some(push));
+        assert!(buf.is_empty());
+
+        let mut buf = BytesMut::from(&b"(12a\r\n"[..]);
+        match codec.decode(&mut buf) {
+            Err(RespMsgError::InvalidBigNumber) => (),
+            otherwise => panic!("unexpected result {:?}", otherwise),
+        }
+
+        let mut buf = BytesMut::from(&b"=3\r\ntxt\r\n"[..]);
+        match codec.decode(&mut buf) {
+            Err(RespMsgError::InvalidVerbatimString) => (),
+            otherwise => panic!("unexpected result {:?}", otherwise),
+        }
+    }
+
+    #[test]
+    fn encode_resp3_values_in_resp2() {
+        let mut buf = BytesMut::new();
+
+        let inmsg = RespValue::Push(vec![
+            RespValue::Map(vec![(RespValue::string("number"), RespValue::Nil)]),
+            RespValue::Boolean(true),
+            RespValue::Double(Double(std::f64::INFINITY)),
+            RespValue::Verbatim {
+                format: "txt".to_owned(),
+                text: "hello".to_owned(),
+            },
+        ]);
+        RespCodec::default().encode(inmsg, &mut buf).unwrap();
+
+        let expected = RespValue::Array(vec![
+            RespValue::Array(vec![RespValue::string("number"), RespValue::Nil]),
+            RespValue::Integer(1),
+            RespValue::bulk_string(b"inf".to_vec()),
+            RespValue::bulk_string(b"hello".to_vec()),
+        ]);
+        let outmsg = RespCodec::default().decode(&mut buf).unwrap();
+
+        assert_eq!(Some(expected), outmsg);
+        assert!(buf.is_empty());
+    }
 }
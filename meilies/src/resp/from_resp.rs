@@ -1,4 +1,5 @@
 use super::RespValue;
+use bytes::Bytes;
 use std::fmt;
 use std::string::FromUtf8Error;
 
@@ -42,7 +43,11 @@ impl FromResp for String {
         match value {
             RespValue::SimpleString(string) => Ok(string),
             RespValue::Error(string) => Ok(string),
-            RespValue::BulkString(bytes) => String::from_utf8(bytes).map_err(InvalidUtf8String),
+            RespValue::BulkString(bytes) => {
+                String::from_utf8(bytes.to_vec()).map_err(InvalidUtf8String)
+            }
+            RespValue::BigNumber(string) => Ok(string),
+            RespValue::Verbatim { text, .. } => Ok(text),
             _ => Err(InvalidRespType),
         }
     }
@@ -90,19 +95,28 @@ impl fmt::Display for RespBytesConvertError {
     }
 }
 
-impl FromResp for Vec<u8> {
+impl FromResp for Bytes {
     type Error = RespBytesConvertError;
 
     fn from_resp(value: RespValue) -> Result<Self, Self::Error> {
         match value {
-            RespValue::SimpleString(string) => Ok(string.into_bytes()),
-            RespValue::Error(string) => Ok(string.into_bytes()),
+            RespValue::SimpleString(string) => Ok(string.into()),
+            RespValue::Error(string) => Ok(string.into()),
             RespValue::BulkString(bytes) => Ok(bytes),
+            RespValue::Verbatim { text, .. } => Ok(text.into()),
             _ => Err(RespBytesConvertError::InvalidRespType),
         }
     }
 }
 
+impl FromResp for Vec<u8> {
+    type Error = RespBytesConvertError;
+
+    fn from_resp(value: RespValue) -> Result<Self, Self::Error> {
+        Bytes::from_resp(value).map(|bytes| bytes.to_vec())
+    }
+}
+
 #[derive(Debug)]
 pub enum RespVecConvertError<E> {
     InvalidRespType,
@@ -125,7 +139,7 @@ impl<T: FromResp> FromResp for Vec<T> {
     fn from_resp(value: RespValue) -> Result<Self, Self::Error> {
         use RespVecConvertError::*;
         match value {
-            RespValue::Array(array) => {
+            RespValue::Array(array) | RespValue::Set(array) | RespValue::Push(array) => {
                 let result: Result<Vec<_>, _> =
                     array.into_iter().map(|e| T::from_resp(e)).collect();
                 result.map_err(InnerRespConvertError)
@@ -2,9 +2,9 @@ mod codec;
 mod from_resp;
 mod resp_value;
 
-pub use self::codec::{RespCodec, RespMsgError};
+pub use self::codec::{Protocol, RespCodec, RespLimits, RespMsgError};
 pub use self::from_resp::{
     FromResp, RespBytesConvertError, RespIntConvertError, RespStringConvertError,
     RespVecConvertError,
 };
-pub use self::resp_value::RespValue;
+pub use self::resp_value::{Double, RespValue};
@@ -1,15 +1,39 @@
 use std::{fmt, str};
 
+use bytes::Bytes;
+
+/// A RESP value, the variants after `Nil` are only sent as-is to the clients
+/// that switched to RESP3, they are converted to RESP2 types for the others.
 #[derive(Clone, PartialEq, Eq)]
 pub enum RespValue {
     SimpleString(String),
     Error(String),
     Integer(i64),
-    BulkString(Vec<u8>),
+    BulkString(Bytes),
     Array(Vec<RespValue>),
     Nil,
+    Map(Vec<(RespValue, RespValue)>),
+    Set(Vec<RespValue>),
+    Push(Vec<RespValue>),
+    Boolean(bool),
+    Double(Double),
+    BigNumber(String),
+    Verbatim { format: String, text: String },
+}
+
+/// A RESP3 double, doubles are compared by their bits: a `NaN` equals
+/// itself, `0.0` and `-0.0` differ, this way a `RespValue` is `Eq`.
+#[derive(Debug, Copy, Clone)]
+pub struct Double(pub f64);
+
+impl PartialEq for Double {
+    fn eq(&self, other: &Double) -> bool {
+        self.0.to_bits() == other.0.to_bits()
+    }
 }
 
+impl Eq for Double {}
+
 impl RespValue {
     pub fn string(string: impl fmt::Display) -> RespValue {
         RespValue::SimpleString(string.to_string())
@@ -19,7 +43,7 @@ impl RespValue {
         RespValue::Error(string.to_string())
     }
 
-    pub fn bulk_string(string: impl Into<Vec<u8>>) -> RespValue {
+    pub fn bulk_string(string: impl Into<Bytes>) -> RespValue {
         RespValue::BulkString(string.into())
     }
 }
@@ -29,7 +53,8 @@ impl PartialEq<&'_ str> for RespValue {
         match self {
             RespValue::SimpleString(string) => string == other,
             RespValue::Error(error) => error == other,
-            RespValue::BulkString(bytes) => bytes.as_slice() == other.as_bytes(),
+            RespValue::BulkString(bytes) => bytes.as_ref() == other.as_bytes(),
+            RespValue::Verbatim { text, .. } => text == other,
             _ => false,
         }
     }
@@ -67,6 +92,17 @@ impl fmt::Debug for RespValue {
             }
             RespValue::Array(elements) => fmt.debug_tuple("Array").field(&elements).finish(),
             RespValue::Nil => fmt.debug_tuple("Nil").finish(),
+            RespValue::Map(pairs) => fmt.debug_tuple("Map").field(&pairs).finish(),
+            RespValue::Set(elements) => fmt.debug_tuple("Set").field(&elements).finish(),
+            RespValue::Push(elements) => fmt.debug_tuple("Push").field(&elements).finish(),
+            RespValue::Boolean(boolean) => fmt.debug_tuple("Boolean").field(&boolean).finish(),
+            RespValue::Double(double) => fmt.debug_tuple("Double").field(&double.0).finish(),
+            RespValue::BigNumber(number) => fmt.debug_tuple("BigNumber").field(&number).finish(),
+            RespValue::Verbatim { format, text } => fmt
+                .debug_struct("Verbatim")
+                .field("format", &format)
+                .field("text", &text)
+                .finish(),
         }
     }
 }
@@ -1,8 +1,10 @@
 use crate::resp::{FromResp, RespBytesConvertError, RespValue};
+use bytes::Bytes;
 use std::{fmt, str};
 
+/// The data of an event, it shares the memory of the message it has been decoded from.
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct EventData(pub Vec<u8>);
+pub struct EventData(pub Bytes);
 
 impl fmt::Debug for EventData {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
@@ -21,8 +23,8 @@ impl FromResp for EventData {
 
     fn from_resp(value: RespValue) -> Result<Self, Self::Error> {
         match value {
-            RespValue::SimpleString(string) => Ok(EventData(string.into_bytes())),
-            RespValue::Error(string) => Ok(EventData(string.into_bytes())),
+            RespValue::SimpleString(string) => Ok(EventData(string.into())),
+            RespValue::Error(string) => Ok(EventData(string.into())),
             RespValue::BulkString(bytes) => Ok(EventData(bytes)),
             _ => Err(RespBytesConvertError::InvalidRespType),
         }
@@ -0,0 +1,129 @@
+use std::fmt;
+use std::str::FromStr;
+
+use crate::stream::EventName;
+
+/// The event names a subscription is interested in, an event is sent to the
+/// subscriber only if its name matches one of the patterns.
+///
+/// A pattern is either an event name or a glob where `*` matches any sequence
+/// of characters (e.g. `User*`). The patterns are separated by commas.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EventFilter(Vec<String>);
+
+impl EventFilter {
+    pub fn new(patterns: Vec<String>) -> Result<EventFilter, EventFilterError> {
+        if patterns.is_empty() {
+            return Err(EventFilterError::NoPattern);
+        }
+
+        for pattern in &patterns {
+            if pattern.is_empty() {
+                return Err(EventFilterError::EmptyPattern);
+            }
+            if pattern.contains(&[',', '[', ']'][..]) {
+                return Err(EventFilterError::InvalidCharacter);
+            }
+        }
+
+        Ok(EventFilter(patterns))
+    }
+
+    pub fn patterns(&self) -> &[String] {
+        &self.0
+    }
+
+    pub fn matches(&self, event_name: &EventName) -> bool {
+        self.0
+            .iter()
+            .any(|pattern| glob_matches(pattern.as_bytes(), event_name.as_str().as_bytes()))
+    }
+}
+
+/// Matches a name against a pattern where `*` matches any sequence of bytes,
+/// backtracking to the last star when a byte does not match.
+fn glob_matches(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    let mut star = None;
+
+    while n < name.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            star = Some((p, n));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == name[n] {
+            p += 1;
+            n += 1;
+        } else if let Some((star_p, star_n)) = star {
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+impl fmt::Display for EventFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0.join(","))
+    }
+}
+
+impl FromStr for EventFilter {
+    type Err = EventFilterError;
+
+    fn from_str(s: &str) -> Result<EventFilter, Self::Err> {
+        let patterns = s.split(',').map(ToOwned::to_owned).collect();
+        EventFilter::new(patterns)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EventFilterError {
+    NoPattern,
+    EmptyPattern,
+    InvalidCharacter,
+}
+
+impl fmt::Display for EventFilterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use EventFilterError::*;
+        match self {
+            NoPattern => f.write_str("filter must contain at least one pattern"),
+            EmptyPattern => f.write_str("filter pattern can not be empty"),
+            InvalidCharacter => f.write_str("filter pattern can not contain commas or brackets"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(name: &str) -> EventName {
+        EventName::new(name.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn match_event_names() {
+        let filter = EventFilter::from_str("UserRegistered,Order*").unwrap();
+        assert!(filter.matches(&name("UserRegistered")));
+        assert!(filter.matches(&name("Order")));
+        assert!(filter.matches(&name("OrderPlaced")));
+        assert!(!filter.matches(&name("UserRegisteredAgain")));
+        assert!(!filter.matches(&name("PlacedOrder")));
+
+        let filter = EventFilter::from_str("*Placed,a*b*c").unwrap();
+        assert!(filter.matches(&name("OrderPlaced")));
+        assert!(!filter.matches(&name("OrderPlacedTwice")));
+        assert!(filter.matches(&name("abbbc")));
+        assert!(filter.matches(&name("axbxcxc")));
+        assert!(!filter.matches(&name("axbxcx")));
+
+        assert_eq!(filter.to_string(), "*Placed,a*b*c");
+        assert!(EventFilter::from_str("").is_err());
+        assert!(EventFilter::from_str("a,,b").is_err());
+    }
+}
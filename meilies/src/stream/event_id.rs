@@ -0,0 +1,82 @@
+use std::fmt;
+use std::str::FromStr;
+use std::string::FromUtf8Error;
+
+use uuid::Uuid;
+
+use crate::resp::{FromResp, RespStringConvertError, RespValue};
+
+/// The unique id of an event, given by the publisher or generated by the server.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EventId(pub Uuid);
+
+impl EventId {
+    /// Generates a new random id.
+    pub fn new_v4() -> EventId {
+        EventId(Uuid::new_v4())
+    }
+
+    /// The id of the events stored before ids existed.
+    pub fn nil() -> EventId {
+        EventId(Uuid::nil())
+    }
+
+    pub fn from_bytes(bytes: [u8; 16]) -> EventId {
+        EventId(Uuid::from_bytes(bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8; 16] {
+        self.0.as_bytes()
+    }
+}
+
+impl fmt::Display for EventId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl FromStr for EventId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<EventId, Self::Err> {
+        Uuid::parse_str(s).map(EventId)
+    }
+}
+
+impl Into<RespValue> for EventId {
+    fn into(self) -> RespValue {
+        RespValue::bulk_string(self.to_string())
+    }
+}
+
+#[derive(Debug)]
+pub enum RespEventIdConvertError {
+    InvalidRespType,
+    InvalidUtf8String(FromUtf8Error),
+    InnerEventIdConvertError(uuid::Error),
+}
+
+impl fmt::Display for RespEventIdConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use RespEventIdConvertError::*;
+        match self {
+            InvalidRespType => write!(f, "invalid RESP type found, expected String"),
+            InvalidUtf8String(e) => write!(f, "invalid UTF8 string; {}", e),
+            InnerEventIdConvertError(e) => write!(f, "inner EventId convert error: {}", e),
+        }
+    }
+}
+
+impl FromResp for EventId {
+    type Error = RespEventIdConvertError;
+
+    fn from_resp(value: RespValue) -> Result<Self, Self::Error> {
+        use RespEventIdConvertError::*;
+        match String::from_resp(value) {
+            Ok(string) => EventId::from_str(&string).map_err(InnerEventIdConvertError),
+            Err(RespStringConvertError::InvalidRespType) => Err(InvalidRespType),
+            Err(RespStringConvertError::InvalidUtf8String(error)) => Err(InvalidUtf8String(error)),
+        }
+    }
+}
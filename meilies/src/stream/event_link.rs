@@ -0,0 +1,66 @@
+use std::fmt;
+
+use crate::resp::{FromResp, RespValue};
+use crate::stream::{EventNumber, StreamName};
+
+/// Where an event has been read from when it is not the stream it was published to,
+/// like the position of the event in the `$all` stream.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct EventLink {
+    pub stream: StreamName,
+    pub number: EventNumber,
+}
+
+impl Into<RespValue> for EventLink {
+    fn into(self) -> RespValue {
+        RespValue::Array(vec![
+            RespValue::string(self.stream),
+            RespValue::Integer(self.number.0 as i64),
+        ])
+    }
+}
+
+#[derive(Debug)]
+pub enum RespEventLinkConvertError {
+    InvalidRespType,
+    MissingField(&'static str),
+    InvalidField(&'static str),
+}
+
+impl fmt::Display for RespEventLinkConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use RespEventLinkConvertError::*;
+        match self {
+            InvalidRespType => write!(f, "invalid RESP type found, expected Array"),
+            MissingField(field) => write!(f, "missing {} field", field),
+            InvalidField(field) => write!(f, "invalid {} field", field),
+        }
+    }
+}
+
+impl FromResp for EventLink {
+    type Error = RespEventLinkConvertError;
+
+    fn from_resp(value: RespValue) -> Result<Self, Self::Error> {
+        use RespEventLinkConvertError::*;
+
+        let mut iter = match value {
+            RespValue::Array(elements) => elements.into_iter(),
+            _otherwise => return Err(InvalidRespType),
+        };
+
+        let stream = iter
+            .next()
+            .map(StreamName::from_resp)
+            .ok_or(MissingField("stream"))?
+            .map_err(|_| InvalidField("stream"))?;
+
+        let number = iter
+            .next()
+            .map(EventNumber::from_resp)
+            .ok_or(MissingField("number"))?
+            .map_err(|_| InvalidField("number"))?;
+
+        Ok(EventLink { stream, number })
+    }
+}
@@ -0,0 +1,171 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use super::EventId;
+use crate::resp::{FromResp, RespValue};
+
+/// The content type of the events published without one.
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// Arbitrary key/value pairs stored with an event (e.g. correlation and causation ids).
+pub type Headers = BTreeMap<String, String>;
+
+/// What is stored along with the name and the data of an event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventMetadata {
+    pub id: EventId,
+    /// The number of milliseconds since the unix epoch when the server stored the event.
+    pub timestamp: u64,
+    pub content_type: String,
+    pub headers: Headers,
+}
+
+impl EventMetadata {
+    /// The metadata of the events stored before metadata existed,
+    /// they have a nil id and a zero timestamp.
+    pub fn legacy() -> EventMetadata {
+        EventMetadata {
+            id: EventId::nil(),
+            timestamp: 0,
+            content_type: DEFAULT_CONTENT_TYPE.to_owned(),
+            headers: Headers::new(),
+        }
+    }
+}
+
+/// The metadata a publisher can give to an event, the server completes it.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct PublishMetadata {
+    /// The id of the event, generated by the server if not given.
+    pub id: Option<EventId>,
+    /// The content type of the data, `DEFAULT_CONTENT_TYPE` if not given.
+    pub content_type: Option<String>,
+    pub headers: Headers,
+}
+
+impl PublishMetadata {
+    /// Completes the metadata with the time the event is stored at.
+    pub fn complete(self, timestamp: u64) -> EventMetadata {
+        EventMetadata {
+            id: self.id.unwrap_or_else(EventId::new_v4),
+            timestamp,
+            content_type: self
+                .content_type
+                .unwrap_or_else(|| DEFAULT_CONTENT_TYPE.to_owned()),
+            headers: self.headers,
+        }
+    }
+}
+
+fn headers_into_resp(headers: Headers) -> RespValue {
+    let pairs = headers
+        .into_iter()
+        .map(|(k, v)| (RespValue::bulk_string(k), RespValue::bulk_string(v)))
+        .collect();
+    RespValue::Map(pairs)
+}
+
+/// Maps are received as arrays of keys and values by the RESP2 clients.
+fn into_pairs(value: RespValue) -> Option<Vec<(RespValue, RespValue)>> {
+    match value {
+        RespValue::Map(pairs) => Some(pairs),
+        RespValue::Array(ref elements) if elements.len() % 2 != 0 => None,
+        RespValue::Array(elements) => {
+            let mut pairs = Vec::with_capacity(elements.len() / 2);
+            let mut elements = elements.into_iter();
+            while let (Some(key), Some(value)) = (elements.next(), elements.next()) {
+                pairs.push((key, value));
+            }
+            Some(pairs)
+        }
+        _ => None,
+    }
+}
+
+impl Into<RespValue> for EventMetadata {
+    fn into(self) -> RespValue {
+        RespValue::Map(vec![
+            (RespValue::string("id"), self.id.into()),
+            (
+                RespValue::string("timestamp"),
+                RespValue::Integer(self.timestamp as i64),
+            ),
+            (
+                RespValue::string("content-type"),
+                RespValue::bulk_string(self.content_type),
+            ),
+            (
+                RespValue::string("headers"),
+                headers_into_resp(self.headers),
+            ),
+        ])
+    }
+}
+
+#[derive(Debug)]
+pub enum RespEventMetadataConvertError {
+    InvalidRespType,
+    MissingField(&'static str),
+    InvalidField(&'static str),
+}
+
+impl fmt::Display for RespEventMetadataConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use RespEventMetadataConvertError::*;
+        match self {
+            InvalidRespType => write!(f, "invalid RESP type found, expected Map"),
+            MissingField(field) => write!(f, "missing {} field", field),
+            InvalidField(field) => write!(f, "invalid {} field", field),
+        }
+    }
+}
+
+impl FromResp for EventMetadata {
+    type Error = RespEventMetadataConvertError;
+
+    fn from_resp(value: RespValue) -> Result<Self, Self::Error> {
+        use RespEventMetadataConvertError::*;
+
+        let mut id = None;
+        let mut timestamp = None;
+        let mut content_type = None;
+        let mut headers = None;
+
+        for (key, value) in into_pairs(value).ok_or(InvalidRespType)? {
+            let key = String::from_resp(key).map_err(|_| InvalidRespType)?;
+            match key.as_str() {
+                "id" => {
+                    let value = EventId::from_resp(value).map_err(|_| InvalidField("id"))?;
+                    id = Some(value);
+                }
+                "timestamp" => {
+                    let value = i64::from_resp(value).map_err(|_| InvalidField("timestamp"))?;
+                    timestamp = Some(value as u64);
+                }
+                "content-type" => {
+                    let value =
+                        String::from_resp(value).map_err(|_| InvalidField("content-type"))?;
+                    content_type = Some(value);
+                }
+                "headers" => {
+                    let mut value_headers = Headers::new();
+                    for (k, v) in into_pairs(value).ok_or(InvalidField("headers"))? {
+                        let k = String::from_resp(k).map_err(|_| InvalidField("headers"))?;
+                        let v = String::from_resp(v).map_err(|_| InvalidField("headers"))?;
+                        value_headers.insert(k, v);
+                    }
+                    headers = Some(value_headers);
+                }
+                // newer servers can send more fields
+                _otherwise => (),
+            }
+        }
+
+        Ok(EventMetadata {
+            id: id.ok_or(MissingField("id"))?,
+            timestamp: timestamp.ok_or(MissingField("timestamp"))?,
+            content_type: content_type.ok_or(MissingField("content-type"))?,
+            headers: headers.ok_or(MissingField("headers"))?,
+        })
+    }
+}
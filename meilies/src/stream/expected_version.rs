@@ -0,0 +1,149 @@
+use std::fmt;
+use std::num::ParseIntError;
+use std::str::FromStr;
+use std::string::FromUtf8Error;
+
+use crate::resp::{FromResp, RespStringConvertError, RespValue};
+use crate::stream::EventNumber;
+
+/// The version a stream must be at for an event to be appended to it.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum ExpectedVersion {
+    /// The event is appended whatever the stream version is.
+    Any,
+    /// The stream must not contain any event.
+    NoStream,
+    /// The last event number of the stream must be this one.
+    Exact(EventNumber),
+}
+
+impl ExpectedVersion {
+    /// Returns `true` if a stream with this last event number satisfies the expected version.
+    pub fn matches(&self, last: Option<EventNumber>) -> bool {
+        match (self, last) {
+            (ExpectedVersion::Any, _) => true,
+            (ExpectedVersion::NoStream, None) => true,
+            (ExpectedVersion::Exact(expected), Some(last)) => *expected == last,
+            (_, _) => false,
+        }
+    }
+}
+
+impl Default for ExpectedVersion {
+    fn default() -> ExpectedVersion {
+        ExpectedVersion::Any
+    }
+}
+
+impl fmt::Display for ExpectedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExpectedVersion::Any => f.write_str("any"),
+            ExpectedVersion::NoStream => f.write_str("no-stream"),
+            ExpectedVersion::Exact(number) => write!(f, "{}", number.0),
+        }
+    }
+}
+
+impl FromStr for ExpectedVersion {
+    type Err = ParseExpectedVersionError;
+
+    fn from_str(s: &str) -> Result<ExpectedVersion, Self::Err> {
+        match s {
+            "any" => Ok(ExpectedVersion::Any),
+            "no-stream" => Ok(ExpectedVersion::NoStream),
+            number => match u64::from_str(number) {
+                Ok(number) => Ok(ExpectedVersion::Exact(EventNumber(number))),
+                Err(e) => Err(ParseExpectedVersionError(e)),
+            },
+        }
+    }
+}
+
+impl Into<RespValue> for ExpectedVersion {
+    fn into(self) -> RespValue {
+        RespValue::bulk_string(self.to_string())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseExpectedVersionError(ParseIntError);
+
+impl fmt::Display for ParseExpectedVersionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "expected version must be \"any\", \"no-stream\" or an event number; {}",
+            self.0
+        )
+    }
+}
+
+#[derive(Debug)]
+pub enum RespExpectedVersionConvertError {
+    InvalidRespType,
+    InvalidUtf8String(FromUtf8Error),
+    InnerExpectedVersionConvertError(ParseExpectedVersionError),
+}
+
+impl fmt::Display for RespExpectedVersionConvertError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use RespExpectedVersionConvertError::*;
+        match self {
+            InvalidRespType => write!(f, "invalid RESP type found, expected String or Integer"),
+            InvalidUtf8String(e) => write!(f, "invalid UTF8 string; {}", e),
+            InnerExpectedVersionConvertError(e) => {
+                write!(f, "inner ExpectedVersion convert error: {}", e)
+            }
+        }
+    }
+}
+
+impl FromResp for ExpectedVersion {
+    type Error = RespExpectedVersionConvertError;
+
+    fn from_resp(value: RespValue) -> Result<Self, Self::Error> {
+        use RespExpectedVersionConvertError::*;
+        match value {
+            RespValue::Integer(number) if number >= 0 => {
+                Ok(ExpectedVersion::Exact(EventNumber(number as u64)))
+            }
+            value => match String::from_resp(value) {
+                Ok(string) => {
+                    ExpectedVersion::from_str(&string).map_err(InnerExpectedVersionConvertError)
+                }
+                Err(RespStringConvertError::InvalidRespType) => Err(InvalidRespType),
+                Err(RespStringConvertError::InvalidUtf8String(e)) => Err(InvalidUtf8String(e)),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_expected_version() {
+        assert_eq!("any".parse(), Ok(ExpectedVersion::Any));
+        assert_eq!("no-stream".parse(), Ok(ExpectedVersion::NoStream));
+        assert_eq!("12".parse(), Ok(ExpectedVersion::Exact(EventNumber(12))));
+
+        assert!("".parse::<ExpectedVersion>().is_err());
+        assert!("-1".parse::<ExpectedVersion>().is_err());
+        assert!("stream".parse::<ExpectedVersion>().is_err());
+    }
+
+    #[test]
+    fn match_expected_version() {
+        assert!(ExpectedVersion::Any.matches(None));
+        assert!(ExpectedVersion::Any.matches(Some(EventNumber(4))));
+
+        assert!(ExpectedVersion::NoStream.matches(None));
+        assert!(!ExpectedVersion::NoStream.matches(Some(EventNumber(0))));
+
+        assert!(ExpectedVersion::Exact(EventNumber(4)).matches(Some(EventNumber(4))));
+        assert!(!ExpectedVersion::Exact(EventNumber(4)).matches(Some(EventNumber(5))));
+        assert!(!ExpectedVersion::Exact(EventNumber(0)).matches(None));
+    }
+}
@@ -1,14 +1,35 @@
 mod event_data;
+mod event_filter;
+mod event_id;
+mod event_link;
+mod event_metadata;
 mod event_name;
 mod event_number;
+mod expected_version;
+mod group_name;
 mod raw_event;
+mod retention_policy;
 mod stream;
 mod stream_name;
 
 pub use self::event_data::EventData;
+pub use self::event_filter::{EventFilter, EventFilterError};
+pub use self::event_id::{EventId, RespEventIdConvertError};
+pub use self::event_link::{EventLink, RespEventLinkConvertError};
+pub use self::event_metadata::{
+    EventMetadata, Headers, PublishMetadata, RespEventMetadataConvertError, DEFAULT_CONTENT_TYPE,
+};
 pub use self::event_name::EventName;
 pub use self::event_number::EventNumber;
-pub use self::raw_event::RawEvent;
+pub use self::expected_version::{ExpectedVersion, ParseExpectedVersionError};
+pub use self::group_name::{GroupName, GroupNameError, RespGroupNameConvertError};
+pub use self::raw_event::{RawEvent, ENVELOPE_VERSION};
+pub use self::retention_policy::RetentionPolicy;
 pub use self::stream::{ParseStreamError, ReadRange, Stream};
-pub use self::stream_name::ALL_STREAMS;
 pub use self::stream_name::{StreamName, StreamNameError};
+pub use self::stream_name::{
+    ALL_STREAMS, CATEGORY_STREAM_PREFIX, EVENT_TYPE_STREAM_PREFIX, SYSTEM_STREAM_PREFIX,
+};
+
+/// An event read from a stream along with its number.
+pub type NumberedEvent = (EventNumber, EventName, EventData);
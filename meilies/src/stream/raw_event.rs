@@ -1,40 +1,215 @@
+use std::convert::TryFrom;
 use std::error::Error;
+use std::str;
 
-use super::{EventData, EventName};
+use bytes::Bytes;
+
+use super::{EventData, EventId, EventMetadata, EventName, Headers};
+
+/// The version of the envelope the events are stored in.
+///
+/// The events stored before the envelope only contain the length of their name,
+/// their name and their data. Their first byte is the first byte of the big endian
+/// name length, always zero, they are considered as the version zero.
+pub const ENVELOPE_VERSION: u8 = 1;
 
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct RawEvent<T>(T);
 
+impl RawEvent<Vec<u8>> {
+    /// Encodes an event in the latest envelope: the version, the timestamp, the id,
+    /// the content type, the headers, the name and finally the data.
+    ///
+    /// The strings are prefixed by their length and the headers by their number.
+    pub fn encode(
+        event_name: &EventName,
+        event_data: &EventData,
+        metadata: &EventMetadata,
+    ) -> RawEvent<Vec<u8>> {
+        let mut raw = Vec::with_capacity(64 + event_name.as_str().len() + event_data.0.len());
+
+        raw.push(ENVELOPE_VERSION);
+        raw.extend_from_slice(&metadata.timestamp.to_be_bytes());
+        raw.extend_from_slice(metadata.id.as_bytes());
+        put_string(&mut raw, &metadata.content_type);
+
+        raw.extend_from_slice(&(metadata.headers.len() as u64).to_be_bytes());
+        for (key, value) in &metadata.headers {
+            put_string(&mut raw, key);
+            put_string(&mut raw, value);
+        }
+
+        put_string(&mut raw, event_name.as_str());
+        raw.extend_from_slice(&event_data.0);
+
+        RawEvent(raw)
+    }
+}
+
+fn put_string(raw: &mut Vec<u8>, string: &str) {
+    raw.extend_from_slice(&(string.len() as u64).to_be_bytes());
+    raw.extend_from_slice(string.as_bytes());
+}
+
+/// Reads the parts of an envelope one after the other.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        if self.0.len() < length {
+            return Err("truncated event".into());
+        }
+
+        let (bytes, rest) = self.0.split_at(length);
+        self.0 = rest;
+        Ok(bytes)
+    }
+
+    fn u64(&mut self) -> Result<u64, Box<dyn Error>> {
+        let bytes = <[u8; 8]>::try_from(self.take(8)?)?;
+        Ok(u64::from_be_bytes(bytes))
+    }
+
+    fn string(&mut self) -> Result<&'a str, Box<dyn Error>> {
+        let length = self.u64()?;
+        if length > self.0.len() as u64 {
+            return Err("truncated event".into());
+        }
+        Ok(str::from_utf8(self.take(length as usize)?)?)
+    }
+}
+
+/// The parts of a stored event, the metadata is only decoded if asked for.
+struct Parts<'a> {
+    name: &'a str,
+    data: &'a [u8],
+    metadata: Option<EventMetadata>,
+}
+
 impl<T: AsRef<[u8]>> RawEvent<T> {
     pub fn new(content: T) -> RawEvent<T> {
         RawEvent(content)
     }
 
-    fn name_size(&self) -> usize {
-        // FIXME: prefer using TryFrom
-        let mut event_name_size: [u8; 8] = [0; 8];
-        for (i, b) in self.0.as_ref().iter().enumerate() {
-            if i == 8 {
-                break;
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+
+    /// The version of the envelope, zero for the events stored before the envelope.
+    pub fn version(&self) -> u8 {
+        match self.0.as_ref().first() {
+            Some(&ENVELOPE_VERSION) => ENVELOPE_VERSION,
+            _ => 0,
+        }
+    }
+
+    fn parts(&self, with_metadata: bool) -> Result<Parts<'_>, Box<dyn Error>> {
+        let mut reader = Reader(self.0.as_ref());
+
+        if self.version() == 0 {
+            let name = reader.string()?;
+            let metadata = if with_metadata {
+                Some(EventMetadata::legacy())
+            } else {
+                None
+            };
+            return Ok(Parts {
+                name,
+                data: reader.0,
+                metadata,
+            });
+        }
+
+        reader.take(1)?;
+        let timestamp = reader.u64()?;
+        let id = <[u8; 16]>::try_from(reader.take(16)?)?;
+        let content_type = reader.string()?;
+
+        let mut headers = Headers::new();
+        for _ in 0..reader.u64()? {
+            let key = reader.string()?;
+            let value = reader.string()?;
+            if with_metadata {
+                headers.insert(key.to_owned(), value.to_owned());
             }
-            event_name_size[i] = *b;
         }
-        usize::from_be_bytes(event_name_size)
+
+        let name = reader.string()?;
+        let metadata = if with_metadata {
+            Some(EventMetadata {
+                id: EventId::from_bytes(id),
+                timestamp,
+                content_type: content_type.to_owned(),
+                headers,
+            })
+        } else {
+            None
+        };
+
+        Ok(Parts {
+            name,
+            data: reader.0,
+            metadata,
+        })
     }
 
     // FIXME: Prefer using a typed Error
-    pub fn name(&self) -> Result<EventName, Box<Error>> {
-        let name_size = self.name_size();
-        let raw_name = &self.0.as_ref()[8..(8 + name_size)];
-        let name = String::from_utf8(raw_name.to_owned())?;
+    pub fn name(&self) -> Result<EventName, Box<dyn Error>> {
+        let parts = self.parts(false)?;
+        Ok(EventName::new(parts.name.to_owned())?)
+    }
+
+    pub fn data(&self) -> Result<EventData, Box<dyn Error>> {
+        let parts = self.parts(false)?;
+        Ok(EventData(Bytes::from(parts.data)))
+    }
+
+    pub fn metadata(&self) -> Result<EventMetadata, Box<dyn Error>> {
+        let parts = self.parts(true)?;
+        Ok(parts.metadata.unwrap_or_else(EventMetadata::legacy))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode_envelope() {
+        let name = EventName::new("my-event".to_owned()).unwrap();
+        let data = EventData("hello".into());
+
+        let mut headers = Headers::new();
+        headers.insert("correlation-id".to_owned(), "42".to_owned());
+        let metadata = EventMetadata {
+            id: EventId::new_v4(),
+            timestamp: 1_571_000_000_000,
+            content_type: "application/json".to_owned(),
+            headers,
+        };
+
+        let raw = RawEvent::encode(&name, &data, &metadata);
+        assert_eq!(raw.version(), ENVELOPE_VERSION);
+        assert_eq!(raw.name().unwrap(), name);
+        assert_eq!(raw.data().unwrap(), data);
+        assert_eq!(raw.metadata().unwrap(), metadata);
 
-        Ok(EventName::new(name)?)
+        let raw = raw.into_inner();
+        let truncated = RawEvent::new(&raw[..30]);
+        assert!(truncated.name().is_err());
     }
 
-    pub fn data(&self) -> EventData {
-        let name_size = self.name_size();
-        let raw_name = &self.0.as_ref()[(8 + name_size)..];
+    #[test]
+    fn decode_legacy_event() {
+        let mut raw = Vec::new();
+        raw.extend_from_slice(&8usize.to_be_bytes());
+        raw.extend_from_slice(b"my-event");
+        raw.extend_from_slice(b"hello");
 
-        EventData(raw_name.to_owned())
+        let raw = RawEvent::new(raw);
+        assert_eq!(raw.version(), 0);
+        assert_eq!(raw.name().unwrap().as_str(), "my-event");
+        assert_eq!(raw.data().unwrap().0, b"hello".to_vec());
+        assert_eq!(raw.metadata().unwrap(), EventMetadata::legacy());
     }
 }
@@ -0,0 +1,16 @@
+/// How long the events of a stream are kept, the server removes
+/// the events exceeding any of the limits in the background.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, Hash)]
+pub struct RetentionPolicy {
+    /// The number of events kept, the oldest ones are removed first.
+    pub max_count: Option<u64>,
+    /// The number of seconds the events are kept after being published.
+    pub max_age: Option<u64>,
+}
+
+impl RetentionPolicy {
+    /// Whether the events are kept indefinitely.
+    pub fn is_unlimited(&self) -> bool {
+        self.max_count.is_none() && self.max_age.is_none()
+    }
+}
@@ -4,7 +4,7 @@ use std::str::FromStr;
 use std::string::FromUtf8Error;
 
 use crate::resp::{FromResp, RespStringConvertError, RespValue};
-use crate::stream::{StreamName, StreamNameError};
+use crate::stream::{EventFilter, EventFilterError, StreamName, StreamNameError};
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub enum ReadRange {
@@ -39,10 +39,15 @@ impl fmt::Display for ReadRange {
     }
 }
 
+/// A stream to subscribe to, written `name[:from[:to]][[filter]]`
+/// (e.g. `order-1:0[OrderPlaced,Order*]`).
+///
+/// The events whose names do not match the filter are not sent to the subscriber.
 #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct Stream {
     pub name: StreamName,
     pub range: ReadRange,
+    pub filter: Option<EventFilter>,
 }
 
 impl Stream {
@@ -51,7 +56,11 @@ impl Stream {
     }
 
     pub fn new(name: StreamName, range: ReadRange) -> Stream {
-        Stream { name, range }
+        Stream {
+            name,
+            range,
+            filter: None,
+        }
     }
 
     pub fn new_from_to(name: StreamName, from: Option<u64>, to: Option<u64>) -> Stream {
@@ -60,7 +69,11 @@ impl Stream {
             (Some(from), None) => ReadRange::ReadFrom(from),
             (_, _) => ReadRange::ReadFromEnd,
         };
-        Stream { name, range }
+        Stream::new(name, range)
+    }
+
+    pub fn with_filter(self, filter: Option<EventFilter>) -> Stream {
+        Stream { filter, ..self }
     }
 }
 
@@ -72,23 +85,17 @@ impl fmt::Debug for Stream {
 
 impl fmt::Display for Stream {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self.range {
-            ReadRange::ReadFromUntil(from, to) => write!(f, "{}:{}:{}", self.name, from, to),
-            ReadRange::ReadFrom(from) => write!(f, "{}:{}", self.name, from),
-            ReadRange::ReadFromEnd => write!(f, "{}", self.name),
+        write!(f, "{}{}", self.name, self.range)?;
+        match &self.filter {
+            Some(filter) => write!(f, "[{}]", filter),
+            None => Ok(()),
         }
     }
 }
 
 impl Into<RespValue> for Stream {
     fn into(self) -> RespValue {
-        let text = match self.range {
-            ReadRange::ReadFromUntil(from, to) => format!("{}:{}:{}", self.name, from, to),
-            ReadRange::ReadFrom(from) => format!("{}:{}", self.name, from),
-            ReadRange::ReadFromEnd => format!("{}", self.name),
-        };
-
-        RespValue::BulkString(text.into_bytes())
+        RespValue::bulk_string(self.to_string())
     }
 }
 
@@ -124,10 +131,7 @@ impl FromResp for Stream {
 
 impl From<StreamName> for Stream {
     fn from(name: StreamName) -> Stream {
-        Stream {
-            name,
-            range: ReadRange::ReadFromEnd,
-        }
+        Stream::new(name, ReadRange::ReadFromEnd)
     }
 }
 
@@ -137,19 +141,25 @@ impl FromStr for Stream {
     fn from_str(s: &str) -> Result<Stream, Self::Err> {
         use ParseStreamError::*;
 
+        let (s, filter) = match s.rfind('[') {
+            Some(index) if s.ends_with(']') => {
+                let filter = &s[index + 1..s.len() - 1];
+                let filter = EventFilter::from_str(filter).map_err(FilterError)?;
+                (&s[..index], Some(filter))
+            }
+            _ => (s, None),
+        };
+
         let mut split = s.split(':');
-        match (split.next(), split.next(), split.next(), split.next()) {
+        let stream = match (split.next(), split.next(), split.next(), split.next()) {
             (Some(name), None, None, None) => {
                 let name = StreamName::from_str(name).map_err(StreamNameError)?;
-                Ok(Stream::from(name))
+                Stream::from(name)
             }
             (Some(name), Some(from), None, None) => {
                 let name = StreamName::new(name.to_owned()).map_err(StreamNameError)?;
                 let number = u64::from_str_radix(from, 10).map_err(StartFromError)?;
-                Ok(Stream {
-                    name,
-                    range: ReadRange::ReadFrom(number),
-                })
+                Stream::new(name, ReadRange::ReadFrom(number))
             }
             (Some(name), Some(from), Some(to), None) => {
                 let name = StreamName::new(name.to_owned()).map_err(StreamNameError)?;
@@ -158,13 +168,12 @@ impl FromStr for Stream {
                 if from >= to {
                     return Err(BoundsError);
                 }
-                Ok(Stream {
-                    name,
-                    range: ReadRange::ReadFromUntil(from, to),
-                })
+                Stream::new(name, ReadRange::ReadFromUntil(from, to))
             }
-            (_, _, _, _) => Err(FormatError),
-        }
+            (_, _, _, _) => return Err(FormatError),
+        };
+
+        Ok(stream.with_filter(filter))
     }
 }
 
@@ -173,6 +182,7 @@ pub enum ParseStreamError {
     StreamNameError(StreamNameError),
     StartFromError(ParseIntError),
     EndToError(ParseIntError),
+    FilterError(EventFilterError),
     BoundsError,
     FormatError,
 }
@@ -185,6 +195,7 @@ impl fmt::Display for ParseStreamError {
             StreamNameError(e) => write!(f, "stream not properly formatted; {}", e),
             StartFromError(e) => write!(f, "stream \"start from\" not properly formatted; {}", e),
             EndToError(e) => write!(f, "stream \"end to\" not properly formatted; {}", e),
+            FilterError(e) => write!(f, "stream filter not properly formatted; {}", e),
             BoundsError => f.write_str("The end bound must be greater than the start bound"),
             FormatError => f.write_str("stream is not properly formatted"),
         }
@@ -256,4 +267,51 @@ mod tests {
         let result = Stream::from_str("default:1:0");
         assert!(result.is_err());
     }
+
+    #[test]
+    fn create_system_stream_from_str() {
+        let stream = Stream::from_str("$ce-order:12").unwrap();
+        assert_eq!(stream.name, "$ce-order");
+        assert!(stream.name.is_system());
+        assert_eq!(stream.range, ReadRange::ReadFrom(12));
+
+        let stream = Stream::from_str("$all").unwrap();
+        assert_eq!(stream.name, StreamName::all());
+
+        let stream = Stream::from_str("$et-UserRegistered:0").unwrap();
+        assert_eq!(stream.name, "$et-UserRegistered");
+        assert!(stream.name.is_system());
+
+        assert!(Stream::from_str("$ce-").is_err());
+        assert!(Stream::from_str("$et-").is_err());
+        assert!(Stream::from_str("$unknown:0").is_err());
+
+        let name = StreamName::new("order-123".to_owned()).unwrap();
+        let category = name.category_stream('-').unwrap();
+        assert_eq!(category, "$ce-order");
+        assert!(name.category_stream('_').is_none());
+        assert!(category.category_stream('-').is_none());
+    }
+
+    #[test]
+    fn create_filtered_stream_from_str() {
+        let stream = Stream::from_str("order-1:5[OrderPlaced,Order*]").unwrap();
+        assert_eq!(stream.name, "order-1");
+        assert_eq!(stream.range, ReadRange::ReadFrom(5));
+        let filter = stream.filter.clone().unwrap();
+        assert_eq!(filter.patterns(), ["OrderPlaced", "Order*"]);
+        assert_eq!(stream.to_string(), "order-1:5[OrderPlaced,Order*]");
+
+        let stream = Stream::from_str("$all[User*]").unwrap();
+        assert_eq!(stream.name, StreamName::all());
+        assert_eq!(stream.range, ReadRange::ReadFromEnd);
+        assert!(stream.filter.is_some());
+
+        let stream = Stream::from_str("order[1]:2").unwrap();
+        assert_eq!(stream.name, "order[1]");
+        assert!(stream.filter.is_none());
+
+        assert!(Stream::from_str("order-1:0[]").is_err());
+        assert!(Stream::from_str("order-1:0[a,]").is_err());
+    }
 }
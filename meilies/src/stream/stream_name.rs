@@ -3,9 +3,19 @@ use std::str::FromStr;
 use std::string::FromUtf8Error;
 
 use crate::resp::{FromResp, RespStringConvertError, RespValue};
+use crate::stream::EventName;
 
 pub const ALL_STREAMS: &str = "$all";
 
+/// The prefix of the streams maintained by the server, like `$all`.
+pub const SYSTEM_STREAM_PREFIX: char = '$';
+
+/// The prefix of the category streams, followed by the category name (e.g. `$ce-order`).
+pub const CATEGORY_STREAM_PREFIX: &str = "$ce-";
+
+/// The prefix of the event type streams, followed by the event name (e.g. `$et-UserRegistered`).
+pub const EVENT_TYPE_STREAM_PREFIX: &str = "$et-";
+
 #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct StreamName(String);
 
@@ -23,9 +33,49 @@ impl StreamName {
             return Err(StreamNameError::ContainColon);
         }
 
+        if name.starts_with(SYSTEM_STREAM_PREFIX) && !is_known_system_stream(&name) {
+            return Err(StreamNameError::UnknownSystemStream);
+        }
+
         Ok(StreamName(name))
     }
 
+    /// The category stream linking the events of this stream, named after the part
+    /// of the name before the first separator (e.g. `$ce-order` for `order-123`).
+    ///
+    /// Returns `None` for the system streams and the names without a category.
+    pub fn category_stream(&self, separator: char) -> Option<StreamName> {
+        if self.is_system() {
+            return None;
+        }
+
+        match self.0.find(separator) {
+            Some(0) | None => None,
+            Some(index) => {
+                let category = &self.0[..index];
+                Some(StreamName(format!(
+                    "{}{}",
+                    CATEGORY_STREAM_PREFIX, category
+                )))
+            }
+        }
+    }
+
+    /// The event type stream linking the events with this name whatever their stream.
+    ///
+    /// Returns `None` if the event name contains a colon, like the other stream names
+    /// the name of an event type stream can not contain one.
+    pub fn event_type_stream(event_name: &EventName) -> Option<StreamName> {
+        let name = format!("{}{}", EVENT_TYPE_STREAM_PREFIX, event_name);
+        StreamName::new(name).ok()
+    }
+
+    /// Whether the stream is maintained by the server, its events are links
+    /// to the events of the other streams and can not be published directly.
+    pub fn is_system(&self) -> bool {
+        self.0.starts_with(SYSTEM_STREAM_PREFIX)
+    }
+
     pub fn into_inner(self) -> String {
         self.0
     }
@@ -39,6 +89,11 @@ impl StreamName {
     }
 }
 
+fn is_known_system_stream(name: &str) -> bool {
+    let prefixed = |prefix: &str| name.starts_with(prefix) && name.len() > prefix.len();
+    name == ALL_STREAMS || prefixed(CATEGORY_STREAM_PREFIX) || prefixed(EVENT_TYPE_STREAM_PREFIX)
+}
+
 impl fmt::Display for StreamName {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         self.0.fmt(f)
@@ -93,6 +148,7 @@ impl FromStr for StreamName {
 pub enum StreamNameError {
     EmptyName,
     ContainColon,
+    UnknownSystemStream,
 }
 
 impl fmt::Display for StreamNameError {
@@ -100,6 +156,9 @@ impl fmt::Display for StreamNameError {
         match self {
             StreamNameError::EmptyName => f.write_str("stream name is empty"),
             StreamNameError::ContainColon => f.write_str("stream name contains a colon (:)"),
+            StreamNameError::UnknownSystemStream => {
+                f.write_str("stream name starts with a $ but is not a system stream")
+            }
         }
     }
 }